@@ -7,11 +7,11 @@ fn main() {
         },
         Windows::Win32::DataExchange::{
             OpenClipboard, CloseClipboard, EmptyClipboard, GetClipboardData, 
-            SetClipboardData
+            SetClipboardData, RegisterClipboardFormatW
         },
         Windows::Win32::KeyboardAndMouseInput::{
             SetCapture, ReleaseCapture, GetKeyState, TrackMouseEvent,
-            TRACKMOUSEEVENT, TRACKMOUSEEVENT_dwFlags
+            TRACKMOUSEEVENT, TRACKMOUSEEVENT_dwFlags, GetDoubleClickTime
         },
         Windows::Win32::Controls::{
             WM_MOUSELEAVE, HOVER_DEFAULT
@@ -23,17 +23,26 @@ fn main() {
             ShowWindow, CreateWindowExW, PostQuitMessage,
             DefWindowProcW, RegisterClassW, LoadCursorW,
             DestroyWindow, GetClientRect, SystemParametersInfoW,
+            MessageBoxW, MESSAGEBOX_STYLE, MESSAGEBOX_RESULT, WM_CLOSE,
+            GetMessageTime, GetSystemMetrics,
             CW_USEDEFAULT, MSG, IDC_ARROW,
             WM_PAINT, WM_SIZE, WM_DESTROY, WM_CHAR, HWND,
-            WM_MOUSEWHEEL, WM_LBUTTONDOWN, WM_ERASEBKGND,
-            WM_LBUTTONUP, WM_KEYDOWN, VK_SHIFT, VK_CONTROL,
+            WM_MOUSEWHEEL, WM_MOUSEHWHEEL, WM_LBUTTONDOWN, WM_ERASEBKGND,
+            WM_LBUTTONUP, WM_KEYDOWN, VK_SHIFT, VK_CONTROL, VK_MENU,
             WM_CREATE, CREATESTRUCTW, WINDOW_LONG_PTR_INDEX,
             WM_MOUSEMOVE, WM_NCDESTROY, SHOW_WINDOW_CMD, WM_LBUTTONDBLCLK,
+            WM_SYSKEYDOWN, WM_SYSCHAR, VK_F4, VK_F12, VK_SPACE, VK_PRIOR, VK_NEXT, VK_HOME, VK_END,
+            SetTimer, KillTimer, WM_TIMER, WM_USER, SendMessageW,
             WINDOW_STYLE, WNDCLASS_STYLES, WNDCLASSW, SIZE_MINIMIZED, 
             WPARAM, LPARAM, SYSTEM_PARAMETERS_INFO_ACTION, VK_LEFT, VK_RIGHT, 
-            VK_UP, VK_DOWN, VK_TAB, VK_RETURN, VK_DELETE, VK_BACK
+            VK_UP, VK_DOWN, VK_TAB, VK_RETURN, VK_DELETE, VK_BACK, VK_ESCAPE, VK_INSERT
         },
         Windows::Win32::Debug::GetLastError,
+        Windows::Win32::Shell::{
+            IFileOpenDialog, FileOpenDialog, IShellItem, SIGDN, 
+            _FILEOPENDIALOGOPTIONS
+        },
+        Windows::Win32::Com::CoTaskMemFree,
         Windows::Win32::Gdi::{
             GetStockObject, BeginPaint, EndPaint, InvalidateRect,
             GetStockObject_iFlags, HBRUSH, PAINTSTRUCT
@@ -49,8 +58,9 @@ fn main() {
             DWRITE_FACTORY_TYPE, DWRITE_FONT_WEIGHT,
             DWRITE_FONT_STYLE, DWRITE_FONT_STRETCH,
             DWRITE_TEXT_ALIGNMENT, DWRITE_PARAGRAPH_ALIGNMENT,
-            DWRITE_TEXT_RANGE, DWRITE_HIT_TEST_METRICS,
-            DWRITE_LINE_SPACING
+            DWRITE_TEXT_RANGE, DWRITE_HIT_TEST_METRICS, DWRITE_TEXT_METRICS,
+            DWRITE_LINE_SPACING, DWRITE_LINE_METRICS,
+            IDWriteTypography, DWRITE_FONT_FEATURE, DWRITE_FONT_FEATURE_TAG
         },
         Windows::Foundation::Numerics::Matrix3x2,
         Windows::Win32::Direct2D::{
@@ -61,7 +71,7 @@ fn main() {
             D2D1_FEATURE_LEVEL, D2D1_BRUSH_PROPERTIES, 
             D2D1_HWND_RENDER_TARGET_PROPERTIES, D2D1_RENDER_TARGET_USAGE,
             D2D1_RENDER_TARGET_TYPE, D2D1_RENDER_TARGET_PROPERTIES,
-            D2D1_FACTORY_TYPE, D2D1_ANTIALIAS_MODE, D2D1_ANTIALIAS_MODE
+            D2D1_FACTORY_TYPE, D2D1_ANTIALIAS_MODE, D2D1_TEXT_ANTIALIAS_MODE
         }
     );
 }
\ No newline at end of file
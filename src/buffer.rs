@@ -1,16 +1,24 @@
 use crate::{
-    settings::{NUMBER_OF_SPACES_PER_TAB, AUTOCOMPLETE_BRACKETS},
-    language_support::{LexicalHighlights, highlight_text},
+    settings::{Settings, IndentStyle, HomeBehavior},
+    language_support::{LexicalHighlights, SemanticTokenTypes, highlight_text, get_comment_tokens, get_electric_characters, get_autoclose_quotes, ElectricAction},
+    lsp_structs::{TextDocumentContentChangeEvent, TextEdit, Position},
+    git_support::{self, MergeConflict, ConflictResolution},
     text_utils
 };
 
 use std::{
     char,
     cmp::{min, max},
-    fs::File,
-    mem::swap,
+    collections::HashMap,
+    fs,
+    io,
+    iter,
+    mem::{swap, take},
+    ops::Range,
     ptr::copy_nonoverlapping,
-    str
+    rc::Rc,
+    str,
+    time::SystemTime
 };
 use bindings::{
     Windows::Win32::SystemServices::*,
@@ -20,6 +28,277 @@ use bindings::{
 
 use ropey::Rope;
 
+#[derive(Copy, Clone, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    Cr
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+            LineEnding::Cr => "\r"
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf8Bom,
+    Utf16Le
+}
+
+// Decodes the contents of a file, the encoding 
+// is identified by the byte order mark if present
+fn decode_text(bytes: &[u8]) -> (String, TextEncoding) {
+    if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        (String::from_utf8_lossy(&bytes[3..]).into_owned(), TextEncoding::Utf8Bom)
+    }
+    else if bytes.starts_with(&[0xFF, 0xFE]) {
+        let code_units: Vec<u16> = bytes[2..].chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+        (String::from_utf16_lossy(&code_units), TextEncoding::Utf16Le)
+    }
+    else {
+        (String::from_utf8_lossy(bytes).into_owned(), TextEncoding::Utf8)
+    }
+}
+
+fn encode_text(text: &str, encoding: TextEncoding) -> Vec<u8> {
+    match encoding {
+        TextEncoding::Utf8 => text.as_bytes().to_vec(),
+        TextEncoding::Utf8Bom => [&[0xEF, 0xBB, 0xBF], text.as_bytes()].concat(),
+        TextEncoding::Utf16Le => {
+            let mut bytes = vec![0xFF, 0xFE];
+            for code_unit in text.encode_utf16() {
+                bytes.extend_from_slice(&code_unit.to_le_bytes());
+            }
+            bytes
+        }
+    }
+}
+
+// Collapses runs of whitespace after the indentation of each line to a single
+// space, drops trailing whitespace and shortens runs of blank lines. Whitespace 
+// inside the protected byte ranges of the text (strings and comments) is kept
+fn normalize_whitespace_in_lines(text: &str, mut protected_ranges: Vec<Range<usize>>, max_blank_lines: usize) -> Vec<String> {
+    protected_ranges.sort_by_key(|range| range.start);
+    let mut range_index = 0;
+    let mut is_protected = |offset: usize| {
+        while range_index < protected_ranges.len() && protected_ranges[range_index].end <= offset {
+            range_index += 1;
+        }
+        range_index < protected_ranges.len() && protected_ranges[range_index].contains(&offset)
+    };
+
+    let mut lines = Vec::new();
+    let mut blank_lines = 0;
+    let mut line_start = 0;
+    for line in text.split('\n') {
+        let line_end = line_start + line.len();
+        let indentation = line.len() - line.trim_start_matches(text_utils::is_whitespace).len();
+
+        let mut normalized = line[..indentation].to_owned();
+        let mut pending_space = false;
+        for (offset, chr) in line[indentation..].char_indices() {
+            if text_utils::is_whitespace(chr) && !is_protected(line_start + indentation + offset) {
+                pending_space = true;
+                continue;
+            }
+            if pending_space {
+                normalized.push(' ');
+                pending_space = false;
+            }
+            normalized.push(chr);
+        }
+
+        // Blank lines inside block comments and strings are left alone
+        let protected_line_end = is_protected(line_end);
+        line_start = line_end + 1;
+        if protected_line_end {
+            lines.push(line.to_owned());
+            blank_lines = 0;
+            continue;
+        }
+
+        if normalized.trim().is_empty() {
+            blank_lines += 1;
+            if blank_lines > max_blank_lines {
+                continue;
+            }
+            normalized.clear();
+        }
+        else {
+            blank_lines = 0;
+        }
+        lines.push(normalized);
+    }
+    lines
+}
+
+// The indentation and full comment marker of a line comment,
+// so doc comments like "///" and "//!" form their own blocks
+fn get_line_comment_prefix(line: &str, comment_marker: &str) -> Option<String> {
+    let indentation = line.len() - line.trim_start().len();
+    let comment = line[indentation..].strip_prefix(comment_marker)?;
+    let marker_length = comment_marker.len() + comment.chars().take_while(|chr| *chr == '/' || *chr == '!').count();
+    Some(line[..indentation + marker_length].to_owned())
+}
+
+// Rewraps the text of comment lines sharing the prefix to the width,
+// empty comment lines separate paragraphs and are kept as they are
+fn reflow_comment_lines(lines: &[String], prefix: &str, width: usize) -> Vec<String> {
+    let mut reflowed_lines = Vec::new();
+    let mut current_line = String::new();
+    for line in lines {
+        let text = line[prefix.len()..].trim();
+        if text.is_empty() {
+            if !current_line.is_empty() {
+                reflowed_lines.push(take(&mut current_line));
+            }
+            reflowed_lines.push(prefix.to_owned());
+            continue;
+        }
+
+        for word in text.split_whitespace() {
+            if !current_line.is_empty() && current_line.chars().count() + 1 + word.chars().count() > width {
+                reflowed_lines.push(take(&mut current_line));
+            }
+            if current_line.is_empty() {
+                current_line.push_str(prefix);
+            }
+            current_line.push(' ');
+            current_line.push_str(word);
+        }
+    }
+    if !current_line.is_empty() {
+        reflowed_lines.push(current_line);
+    }
+    reflowed_lines
+}
+
+// Comments out every non-blank line after its indentation,
+// unless all of them are commented already in which case they are uncommented
+fn toggle_comment_lines(lines: &mut Vec<String>, comment_marker: &str) {
+    let is_commented = |line: &String| line.trim_start().starts_with(comment_marker);
+    let uncomment = lines.iter().filter(|line| !line.trim().is_empty()).all(is_commented);
+
+    for line in lines.iter_mut().filter(|line| !line.trim().is_empty()) {
+        let indentation_length = line.len() - line.trim_start().len();
+        let (indentation, text) = line.split_at(indentation_length);
+        *line = match uncomment {
+            true => {
+                let text = &text[comment_marker.len()..];
+                format!("{}{}", indentation, text.strip_prefix(' ').unwrap_or(text))
+            }
+            false => format!("{}{} {}", indentation, comment_marker, text)
+        };
+    }
+}
+
+fn get_modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// The most common line ending in the text and whether any other line ending
+// is used too, None if the text has no line breaks
+fn detect_line_ending(text: &str) -> Option<(LineEnding, bool)> {
+    let crlf = text.matches("\r\n").count();
+    let lf = text.matches('\n').count() - crlf;
+    let cr = text.matches('\r').count() - crlf;
+
+    let counts = [(LineEnding::Crlf, crlf), (LineEnding::Lf, lf), (LineEnding::Cr, cr)];
+    let used = counts.iter().filter(|(_, count)| *count > 0).count();
+    counts.iter()
+        .filter(|(_, count)| *count > 0)
+        .max_by_key(|(_, count)| *count)
+        .map(|(line_ending, _)| (*line_ending, used > 1))
+}
+
+// The clipboard has to be open. The data is copied
+// with a null terminator into memory owned by the system
+unsafe fn set_clipboard_data(format: u32, data: &str) {
+    let byte_size = data.len() + 1;
+    let clipboard_data_ptr = GlobalAlloc(GlobalAlloc_uFlags::GMEM_ZEROINIT, byte_size);
+    if clipboard_data_ptr == 0 {
+        return;
+    }
+    let memory = GlobalLock(clipboard_data_ptr);
+    if memory.is_null() {
+        GlobalFree(clipboard_data_ptr);
+        return;
+    }
+    // The memory is zero initialized, so the null terminator is already there
+    copy_nonoverlapping(data.as_ptr(), memory as *mut u8, data.len());
+    GlobalUnlock(clipboard_data_ptr);
+
+    // If setting the clipboard data fails, free it
+    // otherwise its now owned by the system
+    if SetClipboardData(format, HANDLE(clipboard_data_ptr)) == HANDLE(0) {
+        GlobalFree(clipboard_data_ptr);
+    }
+}
+
+// Guesses the indentation of a file by sampling the leading whitespace
+// of its lines. Tab indented lines are counted against space indented lines,
+// and the most common increase in indentation between consecutive
+// lines is taken as the indentation width
+fn detect_indentation(rope: &Rope, sample_lines: usize, spaces_per_tab: usize) -> Option<(IndentStyle, usize)> {
+    let mut tab_lines = 0;
+    let mut space_lines = 0;
+    let mut width_counts = [0; 9];
+    let mut previous_indentation = 0;
+
+    for line in rope.lines().take(sample_lines) {
+        let indentation = line.chars().take_while(|chr| *chr == ' ').count();
+        match line.chars().nth(indentation) {
+            // Blank lines say nothing about the indentation
+            None => continue,
+            Some(chr) if text_utils::is_linebreak(chr) => continue,
+            Some('\t') => {
+                // Lines with mixed indentation are ignored
+                if indentation == 0 {
+                    tab_lines += 1;
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        if indentation > 0 {
+            space_lines += 1;
+        }
+        if indentation > previous_indentation {
+            let delta = indentation - previous_indentation;
+            if delta >= 2 && delta < width_counts.len() {
+                width_counts[delta] += 1;
+            }
+        }
+        previous_indentation = indentation;
+    }
+
+    if tab_lines == 0 && space_lines == 0 {
+        return None;
+    }
+    if tab_lines > space_lines {
+        return Some((IndentStyle::Tabs, spaces_per_tab));
+    }
+
+    let mut width = 0;
+    for (delta, count) in width_counts.iter().enumerate() {
+        if *count > 0 && (width == 0 || *count > width_counts[width]) {
+            width = delta;
+        }
+    }
+    if width == 0 {
+        return None;
+    }
+    Some((IndentStyle::Spaces, width))
+}
+
 #[derive(Clone, PartialEq)]
 pub enum SelectionMode {
     Left,
@@ -46,22 +325,38 @@ pub struct TextPosition {
     pub char_offset: usize
 }
 
+// A rectangular selection spanning the columns between
+// the anchor and the caret on every line between them
+#[derive(Copy, Clone, PartialEq)]
+pub struct BlockSelection {
+    anchor: (usize, usize),
+    caret: (usize, usize)
+}
+
 type ShiftDown = bool;
 type CtrlDown = bool;
+// The absolute position of a caret and its anchor
+type Caret = (usize, usize);
+// The number of rows visible in the view, used for paging
+type PageRows = usize;
 
 #[derive(PartialEq)]
 pub enum BufferCommand {
-    LeftClick(TextPosition, ShiftDown),
+    LeftClick(TextPosition, ShiftDown, CtrlDown),
+    LeftAltClick(TextPosition),
     LeftDoubleClick(TextPosition),
+    LeftTripleClick(TextPosition),
     LeftRelease,
     SetMouseSelection(TextPosition),
-    KeyPressed(u32, ShiftDown, CtrlDown, HWND),
+    KeyPressed(u32, ShiftDown, CtrlDown, HWND, PageRows),
+    AltKeyPressed(u32, ShiftDown),
     CharInsert(u16)
 }
 
 #[derive(Clone, PartialEq)]
 pub struct BufferState {
     rope: Rope,
+    contents_version: i32,
 
     caret_char_anchor: usize,
     caret_char_pos: usize,
@@ -73,43 +368,156 @@ pub struct BufferState {
 pub struct TextBuffer {
     pub path: String,
 
+    // Scratch buffers are not backed by a file,
+    // their path is only a name for the tab
+    pub scratch: bool,
+
+    settings: Rc<Settings>,
+
     // The language of the text buffer as
     // identified by its extension
     pub language_identifier: &'static str,
 
+    // The indentation used when inserting tabs and
+    // auto indenting, detected from the file contents
+    pub indent_style: IndentStyle,
+    pub indent_width: usize,
+    // The number of columns between tab stops
+    pub tab_width: usize,
+
+    // How the buffer is written to disk, the line ending is the most
+    // common one in the file, which can also contain others until normalized
+    pub line_ending: LineEnding,
+    pub mixed_line_endings: bool,
+    pub encoding: TextEncoding,
+
     rope: Rope,
+    // The version the contents were last changed to, undoing goes back to the
+    // version of the contents it restores, so equal versions mean equal contents
+    contents_version: i32,
+    // The contents version and encoding as of the last load or save
+    saved_contents_version: i32,
+    saved_encoding: TextEncoding,
+    // The modification time of the file as of the last load or save,
+    // used to detect when the file is changed by another program
+    saved_modified_time: Option<SystemTime>,
     caret_char_anchor: usize,
     caret_char_pos: usize,
     caret_trailing: BOOL,
+    // Added with CTRL+click, edits and movement apply to
+    // these as well as the primary caret above
+    secondary_carets: Vec<Caret>,
+
+    // Characters outside the basic multilingual plane arrive
+    // as two UTF-16 code units, the first is held until the second
+    pending_high_surrogate: Option<u16>,
 
     pub undo_states: Vec<BufferState>,
+    // While above zero, the edits are part of a single undo state
+    // recorded when the outermost group began
+    undo_group_depth: usize,
+    // The version when the outermost group began
+    undo_group_version: i32,
+
+    // Increased on every change to the contents,
+    // the language server tracks the document by it
+    pub version: i32,
+    // The changes since they were last taken by the editor
+    change_events: Vec<TextDocumentContentChangeEvent>,
+    // Set by the editor from the capabilities of the language server
+    pub on_type_formatting_triggers: Vec<char>,
+    // The position after the last typed trigger character and the character itself
+    on_type_formatting_request: Option<(Position, char)>,
 
     pub view_dirty: bool,
+    // The highlights of the last view, they stay valid until the view,
+    // the caret or the contents change. Keyed by the first and last line
+    // of the view, the version of the contents, the caret position and whether brackets were matched
+    lexical_highlights_cache: Option<((usize, usize, i32, usize, bool), LexicalHighlights)>,
+    // The caret position the enclosing brackets are matched for, the editor
+    // sets it once the caret stops moving so the search isn't done on every move
+    bracket_match_pos: Option<usize>,
+
+    // Toggled while pasting text that is typed in rather than taken from
+    // the clipboard, it turns off auto indentation and bracket completion
+    pub paste_mode: bool,
+    // Toggled with the Insert key, typed characters replace
+    // the character after the caret instead of being inserted
+    pub overwrite: bool,
 
     // The selection state of the buffer should be public
     // for the editor to use
     pub currently_selecting: bool,
+    // Replaces the regular selection while active
+    block_selection: Option<BlockSelection>,
 
     cached_column_offset: u32
 }
 
 impl TextBuffer {
-    pub fn new(path: &str, language_identifier: &'static str) -> Self {
-        let file = File::open(path).unwrap();
+    pub fn new(path: &str, language_identifier: &'static str, settings: Rc<Settings>) -> Self {
+        let (text, encoding) = decode_text(fs::read(path).unwrap().as_slice());
+        Self::from_text(path, text.as_str(), encoding, language_identifier, false, settings)
+    }
+
+    pub fn new_scratch(name: &str, text: &str, language_identifier: &'static str, settings: Rc<Settings>) -> Self {
+        Self::from_text(name, text, TextEncoding::Utf8, language_identifier, true, settings)
+    }
+
+    fn from_text(path: &str, text: &str, encoding: TextEncoding, language_identifier: &'static str, scratch: bool, settings: Rc<Settings>) -> Self {
+        let rope = Rope::from_str(text);
+
+        let (indent_style, indent_width) = settings.indent_override
+            .or_else(|| detect_indentation(&rope, settings.indent_detection_sample_lines, settings.number_of_spaces_per_tab))
+            .unwrap_or((IndentStyle::Spaces, settings.number_of_spaces_per_tab));
+
+        let tab_width = settings.tab_display_width.unwrap_or(indent_width);
+
+        let (line_ending, mixed_line_endings) = detect_line_ending(text).unwrap_or((LineEnding::Crlf, false));
+
         let mut text_buffer = Self {
             path: String::from(path),
+            scratch,
+            settings,
             language_identifier,
 
-            rope: Rope::from_reader(file).unwrap(),
+            indent_style,
+            indent_width,
+            tab_width,
+
+            line_ending,
+            mixed_line_endings,
+            encoding,
+
+            saved_contents_version: 0,
+            saved_encoding: encoding,
+            saved_modified_time: if scratch { None } else { get_modified_time(path) },
+            rope,
+            contents_version: 0,
             caret_char_anchor: 0,
             caret_char_pos: 0,
             caret_trailing: BOOL::from(false),
+            secondary_carets: Vec::new(),
+
+            pending_high_surrogate: None,
 
             undo_states: Vec::new(),
+            undo_group_depth: 0,
+            undo_group_version: 0,
+            version: 0,
+            change_events: Vec::new(),
+            on_type_formatting_triggers: Vec::new(),
+            on_type_formatting_request: None,
 
             view_dirty: true,
+            lexical_highlights_cache: None,
+            bracket_match_pos: None,
+
+            paste_mode: false,
+            overwrite: false,
 
             currently_selecting: false,
+            block_selection: None,
 
             cached_column_offset: 0,
         };
@@ -118,44 +526,395 @@ impl TextBuffer {
         text_buffer
     }
 
+    // The rope has an empty line after a final line break, which
+    // isn't counted when the settings hide the final empty line
     #[inline(always)]
     pub fn get_number_of_lines(&self) -> usize {
-        self.rope.len_lines()
+        let ends_with_linebreak = self.rope.len_chars() > 0 && text_utils::is_linebreak(self.rope.char(self.rope.len_chars() - 1));
+        if !self.settings.show_final_empty_line && ends_with_linebreak {
+            self.rope.len_lines() - 1
+        }
+        else {
+            self.rope.len_lines()
+        }
     }
 
     #[inline(always)]
     pub fn get_current_line_visible_length(&self) -> usize {
         let current_line = self.rope.char_to_line(self.get_caret_absolute_pos());
         // Strip line of new line characters, they are not included in the visible length
-        self.rope.line(current_line).to_string().trim_end_matches(|c| c == '\n' || c == '\r').len()
+        self.rope.line(current_line).to_string().trim_end_matches(|c| c == '\n' || c == '\r').chars().count()
+    }
+
+    // The buffer is dirty if its contents or encoding differ
+    // from those it was last loaded or saved with,
+    // scratch buffers are never dirty as they can't be saved
+    pub fn is_dirty(&self) -> bool {
+        !self.scratch && (self.contents_version != self.saved_contents_version || self.encoding != self.saved_encoding)
+    }
+
+    pub fn save(&mut self) -> io::Result<()> {
+        if self.scratch {
+            return Err(io::Error::new(io::ErrorKind::Other, "scratch buffers are not backed by a file"));
+        }
+        // The whitespace cleanup is undone in one step
+        self.begin_undo_group();
+        if self.settings.trim_trailing_whitespace {
+            self.trim_trailing_whitespace();
+        }
+        if let Some(max_blank_lines) = self.settings.max_final_blank_lines {
+            self.trim_final_blank_lines(max_blank_lines);
+        }
+        self.end_undo_group();
+        fs::write(&self.path, encode_text(self.rope.to_string().as_str(), self.encoding))?;
+        self.saved_contents_version = self.contents_version;
+        self.saved_encoding = self.encoding;
+        self.saved_modified_time = get_modified_time(&self.path);
+        Ok(())
+    }
+
+    // Removes the spaces and tabs at the end of every line as a single undo step,
+    // carets inside the removed whitespace end up at the new line end
+    fn trim_trailing_whitespace(&mut self) {
+        let mut undo_state_pushed = false;
+        for line in (0..self.rope.len_lines()).rev() {
+            let line_text = self.get_line_without_linebreak(line);
+            let trimmed_length = line_text.trim_end_matches(|chr| chr == ' ' || chr == '\t').chars().count();
+            let line_length = line_text.chars().count();
+            if trimmed_length == line_length {
+                continue;
+            }
+
+            if !undo_state_pushed {
+                self.push_undo_state();
+                undo_state_pushed = true;
+            }
+            let line_start = self.rope.line_to_char(line);
+            let range = (line_start + trimmed_length)..(line_start + line_length);
+            self.remove_text(range.clone());
+
+            let removed_before = |char_pos: usize| min(char_pos, range.end).saturating_sub(range.start);
+            self.caret_char_pos -= removed_before(self.caret_char_pos);
+            self.caret_char_anchor -= removed_before(self.caret_char_anchor);
+            for (pos, anchor) in &mut self.secondary_carets {
+                *pos -= removed_before(*pos);
+                *anchor -= removed_before(*anchor);
+            }
+        }
+    }
+
+    // Removes the blank lines at the end of the buffer beyond the maximum as a single undo step.
+    // The line break after the last line is kept, so a file ending in one keeps ending in one
+    fn trim_final_blank_lines(&mut self, max_blank_lines: usize) {
+        let ends_with_linebreak = self.rope.len_chars() > 0 && text_utils::is_linebreak(self.rope.char(self.rope.len_chars() - 1));
+        let number_of_lines = self.rope.len_lines() - ends_with_linebreak as usize;
+        let content_lines = match (0..number_of_lines).rev().find(|line| !self.get_line_without_linebreak(*line).trim().is_empty()) {
+            Some(last_content_line) => last_content_line + 1,
+            None => return
+        };
+        if number_of_lines - content_lines <= max_blank_lines {
+            return;
+        }
+
+        let last_kept_line = content_lines + max_blank_lines - 1;
+        let start = if ends_with_linebreak {
+            self.rope.line_to_char(last_kept_line + 1)
+        }
+        else {
+            self.rope.line_to_char(last_kept_line) + self.get_line_without_linebreak(last_kept_line).chars().count()
+        };
+
+        self.push_undo_state();
+        self.remove_text(start..self.rope.len_chars());
+        self.caret_char_pos = min(self.caret_char_pos, self.rope.len_chars());
+        self.caret_char_anchor = min(self.caret_char_anchor, self.rope.len_chars());
+    }
+
+    // A file that was deleted or can't be read counts as unchanged, it is reloaded once it is back
+    pub fn has_changed_on_disk(&self) -> bool {
+        if self.scratch {
+            return false;
+        }
+        match get_modified_time(&self.path) {
+            Some(modified_time) => Some(modified_time) != self.saved_modified_time,
+            None => false
+        }
+    }
+
+    // Replaces the contents with the file on disk as a single undo step.
+    // The caret is moved to the line corresponding to its old line using a 
+    // line diff, and the new line of every old line is returned for the view
+    pub fn reload(&mut self) -> io::Result<Vec<usize>> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                // The reload isn't tried again until the file changes once more
+                self.saved_modified_time = get_modified_time(&self.path);
+                return Err(error);
+            }
+        };
+        let (text, encoding) = decode_text(bytes.as_slice());
+        let old_lines = self.get_lines_without_linebreaks();
+        let (caret_line, caret_column) = self.get_caret_line_and_column();
+
+        self.push_undo_state();
+        self.replace_rope(Rope::from_str(text.as_str()));
+        self.saved_contents_version = self.contents_version;
+        self.saved_modified_time = get_modified_time(&self.path);
+        self.encoding = encoding;
+        self.saved_encoding = encoding;
+        if let Some((line_ending, mixed_line_endings)) = detect_line_ending(text.as_str()) {
+            self.line_ending = line_ending;
+            self.mixed_line_endings = mixed_line_endings;
+        }
+
+        let line_map = git_support::map_lines(&old_lines, &self.get_lines_without_linebreaks());
+        let new_caret_line = line_map[caret_line];
+        let line_length = self.get_line_without_linebreak(new_caret_line).chars().count();
+        self.caret_char_pos = self.rope.line_to_char(new_caret_line) + min(caret_column, line_length);
+        self.caret_char_anchor = self.caret_char_pos;
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
+        Ok(line_map)
+    }
+
+    // Converts every line break in the buffer to 
+    // the given line ending as a single undo step
+    pub fn convert_line_endings(&mut self, line_ending: LineEnding) {
+        self.line_ending = line_ending;
+        self.mixed_line_endings = false;
+
+        let text = self.rope.to_string();
+        let converted_text = text.replace("\r\n", "\n").replace('\r', "\n").replace('\n', line_ending.as_str());
+        if converted_text == text {
+            return;
+        }
+
+        // The number of lines is unchanged, so the caret 
+        // keeps its line and column, clamped to the visible line
+        let (caret_line, caret_column) = self.get_caret_line_and_column();
+
+        self.push_undo_state();
+        self.replace_rope(Rope::from_str(converted_text.as_str()));
+
+        let line_length = self.get_line_without_linebreak(caret_line).chars().count();
+        self.caret_char_pos = self.rope.line_to_char(caret_line) + min(caret_column, line_length);
+        self.caret_char_anchor = self.caret_char_pos;
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
+    }
+
+    // The encoding only affects how the buffer is written, so the
+    // contents are left as is, but the buffer needs saving to apply it
+    pub fn convert_encoding(&mut self, encoding: TextEncoding) {
+        self.encoding = encoding;
+    }
+
+    // All changes to the contents go through these functions,
+    // so every change is recorded for the language server
+    fn insert_text(&mut self, char_pos: usize, text: &str) {
+        let position = self.char_offset_to_lsp_position(char_pos);
+        self.rope.insert(char_pos, text);
+        self.record_change(TextDocumentContentChangeEvent::new_insert_event(position, text.to_owned()));
+    }
+
+    fn remove_text(&mut self, range: Range<usize>) {
+        if range.start >= range.end {
+            return;
+        }
+        let start = self.char_offset_to_lsp_position(range.start);
+        let end = self.char_offset_to_lsp_position(range.end);
+        self.rope.remove(range);
+        self.record_change(TextDocumentContentChangeEvent::new_delete_event(start, end));
+    }
+
+    fn replace_rope(&mut self, rope: Rope) {
+        self.rope = rope;
+        // The secondary carets can't be mapped onto entirely new contents
+        self.secondary_carets.clear();
+        // Earlier changes are made redundant by sending the entire contents
+        self.change_events.clear();
+        self.record_change(TextDocumentContentChangeEvent::new_full_event(self.rope.to_string()));
+    }
+
+    fn record_change(&mut self, change_event: TextDocumentContentChangeEvent) {
+        self.version += 1;
+        self.contents_version = self.version;
+        self.change_events.push(change_event);
+    }
+
+    pub fn take_change_events(&mut self) -> Vec<TextDocumentContentChangeEvent> {
+        take(&mut self.change_events)
+    }
+
+    pub fn take_on_type_formatting_request(&mut self) -> Option<(Position, char)> {
+        self.on_type_formatting_request.take()
+    }
+
+    fn queue_on_type_formatting(&mut self, chr: char) {
+        if self.on_type_formatting_triggers.contains(&chr) {
+            self.on_type_formatting_request = Some((self.char_offset_to_lsp_position(self.get_caret_absolute_pos()), chr));
+        }
+    }
+
+    // Applies edits from the language server as a single undo step
+    pub fn apply_text_edits(&mut self, edits: &[TextEdit]) {
+        self.begin_undo_group();
+        self.apply_text_edits_to_current_undo_step(edits);
+        self.end_undo_group();
+    }
+
+    // Applies edits without recording an undo state, so they
+    // become part of the undo step of the edit that caused them
+    pub fn apply_text_edits_to_current_undo_step(&mut self, edits: &[TextEdit]) {
+        let mut ranges: Vec<(Range<usize>, &str)> = edits.iter().filter_map(|edit| {
+            let start = self.lsp_position_to_char_offset(&edit.range.start)?;
+            let end = self.lsp_position_to_char_offset(&edit.range.end)?;
+            Some((start..max(start, end), edit.new_text.as_str()))
+        }).collect();
+        // Apply from the end of the document, so earlier edits keep their positions.
+        // Edits at the same position are applied last to first to keep their order
+        ranges.reverse();
+        ranges.sort_by(|a, b| b.0.start.cmp(&a.0.start));
+
+        let mut carets: Vec<Caret> = iter::once((self.get_caret_absolute_pos(), self.caret_char_anchor))
+            .chain(self.secondary_carets.iter().copied())
+            .collect();
+        for (range, text) in ranges {
+            let text_length = text.chars().count();
+            for pos in carets.iter_mut().flat_map(|(pos, anchor)| iter::once(pos).chain(iter::once(anchor))) {
+                if *pos >= range.end {
+                    *pos = *pos + text_length - range.len();
+                }
+                else if *pos > range.start {
+                    *pos = range.start + text_length;
+                }
+            }
+            self.remove_text(range.clone());
+            self.insert_text(range.start, text);
+        }
+
+        let (caret_absolute_pos, caret_char_anchor) = carets.remove(0);
+        self.secondary_carets = carets;
+        self.caret_char_pos = caret_absolute_pos;
+        self.caret_char_anchor = caret_char_anchor;
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
+    }
+
+    // LSP positions count the characters of a line in UTF-16 code units. Positions
+    // past the end of a line are clamped to it, positions past the last line are invalid
+    pub fn lsp_position_to_char_offset(&self, position: &Position) -> Option<usize> {
+        if position.line >= self.rope.len_lines() {
+            return None;
+        }
+        let line_start = self.rope.line_to_char(position.line);
+        let line_end = line_start + self.get_line_without_linebreak(position.line).chars().count();
+        let utf16_offset = min(self.rope.char_to_utf16_cu(line_start) + position.character, self.rope.char_to_utf16_cu(line_end));
+        // An offset between the halves of a surrogate pair goes to the start of the character
+        Some(self.rope.utf16_cu_to_char(utf16_offset))
+    }
+
+    // The LSP position of the character at a text position of the layout, None unless it is part of a word
+    pub fn text_pos_to_lsp_position(&self, text_pos: TextPosition) -> Option<Position> {
+        let char_offset = self.view_offset_to_char(self.rope.line_to_char(text_pos.line_offset), text_pos.char_offset);
+        match self.rope.chars_at(char_offset).next() {
+            Some(chr) if text_utils::is_word(chr) => Some(self.char_offset_to_lsp_position(char_offset)),
+            _ => None
+        }
+    }
+
+    pub fn get_caret_lsp_position(&self) -> Position {
+        self.char_offset_to_lsp_position(self.get_caret_absolute_pos())
+    }
+
+    pub fn char_offset_to_lsp_position(&self, char_offset: usize) -> Position {
+        let line = self.rope.char_to_line(char_offset);
+        let line_start = self.rope.line_to_char(line);
+        Position {
+            line,
+            character: self.rope.char_to_utf16_cu(char_offset) - self.rope.char_to_utf16_cu(line_start)
+        }
     }
 
     #[inline(always)]
     fn push_undo_state(&mut self) {
+        if self.undo_group_depth > 0 {
+            return;
+        }
         self.undo_states.push(BufferState {
             rope: self.rope.clone(),
+            contents_version: self.contents_version,
             caret_char_anchor: self.caret_char_anchor,
             caret_char_pos: self.caret_char_pos,
             caret_trailing: self.caret_trailing,
         });
     }
 
+    // Every edit until the matching end_undo_group is undone in one step,
+    // groups can be nested in which case the outermost group decides
+    pub fn begin_undo_group(&mut self) {
+        if self.undo_group_depth == 0 {
+            self.push_undo_state();
+            self.undo_group_version = self.version;
+        }
+        self.undo_group_depth += 1;
+    }
+
+    // A group without any edits leaves no undo state behind
+    pub fn end_undo_group(&mut self) {
+        if self.undo_group_depth == 0 {
+            return;
+        }
+        self.undo_group_depth -= 1;
+        if self.undo_group_depth == 0 && self.version == self.undo_group_version {
+            self.undo_states.pop();
+        }
+    }
+
+    // Scrolls the view to the caret the next time it is drawn, every
+    // change to the caret position that isn't made with the mouse calls this
+    pub fn ensure_caret_visible(&mut self) {
+        self.view_dirty = true;
+    }
+
     #[inline(always)]
     fn undo(&mut self) {
         if self.undo_states.len() > 1 {
             let state = self.undo_states.pop().unwrap();
-            self.rope = state.rope;
+            self.replace_rope(state.rope);
+            self.contents_version = state.contents_version;
             self.caret_char_anchor = state.caret_char_anchor;
             self.caret_char_pos = state.caret_char_pos;
             self.caret_trailing = state.caret_trailing;
         }
         else if self.undo_states.len() == 1 {
-            let state = self.undo_states.last().unwrap();
-            self.rope = state.rope.clone();
+            let state = self.undo_states.last().unwrap().clone();
+            self.replace_rope(state.rope);
+            self.contents_version = state.contents_version;
             self.caret_char_anchor = state.caret_char_anchor;
             self.caret_char_pos = state.caret_char_pos;
             self.caret_trailing = state.caret_trailing;
         }
+        self.ensure_caret_visible();
+    }
+
+    // The number of columns one level of indentation takes up, with
+    // tabs a level is always a single tab however wide it is drawn
+    fn get_indent_level_columns(&self) -> usize {
+        match self.indent_style {
+            IndentStyle::Spaces => self.indent_width,
+            IndentStyle::Tabs => self.tab_width
+        }
+    }
+
+    // Creates the whitespace needed to indent by the given
+    // number of columns using the indentation style of the buffer
+    fn get_indentation(&self, columns: usize) -> String {
+        match self.indent_style {
+            IndentStyle::Spaces => " ".repeat(columns),
+            IndentStyle::Tabs => format!("{}{}", "\t".repeat(columns / self.tab_width), " ".repeat(columns % self.tab_width))
+        }
     }
 
     #[inline(always)]
@@ -190,9 +949,53 @@ impl TextBuffer {
     }
 
     #[inline(always)]
-    fn left_click(&mut self, text_pos: TextPosition, extend_current_selection: bool) {
+    fn set_caret_pos(&mut self, char_pos: usize, extend_current_selection: bool) {
+        self.caret_char_pos = char_pos;
+        self.caret_trailing = BOOL::from(false);
+        if !extend_current_selection {
+            self.caret_char_anchor = self.caret_char_pos;
+        }
+
+        // Reset the cached width
+        self.cached_column_offset = 0;
+        self.ensure_caret_visible();
+    }
+
+    // Moves to the first non-whitespace character of the line,
+    // or to the start of the line if the caret is already there
+    fn move_to_line_start(&mut self, shift_down: bool) {
+        let (line, column) = self.get_caret_line_and_column();
+        let indentation = self.rope.line(line).chars().take_while(|chr| text_utils::is_whitespace(*chr)).count();
+        let target_column = match self.settings.home_behavior {
+            HomeBehavior::Smart if column != indentation => indentation,
+            _ => 0
+        };
+        self.set_caret_pos(self.rope.line_to_char(line) + target_column, shift_down);
+    }
+
+    fn move_to_line_end(&mut self, shift_down: bool) {
+        let (line, _) = self.get_caret_line_and_column();
+        self.set_caret_pos(self.rope.line_to_char(line) + self.get_current_line_visible_length(), shift_down);
+    }
+
+    fn left_click(&mut self, text_pos: TextPosition, extend_current_selection: bool, add_caret: bool) {
+        self.block_selection = None;
+
+        // CTRL+click keeps the current caret as a secondary caret
+        // and places the primary caret at the click
+        if add_caret && !extend_current_selection {
+            let caret = (self.get_caret_absolute_pos(), self.caret_char_anchor);
+            if !self.secondary_carets.contains(&caret) {
+                self.secondary_carets.push(caret);
+            }
+        }
+        else {
+            self.secondary_carets.clear();
+        }
+
         self.set_mouse_selection(text_pos);
         let caret_absolute_pos = self.get_caret_absolute_pos();
+        self.secondary_carets.retain(|caret| caret.0 != caret_absolute_pos);
 
         if !extend_current_selection {
             self.caret_char_anchor = caret_absolute_pos;
@@ -203,33 +1006,221 @@ impl TextBuffer {
         self.cached_column_offset = 0;
     }
 
-    #[inline(always)]
-    fn left_double_click(&mut self, text_pos: TextPosition) {
+    // Starts a block selection, dragging the mouse extends it
+    fn left_alt_click(&mut self, text_pos: TextPosition) {
+        self.block_selection = None;
         self.set_mouse_selection(text_pos);
+        self.caret_char_anchor = self.get_caret_absolute_pos();
 
-        // Find the boundary on each side of the cursor
-        let left_count = self.get_boundary_char_count(CharSearchDirection::Backward);
-        let right_count = self.get_boundary_char_count(CharSearchDirection::Forward);
-
-        // Set the anchor position at the left edge
-        self.caret_char_anchor = self.caret_char_pos - left_count;
+        let (line, _) = self.get_caret_line_and_column();
+        let position = (line, self.get_caret_display_column());
+        self.block_selection = Some(BlockSelection { anchor: position, caret: position });
+        self.currently_selecting = true;
+    }
 
-        // Set the caret position at the right edge
-        self.caret_char_pos += right_count;
+    pub fn is_block_selecting(&self) -> bool {
+        self.currently_selecting && self.block_selection.is_some()
     }
 
-    #[inline(always)]
-    fn left_release(&mut self) {
-        self.currently_selecting = false;
+    // The selected char range of every line in the block selection. The block
+    // columns are display columns, so the lines line up visually when tabs are mixed in
+    fn get_block_line_ranges(&self) -> Vec<Range<usize>> {
+        let block_selection = match self.block_selection {
+            Some(block_selection) => block_selection,
+            None => return Vec::new()
+        };
+        let start_column = min(block_selection.anchor.1, block_selection.caret.1);
+        let end_column = max(block_selection.anchor.1, block_selection.caret.1);
+        let first_line = min(block_selection.anchor.0, block_selection.caret.0);
+        let last_line = max(block_selection.anchor.0, block_selection.caret.0);
+
+        (first_line..=min(last_line, self.rope.len_lines() - 1)).map(|line| {
+            let line_start = self.rope.line_to_char(line);
+            (line_start + self.display_column_to_char_column(line, start_column))..(line_start + self.display_column_to_char_column(line, end_column))
+        }).collect()
     }
 
-    fn set_selection(&mut self, mode: SelectionMode, count: usize, extend_current_selection: bool) {
-        match mode {
-            SelectionMode::Left | SelectionMode::Right => {
-                self.caret_char_pos = self.get_caret_absolute_pos();
+    pub fn get_block_selection_ranges(&self, line_start: usize, line_end: usize) -> Vec<TextRange> {
+        let char_start = self.rope.line_to_char(line_start);
+        let char_end = self.rope.line_to_char(min(self.rope.len_lines(), line_end + 1));
 
-                if mode == SelectionMode::Left {
-                    if self.caret_char_pos > 0 {
+        self.get_block_line_ranges().into_iter()
+            .filter(|range| !range.is_empty() && range.start >= char_start && range.end <= char_end)
+            .map(|range| {
+                let start = self.char_to_view_offset(char_start, range.start);
+                let end = self.char_to_view_offset(char_start, range.end);
+                TextRange { start: start as u32, length: (end - start) as u32 }
+            })
+            .collect()
+    }
+
+    // Replaces the block on every line with the text. Without a width, a char before
+    // or after the block is deleted on every line when a delete direction is given
+    fn edit_block_selection(&mut self, text: &str, delete_direction: Option<CharSearchDirection>) {
+        let mut block_selection = match self.block_selection {
+            Some(block_selection) => block_selection,
+            None => return
+        };
+        let mut column = min(block_selection.anchor.1, block_selection.caret.1);
+        let has_width = block_selection.anchor.1 != block_selection.caret.1;
+        if !has_width && delete_direction == Some(CharSearchDirection::Backward) {
+            column = column.saturating_sub(1);
+        }
+
+        // Edit from the last line up, so the earlier lines keep their positions
+        let (caret_line, _) = block_selection.caret;
+        let mut caret_line_column = None;
+        for mut range in self.get_block_line_ranges().into_iter().rev() {
+            let line = self.rope.char_to_line(range.start);
+            let reaches_block = self.get_display_column(range.start) == column;
+            if !has_width {
+                let line_end = self.rope.line_to_char(line) + self.get_line_without_linebreak(line).chars().count();
+                match delete_direction {
+                    Some(CharSearchDirection::Backward) if range.start > self.rope.line_to_char(line) => range.start -= 1,
+                    Some(CharSearchDirection::Forward) if range.end < line_end => range.end += 1,
+                    _ => {}
+                }
+            }
+            self.remove_text(range.clone());
+            self.insert_text(range.start, text);
+
+            // Deleting a tab can move the block by more than one column
+            if line == caret_line && reaches_block {
+                caret_line_column = Some(self.get_display_column(range.start + text.chars().count()));
+            }
+        }
+
+        column = caret_line_column.unwrap_or(column + text.chars().count());
+        block_selection.anchor.1 = column;
+        block_selection.caret.1 = column;
+        self.block_selection = Some(block_selection);
+
+        self.caret_char_pos = self.rope.line_to_char(caret_line) + self.display_column_to_char_column(caret_line, column);
+        self.caret_char_anchor = self.caret_char_pos;
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
+    }
+
+    // Runs the action at every caret, going from the last selection start in the document
+    // to the first so the edits don't move the carets that are yet to be visited.
+    // Overlapping selections are merged first, otherwise the edit of the inner one
+    // would land in text the edit of the outer one has already replaced
+    fn for_each_caret<F: FnMut(&mut Self)>(&mut self, mut action: F) {
+        if self.secondary_carets.is_empty() {
+            action(self);
+            return;
+        }
+
+        let mut carets: Vec<(Caret, bool)> = iter::once(((self.get_caret_absolute_pos(), self.caret_char_anchor), true))
+            .chain(self.secondary_carets.iter().map(|caret| (*caret, false)))
+            .collect();
+        carets.sort_by_key(|((pos, anchor), _)| min(*pos, *anchor));
+        let mut merged_carets: Vec<(Caret, bool)> = Vec::new();
+        for ((pos, anchor), primary) in carets {
+            if let Some(((last_pos, last_anchor), last_primary)) = merged_carets.last_mut() {
+                let last_end = max(*last_pos, *last_anchor);
+                if min(pos, anchor) < last_end {
+                    // The merged selection keeps the direction of the one that starts first
+                    let end = max(last_end, max(pos, anchor));
+                    if *last_pos > *last_anchor {
+                        *last_pos = end;
+                    }
+                    else {
+                        *last_anchor = end;
+                    }
+                    *last_primary |= primary;
+                    continue;
+                }
+            }
+            merged_carets.push(((pos, anchor), primary));
+        }
+        merged_carets.reverse();
+
+        let mut visited_carets: Vec<(Caret, bool)> = Vec::new();
+        for ((pos, anchor), primary) in merged_carets {
+            self.caret_char_pos = pos;
+            self.caret_char_anchor = anchor;
+            self.caret_trailing = BOOL::from(false);
+
+            let edit_pos = min(pos, anchor);
+            let length = self.rope.len_chars();
+            action(self);
+            let new_length = self.rope.len_chars();
+
+            // The visited carets follow the edit, so they move along with the text
+            for ((visited_pos, visited_anchor), _) in &mut visited_carets {
+                for visited in [visited_pos, visited_anchor].iter_mut() {
+                    if **visited >= edit_pos {
+                        **visited = max(edit_pos, (**visited + new_length).saturating_sub(length));
+                    }
+                }
+            }
+            visited_carets.push(((self.get_caret_absolute_pos(), self.caret_char_anchor), primary));
+        }
+
+        // Carets that ran into each other are merged, keeping the primary caret
+        visited_carets.sort_by(|a, b| (a.0).0.cmp(&(b.0).0));
+        visited_carets.dedup_by(|removed, kept| {
+            if (removed.0).0 == (kept.0).0 {
+                kept.1 |= removed.1;
+                return true;
+            }
+            false
+        });
+
+        self.secondary_carets.clear();
+        for ((pos, anchor), primary) in visited_carets {
+            if primary {
+                self.caret_char_pos = pos;
+                self.caret_char_anchor = anchor;
+                self.caret_trailing = BOOL::from(false);
+            }
+            else {
+                self.secondary_carets.push((pos, anchor));
+            }
+        }
+        self.ensure_caret_visible();
+    }
+
+    #[inline(always)]
+    fn left_double_click(&mut self, text_pos: TextPosition) {
+        self.secondary_carets.clear();
+        self.set_mouse_selection(text_pos);
+
+        // Find the boundary on each side of the cursor
+        let left_count = self.get_boundary_char_count(CharSearchDirection::Backward);
+        let right_count = self.get_boundary_char_count(CharSearchDirection::Forward);
+
+        // Set the anchor position at the left edge
+        self.caret_char_anchor = self.caret_char_pos - left_count;
+
+        // Set the caret position at the right edge
+        self.caret_char_pos += right_count;
+    }
+
+    // Selects the clicked line including its line break
+    fn left_triple_click(&mut self, text_pos: TextPosition) {
+        self.secondary_carets.clear();
+        self.set_mouse_selection(text_pos);
+
+        let line = self.rope.char_to_line(self.get_caret_absolute_pos());
+        self.caret_char_anchor = self.rope.line_to_char(line);
+        self.caret_char_pos = self.rope.line_to_char(line) + self.rope.line(line).len_chars();
+        self.caret_trailing = BOOL::from(false);
+    }
+
+    #[inline(always)]
+    fn left_release(&mut self) {
+        self.currently_selecting = false;
+    }
+
+    fn set_selection(&mut self, mode: SelectionMode, count: usize, extend_current_selection: bool) {
+        match mode {
+            SelectionMode::Left | SelectionMode::Right => {
+                self.caret_char_pos = self.get_caret_absolute_pos();
+
+                if mode == SelectionMode::Left {
+                    if self.caret_char_pos > 0 {
                         self.caret_char_pos -= count;
                     }
                 }
@@ -246,22 +1237,27 @@ impl TextBuffer {
             }
             SelectionMode::Up | SelectionMode::Down => {
                 let current_line = self.rope.char_to_line(self.get_caret_absolute_pos());
-                let target_line_idx;
-                let target_linebreak_count = if mode == SelectionMode::Up {
+                let last_line = self.get_number_of_lines() - 1;
+                let target_line_idx = if mode == SelectionMode::Up {
                     // If we're on the first line, return
                     if current_line == 0 {
                         return;
                     }
-                    target_line_idx = current_line - 1;
-                    self.linebreaks_before_line(current_line)
+                    current_line.saturating_sub(count)
                 }
                 else {
-                    // If we're on the last line, return
-                    if current_line == self.rope.len_lines() - 1 {
+                    // If we're on the last line, or past it on a hidden final empty line, return
+                    if current_line >= last_line {
                         return;
                     }
-                    target_line_idx = current_line + 1;
-                    self.linebreaks_before_line(target_line_idx)
+                    min(current_line + count, last_line)
+                };
+                // The last line of the rope has no linebreak of its own
+                let target_linebreak_count = if target_line_idx + 1 < self.rope.len_lines() {
+                    self.linebreaks_before_line(target_line_idx + 1)
+                }
+                else {
+                    0
                 };
 
                 let target_line = self.rope.line(target_line_idx);
@@ -281,12 +1277,13 @@ impl TextBuffer {
         if !extend_current_selection {
             self.caret_char_anchor = self.get_caret_absolute_pos();
         }
-        self.view_dirty = true;
+        self.ensure_caret_visible();
     }
 
     fn set_mouse_selection(&mut self, text_pos: TextPosition) {
+        // The text position is a UTF-16 offset in the text layout
         self.caret_char_pos = min(
-            self.rope.line_to_char(text_pos.line_offset) + text_pos.char_offset, 
+            self.view_offset_to_char(self.rope.line_to_char(text_pos.line_offset), text_pos.char_offset), 
             self.rope.len_chars()
         );
 
@@ -295,7 +1292,18 @@ impl TextBuffer {
         if self.caret_char_pos == self.rope.len_chars() {
             self.caret_trailing = BOOL::from(false);
         }
-        self.view_dirty = true;
+
+        // A block selection follows the caret instead of selecting the text in between
+        if self.block_selection.is_some() {
+            let caret_absolute_pos = self.caret_char_pos + (self.caret_trailing.0 as usize);
+            let line = self.rope.char_to_line(caret_absolute_pos);
+            let column = self.get_display_column(caret_absolute_pos);
+            if let Some(block_selection) = &mut self.block_selection {
+                block_selection.caret = (line, column);
+            }
+            self.caret_char_anchor = caret_absolute_pos;
+        }
+        self.ensure_caret_visible();
     }
 
     fn select_all(&mut self) {
@@ -309,42 +1317,96 @@ impl TextBuffer {
 
         let caret_anchor = self.caret_char_anchor;
         if caret_absolute_pos < self.caret_char_anchor {
-            self.rope.remove(caret_absolute_pos..caret_anchor);
+            self.remove_text(caret_absolute_pos..caret_anchor);
             self.caret_char_pos = caret_absolute_pos;
             self.caret_char_anchor = self.caret_char_pos;
         }
         else {
-            self.rope.remove(caret_anchor..caret_absolute_pos);
+            self.remove_text(caret_anchor..caret_absolute_pos);
             let caret_anchor_delta = caret_absolute_pos - self.caret_char_anchor;
             self.caret_char_pos = caret_absolute_pos - caret_anchor_delta;
         };
 
         self.caret_trailing = BOOL::from(false);
-        self.view_dirty = true;
+        self.ensure_caret_visible();
+    }
+
+    // If the caret is on a run of blank lines that is already as long as allowed, the run
+    // is shortened to the maximum and true is returned to indicate no newline should be added
+    fn trim_blank_lines_at_caret(&mut self, max_blank_lines: usize) -> bool {
+        let is_blank = |buffer: &Self, line: usize| buffer.get_line_without_linebreak(line).trim().is_empty();
+
+        let caret_line = self.rope.char_to_line(self.get_caret_absolute_pos());
+        if self.get_caret_absolute_pos() != self.caret_char_anchor || !is_blank(self, caret_line) {
+            return false;
+        }
+
+        let mut first_line = caret_line;
+        while first_line > 0 && is_blank(self, first_line - 1) {
+            first_line -= 1;
+        }
+        let mut last_line = caret_line;
+        while last_line + 1 < self.rope.len_lines() && is_blank(self, last_line + 1) {
+            last_line += 1;
+        }
+
+        let blank_lines = last_line - first_line + 1;
+        if blank_lines < max_blank_lines {
+            return false;
+        }
+
+        if blank_lines > max_blank_lines {
+            let start = self.rope.line_to_char(first_line + max_blank_lines);
+            let end = if last_line + 1 < self.rope.len_lines() { self.rope.line_to_char(last_line + 1) } else { self.rope.len_chars() };
+            self.remove_text(start..end);
+        }
+
+        // Leave the caret at the start of the last remaining blank line
+        let caret_line = min(first_line + max_blank_lines.saturating_sub(1), self.rope.len_lines() - 1);
+        self.caret_char_pos = self.rope.line_to_char(caret_line);
+        self.caret_char_anchor = self.caret_char_pos;
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
+        true
+    }
+
+    fn auto_indent_enabled(&self) -> bool {
+        self.settings.auto_indent && !self.paste_mode
     }
 
     fn insert_newline(&mut self) {
+        if self.settings.trim_blank_lines_on_enter && self.trim_blank_lines_at_caret(self.settings.max_consecutive_blank_lines) {
+            return;
+        }
+
+        if !self.auto_indent_enabled() {
+            self.insert_chars(self.line_ending.as_str());
+            return;
+        }
+
         let offset = self.get_leading_whitespace_offset();
+        let line_ending = self.line_ending.as_str();
 
         // Search back for an open bracket, to see if auto indentation might
         // be necessary
         let mut chars = self.rope.chars_at(self.get_caret_absolute_pos());
         while let Some(prev_char) = chars.prev() {
-            if let Some(brackets) = text_utils::is_opening_bracket(prev_char) {
+            if let Some(brackets) = text_utils::is_opening_bracket(prev_char, &self.settings.autocomplete_brackets) {
                 // If we can find a matching bracket separated only by whitespace
                 // then we will insert double newlines and insert the cursor
                 // in the middle of the new scope
                 for next_char in self.rope.chars_at(self.get_caret_absolute_pos()) {
                     if next_char == brackets.1 {
+                        let indentation = self.get_indentation(offset);
                         let change_notification = self.insert_chars(
                             format!("{}{}{}{}{}", 
-                                "\r\n", 
-                                " ".repeat(offset),
-                                " ".repeat(NUMBER_OF_SPACES_PER_TAB),
-                                "\r\n",
-                                " ".repeat(offset)
+                                line_ending, 
+                                indentation,
+                                self.get_indentation(self.get_indent_level_columns()),
+                                line_ending,
+                                indentation
                             ).as_str());
-                        self.set_selection(SelectionMode::Left, offset + 2, false);
+                        self.set_selection(SelectionMode::Left, indentation.len() + line_ending.len(), false);
                         return change_notification;
                     }
                     else if text_utils::is_whitespace(next_char) {
@@ -354,10 +1416,9 @@ impl TextBuffer {
                 }
 
                 // If no matching bracket is found, simply insert a new line
-                // and indent NUMBER_OF_SPACES_PER_TAB extra for the new scope
+                // and indent one level extra for the new scope
                 let change_notification = self.insert_chars(
-                    format!("{}{}{}", "\r\n", " ".repeat(offset), 
-                    " ".repeat(NUMBER_OF_SPACES_PER_TAB)).as_str());
+                    format!("{}{}", line_ending, self.get_indentation(offset + self.get_indent_level_columns())).as_str());
                 return change_notification;
             }
             if text_utils::is_whitespace(prev_char) {
@@ -366,7 +1427,7 @@ impl TextBuffer {
             break;
         }
 
-        self.insert_chars(format!("{}{}", "\r\n", " ".repeat(offset)).as_str())
+        self.insert_chars(format!("{}{}", line_ending, self.get_indentation(offset)).as_str())
     }
 
     fn insert_bracket(&mut self, bracket_pair: (char, char)) {
@@ -377,6 +1438,41 @@ impl TextBuffer {
         self.set_selection(SelectionMode::Left, 1, false);
     }
 
+    // Surrounds the selection with the quotes, steps over the closing quote at the
+    // caret or inserts both quotes with the caret in between. Returns false when
+    // the quote is typed as is, like an apostrophe right after a word
+    fn insert_quote(&mut self, quote: char) -> bool {
+        let caret_absolute_pos = self.get_caret_absolute_pos();
+        if caret_absolute_pos != self.caret_char_anchor {
+            let start = min(caret_absolute_pos, self.caret_char_anchor);
+            let end = max(caret_absolute_pos, self.caret_char_anchor);
+            // With several carets this is inside their undo group, so
+            // quoting all the selections is undone in one step
+            self.push_undo_state();
+            self.insert_text(end, quote.encode_utf8(&mut [0; 4]));
+            self.insert_text(start, quote.encode_utf8(&mut [0; 4]));
+            // The selection stays on the text between the quotes
+            self.caret_char_anchor = start + 1;
+            self.caret_char_pos = end + 1;
+            self.caret_trailing = BOOL::from(false);
+            self.ensure_caret_visible();
+            return true;
+        }
+
+        let next_char = self.rope.chars_at(caret_absolute_pos).next();
+        if next_char == Some(quote) {
+            self.set_selection(SelectionMode::Right, 1, false);
+            return true;
+        }
+
+        let prev_char = self.rope.chars_at(caret_absolute_pos).prev();
+        if prev_char.map_or(false, |chr| text_utils::is_word(chr) || chr == quote) || next_char.map_or(false, text_utils::is_word) {
+            return false;
+        }
+        self.insert_bracket((quote, quote));
+        true
+    }
+
     fn insert_chars(&mut self, chars: &str) {
         // If we are currently selecting text, 
         // delete text before insertion
@@ -386,22 +1482,44 @@ impl TextBuffer {
 
         let caret_absolute_pos = self.get_caret_absolute_pos();
 
-        self.rope.insert(caret_absolute_pos, chars);
-        self.set_selection(SelectionMode::Right, chars.len(), false);
-        self.view_dirty = true;
+        self.insert_text(caret_absolute_pos, chars);
+        self.set_selection(SelectionMode::Right, chars.chars().count(), false);
+        self.ensure_caret_visible();
     }
 
     fn insert_char(&mut self, character: u16) {
-        let chr = (character as u8) as char;
+        let code_units = match (self.pending_high_surrogate.take(), character) {
+            (_, 0xD800..=0xDBFF) => {
+                self.pending_high_surrogate = Some(character);
+                return;
+            }
+            (Some(high_surrogate), 0xDC00..=0xDFFF) => vec![high_surrogate, character],
+            _ => vec![character]
+        };
+        let chr = match char::decode_utf16(code_units).next() {
+            Some(Ok(chr)) => chr,
+            // Lone surrogates can't be represented in the rope
+            _ => return
+        };
+
+        if !self.paste_mode && self.settings.autoclose_quotes && get_autoclose_quotes(self.language_identifier).contains(&chr) && self.insert_quote(chr) {
+            return;
+        }
 
         // If we are currently selecting text, 
         // delete text before insertion
-        if self.get_caret_absolute_pos() != self.caret_char_anchor {
+        let replacing_selection = self.get_caret_absolute_pos() != self.caret_char_anchor;
+        if replacing_selection {
             self.delete_selection();
         }
 
         let mut caret_absolute_pos = self.get_caret_absolute_pos();
-        for brackets in &AUTOCOMPLETE_BRACKETS {
+        let settings = self.settings.clone();
+        let autocomplete_brackets: &[(char, char)] = match self.paste_mode {
+            true => &[],
+            false => &settings.autocomplete_brackets
+        };
+        for brackets in autocomplete_brackets {
             if chr == brackets.0 {
                 self.insert_bracket(*brackets);
                 return;
@@ -409,27 +1527,64 @@ impl TextBuffer {
             // Special case when inserting a closing bracket
             // while the caret is next to closing bracket. Simply
             // advance the caret position once
-            if chr == brackets.1 {
-                if self.rope.char(caret_absolute_pos) == brackets.1 {
-                    self.set_selection(SelectionMode::Right, 1, false);
-                    return;
-                }
-                // Otherwise if possible move the scope indent back once
-                else {
-                    let offset = self.get_leading_whitespace_offset();
-                    let current_char_pos = caret_absolute_pos - self.rope.line_to_char(self.rope.char_to_line(caret_absolute_pos));
-                    if offset >= NUMBER_OF_SPACES_PER_TAB && current_char_pos == offset {
-                        self.set_selection(SelectionMode::Left, NUMBER_OF_SPACES_PER_TAB, true);
-                    }
-                }
+            if chr == brackets.1 && self.rope.chars_at(caret_absolute_pos).next() == Some(brackets.1) {
+                self.set_selection(SelectionMode::Right, 1, false);
+                return;
             }
         }
 
+        if self.auto_indent_enabled() {
+            self.reindent_for_electric_character(chr);
+        }
         caret_absolute_pos = self.get_caret_absolute_pos();
 
-        self.rope.insert_char(caret_absolute_pos, chr);
+        // In overwrite mode the character after the caret is replaced, unless it ends the line
+        if self.overwrite && !replacing_selection {
+            if let Some(next_char) = self.rope.chars_at(caret_absolute_pos).next() {
+                if !text_utils::is_linebreak(next_char) {
+                    self.remove_text(caret_absolute_pos..(caret_absolute_pos + 1));
+                }
+            }
+        }
+        self.insert_text(caret_absolute_pos, chr.encode_utf8(&mut [0; 4]));
         self.set_selection(SelectionMode::Right, 1, false);
-        self.view_dirty = true;
+        self.ensure_caret_visible();
+        self.queue_on_type_formatting(chr);
+    }
+
+    // Reindents the line when the character is electric in the language
+    // and only whitespace precedes the caret on the line
+    fn reindent_for_electric_character(&mut self, chr: char) {
+        let action = if text_utils::is_closing_bracket(chr, &self.settings.autocomplete_brackets).is_some() {
+            ElectricAction::Dedent
+        }
+        else {
+            match get_electric_characters(self.language_identifier).iter().find(|(electric_character, _)| *electric_character == chr) {
+                Some((_, action)) => *action,
+                None => return
+            }
+        };
+
+        let caret_absolute_pos = self.get_caret_absolute_pos();
+        let line_start = self.rope.line_to_char(self.rope.char_to_line(caret_absolute_pos));
+        if !self.rope.slice(line_start..caret_absolute_pos).chars().all(|chr| chr == ' ' || chr == '\t') {
+            return;
+        }
+
+        let offset = self.get_leading_whitespace_offset();
+        let indent_level_columns = self.get_indent_level_columns();
+        let columns = match action {
+            ElectricAction::Dedent if offset >= indent_level_columns => offset - indent_level_columns,
+            ElectricAction::Dedent => return,
+            ElectricAction::ColumnZero => 0
+        };
+
+        let indentation = self.get_indentation(columns);
+        self.remove_text(line_start..caret_absolute_pos);
+        self.insert_text(line_start, &indentation);
+        self.caret_char_pos = line_start + indentation.chars().count();
+        self.caret_char_anchor = self.caret_char_pos;
+        self.caret_trailing = BOOL::from(false);
     }
 
     fn delete_right(&mut self) {
@@ -448,12 +1603,12 @@ impl TextBuffer {
         if self.see_chars("\r\n") { 
             offset = 2 
         }
-        else if self.see_chars(" ".repeat(NUMBER_OF_SPACES_PER_TAB).as_str()) {
-            offset = NUMBER_OF_SPACES_PER_TAB;
+        else if self.see_chars(" ".repeat(self.indent_width).as_str()) {
+            offset = self.indent_width;
         }
 
         let next_char_pos = min(caret_absolute_pos + offset, self.rope.len_chars());
-        self.rope.remove(caret_absolute_pos..next_char_pos);
+        self.remove_text(caret_absolute_pos..next_char_pos);
     }
 
     fn delete_right_by_word(&mut self) {
@@ -487,50 +1642,666 @@ impl TextBuffer {
         if self.see_prev_chars("\r\n") { 
             offset = 2 
         }
-        else if self.see_prev_chars(" ".repeat(NUMBER_OF_SPACES_PER_TAB).as_str()) {
-            offset = NUMBER_OF_SPACES_PER_TAB;
+        else if self.see_prev_chars(" ".repeat(self.indent_width).as_str()) {
+            offset = self.indent_width;
+        }
+        let previous_char_pos = caret_absolute_pos.saturating_sub(offset);
+
+        self.remove_text(previous_char_pos..caret_absolute_pos);
+        self.set_selection(SelectionMode::Left, offset, false);
+    }
+
+    fn delete_left_by_word(&mut self) {
+        let caret_absolute_pos = self.get_caret_absolute_pos();
+
+        // If we are currently selecting text, 
+        // simply delete the selected text
+        if caret_absolute_pos != self.caret_char_anchor {
+            self.delete_selection();
+            return;
+        }
+
+        // Start by moving left once, then get the boundary count
+        self.set_selection(SelectionMode::Left, 1, true);
+        let count = self.get_boundary_char_count(CharSearchDirection::Backward);
+        self.set_selection(SelectionMode::Left, count, true);
+        self.delete_selection();
+    }
+
+    // Gets the first and last line touched by the selection,
+    // a selection ending at the very start of a line doesn't include it
+    fn get_selected_line_range(&self) -> (usize, usize) {
+        let caret_absolute_pos = self.get_caret_absolute_pos();
+        let selection_start = min(caret_absolute_pos, self.caret_char_anchor);
+        let selection_end = max(caret_absolute_pos, self.caret_char_anchor);
+
+        let first_line = self.rope.char_to_line(selection_start);
+        let mut last_line = self.rope.char_to_line(selection_end);
+        if last_line > first_line && self.rope.line_to_char(last_line) == selection_end {
+            last_line -= 1;
+        }
+        (first_line, last_line)
+    }
+
+    fn get_line_without_linebreak(&self, line: usize) -> String {
+        self.rope.line(line).to_string().trim_end_matches(text_utils::is_linebreak).to_owned()
+    }
+
+    pub fn get_text(&self) -> String {
+        self.rope.to_string()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rope.len_chars() == 0
+    }
+
+    pub fn get_lines_without_linebreaks(&self) -> Vec<String> {
+        (0..self.rope.len_lines()).map(|line| self.get_line_without_linebreak(line)).collect()
+    }
+
+    // Replaces the selected lines with the lines produced by the
+    // transform as a single undo step, and selects the resulting lines
+    fn transform_selected_lines<F: FnOnce(&mut Vec<String>)>(&mut self, transform: F) {
+        let (first_line, last_line) = self.get_selected_line_range();
+        let mut lines: Vec<String> = (first_line..=last_line).map(|line| self.get_line_without_linebreak(line)).collect();
+        transform(&mut lines);
+
+        let start = self.rope.line_to_char(first_line);
+        let end = self.rope.line_to_char(last_line) + self.get_line_without_linebreak(last_line).chars().count();
+        let text = lines.join(self.line_ending.as_str());
+
+        self.push_undo_state();
+        self.remove_text(start..end);
+        self.insert_text(start, text.as_str());
+
+        self.caret_char_anchor = start;
+        self.caret_char_pos = start + text.chars().count();
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
+    }
+
+    // Inserts a copy of the selected lines below them as a single
+    // undo step, the caret and selection move along to the copy
+    fn duplicate_lines(&mut self) {
+        let (first_line, last_line) = self.get_selected_line_range();
+        let start = self.rope.line_to_char(first_line);
+        let end = self.rope.line_to_char(last_line) + self.get_line_without_linebreak(last_line).chars().count();
+        let text = format!("{}{}", self.line_ending.as_str(), self.rope.slice(start..end));
+        let text_length = text.chars().count();
+
+        let caret_absolute_pos = self.get_caret_absolute_pos();
+        self.push_undo_state();
+        self.insert_text(end, text.as_str());
+
+        self.caret_char_pos = caret_absolute_pos + text_length;
+        self.caret_char_anchor += text_length;
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
+    }
+
+    // Swaps the selected lines with the line above or below them
+    // as a single undo step, keeping the caret on the same text
+    fn move_lines(&mut self, up: bool) {
+        let (first_line, last_line) = self.get_selected_line_range();
+        let other_line = match up {
+            true if first_line > 0 => first_line - 1,
+            false if last_line + 1 < self.rope.len_lines() => last_line + 1,
+            _ => return
+        };
+
+        let (range_start, range_end) = (min(first_line, other_line), max(last_line, other_line));
+        let mut lines: Vec<String> = (range_start..=range_end).map(|line| self.get_line_without_linebreak(line)).collect();
+        match up {
+            true => lines.rotate_left(1),
+            false => lines.rotate_right(1)
+        }
+
+        let start = self.rope.line_to_char(range_start);
+        let end = self.rope.line_to_char(range_end) + self.get_line_without_linebreak(range_end).chars().count();
+        let text = lines.join(self.line_ending.as_str());
+        let offset = self.get_line_without_linebreak(other_line).chars().count() + self.line_ending.as_str().len();
+
+        let caret_absolute_pos = self.get_caret_absolute_pos();
+        self.push_undo_state();
+        self.remove_text(start..end);
+        self.insert_text(start, text.as_str());
+
+        match up {
+            true => {
+                self.caret_char_pos = caret_absolute_pos - offset;
+                self.caret_char_anchor -= offset;
+            }
+            false => {
+                self.caret_char_pos = caret_absolute_pos + offset;
+                self.caret_char_anchor += offset;
+            }
+        }
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
+    }
+
+    // Rewraps the block of line comments around the caret as a single undo step
+    fn reflow_comment(&mut self) {
+        let comment_marker = get_comment_tokens(self.language_identifier).single_line;
+        let (caret_line, _) = self.get_caret_line_and_column();
+        let prefix = match get_line_comment_prefix(&self.get_line_without_linebreak(caret_line), comment_marker) {
+            Some(prefix) => prefix,
+            None => return
+        };
+
+        let is_in_block = |buffer: &Self, line: usize| {
+            get_line_comment_prefix(&buffer.get_line_without_linebreak(line), comment_marker).as_ref() == Some(&prefix)
+        };
+        let mut first_line = caret_line;
+        while first_line > 0 && is_in_block(self, first_line - 1) {
+            first_line -= 1;
+        }
+        let mut last_line = caret_line;
+        while last_line + 1 < self.rope.len_lines() && is_in_block(self, last_line + 1) {
+            last_line += 1;
+        }
+
+        self.caret_char_anchor = self.rope.line_to_char(first_line);
+        self.caret_char_pos = self.rope.line_to_char(last_line) + self.get_line_without_linebreak(last_line).chars().count();
+        self.caret_trailing = BOOL::from(false);
+        let width = self.settings.comment_reflow_width;
+        self.transform_selected_lines(|lines| *lines = reflow_comment_lines(lines, &prefix, width));
+    }
+
+    // Comments or uncomments the selected lines as a single undo step,
+    // without a selection the caret stays on the same text
+    fn toggle_line_comment(&mut self) {
+        let comment_marker = get_comment_tokens(self.language_identifier).single_line;
+
+        let had_selection = self.get_caret_absolute_pos() != self.caret_char_anchor;
+        let (caret_line, caret_column) = self.get_caret_line_and_column();
+        let line_length = self.get_line_without_linebreak(caret_line).chars().count();
+
+        self.transform_selected_lines(|lines| toggle_comment_lines(lines, comment_marker));
+
+        if !had_selection {
+            let new_line_length = self.get_line_without_linebreak(caret_line).chars().count();
+            let column = (caret_column + new_line_length).saturating_sub(line_length);
+            self.caret_char_pos = self.rope.line_to_char(caret_line) + min(column, new_line_length);
+            self.caret_char_anchor = self.caret_char_pos;
+        }
+    }
+
+    // Normalizes the whitespace of the selected lines, or 
+    // the whole buffer without a selection, as a single undo step
+    fn normalize_whitespace(&mut self) {
+        if self.get_caret_absolute_pos() == self.caret_char_anchor {
+            self.select_all();
+        }
+
+        // The lexer finds the strings and comments to leave untouched
+        let (first_line, last_line) = self.get_selected_line_range();
+        let text = (first_line..=last_line).map(|line| self.get_line_without_linebreak(line)).collect::<Vec<String>>().join("\n");
+        let region_start = self.rope.line_to_char(first_line);
+        let lexical_highlights = highlight_text(text.as_str(), region_start, region_start, self.language_identifier, 
+                                                self.rope.chars_at(region_start), self.rope.chars_at(region_start), 0, &self.settings.autocomplete_brackets);
+        let protected_ranges = lexical_highlights.highlight_tokens.iter()
+            .filter(|(_, token_type)| *token_type == SemanticTokenTypes::Comment || *token_type == SemanticTokenTypes::Literal)
+            .map(|(range, _)| (range.startPosition as usize)..((range.startPosition + range.length) as usize))
+            .collect();
+
+        let normalized_lines = normalize_whitespace_in_lines(text.as_str(), protected_ranges, self.settings.max_consecutive_blank_lines);
+        self.transform_selected_lines(|lines| *lines = normalized_lines);
+    }
+
+    // Finds the closest pair of brackets enclosing the range,
+    // returns the positions of the opening and closing bracket
+    fn find_enclosing_brackets(&self, start: usize, end: usize) -> Option<(usize, usize)> {
+        let (opening_pos, bracket_type) = self.find_unclosed_bracket(start)?;
+
+        let mut open_count = 0;
+        for (offset, chr) in self.rope.chars_at(end).enumerate() {
+            if chr == bracket_type.0 {
+                open_count += 1;
+            }
+            else if chr == bracket_type.1 {
+                if open_count == 0 {
+                    return Some((opening_pos, end + offset));
+                }
+                open_count -= 1;
+            }
+        }
+        None
+    }
+
+    // Finds the closest opening bracket before the position that isn't
+    // closed before it, returns its position and the bracket pair
+    fn find_unclosed_bracket(&self, char_pos: usize) -> Option<(usize, (char, char))> {
+        let mut closed_map: HashMap<char, usize> = HashMap::new();
+        let mut opening_pos = char_pos;
+        let mut chars = self.rope.chars_at(char_pos);
+        while let Some(chr) = chars.prev() {
+            opening_pos -= 1;
+            if let Some(brackets) = text_utils::is_closing_bracket(chr, &self.settings.autocomplete_brackets) {
+                *closed_map.entry(brackets.1).or_insert(0) += 1;
+            }
+            else if let Some(brackets) = text_utils::is_opening_bracket(chr, &self.settings.autocomplete_brackets) {
+                match closed_map.get_mut(&brackets.1) {
+                    Some(count) if *count > 0 => *count -= 1,
+                    _ => return Some((opening_pos, brackets))
+                }
+            }
+        }
+        None
+    }
+
+    // Inserts the closing bracket of the innermost bracket left open before the caret.
+    // A closing brace goes on a line of its own, indented like the line of the opening brace
+    fn close_enclosing_block(&mut self) {
+        let (opening_pos, brackets) = match self.find_unclosed_bracket(self.get_caret_absolute_pos()) {
+            Some(unclosed_bracket) => unclosed_bracket,
+            None => return
+        };
+
+        self.push_undo_state();
+        if brackets.0 != '{' {
+            self.insert_chars(brackets.1.encode_utf8(&mut [0; 4]));
+            return;
+        }
+
+        let indentation: String = self.rope.line(self.rope.char_to_line(opening_pos)).chars()
+            .take_while(|chr| *chr == ' ' || *chr == '\t')
+            .collect();
+        let caret_absolute_pos = self.get_caret_absolute_pos();
+        let line_start = self.rope.line_to_char(self.rope.char_to_line(caret_absolute_pos));
+        if self.caret_char_anchor == caret_absolute_pos && self.rope.slice(line_start..caret_absolute_pos).chars().all(|chr| chr == ' ' || chr == '\t') {
+            // On a blank line the brace replaces the indentation
+            self.remove_text(line_start..caret_absolute_pos);
+            self.caret_char_pos = line_start;
+            self.caret_char_anchor = line_start;
+            self.caret_trailing = BOOL::from(false);
+            self.insert_chars(&format!("{}}}", indentation));
+        }
+        else {
+            self.insert_chars(&format!("{}{}}}", self.line_ending.as_str(), indentation));
+        }
+    }
+
+    // Widens the selection to the next enclosing scope: the word at the caret,
+    // the line, the contents of each enclosing bracket pair, the brackets
+    // themselves and finally the whole buffer
+    fn expand_selection(&mut self) {
+        let caret_absolute_pos = self.get_caret_absolute_pos();
+        let start = min(caret_absolute_pos, self.caret_char_anchor);
+        let end = max(caret_absolute_pos, self.caret_char_anchor);
+
+        let mut scopes = Vec::new();
+
+        let mut word_start = start;
+        let mut chars = self.rope.chars_at(start);
+        while let Some(chr) = chars.prev() {
+            if !text_utils::is_word(chr) {
+                break;
+            }
+            word_start -= 1;
+        }
+        let word_end = end + self.rope.chars_at(end).take_while(|chr| text_utils::is_word(*chr)).count();
+        scopes.push((word_start, word_end));
+
+        let line = self.rope.char_to_line(start);
+        if self.rope.char_to_line(end) == line {
+            let line_text = self.get_line_without_linebreak(line);
+            let indentation = line_text.chars().take_while(|chr| text_utils::is_whitespace(*chr)).count();
+            let line_start = self.rope.line_to_char(line);
+            scopes.push((line_start + indentation, line_start + line_text.chars().count()));
+        }
+
+        let (mut scope_start, mut scope_end) = (start, end);
+        while let Some((opening_pos, closing_pos)) = self.find_enclosing_brackets(scope_start, scope_end) {
+            scopes.push((opening_pos + 1, closing_pos));
+            scopes.push((opening_pos, closing_pos + 1));
+            scope_start = opening_pos;
+            scope_end = closing_pos + 1;
+        }
+        scopes.push((0, self.rope.len_chars()));
+
+        let next_scope = scopes.into_iter()
+            .filter(|(scope_start, scope_end)| *scope_start <= start && *scope_end >= end && scope_end - scope_start > end - start)
+            .min_by_key(|(scope_start, scope_end)| scope_end - scope_start);
+
+        if let Some((scope_start, scope_end)) = next_scope {
+            self.caret_char_anchor = scope_start;
+            self.caret_char_pos = scope_end;
+            self.caret_trailing = BOOL::from(false);
+            self.ensure_caret_visible();
+        }
+    }
+
+    fn sort_lines(&mut self) {
+        self.transform_selected_lines(|lines| lines.sort());
+    }
+
+    fn sort_lines_by_length(&mut self) {
+        self.transform_selected_lines(|lines| lines.sort_by_key(|line| line.chars().count()));
+    }
+
+    fn reverse_lines(&mut self) {
+        self.transform_selected_lines(|lines| lines.reverse());
+    }
+
+    // Finds the char position of the first occurrence of the needle at or after the
+    // given char position. The rope is searched a chunk at a time, the end of the
+    // previous chunk is kept in front of the next to find occurrences spanning both
+    fn find(&self, needle: &str, char_pos: usize) -> Option<usize> {
+        if needle.is_empty() || char_pos > self.rope.len_chars() {
+            return None;
+        }
+
+        let start_byte = self.rope.char_to_byte(char_pos);
+        let (chunks, chunk_byte_idx, _, _) = self.rope.chunks_at_byte(start_byte);
+        let mut window = String::new();
+        // The byte position of the start of the window in the rope
+        let mut window_start = start_byte;
+        for (index, chunk) in chunks.enumerate() {
+            window.push_str(if index == 0 { &chunk[start_byte - chunk_byte_idx..] } else { chunk });
+            if let Some(byte_offset) = window.find(needle) {
+                return Some(self.rope.byte_to_char(window_start + byte_offset));
+            }
+
+            let mut kept_start = window.len().saturating_sub(needle.len() - 1);
+            while !window.is_char_boundary(kept_start) {
+                kept_start -= 1;
+            }
+            window.drain(..kept_start);
+            window_start += kept_start;
+        }
+        None
+    }
+
+    // The lines with an occurrence of the needle, each line only once
+    pub fn find_lines(&self, needle: &str) -> Vec<usize> {
+        let needle_length = needle.chars().count();
+        let mut lines: Vec<usize> = Vec::new();
+        let mut char_pos = 0;
+        while let Some(match_pos) = self.find(needle, char_pos) {
+            let line = self.rope.char_to_line(match_pos);
+            if lines.last() != Some(&line) {
+                lines.push(line);
+            }
+            char_pos = match_pos + needle_length;
+        }
+        lines
+    }
+
+    // Replaces the next occurrence of the needle after the caret, wrapping
+    // around to the start of the buffer if none is found. False if there is none
+    pub fn replace_next(&mut self, needle: &str, replacement: &str) -> bool {
+        let caret_absolute_pos = self.get_caret_absolute_pos();
+        let search_start = min(caret_absolute_pos, self.caret_char_anchor);
+
+        let match_pos = match self.find(needle, search_start).or_else(|| self.find(needle, 0)) {
+            Some(match_pos) => match_pos,
+            None => return false
+        };
+
+        self.push_undo_state();
+        self.remove_text(match_pos..match_pos + needle.chars().count());
+        self.insert_text(match_pos, replacement);
+
+        // Place the caret after the replaced text
+        self.caret_char_pos = min(match_pos + replacement.chars().count(), self.rope.len_chars());
+        self.caret_char_anchor = self.caret_char_pos;
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
+        true
+    }
+
+    // Replaces every occurrence of the needle in the buffer
+    // as a single undo step, returning the number of replacements
+    pub fn replace_all(&mut self, needle: &str, replacement: &str) -> usize {
+        let needle_length = needle.chars().count();
+        let replacement_length = replacement.chars().count();
+
+        let mut caret_absolute_pos = self.get_caret_absolute_pos();
+        let mut replacements = 0;
+        let mut char_pos = 0;
+        self.begin_undo_group();
+        while let Some(match_pos) = self.find(needle, char_pos) {
+            self.remove_text(match_pos..match_pos + needle_length);
+            self.insert_text(match_pos, replacement);
+
+            // Keep the caret on the same text, if the caret
+            // was inside the replaced text, move it past the replacement
+            if caret_absolute_pos >= match_pos + needle_length {
+                caret_absolute_pos = caret_absolute_pos + replacement_length - needle_length;
+            }
+            else if caret_absolute_pos > match_pos {
+                caret_absolute_pos = match_pos + replacement_length;
+            }
+
+            char_pos = match_pos + replacement_length;
+            replacements += 1;
+        }
+        self.end_undo_group();
+
+        if replacements > 0 {
+            self.caret_char_pos = min(caret_absolute_pos, self.rope.len_chars());
+            self.caret_char_anchor = self.caret_char_pos;
+            self.caret_trailing = BOOL::from(false);
+            self.ensure_caret_visible();
+        }
+        replacements
+    }
+
+    // Parses and creates ranges of highlight information directly
+    // from the text buffer displayed on the screen
+    pub fn get_lexical_highlights(&mut self, line_start: usize, line_end: usize) -> LexicalHighlights {
+        let caret_absolute_pos = self.get_caret_absolute_pos();
+        let match_brackets = self.brackets_matched();
+        let cache_key = (line_start, line_end, self.version, caret_absolute_pos, match_brackets);
+        if let Some((key, lexical_highlights)) = &self.lexical_highlights_cache {
+            if *key == cache_key {
+                return lexical_highlights.clone();
+            }
+        }
+
+        let text_in_current_view = self.get_text_view_as_string(line_start, line_end);
+        let start_it = self.rope.chars_at(self.rope.line_to_char(line_start));
+        let caret_it = self.rope.chars_at(caret_absolute_pos);
+
+        let view_char_start = self.rope.line_to_char(line_start);
+        let view_byte_start = self.rope.char_to_byte(view_char_start);
+        let mut lexical_highlights = highlight_text(text_in_current_view.as_str(), view_char_start, 
+                                                    caret_absolute_pos, self.language_identifier, start_it, caret_it,
+                                                    if match_brackets { self.settings.max_bracket_match_distance } else { 0 },
+                                                    &self.settings.autocomplete_brackets);
+
+        // The highlighter works on byte offsets and the bracket matching on
+        // char offsets, both are converted to UTF-16 offsets for the text layout
+        for (range, _) in &mut lexical_highlights.highlight_tokens {
+            let start = self.rope.byte_to_char(view_byte_start + range.startPosition as usize);
+            let end = self.rope.byte_to_char(view_byte_start + (range.startPosition + range.length) as usize);
+            range.startPosition = self.char_to_view_offset(view_char_start, start) as u32;
+            range.length = (self.char_to_view_offset(view_char_start, end) - range.startPosition as usize) as u32;
+        }
+        if let Some(enclosing_brackets) = &mut lexical_highlights.enclosing_brackets {
+            for bracket_pos in enclosing_brackets.iter_mut().flatten() {
+                *bracket_pos = self.char_to_view_offset(view_char_start, view_char_start + *bracket_pos);
+            }
+        }
+        self.lexical_highlights_cache = Some((cache_key, lexical_highlights.clone()));
+        lexical_highlights
+    }
+
+    pub fn match_brackets_at_caret(&mut self) {
+        self.bracket_match_pos = Some(self.get_caret_absolute_pos());
+    }
+
+    // Whether the brackets enclosing the caret are highlighted at its current position
+    pub fn brackets_matched(&self) -> bool {
+        self.settings.highlight_enclosing_brackets && self.bracket_match_pos == Some(self.get_caret_absolute_pos())
+    }
+
+    // Text layouts are indexed by UTF-16 code units relative
+    // to the start of the view while the rope is indexed by chars
+    fn char_to_view_offset(&self, view_char_start: usize, char_pos: usize) -> usize {
+        self.rope.char_to_utf16_cu(char_pos) - self.rope.char_to_utf16_cu(view_char_start)
+    }
+
+    // Converts a line and UTF-16 column, as used by LSP, to a view offset
+    pub fn get_view_offset(&self, line: usize, utf16_column: usize, line_start: usize) -> Option<usize> {
+        if line < line_start || line >= self.rope.len_lines() {
+            return None;
         }
-        let previous_char_pos = caret_absolute_pos.saturating_sub(offset);
+        let line_utf16_start = self.rope.char_to_utf16_cu(self.rope.line_to_char(line));
+        let view_utf16_start = self.rope.char_to_utf16_cu(self.rope.line_to_char(line_start));
+        Some(line_utf16_start + utf16_column - view_utf16_start)
+    }
 
-        self.rope.remove(previous_char_pos..caret_absolute_pos);
-        self.set_selection(SelectionMode::Left, offset, false);
+    fn view_offset_to_char(&self, view_char_start: usize, view_offset: usize) -> usize {
+        let utf16_pos = self.rope.char_to_utf16_cu(view_char_start) + view_offset;
+        self.rope.utf16_cu_to_char(min(utf16_pos, self.rope.len_utf16_cu()))
     }
 
-    fn delete_left_by_word(&mut self) {
+    // The start of the word ending at the caret and the word itself, empty if there is none
+    pub fn get_word_before_caret(&self) -> (usize, String) {
         let caret_absolute_pos = self.get_caret_absolute_pos();
+        let mut word_start = caret_absolute_pos;
+        let mut chars = self.rope.chars_at(caret_absolute_pos);
+        while let Some(chr) = chars.prev() {
+            if !text_utils::is_word(chr) {
+                break;
+            }
+            word_start -= 1;
+        }
+        (word_start, self.rope.slice(word_start..caret_absolute_pos).to_string())
+    }
 
-        // If we are currently selecting text, 
-        // simply delete the selected text
-        if caret_absolute_pos != self.caret_char_anchor {
-            self.delete_selection();
+    // Replaces the text from the word start up to the caret with the completion
+    pub fn complete_word(&mut self, word_start: usize, completion: &str) {
+        let caret_absolute_pos = self.get_caret_absolute_pos();
+        if word_start > caret_absolute_pos {
             return;
         }
 
-        // Start by moving left once, then get the boundary count
-        self.set_selection(SelectionMode::Left, 1, true);
-        let count = self.get_boundary_char_count(CharSearchDirection::Backward);
-        self.set_selection(SelectionMode::Left, count, true);
-        self.delete_selection();
+        self.push_undo_state();
+        self.remove_text(word_start..caret_absolute_pos);
+        self.insert_text(word_start, completion);
+
+        self.caret_char_pos = word_start + completion.chars().count();
+        self.caret_char_anchor = self.caret_char_pos;
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
     }
 
-    // Parses and creates ranges of highlight information directly
-    // from the text buffer displayed on the screen
-    pub fn get_lexical_highlights(&mut self, line_start: usize, line_end: usize) -> LexicalHighlights {
-        let caret_absolute_pos = self.get_caret_absolute_pos();
+    // Replaces the selection in one undo step and selects the new text
+    pub fn replace_selection(&mut self, text: &str) {
+        let Range { start, end } = self.get_selection_char_range();
 
-        let text_in_current_view = self.get_text_view_as_string(line_start, line_end);
-        let start_it = self.rope.chars_at(self.rope.line_to_char(line_start));
-        let caret_it = self.rope.chars_at(caret_absolute_pos);
+        self.push_undo_state();
+        self.remove_text(start..end);
+        self.insert_text(start, text);
+
+        self.caret_char_anchor = start;
+        self.caret_char_pos = start + text.chars().count();
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
+    }
+
+    // Keeps one or both sides of a merge conflict and removes the markers in one undo
+    // step, nothing is changed if the markers have moved since the conflict was found
+    pub fn resolve_merge_conflict(&mut self, conflict: &MergeConflict, resolution: ConflictResolution) -> bool {
+        if !git_support::find_merge_conflicts(&self.get_lines_without_linebreaks()).contains(conflict) {
+            return false;
+        }
+
+        let get_lines = |lines: Range<usize>| self.rope.slice(self.rope.line_to_char(lines.start)..self.rope.line_to_char(lines.end)).to_string();
+        let kept_text = match resolution {
+            ConflictResolution::Current => get_lines(conflict.current_lines()),
+            ConflictResolution::Incoming => get_lines(conflict.incoming_lines()),
+            ConflictResolution::Both => get_lines(conflict.current_lines()) + &get_lines(conflict.incoming_lines())
+        };
+        let start = self.rope.line_to_char(conflict.start);
+        let end = self.rope.line_to_char(min(conflict.end + 1, self.rope.len_lines()));
+
+        self.push_undo_state();
+        self.remove_text(start..end);
+        self.insert_text(start, &kept_text);
+
+        self.caret_char_pos = start;
+        self.caret_char_anchor = start;
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
+        true
+    }
+
+    pub fn move_caret_to_line(&mut self, line: usize) {
+        let line = min(line, self.get_number_of_lines() - 1);
+        self.caret_char_pos = self.rope.line_to_char(line);
+        self.caret_char_anchor = self.caret_char_pos;
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
+    }
+
+    // Returns false if the position is past the last line
+    pub fn move_caret_to_lsp_position(&mut self, position: &Position) -> bool {
+        let char_offset = match self.lsp_position_to_char_offset(position) {
+            Some(char_offset) => char_offset,
+            None => return false
+        };
+        self.caret_char_pos = char_offset;
+        self.caret_char_anchor = self.caret_char_pos;
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
+        true
+    }
 
-        highlight_text(text_in_current_view.as_str(), self.rope.line_to_char(line_start), 
-                       caret_absolute_pos, self.language_identifier, start_it, caret_it)
+    pub fn move_caret_to_end(&mut self) {
+        self.caret_char_pos = self.rope.len_chars();
+        self.caret_char_anchor = self.caret_char_pos;
+        self.caret_trailing = BOOL::from(false);
+        self.ensure_caret_visible();
     }
 
     pub fn get_caret_line_and_column(&self) -> (usize, usize) {
-        let caret_absolute_pos = self.get_caret_absolute_pos();
-        let line = self.rope.char_to_line(caret_absolute_pos);
-        let line_start = self.rope.line_to_char(line);
-        (line, caret_absolute_pos - line_start)
+        self.get_line_and_column(self.get_caret_absolute_pos())
+    }
+
+    // The column of the caret as displayed, with tabs
+    // extending to the next multiple of the tab width
+    pub fn get_caret_display_column(&self) -> usize {
+        self.get_display_column(self.get_caret_absolute_pos())
+    }
+
+    fn get_display_column(&self, char_pos: usize) -> usize {
+        let line_start = self.rope.line_to_char(self.rope.char_to_line(char_pos));
+        self.rope.slice(line_start..char_pos).chars().fold(0, |column, chr| match chr {
+            '\t' => (column / self.tab_width + 1) * self.tab_width,
+            _ => column + 1
+        })
+    }
+
+    // The char column of the first char on the line that starts at or after
+    // the display column, clamped to the end of the line
+    fn display_column_to_char_column(&self, line: usize, display_column: usize) -> usize {
+        let line_text = self.get_line_without_linebreak(line);
+        let mut column = 0;
+        for (char_column, chr) in line_text.chars().enumerate() {
+            if column >= display_column {
+                return char_column;
+            }
+            column = match chr {
+                '\t' => (column / self.tab_width + 1) * self.tab_width,
+                _ => column + 1
+            };
+        }
+        line_text.chars().count()
+    }
+
+    fn get_line_and_column(&self, char_pos: usize) -> (usize, usize) {
+        let line = self.rope.char_to_line(char_pos);
+        (line, char_pos - self.rope.line_to_char(line))
+    }
+
+    // Positions past the end of the text are on the last line, the ranges of
+    // the diagnostics can be stale until the language server publishes them again
+    pub fn get_line_of_char(&self, char_pos: usize) -> usize {
+        self.rope.char_to_line(min(char_pos, self.rope.len_chars()))
     }
 
     pub fn get_caret_offset(&mut self, line_start: usize, line_end: usize) -> Option<usize> {
@@ -540,7 +2311,42 @@ impl TextBuffer {
         if self.caret_char_pos < char_start || self.caret_char_pos > char_end {
             return None;
         }
-        Some(self.caret_char_pos - char_start)
+        Some(self.char_to_view_offset(char_start, self.caret_char_pos))
+    }
+
+    pub fn get_secondary_caret_offsets(&self, line_start: usize, line_end: usize) -> Vec<usize> {
+        let char_start = self.rope.line_to_char(line_start);
+        let char_end = self.rope.line_to_char(min(self.rope.len_lines(), line_end + 1));
+
+        self.secondary_carets.iter()
+            .filter(|(pos, _)| (char_start..=char_end).contains(pos))
+            .map(|(pos, _)| self.char_to_view_offset(char_start, *pos))
+            .collect()
+    }
+
+    pub fn get_secondary_selection_ranges(&self, line_start: usize, line_end: usize) -> Vec<TextRange> {
+        let char_start = self.rope.line_to_char(line_start);
+        let char_end = self.rope.line_to_char(min(self.rope.len_lines(), line_end + 1));
+
+        self.secondary_carets.iter()
+            .filter(|(pos, anchor)| pos != anchor)
+            .map(|(pos, anchor)| {
+                let start = self.char_to_view_offset(char_start, min(*pos, *anchor).clamp(char_start, char_end));
+                let end = self.char_to_view_offset(char_start, max(*pos, *anchor).clamp(char_start, char_end));
+                TextRange { start: start as u32, length: (end - start) as u32 }
+            })
+            .filter(|range| range.length > 0)
+            .collect()
+    }
+
+    // The offset of the end of the line, relative to the first line in view
+    pub fn get_line_end_offset(&self, line: usize, line_start: usize) -> Option<usize> {
+        if line < line_start || line >= self.rope.len_lines() {
+            return None;
+        }
+        let line_length = self.get_line_without_linebreak(line).chars().count();
+        let view_char_start = self.rope.line_to_char(line_start);
+        Some(self.char_to_view_offset(view_char_start, self.rope.line_to_char(line) + line_length))
     }
 
     fn copy_selection(&mut self, hwnd: HWND) {
@@ -548,28 +2354,25 @@ impl TextBuffer {
             if OpenClipboard(hwnd).0 > 0 {
                 if EmptyClipboard().0 > 0 {
                     let data = self.get_selection_data();
-                    if data.is_empty() {
-                        CloseClipboard();
-                        return;
+                    if !data.is_empty() {
+                        set_clipboard_data(CLIPBOARD_FORMATS::CF_TEXT.0, &data);
                     }
-                    // +1 since str.len() returns the length minus the null-byte
-                    let byte_size = data.len() + 1;
-                    let clipboard_data_ptr = GlobalAlloc(GlobalAlloc_uFlags::GMEM_ZEROINIT, byte_size);
-                    if !clipboard_data_ptr != 0 {
-                        let memory = GlobalLock(clipboard_data_ptr);
-                        if !memory.is_null() {
-                            copy_nonoverlapping(data.as_ptr(), memory as *mut u8, byte_size);
-                            GlobalUnlock(clipboard_data_ptr);
-
-                            // If setting the clipboard data fails, free it
-                            // otherwise its now owned by the system
-                            if SetClipboardData(CLIPBOARD_FORMATS::CF_TEXT.0, HANDLE(clipboard_data_ptr)) == HANDLE(0) {
-                                GlobalFree(clipboard_data_ptr);
-                            }
-                        }
-                        else {
-                            GlobalFree(clipboard_data_ptr);
-                        }
+                }
+                CloseClipboard();
+            }
+        }
+    }
+
+    // Places the selection on the clipboard both as plain text and as the HTML, 
+    // which is already in the CF_HTML format, so programs can paste either
+    pub fn copy_selection_as_html(&mut self, hwnd: HWND, html: &str) {
+        unsafe {
+            if OpenClipboard(hwnd).0 > 0 {
+                if EmptyClipboard().0 > 0 {
+                    let data = self.get_selection_data();
+                    if !data.is_empty() {
+                        set_clipboard_data(CLIPBOARD_FORMATS::CF_TEXT.0, &data);
+                        set_clipboard_data(RegisterClipboardFormatW("HTML Format"), html);
                     }
                 }
                 CloseClipboard();
@@ -599,8 +2402,8 @@ impl TextBuffer {
         self.caret_trailing = BOOL::from(false);
         self.caret_char_anchor = self.caret_char_pos;
 
-        self.rope.remove(current_line_chars..current_line_chars + current_line_length);
-        self.view_dirty = true;
+        self.remove_text(current_line_chars..current_line_chars + current_line_length);
+        self.ensure_caret_visible();
     }
 
     fn paste(&mut self, hwnd: HWND) {
@@ -618,7 +2421,7 @@ impl TextBuffer {
 
                     self.insert_chars(chars);
                     GlobalUnlock(clipboard_data_ptr.0 as isize);
-                    self.view_dirty = true;
+                    self.ensure_caret_visible();
                 }
 
                 CloseClipboard();
@@ -626,6 +2429,17 @@ impl TextBuffer {
         }
     }
 
+    // The first and last line of the selection, None if nothing is selected
+    pub fn get_selected_lines(&self) -> Option<(usize, usize)> {
+        let caret_absolute_pos = self.get_caret_absolute_pos();
+        if caret_absolute_pos == self.caret_char_anchor {
+            return None;
+        }
+        let first_line = self.rope.char_to_line(min(caret_absolute_pos, self.caret_char_anchor));
+        let last_line = self.rope.char_to_line(max(caret_absolute_pos, self.caret_char_anchor));
+        Some((first_line, last_line))
+    }
+
     pub fn get_selection_range(&self, line_start: usize, line_end: usize) -> Option<TextRange> {
         let char_start = self.rope.line_to_char(line_start);
         let char_end = self.rope.line_to_char(min(self.rope.len_lines(), line_end + 1));
@@ -635,16 +2449,16 @@ impl TextBuffer {
             return None;
         }
  
-        // Saturating sub ensures that the carets don't go below 0
-        let mut caret_begin = self.caret_char_anchor.saturating_sub(char_start);
-        let mut caret_end = caret_absolute_pos.saturating_sub(char_start);
+        // Clamp the carets to the view before converting them to view offsets
+        let mut caret_begin = self.caret_char_anchor.clamp(char_start, char_end);
+        let mut caret_end = caret_absolute_pos.clamp(char_start, char_end);
 
         if caret_begin > caret_end {
             swap(&mut caret_begin, &mut caret_end);
         }
 
-        caret_begin = min(caret_begin, char_end);
-        caret_end = min(caret_end, char_end);
+        caret_begin = self.char_to_view_offset(char_start, caret_begin);
+        caret_end = self.char_to_view_offset(char_start, caret_end);
 
         let range =  TextRange {
             start: caret_begin as u32,
@@ -654,6 +2468,49 @@ impl TextBuffer {
         Some(range)
     }
 
+    // The part of a char range inside the view as view offsets, an empty range covers
+    // the character after it so that it can still be seen. At the end of the text
+    // there is none, so the range is left empty for the renderer to widen
+    pub fn get_view_range(&self, range: &Range<usize>, line_start: usize, line_end: usize) -> Option<TextRange> {
+        let char_start = self.rope.line_to_char(line_start);
+        let char_end = self.rope.line_to_char(min(self.rope.len_lines(), line_end + 1));
+
+        if range.is_empty() && range.start == self.rope.len_chars() && range.start >= char_start && range.start <= char_end {
+            return Some(TextRange {
+                start: self.char_to_view_offset(char_start, range.start) as u32,
+                length: 0
+            });
+        }
+
+        let range_start = max(range.start, char_start);
+        let range_end = min(max(range.end, range.start + 1), char_end);
+        if range_start >= range_end {
+            return None;
+        }
+
+        let view_start = self.char_to_view_offset(char_start, range_start);
+        let view_end = self.char_to_view_offset(char_start, range_end);
+        Some(TextRange {
+            start: view_start as u32,
+            length: (view_end - view_start) as u32
+        })
+    }
+
+    // The spaces and tabs inside the selection in the view, as view offsets
+    pub fn get_selected_whitespace(&self, line_start: usize, line_end: usize) -> Vec<(usize, char)> {
+        let char_start = self.rope.line_to_char(line_start);
+        let char_end = self.rope.line_to_char(min(self.rope.len_lines(), line_end + 1));
+
+        let caret_absolute_pos = self.get_caret_absolute_pos();
+        let selection_start = min(caret_absolute_pos, self.caret_char_anchor).clamp(char_start, char_end);
+        let selection_end = max(caret_absolute_pos, self.caret_char_anchor).clamp(char_start, char_end);
+
+        self.rope.slice(selection_start..selection_end).chars().enumerate()
+            .filter(|(_, chr)| *chr == ' ' || *chr == '\t')
+            .map(|(index, chr)| (self.char_to_view_offset(char_start, selection_start + index), chr))
+            .collect()
+    }
+
     fn linebreaks_before_line(&self, line: usize) -> usize {
         let mut line_start = self.rope.chars_at(self.rope.line_to_char(line));
         match line_start.prev() {
@@ -689,15 +2546,35 @@ impl TextBuffer {
         true
     }
 
+    // The selected text, empty if nothing is selected
+    pub fn get_selected_text(&self) -> String {
+        let Range { start, end } = self.get_selection_char_range();
+        if start >= end {
+            return String::new();
+        }
+        self.rope.slice(start..end).to_string()
+    }
+
+    // The selected chars, clamped to the end of the rope
+    pub fn get_selection_char_range(&self) -> Range<usize> {
+        let start = min(self.caret_char_anchor, self.get_caret_absolute_pos());
+        let end = min(max(self.caret_char_anchor, self.get_caret_absolute_pos()), self.rope.len_chars());
+        start..end
+    }
+
     fn get_selection_data(&self) -> String {
-        let caret_absolute_pos = self.get_caret_absolute_pos();
+        // The end of the rope is a valid exclusive bound, clamping
+        // to it also keeps an empty rope from being sliced out of bounds
+        let len_chars = self.rope.len_chars();
+        let caret_absolute_pos = min(self.get_caret_absolute_pos(), len_chars);
+        let caret_char_anchor = min(self.caret_char_anchor, len_chars);
 
-        match self.caret_char_anchor {
+        match caret_char_anchor {
             anchor if anchor > caret_absolute_pos => {
-                self.rope.slice(caret_absolute_pos..min(self.caret_char_anchor, self.rope.len_chars() - 1)).to_string()
+                self.rope.slice(caret_absolute_pos..anchor).to_string()
             },
             anchor if anchor < caret_absolute_pos => {
-                self.rope.slice(self.caret_char_anchor..min(caret_absolute_pos, self.rope.len_chars() - 1)).to_string()
+                self.rope.slice(anchor..caret_absolute_pos).to_string()
             },
             // If nothing is selected, copy current line
             _ => self.rope.line(self.rope.char_to_line(caret_absolute_pos)).to_string()
@@ -712,7 +2589,7 @@ impl TextBuffer {
         for chr in line_slice {
             match chr {
                 ' ' => offset += 1,
-                '\t' => offset += NUMBER_OF_SPACES_PER_TAB,
+                '\t' => offset += self.tab_width,
                 _ => break
             }
         }
@@ -778,11 +2655,70 @@ impl TextBuffer {
 
     pub fn execute_command(&mut self, cmd: &BufferCommand) {
         match *cmd {
-            BufferCommand::LeftClick(text_pos, shift_down)              => self.left_click(text_pos, shift_down),
+            BufferCommand::LeftClick(text_pos, shift_down, ctrl_down)   => self.left_click(text_pos, shift_down, ctrl_down),
+            BufferCommand::LeftAltClick(text_pos)                       => self.left_alt_click(text_pos),
             BufferCommand::LeftDoubleClick(text_pos)                    => self.left_double_click(text_pos),
+            BufferCommand::LeftTripleClick(text_pos)                    => self.left_triple_click(text_pos),
             BufferCommand::LeftRelease                                  => self.left_release(),
             BufferCommand::SetMouseSelection(text_pos)                  => self.set_mouse_selection(text_pos),
-            BufferCommand::KeyPressed(key, shift_down, ctrl_down, hwnd) => {
+            BufferCommand::KeyPressed(key, shift_down, ctrl_down, hwnd, page_rows) => {
+                if self.block_selection.is_some() {
+                    match (key, ctrl_down) {
+                        (VK_BACK, false) => {
+                            self.push_undo_state();
+                            self.edit_block_selection("", Some(CharSearchDirection::Backward));
+                            return;
+                        }
+                        (VK_DELETE, false) => {
+                            self.push_undo_state();
+                            self.edit_block_selection("", Some(CharSearchDirection::Forward));
+                            return;
+                        }
+                        // Pressing a modifier keeps the block selection
+                        (VK_SHIFT, _) | (VK_CONTROL, _) => return,
+                        _ => self.block_selection = None
+                    }
+                }
+                if !self.secondary_carets.is_empty() {
+                    match (key, ctrl_down) {
+                        (VK_ESCAPE, _) => {
+                            self.secondary_carets.clear();
+                            return;
+                        }
+                        (VK_SHIFT, _) | (VK_CONTROL, _) => return,
+                        (VK_BACK, _) | (VK_DELETE, _) | (VK_TAB, _) | (VK_RETURN, false) |
+                        (VK_LEFT, _) | (VK_RIGHT, _) | (VK_UP, _) | (VK_DOWN, _) | (VK_HOME, false) | (VK_END, false) => {}
+                        // Everything else only applies to the primary caret
+                        _ => self.secondary_carets.clear()
+                    }
+                }
+                if !self.secondary_carets.is_empty() {
+                    let indentation = match self.indent_style {
+                        IndentStyle::Spaces => " ".repeat(self.indent_width),
+                        IndentStyle::Tabs => "\t".to_owned()
+                    };
+                    // The edits at all the carets are undone together
+                    self.begin_undo_group();
+                    self.for_each_caret(|buffer| match (key, ctrl_down) {
+                        (VK_LEFT, false)   => buffer.move_left(shift_down),
+                        (VK_LEFT, true)    => buffer.move_left_by_word(shift_down),
+                        (VK_RIGHT, false)  => buffer.move_right(shift_down),
+                        (VK_RIGHT, true)   => buffer.move_right_by_word(shift_down),
+                        (VK_DOWN, _)       => buffer.set_selection(SelectionMode::Down, 1, shift_down),
+                        (VK_UP, _)         => buffer.set_selection(SelectionMode::Up, 1, shift_down),
+                        (VK_HOME, _)       => buffer.move_to_line_start(shift_down),
+                        (VK_END, _)        => buffer.move_to_line_end(shift_down),
+                        (VK_TAB, _)        => buffer.insert_chars(indentation.as_str()),
+                        (VK_RETURN, _)     => buffer.insert_newline(),
+                        (VK_DELETE, false) => buffer.delete_right(),
+                        (VK_DELETE, true)  => buffer.delete_right_by_word(),
+                        (VK_BACK, false)   => buffer.delete_left(),
+                        (VK_BACK, true)    => buffer.delete_left_by_word(),
+                        _ => {}
+                    });
+                    self.end_undo_group();
+                    return;
+                }
                 match (key, ctrl_down) {
                     (VK_LEFT, false)   => self.move_left(shift_down),
                     (VK_LEFT, true)    => self.move_left_by_word(shift_down),
@@ -790,13 +2726,25 @@ impl TextBuffer {
                     (VK_RIGHT, true)   => self.move_right_by_word(shift_down),
                     (VK_DOWN, _)       => self.set_selection(SelectionMode::Down, 1, shift_down),
                     (VK_UP, _)         => self.set_selection(SelectionMode::Up, 1, shift_down),
+                    (VK_NEXT, _)       => self.set_selection(SelectionMode::Down, page_rows, shift_down),
+                    (VK_PRIOR, _)      => self.set_selection(SelectionMode::Up, page_rows, shift_down),
+                    (VK_HOME, false)   => self.move_to_line_start(shift_down),
+                    (VK_HOME, true)    => self.set_caret_pos(0, shift_down),
+                    (VK_END, false)    => self.move_to_line_end(shift_down),
+                    (VK_END, true)     => self.set_caret_pos(self.rope.len_chars(), shift_down),
+                    (VK_INSERT, false) if !shift_down => self.overwrite = !self.overwrite,
                     (VK_TAB, _)        => {
                         self.push_undo_state();
-                        self.insert_chars(" ".repeat(NUMBER_OF_SPACES_PER_TAB).as_str());
+                        let indentation = match self.indent_style {
+                            IndentStyle::Spaces => " ".repeat(self.indent_width),
+                            IndentStyle::Tabs => "\t".to_owned()
+                        };
+                        self.insert_chars(indentation.as_str());
                     },
                     (VK_RETURN, false) => {
                         self.push_undo_state();
                         self.insert_newline();
+                        self.queue_on_type_formatting('\n');
                     },
                     (VK_DELETE, false) => {
                         self.push_undo_state();
@@ -836,15 +2784,710 @@ impl TextBuffer {
                     (0x5A, true) => {
                         self.undo();
                     }
+                    // CTRL+D (Duplicate lines)
+                    (0x44, true) => {
+                        self.duplicate_lines();
+                    }
+                    // CTRL+/ (Toggle line comment)
+                    (0xBF, true) => {
+                        self.toggle_line_comment();
+                    }
+                    _ => {}
+                }
+            }
+            BufferCommand::AltKeyPressed(key, shift_down) => {
+                self.secondary_carets.clear();
+                match (key, shift_down) {
+                    // ALT+S (Sort lines)
+                    (0x53, _) => self.sort_lines(),
+                    // ALT+L (Sort lines by length)
+                    (0x4C, _) => self.sort_lines_by_length(),
+                    // ALT+R (Reverse lines)
+                    (0x52, _) => self.reverse_lines(),
+                    // ALT+N (Normalize whitespace)
+                    (0x4E, _) => self.normalize_whitespace(),
+                    // ALT+W (Expand selection to the enclosing scope)
+                    (0x57, _) => self.expand_selection(),
+                    // ALT+Q (Reflow the comment block around the caret)
+                    (0x51, _) => self.reflow_comment(),
+                    // ALT+C (Close the enclosing block)
+                    (0x43, _) => self.close_enclosing_block(),
+                    // ALT+P (Toggle paste mode)
+                    (0x50, _) => self.paste_mode = !self.paste_mode,
+                    // ALT+Up (Move lines up), ALT+Down (Move lines down)
+                    (VK_UP, _) => self.move_lines(true),
+                    (VK_DOWN, _) => self.move_lines(false),
+                    // ALT+E (Cycle line endings, or normalize mixed line endings first), ALT+SHIFT+E (Cycle encodings)
+                    (0x45, false) if self.mixed_line_endings => self.convert_line_endings(self.line_ending),
+                    (0x45, false) => {
+                        self.convert_line_endings(match self.line_ending {
+                            LineEnding::Lf => LineEnding::Crlf,
+                            LineEnding::Crlf => LineEnding::Cr,
+                            LineEnding::Cr => LineEnding::Lf
+                        });
+                    }
+                    (0x45, true) => {
+                        self.convert_encoding(match self.encoding {
+                            TextEncoding::Utf8 => TextEncoding::Utf8Bom,
+                            TextEncoding::Utf8Bom => TextEncoding::Utf16Le,
+                            TextEncoding::Utf16Le => TextEncoding::Utf8
+                        });
+                    }
                     _ => {}
                 }
             }
             BufferCommand::CharInsert(character) => {
-                if text_utils::is_whitespace((character as u8) as char) {
+                if self.block_selection.is_some() {
+                    // Characters outside the basic multilingual plane aren't supported in block selections
+                    if let Some(chr) = char::from_u32(character as u32) {
+                        self.push_undo_state();
+                        self.edit_block_selection(chr.encode_utf8(&mut [0; 4]), None);
+                    }
+                    return;
+                }
+                // The edits at all the carets are undone together, while a single
+                // caret starts a new undo step at whitespace. The halves of a
+                // surrogate pair are never whitespace
+                let multiple_carets = !self.secondary_carets.is_empty();
+                if multiple_carets {
+                    self.begin_undo_group();
+                }
+                else if char::from_u32(character as u32).map_or(false, text_utils::is_whitespace) {
                     self.push_undo_state();
                 }
-                self.insert_char(character);
+                // Every caret needs the pending half of a surrogate pair
+                let pending_high_surrogate = self.pending_high_surrogate;
+                self.for_each_caret(|buffer| {
+                    buffer.pending_high_surrogate = pending_high_surrogate;
+                    buffer.insert_char(character);
+                });
+                if multiple_carets {
+                    self.end_undo_group();
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsp_structs::{Position, Range as LSPRange};
+
+    fn new_buffer(text: &str) -> TextBuffer {
+        TextBuffer::new_scratch("Test", text, "", Rc::new(Settings::default()))
+    }
+
+    fn new_edit(start: (usize, usize), end: (usize, usize), new_text: &str) -> TextEdit {
+        TextEdit {
+            range: LSPRange {
+                start: Position { line: start.0, character: start.1 },
+                end: Position { line: end.0, character: end.1 }
+            },
+            new_text: new_text.to_owned()
+        }
+    }
+
+    #[test]
+    fn text_edits_are_applied_in_reverse_document_order() {
+        let mut buffer = new_buffer("int a;\nint b;\n");
+        buffer.apply_text_edits(&[
+            new_edit((0, 4), (0, 5), "first"),
+            new_edit((1, 4), (1, 5), "second")
+        ]);
+        assert_eq!(buffer.get_text(), "int first;\nint second;\n");
+    }
+
+    #[test]
+    fn text_edits_at_the_same_position_keep_their_order() {
+        let mut buffer = new_buffer("ab");
+        buffer.apply_text_edits(&[
+            new_edit((0, 1), (0, 1), "1"),
+            new_edit((0, 1), (0, 1), "2")
+        ]);
+        assert_eq!(buffer.get_text(), "a12b");
+    }
+
+    #[test]
+    fn text_edits_are_a_single_undo_step() {
+        let mut buffer = new_buffer("a b c");
+        let undo_states = buffer.undo_states.len();
+        buffer.apply_text_edits(&[
+            new_edit((0, 0), (0, 1), "x"),
+            new_edit((0, 2), (0, 3), "y"),
+            new_edit((0, 4), (0, 5), "z")
+        ]);
+        assert_eq!(buffer.undo_states.len(), undo_states + 1);
+
+        buffer.undo();
+        assert_eq!(buffer.get_text(), "a b c");
+    }
+
+    #[test]
+    fn text_edits_keep_the_caret_on_the_same_text() {
+        let mut buffer = new_buffer("{\nx;\n}");
+        buffer.caret_char_pos = 3;
+        buffer.caret_char_anchor = 3;
+        buffer.apply_text_edits(&[new_edit((1, 0), (1, 0), "    ")]);
+        assert_eq!(buffer.get_text(), "{\n    x;\n}");
+        assert_eq!(buffer.get_caret_absolute_pos(), 7);
+    }
+
+    #[test]
+    fn lsp_positions_count_utf16_code_units() {
+        let buffer = new_buffer("a\n\u{1F600}b\u{E9}c\n");
+        assert_eq!(buffer.lsp_position_to_char_offset(&Position { line: 1, character: 2 }), Some(3));
+        assert_eq!(buffer.lsp_position_to_char_offset(&Position { line: 1, character: 4 }), Some(5));
+        // Between the halves of the surrogate pair
+        assert_eq!(buffer.lsp_position_to_char_offset(&Position { line: 1, character: 1 }), Some(2));
+
+        let position = buffer.char_offset_to_lsp_position(5);
+        assert_eq!((position.line, position.character), (1, 4));
+    }
+
+    #[test]
+    fn lsp_positions_are_clamped_to_the_line() {
+        let buffer = new_buffer("ab\r\ncd");
+        assert_eq!(buffer.lsp_position_to_char_offset(&Position { line: 0, character: 10 }), Some(2));
+        assert_eq!(buffer.lsp_position_to_char_offset(&Position { line: 2, character: 0 }), None);
+    }
+
+    #[test]
+    fn hovered_text_positions_are_only_words() {
+        let buffer = new_buffer("a = 1\n\u{1F600}bc\n");
+        let position = buffer.text_pos_to_lsp_position(TextPosition { line_offset: 1, char_offset: 3 }).unwrap();
+        assert_eq!((position.line, position.character), (1, 3));
+        assert!(buffer.text_pos_to_lsp_position(TextPosition { line_offset: 0, char_offset: 1 }).is_none());
+        // The emoji is not part of a word
+        assert!(buffer.text_pos_to_lsp_position(TextPosition { line_offset: 1, char_offset: 0 }).is_none());
+    }
+
+    #[test]
+    fn caret_moves_to_lsp_positions_within_the_document() {
+        let mut buffer = new_buffer("ab\n\u{1F600}cd");
+        assert!(buffer.move_caret_to_lsp_position(&Position { line: 1, character: 3 }));
+        assert_eq!(buffer.get_caret_line_and_column(), (1, 2));
+        assert!(!buffer.move_caret_to_lsp_position(&Position { line: 5, character: 0 }));
+        assert_eq!(buffer.get_caret_line_and_column(), (1, 2));
+    }
+
+    #[test]
+    fn view_ranges_are_clamped_to_the_view() {
+        let buffer = new_buffer("ab\n\u{1F600}cd\nef\ngh");
+        // The emoji takes up two UTF-16 code units
+        assert!(buffer.get_view_range(&(4..6), 1, 2) == Some(TextRange { start: 2, length: 2 }));
+        assert!(buffer.get_view_range(&(1..10), 1, 1) == Some(TextRange { start: 0, length: 5 }));
+        // An empty range covers the next character
+        assert!(buffer.get_view_range(&(3..3), 1, 2) == Some(TextRange { start: 0, length: 2 }));
+        assert!(buffer.get_view_range(&(0..2), 1, 2).is_none());
+        assert!(buffer.get_view_range(&(20..20), 0, 3).is_none());
+        // An empty range at the end of the text stays empty, when the end is in view
+        assert!(buffer.get_view_range(&(12..12), 1, 3) == Some(TextRange { start: 10, length: 0 }));
+        assert!(buffer.get_view_range(&(12..12), 1, 2).is_none());
+        // A range running past the end of the text is cut off there
+        assert!(buffer.get_view_range(&(10..20), 2, 3) == Some(TextRange { start: 3, length: 2 }));
+    }
+
+    #[test]
+    fn text_edits_outside_the_document_are_ignored() {
+        let mut buffer = new_buffer("abc");
+        buffer.apply_text_edits(&[
+            new_edit((5, 0), (5, 1), "x"),
+            new_edit((0, 10), (0, 10), "d")
+        ]);
+        assert_eq!(buffer.get_text(), "abcd");
+    }
+
+    #[test]
+    fn display_columns_extend_tabs_to_the_next_tab_stop() {
+        let mut buffer = new_buffer("a\tb\n\tc");
+        buffer.tab_width = 4;
+        assert_eq!(buffer.get_display_column(1), 1);
+        assert_eq!(buffer.get_display_column(2), 4);
+        assert_eq!(buffer.get_display_column(5), 4);
+        assert_eq!(buffer.display_column_to_char_column(0, 2), 2);
+        assert_eq!(buffer.display_column_to_char_column(0, 4), 2);
+        assert_eq!(buffer.display_column_to_char_column(0, 10), 3);
+    }
+
+    #[test]
+    fn block_selection_lines_up_tabs_and_spaces() {
+        let mut buffer = new_buffer("\tabc\n    def\nx");
+        buffer.tab_width = 4;
+        buffer.block_selection = Some(BlockSelection { anchor: (0, 4), caret: (2, 5) });
+        assert_eq!(buffer.get_block_line_ranges(), vec![1..2, 9..10, 14..14]);
+
+        buffer.edit_block_selection("", None);
+        assert_eq!(buffer.get_text(), "\tbc\n    ef\nx");
+    }
+
+    #[test]
+    fn moving_past_the_last_line_clamps_to_it() {
+        let mut buffer = new_buffer("first\nsecond\nthird");
+        buffer.move_caret_to_line(1);
+        assert_eq!(buffer.get_caret_line_and_column(), (1, 0));
+        buffer.move_caret_to_line(100);
+        assert_eq!(buffer.get_caret_line_and_column(), (2, 0));
+        assert!(buffer.view_dirty);
+    }
+
+    #[test]
+    fn mixed_line_endings_are_normalized_to_the_most_common() {
+        let mut buffer = new_buffer("a\r\nb\nc\r\nd");
+        assert!(buffer.line_ending == LineEnding::Crlf);
+        assert!(buffer.mixed_line_endings);
+
+        buffer.convert_line_endings(buffer.line_ending);
+        assert_eq!(buffer.get_text(), "a\r\nb\r\nc\r\nd");
+        assert!(!buffer.mixed_line_endings);
+    }
+
+    #[test]
+    fn final_blank_lines_are_trimmed_to_the_maximum() {
+        let mut buffer = new_buffer("a\n\n  \n\n");
+        buffer.trim_final_blank_lines(1);
+        assert_eq!(buffer.get_text(), "a\n\n");
+        buffer.trim_final_blank_lines(0);
+        assert_eq!(buffer.get_text(), "a\n");
+
+        let mut buffer = new_buffer("a\n\n  ");
+        buffer.trim_final_blank_lines(0);
+        assert_eq!(buffer.get_text(), "a");
+
+        let mut buffer = new_buffer("\n\n");
+        buffer.trim_final_blank_lines(0);
+        assert_eq!(buffer.get_text(), "\n\n");
+    }
+
+    #[test]
+    fn triple_click_selects_the_line_with_its_line_break() {
+        let mut buffer = new_buffer("ab\r\ncd\r\nef");
+        buffer.execute_command(&BufferCommand::LeftTripleClick(TextPosition { line_offset: 1, char_offset: 1 }));
+        assert_eq!(buffer.get_selected_text(), "cd\r\n");
+
+        buffer.execute_command(&BufferCommand::LeftTripleClick(TextPosition { line_offset: 2, char_offset: 0 }));
+        assert_eq!(buffer.get_selected_text(), "ef");
+    }
+
+    #[test]
+    fn closing_a_block_lines_the_brace_up_with_the_opening_line() {
+        let mut buffer = new_buffer("  if (a) {\n    b();\n    ");
+        buffer.move_caret_to_end();
+        buffer.execute_command(&BufferCommand::AltKeyPressed(0x43, false));
+        assert_eq!(buffer.get_text(), "  if (a) {\n    b();\n  }");
+
+        let mut buffer = new_buffer("  if (a) {\n    f(b");
+        buffer.move_caret_to_end();
+        buffer.execute_command(&BufferCommand::AltKeyPressed(0x43, false));
+        buffer.execute_command(&BufferCommand::AltKeyPressed(0x43, false));
+        assert_eq!(buffer.get_text(), "  if (a) {\n    f(b)\n  }");
+    }
+
+    #[test]
+    fn characters_outside_of_ascii_are_typed_whole() {
+        let mut buffer = new_buffer("");
+        for character in [0xE9, 0xD83D, 0xDE00].iter() {
+            buffer.execute_command(&BufferCommand::CharInsert(*character));
+        }
+        assert_eq!(buffer.get_text(), "é😀");
+        assert_eq!(buffer.get_caret_absolute_pos(), 2);
+    }
+
+    #[test]
+    fn typing_a_closing_bracket_at_the_end_of_the_text_inserts_it() {
+        let mut buffer = new_buffer("");
+        buffer.execute_command(&BufferCommand::CharInsert(')' as u16));
+        assert_eq!(buffer.get_text(), ")");
+        assert_eq!(buffer.get_caret_absolute_pos(), 1);
+    }
+
+    #[test]
+    fn only_the_configured_closing_brackets_dedent_the_line() {
+        let settings = Rc::new(Settings { autocomplete_brackets: vec![('<', '>')], ..Settings::default() });
+        for (chr, expected) in [('>', "a\n    >\nb"), ('}', "a\n        }\nb")].iter() {
+            let mut buffer = TextBuffer::new_scratch("Test", "a\n        \nb", "", settings.clone());
+            buffer.indent_style = IndentStyle::Spaces;
+            buffer.indent_width = 4;
+            buffer.move_caret_to_line(1);
+            buffer.execute_command(&BufferCommand::KeyPressed(VK_END, false, false, HWND::default(), 1));
+            buffer.execute_command(&BufferCommand::CharInsert(*chr as u16));
+            assert_eq!(buffer.get_text(), *expected);
+        }
+    }
+
+    #[test]
+    fn trailing_whitespace_is_trimmed_and_the_caret_clamped() {
+        let mut buffer = new_buffer("a  \r\n\t\r\nb c \t");
+        buffer.move_caret_to_end();
+        buffer.trim_trailing_whitespace();
+        assert_eq!(buffer.get_text(), "a\r\n\r\nb c");
+        assert_eq!(buffer.get_caret_line_and_column(), (2, 3));
+    }
+
+    #[test]
+    fn new_lines_use_the_line_ending_of_the_file() {
+        let mut buffer = new_buffer("fn f() {}\nx");
+        buffer.move_caret_to_line(0);
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_END, false, false, HWND::default(), 1));
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_LEFT, false, false, HWND::default(), 1));
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_RETURN, false, false, HWND::default(), 1));
+        assert_eq!(buffer.get_text(), "fn f() {\n    \n}\nx");
+    }
+
+    #[test]
+    fn only_whitespace_inside_the_selection_is_returned() {
+        let mut buffer = new_buffer("a b\r\n\tc d");
+        buffer.execute_command(&BufferCommand::LeftClick(TextPosition { line_offset: 0, char_offset: 2 }, false, false));
+        buffer.execute_command(&BufferCommand::LeftClick(TextPosition { line_offset: 1, char_offset: 2 }, true, false));
+        assert_eq!(buffer.get_selected_whitespace(0, 1), vec![(5, '\t')]);
+    }
+
+    #[test]
+    fn quotes_are_closed_skipped_and_wrapped_around_the_selection() {
+        let mut buffer = TextBuffer::new_scratch("Test", "", crate::language_support::CPP_LANGUAGE_IDENTIFIER, Rc::new(Settings::default()));
+        buffer.execute_command(&BufferCommand::CharInsert('"' as u16));
+        buffer.execute_command(&BufferCommand::CharInsert('a' as u16));
+        buffer.execute_command(&BufferCommand::CharInsert('"' as u16));
+        assert_eq!(buffer.get_text(), "\"a\"");
+        assert_eq!(buffer.get_caret_line_and_column(), (0, 3));
+
+        buffer.execute_command(&BufferCommand::KeyPressed(0x41, false, true, HWND::default(), 1));
+        buffer.execute_command(&BufferCommand::CharInsert('"' as u16));
+        assert_eq!(buffer.get_text(), "\"\"a\"\"");
+        assert_eq!(buffer.get_selected_text(), "\"a\"");
+    }
+
+    #[test]
+    fn the_final_empty_line_is_counted_unless_hidden() {
+        let hidden = Rc::new(Settings { show_final_empty_line: false, ..Settings::default() });
+        for (text, shown_lines, hidden_lines) in [("a\nb", 2, 2), ("a\nb\n", 3, 2), ("", 1, 1), ("\n", 2, 1)].iter() {
+            assert_eq!(new_buffer(text).get_number_of_lines(), *shown_lines);
+            assert_eq!(TextBuffer::new_scratch("Test", text, "", hidden.clone()).get_number_of_lines(), *hidden_lines);
+        }
+    }
+
+    #[test]
+    fn moving_down_stops_at_the_last_line_when_the_final_empty_line_is_hidden() {
+        let hidden = Rc::new(Settings { show_final_empty_line: false, ..Settings::default() });
+        let mut buffer = TextBuffer::new_scratch("Test", "ab\ncd\n", "", hidden);
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_DOWN, false, false, HWND::default(), 1));
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_DOWN, false, false, HWND::default(), 1));
+        assert_eq!(buffer.get_caret_line_and_column(), (1, 0));
+        buffer.move_caret_to_line(100);
+        assert_eq!(buffer.get_caret_line_and_column(), (1, 0));
+
+        // The end of the text is still reachable, moving down from there stays put
+        buffer.move_caret_to_end();
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_DOWN, false, false, HWND::default(), 1));
+        assert_eq!(buffer.get_caret_line_and_column(), (2, 0));
+
+        let mut buffer = new_buffer("ab\ncd\n");
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_DOWN, false, false, HWND::default(), 1));
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_DOWN, false, false, HWND::default(), 1));
+        assert_eq!(buffer.get_caret_line_and_column(), (2, 0));
+    }
+
+    #[test]
+    fn overwrite_mode_replaces_up_to_the_line_end() {
+        let mut buffer = new_buffer("ab\ncd");
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_INSERT, false, false, HWND::default(), 1));
+        for chr in "xyz".chars() {
+            buffer.execute_command(&BufferCommand::CharInsert(chr as u16));
+        }
+        assert_eq!(buffer.get_text(), "xyz\ncd");
+
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_INSERT, false, false, HWND::default(), 1));
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_HOME, false, false, HWND::default(), 1));
+        buffer.execute_command(&BufferCommand::CharInsert('w' as u16));
+        assert_eq!(buffer.get_text(), "wxyz\ncd");
+    }
+
+    #[test]
+    fn replacing_the_selection_selects_the_new_text_and_undoes_in_one_step() {
+        let mut buffer = new_buffer("b\na\nc");
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_END, true, true, HWND::default(), 1));
+        buffer.replace_selection("a\nb\nc");
+        assert_eq!(buffer.get_text(), "a\nb\nc");
+        assert_eq!(buffer.get_selected_text(), "a\nb\nc");
+
+        buffer.execute_command(&BufferCommand::KeyPressed(0x5A, false, true, HWND::default(), 1));
+        assert_eq!(buffer.get_text(), "b\na\nc");
+    }
+
+    #[test]
+    fn tabs_are_drawn_at_the_tab_width_while_indenting_by_the_indent_width() {
+        let settings = Rc::new(Settings { tab_display_width: Some(8), ..Settings::default() });
+        let mut buffer = TextBuffer::new_scratch("Test", "\tx\n    y", "", settings);
+        buffer.indent_style = IndentStyle::Spaces;
+        buffer.indent_width = 4;
+        assert_eq!(buffer.get_display_column(1), 8);
+
+        buffer.move_caret_to_end();
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_HOME, false, false, HWND::default(), 1));
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_TAB, false, false, HWND::default(), 1));
+        assert_eq!(buffer.get_text(), "\tx\n        y");
+    }
+
+    #[test]
+    fn tab_indented_files_indent_and_dedent_by_whole_tabs() {
+        let settings = Rc::new(Settings { tab_display_width: Some(8), ..Settings::default() });
+        let mut buffer = TextBuffer::new_scratch("Test", "x\n\tif (a) {\ny", "", settings);
+        buffer.indent_style = IndentStyle::Tabs;
+        buffer.indent_width = 4;
+        buffer.move_caret_to_line(1);
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_END, false, false, HWND::default(), 1));
+        buffer.execute_command(&BufferCommand::KeyPressed(VK_RETURN, false, false, HWND::default(), 1));
+        assert_eq!(buffer.get_text(), "x\n\tif (a) {\n\t\t\ny");
+
+        buffer.execute_command(&BufferCommand::CharInsert('}' as u16));
+        assert_eq!(buffer.get_text(), "x\n\tif (a) {\n\t}\ny");
+    }
+
+    #[test]
+    fn resolving_a_merge_conflict_removes_the_markers() {
+        let text = "a\n<<<<<<< HEAD\nours\n||||||| base\nbase\n=======\ntheirs\n>>>>>>> branch\nb";
+        let conflict = MergeConflict { start: 1, base: Some(3), separator: 5, end: 7 };
+        for (resolution, expected) in [
+            (ConflictResolution::Current, "a\nours\nb"),
+            (ConflictResolution::Incoming, "a\ntheirs\nb"),
+            (ConflictResolution::Both, "a\nours\ntheirs\nb")
+        ].iter() {
+            let mut buffer = new_buffer(text);
+            assert_eq!(git_support::find_merge_conflicts(&buffer.get_lines_without_linebreaks()), vec![conflict.clone()]);
+            assert!(buffer.resolve_merge_conflict(&conflict, *resolution));
+            assert_eq!(buffer.get_text(), *expected);
+
+            buffer.execute_command(&BufferCommand::KeyPressed(0x5A, false, true, HWND::default(), 1));
+            assert_eq!(buffer.get_text(), text);
+        }
+
+        // Markers that were edited away are left alone
+        let mut buffer = new_buffer("<<<<<<< HEAD\nours\n>>>>>>> branch");
+        assert!(git_support::find_merge_conflicts(&buffer.get_lines_without_linebreaks()).is_empty());
+        assert!(!buffer.resolve_merge_conflict(&MergeConflict { start: 0, base: None, separator: 1, end: 2 }, ConflictResolution::Current));
+    }
+
+    #[test]
+    fn deleted_files_are_not_reloaded() {
+        let path = std::env::temp_dir().join(format!("nimble_deleted_file_test_{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "text").unwrap();
+        let mut buffer = TextBuffer::new(path, "", Rc::new(Settings::default()));
+        assert!(!buffer.has_changed_on_disk());
+
+        fs::remove_file(path).unwrap();
+        assert!(!buffer.has_changed_on_disk());
+        assert!(buffer.reload().is_err());
+        assert_eq!(buffer.get_text(), "text");
+    }
+
+    #[test]
+    fn selections_up_to_the_last_char_are_copied() {
+        let mut buffer = new_buffer("abc\ndef");
+        buffer.select_all();
+        assert_eq!(buffer.get_selection_data(), "abc\ndef");
+
+        buffer.caret_char_anchor = 4;
+        buffer.caret_char_pos = 7;
+        assert_eq!(buffer.get_selection_data(), "def");
+
+        // The caret on the trailing side of the last char, as after clicking right of it
+        buffer.caret_char_pos = 6;
+        buffer.caret_trailing = BOOL::from(true);
+        assert_eq!(buffer.get_selection_data(), "def");
+
+        // Selected backwards
+        buffer.caret_char_anchor = 7;
+        buffer.caret_char_pos = 4;
+        buffer.caret_trailing = BOOL::from(false);
+        assert_eq!(buffer.get_selection_data(), "def");
+
+        let mut buffer = new_buffer("");
+        buffer.select_all();
+        assert_eq!(buffer.get_selection_data(), "");
+    }
+
+    #[test]
+    fn find_spans_rope_chunks() {
+        // Long enough to be split into several chunks, with multi-byte chars on both sides of the needle
+        let text = format!("{}needle{}", "\u{E9}".repeat(3000), "x".repeat(3000));
+        let buffer = new_buffer(&text);
+        assert!(buffer.rope.chunks().count() > 1);
+        assert_eq!(buffer.find("needle", 0), Some(3000));
+        assert_eq!(buffer.find("needle", 3000), Some(3000));
+        assert_eq!(buffer.find("needle", 3001), None);
+        assert_eq!(buffer.find("\u{E9}x", 0), None);
+        assert_eq!(buffer.find("", 0), None);
+        assert_eq!(buffer.find("x", buffer.rope.len_chars()), None);
+    }
+
+    #[test]
+    fn find_lines_lists_every_line_with_a_match_once() {
+        let buffer = new_buffer("aa\nb\r\nba a\n");
+        assert_eq!(buffer.find_lines("a"), vec![0, 2]);
+        assert_eq!(buffer.find_lines("b"), vec![1, 2]);
+        assert!(buffer.find_lines("c").is_empty());
+        assert!(buffer.find_lines("").is_empty());
+    }
+
+    #[test]
+    fn replace_next_wraps_around() {
+        let mut buffer = new_buffer("a1 a2 a3");
+        buffer.caret_char_pos = 4;
+        buffer.caret_char_anchor = 4;
+        assert!(buffer.replace_next("a", "b"));
+        assert_eq!(buffer.get_text(), "a1 a2 b3");
+        assert_eq!(buffer.get_caret_absolute_pos(), 7);
+
+        assert!(buffer.replace_next("a", "b"));
+        assert_eq!(buffer.get_text(), "b1 a2 b3");
+        assert!(buffer.replace_next("a", "b"));
+        assert!(!buffer.replace_next("a", "b"));
+        assert_eq!(buffer.get_text(), "b1 b2 b3");
+    }
+
+    #[test]
+    fn replace_all_is_a_single_undo_step() {
+        let mut buffer = new_buffer("x + x * x");
+        let undo_states = buffer.undo_states.len();
+        assert_eq!(buffer.replace_all("x", "value"), 3);
+        assert_eq!(buffer.get_text(), "value + value * value");
+        assert_eq!(buffer.undo_states.len(), undo_states + 1);
+
+        buffer.undo();
+        assert_eq!(buffer.get_text(), "x + x * x");
+    }
+
+    #[test]
+    fn replace_all_keeps_the_caret_out_of_replaced_text() {
+        // Inside an occurrence, the caret moves past its replacement
+        let mut buffer = new_buffer("foo foo bar");
+        buffer.caret_char_pos = 5;
+        buffer.caret_char_anchor = 5;
+        assert_eq!(buffer.replace_all("foo", "x"), 2);
+        assert_eq!(buffer.get_text(), "x x bar");
+        assert_eq!(buffer.get_caret_absolute_pos(), 3);
+
+        // After the occurrences, the caret stays on the same text
+        let mut buffer = new_buffer("foo foo bar");
+        buffer.caret_char_pos = 9;
+        buffer.caret_char_anchor = 9;
+        buffer.replace_all("foo", "x");
+        assert_eq!(buffer.get_caret_absolute_pos(), 5);
+
+        // At the end of text that gets shorter, the caret is clamped to the end
+        let mut buffer = new_buffer("foofoo");
+        buffer.caret_char_pos = 6;
+        buffer.caret_char_anchor = 6;
+        buffer.replace_all("foo", "");
+        assert_eq!(buffer.get_text(), "");
+        assert_eq!(buffer.get_caret_absolute_pos(), 0);
+    }
+
+    #[test]
+    fn converting_the_encoding_makes_the_buffer_dirty() {
+        let path = std::env::temp_dir().join(format!("nimble_encoding_test_{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "text").unwrap();
+        let mut buffer = TextBuffer::new(path, "", Rc::new(Settings::default()));
+        assert!(!buffer.is_dirty());
+
+        buffer.convert_encoding(TextEncoding::Utf16Le);
+        assert!(buffer.is_dirty());
+        buffer.convert_encoding(TextEncoding::Utf8);
+        assert!(!buffer.is_dirty());
+
+        buffer.convert_encoding(TextEncoding::Utf8Bom);
+        buffer.save().unwrap();
+        assert!(!buffer.is_dirty());
+        assert_eq!(fs::read(path).unwrap(), b"\xEF\xBB\xBFtext");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn undoing_back_to_the_saved_contents_makes_the_buffer_clean() {
+        let path = std::env::temp_dir().join(format!("nimble_dirty_test_{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "text").unwrap();
+        let mut buffer = TextBuffer::new(path, "", Rc::new(Settings::default()));
+        buffer.move_caret_to_end();
+        buffer.execute_command(&BufferCommand::CharInsert(' ' as u16));
+        buffer.execute_command(&BufferCommand::CharInsert('a' as u16));
+        assert!(buffer.is_dirty());
+
+        buffer.undo();
+        assert!(!buffer.is_dirty());
+
+        buffer.execute_command(&BufferCommand::CharInsert(' ' as u16));
+        buffer.save().unwrap();
+        assert!(!buffer.is_dirty());
+        buffer.undo();
+        assert!(buffer.is_dirty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn selections_are_visited_from_their_starts() {
+        // The second selection is selected backwards, so
+        // its caret is where the first selection ends
+        let mut buffer = new_buffer("abcdefgh");
+        buffer.caret_char_anchor = 0;
+        buffer.caret_char_pos = 3;
+        buffer.secondary_carets = vec![(3, 5)];
+        buffer.for_each_caret(|buffer| {
+            let start = min(buffer.caret_char_pos, buffer.caret_char_anchor);
+            buffer.insert_text(start, "|");
+        });
+        assert_eq!(buffer.get_text(), "|abc|defgh");
+    }
+
+    #[test]
+    fn typing_over_overlapping_selections_replaces_them_once() {
+        // A selection nested inside the primary one
+        let mut buffer = new_buffer("abcdefgh");
+        buffer.caret_char_anchor = 0;
+        buffer.caret_char_pos = 6;
+        buffer.secondary_carets = vec![(5, 3)];
+        buffer.execute_command(&BufferCommand::CharInsert('x' as u16));
+        assert_eq!(buffer.get_text(), "xgh");
+        assert_eq!(buffer.get_caret_absolute_pos(), 1);
+        assert!(buffer.secondary_carets.is_empty());
+
+        // Selections running into each other, the first one selected backwards
+        let mut buffer = new_buffer("abcdefgh");
+        buffer.caret_char_anchor = 4;
+        buffer.caret_char_pos = 1;
+        buffer.secondary_carets = vec![(6, 3), (7, 7)];
+        buffer.execute_command(&BufferCommand::CharInsert('x' as u16));
+        assert_eq!(buffer.get_text(), "axgxh");
+        assert_eq!(buffer.get_caret_absolute_pos(), 2);
+        assert_eq!(buffer.secondary_carets, vec![(4, 4)]);
+    }
+
+    #[test]
+    fn a_character_typed_at_several_carets_is_undone_in_one_step() {
+        let mut buffer = new_buffer("ab\nab");
+        buffer.caret_char_anchor = 2;
+        buffer.caret_char_pos = 2;
+        buffer.secondary_carets = vec![(5, 5)];
+        buffer.execute_command(&BufferCommand::CharInsert('x' as u16));
+        buffer.execute_command(&BufferCommand::CharInsert('y' as u16));
+        assert_eq!(buffer.get_text(), "abxy\nabxy");
+
+        buffer.undo();
+        assert_eq!(buffer.get_text(), "abx\nabx");
+    }
+
+    #[test]
+    fn quoting_several_selections_is_undone_in_one_step() {
+        let mut buffer = TextBuffer::new_scratch("Test", "a b", crate::language_support::RUST_LANGUAGE_IDENTIFIER, Rc::new(Settings::default()));
+        buffer.caret_char_anchor = 0;
+        buffer.caret_char_pos = 1;
+        buffer.secondary_carets = vec![(2, 3)];
+        buffer.execute_command(&BufferCommand::CharInsert('"' as u16));
+        assert_eq!(buffer.get_text(), "\"a\" \"b\"");
+
+        buffer.undo();
+        assert_eq!(buffer.get_text(), "a b");
+    }
+}
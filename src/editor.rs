@@ -1,48 +1,182 @@
 use std::{
+    cmp::min,
     collections::HashMap,
+    io::Write,
+    ops::Range,
     str,
-    path::Path
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    rc::Rc,
+    slice::from_raw_parts,
+    thread,
+    time::{Duration, Instant}
 };
 
 use bindings::{
-    Windows::Win32::WindowsAndMessaging::*,
+    Windows::Win32::{
+        WindowsAndMessaging::*,
+        Shell::{IFileOpenDialog, FileOpenDialog, SIGDN, _FILEOPENDIALOGOPTIONS},
+        Com::CoTaskMemFree,
+        SystemServices::PWSTR
+    }
 };
 use windows::Result;
 
 use crate::{
-    settings::{SCROLL_LINES_PER_ROLL, SCROLL_LINES_PER_DRAG, SCROLL_ZOOM_DELTA},
+    settings::{Settings, IndentStyle, TextAntialiasing, SETTINGS_FILE},
     renderer::TextRenderer,
-    language_support::{CPP_FILE_EXTENSIONS, CPP_LANGUAGE_IDENTIFIER, RUST_FILE_EXTENSIONS, RUST_LANGUAGE_IDENTIFIER},
+    theme::Theme,
+    language_support::{SemanticToken, decode_semantic_tokens, semantic_token_type_from_name, CPP_FILE_EXTENSIONS, CPP_LANGUAGE_IDENTIFIER, RUST_FILE_EXTENSIONS, RUST_LANGUAGE_IDENTIFIER,
+                       PYTHON_FILE_EXTENSIONS, PYTHON_LANGUAGE_IDENTIFIER, PLAIN_TEXT_LANGUAGE_IDENTIFIER},
     buffer::{BufferCommand, TextRange, TextBuffer},
+    git_support::{self, GitDiff, MergeConflict},
+    file_tree::FileTree,
+    lsp_client::{LSPClient, LSPRequestType, path_to_uri, uri_to_path},
+    lsp_structs::{
+        GenericResponse, SemanticTokensResult, SemanticTokensParams, DidOpenTextDocumentParams,
+        DidChangeTextDocumentParams, DidCloseTextDocumentParams, InitializeResult, DocumentOnTypeFormattingParams, TextEdit,
+        TextDocumentPositionParams, CompletionResult, HoverResult, DefinitionResult, Position,
+        PublishDiagnosticsParams, TextDocumentContentChangeEvent, TextDocumentSync, TextDocumentSyncKind
+    },
+    word_completion::{self, WordIndex, CompletionList},
+    scratch_backup::{self, ScratchBackups},
+    text_utils,
     util::unwrap_hresult
 };
 
 type MousePos = (f32, f32);
 type ShiftDown = bool;
 type CtrlDown = bool;
+// Positive when the wheel is rotated away from the user, one notch is WHEEL_DELTA
+type WheelDelta = i32;
+
+const WHEEL_DELTA: i32 = 120;
+
+const GIT_DIFF_TIMER_ID: usize = 1;
+const FILE_CHANGE_TIMER_ID: usize = 2;
+const WORD_INDEX_TIMER_ID: usize = 3;
+const BRACKET_MATCH_TIMER_ID: usize = 4;
+const SCRATCH_BACKUP_TIMER_ID: usize = 5;
+const HOVER_TIMER_ID: usize = 6;
+const SHELL_COMMAND_TIMER_ID: usize = 7;
+
+// The output of a shell command is passed to the window procedure like the
+// responses of the language servers, see handle_shell_command_output
+pub const WM_SHELL_COMMAND_OUTPUT: u32 = WM_USER + 3;
 
-const TEXT_ORIGIN: (f32, f32) = (0.0_f32, 0.0_f32);
+// A crashed language server is restarted after this delay, which doubles with
+// every crash in a row so a server that dies right away doesn't keep respawning
+const LSP_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_LSP_RESTART_DELAY: Duration = Duration::from_secs(60);
 
 #[derive(PartialEq)]
 pub enum EditorCommand {
-    ScrollUp(CtrlDown),
-    ScrollDown(CtrlDown),
-    LeftClick(MousePos, ShiftDown),
+    MouseWheel(WheelDelta, CtrlDown),
+    // Positive scrolls right
+    MouseHorizontalWheel(WheelDelta),
+    LeftClick(MousePos, ShiftDown, CtrlDown),
+    LeftAltClick(MousePos),
     LeftDoubleClick(MousePos),
+    LeftTripleClick(MousePos),
     LeftRelease,
     MouseMove(MousePos),
     KeyPressed(u32, ShiftDown, CtrlDown),
-    CharInsert(u16)
+    AltKeyPressed(u32, ShiftDown),
+    CharInsert(u16),
+    NextTab,
+    PrevTab,
+    CloseDocument,
+    GoToLine(usize)
+}
+
+// The last crash of a language server and how many crashes came right before it
+struct LSPCrash {
+    time: Instant,
+    count: u32
+}
+
+impl LSPCrash {
+    fn get_restart_delay(&self) -> Duration {
+        let factor = 1u32.checked_shl(self.count.saturating_sub(1)).unwrap_or(u32::MAX);
+        min(LSP_RESTART_DELAY.saturating_mul(factor), MAX_LSP_RESTART_DELAY)
+    }
 }
 
 pub struct TextView {
     pub line_offset: usize,
-    pub column_offset: usize
+    pub column_offset: usize,
+
+    // How many pixels of the top line are scrolled out of view,
+    // always less than the height of a line
+    pub pixel_offset: f32,
+
+    // Word wrapping is toggled per document, 
+    // so it lives with the view rather than the renderer
+    pub word_wrap: bool
+}
+
+// What the language server knows about the word
+// under the mouse, drawn in a tooltip next to it
+pub struct HoverTooltip {
+    pub text: String,
+    pub mouse_pos: MousePos
+}
+
+// Hints are shown like information, the
+// severities are ordered from least to most severe
+#[derive(Copy, Clone, PartialEq, PartialOrd, Debug)]
+pub enum DiagnosticSeverity {
+    Information,
+    Warning,
+    Error
+}
+
+impl DiagnosticSeverity {
+    // Diagnostics without a severity are taken to be errors
+    pub fn from_lsp(severity: Option<u8>) -> Self {
+        match severity {
+            Some(2) => DiagnosticSeverity::Warning,
+            Some(3) | Some(4) => DiagnosticSeverity::Information,
+            _ => DiagnosticSeverity::Error
+        }
+    }
+}
+
+// A problem the language server found in the char range of the document
+pub struct DocumentDiagnostic {
+    pub range: Range<usize>,
+    pub severity: DiagnosticSeverity
 }
 
 pub struct TextDocument {
     pub buffer: TextBuffer,
-    pub view: TextView
+    pub view: TextView,
+
+    // Changes compared to the version committed
+    // in git, None if the file isn't tracked
+    pub git_diff: Option<GitDiff>,
+    // Found again along with the git diff once typing pauses
+    pub merge_conflicts: Vec<MergeConflict>,
+
+    // The blame of a line shown as a hint after
+    // the line, only visible while the caret is on it
+    pub blame_hint: Option<(usize, String)>,
+
+    // Decoded from the last semantic tokens response of the language server
+    pub semantic_tokens: Vec<SemanticToken>,
+    // Replaced whenever the language server publishes the diagnostics of the document
+    pub diagnostics: Vec<DocumentDiagnostic>,
+    // The lines with the text typed into the replace prompt, marked on the scrollbar
+    pub search_match_lines: Vec<usize>,
+
+    // The words of the document offered by word completion,
+    // and the completion list if it is currently open
+    pub word_index: WordIndex,
+    pub completion: Option<CompletionList>,
+    pub hover_tooltip: Option<HoverTooltip>,
+
+    // The file the text of a scratch document is backed up to, see scratch_backup
+    pub scratch_backup: Option<PathBuf>
 }
 
 fn scroll_view_up(text_document: &mut TextDocument, lines_per_roll: usize) {
@@ -51,21 +185,131 @@ fn scroll_view_up(text_document: &mut TextDocument, lines_per_roll: usize) {
     }
     else {
         text_document.view.line_offset = 0;
+        text_document.view.pixel_offset = 0.0;
     }
 }
 
-fn scroll_view_down(text_document: &mut TextDocument, lines_per_roll: usize) {
+// Scrolls down by a number of pixels, or up if negative, carrying
+// whole lines over into the line offset. The view stops with
+// the first or last line at the top, without a partial line above it
+fn scroll_view_by_pixels(text_document: &mut TextDocument, pixels: f32, line_spacing: f32, max_line_offset: usize) {
+    let view = &mut text_document.view;
+
+    let position = view.line_offset as f32 * line_spacing + view.pixel_offset + pixels;
+    let position = position.clamp(0.0, max_line_offset as f32 * line_spacing);
+    view.line_offset = min((position / line_spacing) as usize, max_line_offset);
+    view.pixel_offset = position - view.line_offset as f32 * line_spacing;
+}
+
+fn scroll_view_down(text_document: &mut TextDocument, lines_per_roll: usize, max_line_offset: usize) {
     let new_top = text_document.view.line_offset + lines_per_roll;
-    let number_of_lines = text_document.buffer.get_number_of_lines();
 
-    if new_top >= number_of_lines {
-        text_document.view.line_offset = number_of_lines - 1;
+    if new_top >= max_line_offset {
+        text_document.view.line_offset = max_line_offset;
+        text_document.view.pixel_offset = 0.0;
     }
     else {
         text_document.view.line_offset = new_top;
     }
 }
 
+// Moves the caret to the start of the next or previous
+// changed hunk relative to the caret line
+fn jump_to_hunk(text_document: &mut TextDocument, forward: bool) {
+    let git_diff = match &text_document.git_diff {
+        Some(git_diff) => git_diff,
+        None => return
+    };
+
+    let (caret_line, _) = text_document.buffer.get_caret_line_and_column();
+    let hunk = if forward {
+        git_diff.hunks.iter().find(|hunk| hunk.line > caret_line)
+    }
+    else {
+        git_diff.hunks.iter().rev().find(|hunk| hunk.line < caret_line)
+    };
+
+    if let Some(hunk) = hunk {
+        text_document.buffer.move_caret_to_line(hunk.line);
+    }
+}
+
+// Toggles the blame hint for the caret line
+fn toggle_blame_hint(text_document: &mut TextDocument) {
+    let (caret_line, _) = text_document.buffer.get_caret_line_and_column();
+    if let Some((line, _)) = &text_document.blame_hint {
+        if *line == caret_line {
+            text_document.blame_hint = None;
+            return;
+        }
+    }
+
+    let buffer = &text_document.buffer;
+    text_document.blame_hint = git_support::blame_line(&buffer.path, &buffer.get_text(), caret_line)
+        .map(|blame_info| (caret_line, blame_info.to_hint()));
+}
+
+// The text to replace typed after CTRL+H and, once
+// confirmed with enter, the text to replace it with
+struct ReplacePrompt {
+    needle: String,
+    replacement: Option<String>
+}
+
+pub struct ShellCommandOutput {
+    id: usize,
+    result: std::result::Result<String, String>
+}
+
+// The command the selection of a document is being piped through. The output
+// only replaces the selection if the document hasn't changed in the meantime
+struct RunningShellCommand {
+    id: usize,
+    process_id: u32,
+    command_line: String,
+    path: String,
+    version: i32,
+    selection: Range<usize>
+}
+
+fn spawn_shell_command(command_line: &str) -> std::io::Result<Child> {
+    Command::new("cmd")
+        .arg("/C")
+        .arg(command_line)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+}
+
+// Runs on a separate thread, writes the input to the stdin of the command and returns its
+// stdout, or its stderr if it fails. The input is written from yet another thread so a
+// command writing output before reading all of it can't block
+fn wait_for_shell_command(mut child: Child, input: String) -> std::result::Result<String, String> {
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output().map_err(|error| format!("Failed to run the command: {}", error))?;
+    // A command that doesn't read all of its input closes the pipe early, which isn't an error
+    let _ = writer.join();
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+    else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(match stderr.trim() {
+            "" => format!("The command failed with {}", output.status),
+            stderr => stderr.to_owned()
+        })
+    }
+}
+
+// Every key press reaches the prompts before the character it types arrives as
+// CharInsert, so the keys that type characters are left alone by the prompts
+fn is_typing_key(key: u32, ctrl_down: CtrlDown) -> bool {
+    !ctrl_down && (key == VK_SPACE || key == VK_SHIFT || (0x30..=0x5A).contains(&key) || (0x60..=0x6F).contains(&key) || (0xBA..=0xE2).contains(&key))
+}
+
 pub fn scroll_view_left(text_document: &mut TextDocument, lines_per_roll: usize) {
     if text_document.view.column_offset >= lines_per_roll {
         text_document.view.column_offset -= lines_per_roll;
@@ -87,24 +331,114 @@ pub fn scroll_view_right(text_document: &mut TextDocument, lines_per_roll: usize
 pub struct Editor {
     hwnd: HWND,
     renderer: TextRenderer,
+    settings: Rc<Settings>,
 
     documents: HashMap<String, TextDocument>,
+    // The paths of the open documents in the order
+    // they were opened, used for ordering the tabs
+    document_order: Vec<String>,
     current_document: String,
+
+    file_tree: FileTree,
+
+    // One language server per language identifier
+    lsp_clients: HashMap<&'static str, LSPClient>,
+    // The languages whose server crashed, kept after the restart to back off if it crashes again
+    lsp_crashes: HashMap<&'static str, LSPCrash>,
+
+    // The directory the scratch documents are backed up to, None if they aren't backed up
+    scratch_backups: Option<ScratchBackups>,
+
+    // The line number typed so far after CTRL+G, shown in
+    // the status bar until it is confirmed or cancelled
+    go_to_line_prompt: Option<String>,
+    // The command line typed so far after ALT+!, the
+    // selection is piped through it once confirmed
+    shell_command_prompt: Option<String>,
+    replace_prompt: Option<ReplacePrompt>,
+    shell_command: Option<RunningShellCommand>,
+    // Counts the shell commands run, so the output of a cancelled one can be told apart
+    shell_command_count: usize,
+    // Shown in the status bar in place of the document
+    // status until the next key press or click
+    status_message: Option<String>,
+
+    // Where the mouse last moved to, the language server is
+    // asked about the word there once the hover timer fires
+    hover_mouse_pos: Option<MousePos>,
+
+    // Overwrite mode applies to every document, so switching
+    // documents doesn't change it behind the user's back
+    overwrite: bool,
+
+    // Zooming changes the font size by whole notches, precise
+    // wheels report smaller deltas which are added up here
+    zoom_wheel_delta: WheelDelta,
+    // The view scrolls by whole columns, so the horizontal deltas are added up the same way
+    horizontal_wheel_delta: WheelDelta
 }
 
 impl Editor {
     pub fn new(hwnd: HWND) -> Result<Self> {
-        Ok(Self {
+        // Failures loading the settings and the theme and backing up the
+        // scratch documents are shown in the status bar once the window is up
+        let mut status_message = None;
+        let settings = Rc::new(Settings::load(SETTINGS_FILE).unwrap_or_else(|error| {
+            status_message = Some(format!("Failed to parse the settings {}, using the defaults: {}", SETTINGS_FILE, error));
+            Settings::default()
+        }));
+        if settings.auto_reload_changed_files {
+            unsafe { SetTimer(hwnd, FILE_CHANGE_TIMER_ID, settings.file_change_poll_interval_ms, None); }
+        }
+
+        let theme_colors = Theme::load_colors(&settings.theme_file).unwrap_or_else(|error| {
+            status_message = Some(format!("Failed to parse the theme {}, using the default theme: {}", settings.theme_file, error));
+            HashMap::new()
+        });
+
+        let scratch_backups = match settings.backup_scratch_documents.then(ScratchBackups::lock) {
+            Some(Ok(scratch_backups)) => Some(scratch_backups),
+            Some(Err(error)) => {
+                status_message = Some(format!("Failed to create the scratch backup directory, scratch documents won't be backed up: {}", error));
+                None
+            }
+            None => None
+        };
+
+        let mut editor = Self {
             hwnd,
-            renderer: TextRenderer::new(hwnd, "Consolas", 20.0)?,
+            renderer: TextRenderer::new(hwnd, &settings.font_family, settings.font_size, &theme_colors, settings.clone())?,
+            settings,
             documents: HashMap::new(),
+            document_order: Vec::new(),
             current_document: "".to_owned(),
-        })
+            file_tree: FileTree::new(),
+            lsp_clients: HashMap::new(),
+            lsp_crashes: HashMap::new(),
+            scratch_backups,
+            go_to_line_prompt: None,
+            shell_command_prompt: None,
+            replace_prompt: None,
+            shell_command: None,
+            shell_command_count: 0,
+            status_message,
+            hover_mouse_pos: None,
+            overwrite: false,
+            zoom_wheel_delta: 0,
+            horizontal_wheel_delta: 0
+        };
+        editor.restore_scratch_documents();
+        Ok(editor)
     }
 
     pub fn open_file(&mut self, path: &str) {
-        let os_path = Path::new(path);
-        let extension = os_path.extension().unwrap().to_str().unwrap();
+        if self.documents.contains_key(path) {
+            self.switch_document(path);
+            return;
+        }
+
+        // Files without an extension, like the standard headers of MSVC, open as plain text
+        let extension = Path::new(path).extension().and_then(|extension| extension.to_str()).unwrap_or("");
 
         let language_identifier = 
         if CPP_FILE_EXTENSIONS.contains(&extension) {
@@ -113,34 +447,560 @@ impl Editor {
         else if RUST_FILE_EXTENSIONS.contains(&extension) {
             RUST_LANGUAGE_IDENTIFIER
         }
+        else if PYTHON_FILE_EXTENSIONS.contains(&extension) {
+            PYTHON_LANGUAGE_IDENTIFIER
+        }
         else {
-            ""
+            PLAIN_TEXT_LANGUAGE_IDENTIFIER
         };
 
+        let mut buffer = TextBuffer::new(path, language_identifier, self.settings.clone());
+        buffer.overwrite = self.overwrite;
+        if self.settings.open_files_at_end || self.settings.open_at_end_extensions.iter().any(|open_at_end| open_at_end == extension) {
+            buffer.move_caret_to_end();
+        }
+        let git_diff = GitDiff::new(path).map(|mut git_diff| {
+            git_diff.update(&buffer.get_lines_without_linebreaks());
+            git_diff
+        });
+        let word_index = WordIndex::new(&buffer.get_text());
+        let merge_conflicts = git_support::find_merge_conflicts(&buffer.get_lines_without_linebreaks());
+
         self.documents.insert(
             path.to_string(),
             TextDocument {
-                buffer: TextBuffer::new(path, language_identifier),
+                buffer,
                 view: TextView {
                     line_offset: 0,
-                    column_offset: 0 
-                }
+                    column_offset: 0,
+                    pixel_offset: 0.0,
+                    word_wrap: self.settings.word_wrap
+                },
+                git_diff,
+                merge_conflicts,
+                blame_hint: None,
+                semantic_tokens: Vec::new(),
+                diagnostics: Vec::new(),
+                search_match_lines: Vec::new(),
+                word_index,
+                completion: None,
+                hover_tooltip: None,
+                scratch_backup: None
             }
         );
+        self.document_order.push(path.to_string());
         self.current_document = path.to_string();
+        self.open_lsp_document(path);
+    }
+
+    // Starts the language server for the language of the document
+    // unless it is already running, and opens the document on it
+    fn open_lsp_document(&mut self, path: &str) {
+        let document = match self.documents.get_mut(path) {
+            Some(document) => document,
+            None => return
+        };
+        let language_identifier = document.buffer.language_identifier;
+
+        if !self.lsp_clients.contains_key(language_identifier) {
+            // The workspace is the root if one is open, otherwise the directory of the file
+            let root_path = match self.file_tree.root.as_ref().and_then(|root| root.to_str()) {
+                Some(root) => root.to_owned(),
+                None => Path::new(path).parent().and_then(|parent| parent.to_str()).unwrap_or(path).to_owned()
+            };
+            match LSPClient::new(self.hwnd, language_identifier) {
+                Some(mut client) => {
+                    client.initialize(&root_path);
+                    self.lsp_clients.insert(language_identifier, client);
+                }
+                None => return
+            }
+        }
+
+        if let Some(client) = self.lsp_clients.get_mut(language_identifier) {
+            document.buffer.on_type_formatting_triggers = client.on_type_formatting_triggers.clone();
+            client.send_notification("textDocument/didOpen", DidOpenTextDocumentParams::new(
+                path_to_uri(path), language_identifier, document.buffer.version, document.buffer.get_text()
+            ));
+            client.send_request("textDocument/semanticTokens/full", SemanticTokensParams::new(path_to_uri(path)), LSPRequestType::SemanticTokensRequest(path.to_owned()));
+        }
+    }
+
+    // Forwards the changes made to the document since the last call to the language server
+    fn send_lsp_changes(&mut self, path: &str) {
+        let document = match self.documents.get_mut(path) {
+            Some(document) => document,
+            None => return
+        };
+        let content_changes = document.buffer.take_change_events();
+        let on_type_formatting_request = document.buffer.take_on_type_formatting_request();
+        if content_changes.is_empty() || document.buffer.scratch {
+            return;
+        }
+
+        if let Some(client) = self.lsp_clients.get_mut(document.buffer.language_identifier) {
+            let content_changes = match client.text_document_sync {
+                TextDocumentSyncKind::None => None,
+                TextDocumentSyncKind::Full => Some(vec![TextDocumentContentChangeEvent::new_full_event(document.buffer.get_text())]),
+                TextDocumentSyncKind::Incremental => Some(content_changes)
+            };
+            if let Some(content_changes) = content_changes {
+                client.send_notification("textDocument/didChange", DidChangeTextDocumentParams::new(path_to_uri(path), document.buffer.version, content_changes));
+            }
+            client.send_request("textDocument/semanticTokens/full", SemanticTokensParams::new(path_to_uri(path)), LSPRequestType::SemanticTokensRequest(path.to_owned()));
+            if let Some((position, chr)) = on_type_formatting_request {
+                let params = DocumentOnTypeFormattingParams::new(
+                    path_to_uri(path), position, chr, document.buffer.indent_width, document.buffer.indent_style == IndentStyle::Spaces
+                );
+                client.send_request("textDocument/onTypeFormatting", params, LSPRequestType::OnTypeFormattingRequest(path.to_owned(), document.buffer.version));
+            }
+        }
+    }
+
+    // The crash message of a client that was already replaced is ignored
+    pub fn handle_lsp_crash(&mut self, client_id: usize) {
+        let language_identifier = match self.lsp_clients.iter().find(|(_, client)| client.id == client_id) {
+            Some((language_identifier, _)) => *language_identifier,
+            None => return
+        };
+        println!("The {} language server crashed", language_identifier);
+        self.lsp_clients.remove(language_identifier);
+
+        // A server that ran for a while before crashing starts over with the shortest delay
+        let time = Instant::now();
+        let count = match self.lsp_crashes.get(language_identifier) {
+            Some(crash) if time.duration_since(crash.time) < crash.get_restart_delay() + MAX_LSP_RESTART_DELAY => crash.count + 1,
+            _ => 1
+        };
+        self.lsp_crashes.insert(language_identifier, LSPCrash { time, count });
+    }
+
+    // Starts the crashed language servers again once their delay has passed, or right away when
+    // forced, and opens their documents on them. The documents are sent whole, so any changes made
+    // while the server was down have to be taken from the buffers before this is called
+    fn restart_crashed_lsp_clients(&mut self, force: bool) {
+        let now = Instant::now();
+        let language_identifiers: Vec<&'static str> = self.lsp_crashes.iter()
+            .filter(|(language_identifier, crash)| {
+                !self.lsp_clients.contains_key(*language_identifier) && (force || now.duration_since(crash.time) >= crash.get_restart_delay())
+            })
+            .map(|(language_identifier, _)| *language_identifier)
+            .collect();
+
+        for language_identifier in language_identifiers {
+            let paths: Vec<String> = self.document_order.iter()
+                .filter(|path| self.documents.get(*path).map_or(false, |document| {
+                    !document.buffer.scratch && document.buffer.language_identifier == language_identifier
+                }))
+                .cloned()
+                .collect();
+            // Opening a document of the language later starts the server anyway
+            if paths.is_empty() {
+                continue;
+            }
+
+            println!("Restarting the {} language server", language_identifier);
+            for path in paths {
+                self.open_lsp_document(&path);
+            }
+
+            // A server that fails to start backs off like one that crashed
+            if !self.lsp_clients.contains_key(language_identifier) {
+                if let Some(crash) = self.lsp_crashes.get_mut(language_identifier) {
+                    crash.time = now;
+                    crash.count += 1;
+                }
+            }
+        }
+    }
+
+    // Opens a scratch buffer seeded with the current selection,
+    // it keeps the language of the current document for highlighting
+    pub fn open_scratch_document(&mut self) {
+        let (text, language_identifier) = match self.documents.get(&self.current_document) {
+            Some(document) => (document.buffer.get_selected_text(), document.buffer.language_identifier),
+            None => (String::new(), PLAIN_TEXT_LANGUAGE_IDENTIFIER)
+        };
+
+        let scratch_backup = self.scratch_backups.as_ref().map(|scratch_backups| scratch_backups.new_backup_path(language_identifier));
+        self.add_scratch_document(&text, language_identifier, scratch_backup);
+        self.schedule_scratch_backup();
+    }
+
+    // Reopens the scratch documents backed up when the editor last closed
+    fn restore_scratch_documents(&mut self) {
+        let backups = match &self.scratch_backups {
+            Some(scratch_backups) => scratch_backups.claim_backups(),
+            None => return
+        };
+        for (path, text, language_identifier) in backups {
+            self.add_scratch_document(&text, language_identifier, Some(path));
+        }
+    }
+
+    fn add_scratch_document(&mut self, text: &str, language_identifier: &'static str, scratch_backup: Option<PathBuf>) {
+        let mut number = 1;
+        while self.documents.contains_key(&format!("Scratch {}", number)) {
+            number += 1;
+        }
+        let name = format!("Scratch {}", number);
+        let mut buffer = TextBuffer::new_scratch(&name, text, language_identifier, self.settings.clone());
+        buffer.overwrite = self.overwrite;
+        let merge_conflicts = git_support::find_merge_conflicts(&buffer.get_lines_without_linebreaks());
+
+        self.documents.insert(
+            name.clone(),
+            TextDocument {
+                buffer,
+                view: TextView {
+                    line_offset: 0,
+                    column_offset: 0,
+                    pixel_offset: 0.0,
+                    word_wrap: self.settings.word_wrap
+                },
+                git_diff: None,
+                merge_conflicts,
+                blame_hint: None,
+                semantic_tokens: Vec::new(),
+                diagnostics: Vec::new(),
+                search_match_lines: Vec::new(),
+                word_index: WordIndex::new(text),
+                completion: None,
+                hover_tooltip: None,
+                scratch_backup
+            }
+        );
+        self.document_order.push(name.clone());
+        self.current_document = name;
+    }
+
+    pub fn switch_document(&mut self, path: &str) {
+        if self.documents.contains_key(path) {
+            self.current_document = path.to_string();
+        }
+    }
+
+    // Cycles through the open documents in the order they were opened
+    fn cycle_documents(&mut self, forward: bool) {
+        if let Some(index) = self.document_order.iter().position(|path| *path == self.current_document) {
+            let count = self.document_order.len();
+            let next_index = if forward { (index + 1) % count } else { (index + count - 1) % count };
+            self.current_document = self.document_order[next_index].clone();
+        }
+    }
+
+    // Closes the current document and switches to the next one.
+    // Dirty documents are only closed after the user confirms discarding
+    // the unsaved changes, returns whether the document was closed
+    pub fn close_document(&mut self) -> bool {
+        // A scratch document is never dirty, but its text is lost once it is closed
+        let dirty = match self.documents.get(&self.current_document) {
+            Some(document) if document.buffer.scratch => {
+                self.settings.confirm_closing_scratch_documents && !document.buffer.is_empty()
+            }
+            Some(document) => document.buffer.is_dirty(),
+            None => return false
+        };
+        if dirty && !self.confirm_discard_changes(&[&self.current_document]) {
+            return false;
+        }
+
+        if let Some(document) = self.documents.remove(&self.current_document) {
+            if let Some(scratch_backup) = &document.scratch_backup {
+                scratch_backup::delete_backup(scratch_backup);
+            }
+            // The server has to forget the document, or opening it again would open it twice
+            if !document.buffer.scratch {
+                if let Some(client) = self.lsp_clients.get_mut(document.buffer.language_identifier) {
+                    client.send_notification("textDocument/didClose", DidCloseTextDocumentParams::new(path_to_uri(&self.current_document)));
+                }
+            }
+        }
+        self.renderer.remove_buffer_layout(&self.current_document);
+
+        if let Some(index) = self.document_order.iter().position(|path| *path == self.current_document) {
+            self.document_order.remove(index);
+            self.current_document = match self.document_order.get(index).or_else(|| self.document_order.last()) {
+                Some(path) => path.clone(),
+                None => "".to_owned()
+            };
+        }
+        true
+    }
+
+    // A document that fails to save stays dirty, the error is shown in the status bar
+    fn save_current_document(&mut self) {
+        if let Some(document) = self.documents.get_mut(&self.current_document) {
+            if let Err(error) = document.buffer.save() {
+                self.status_message = Some(format!("Failed to save {}: {}", self.current_document, error));
+            }
+        }
+    }
+
+    // Asks the user whether the unsaved changes to the documents can be
+    // thrown away, listing the files so they know what would be lost
+    fn confirm_discard_changes(&self, paths: &[&str]) -> bool {
+        let message = format!("The following files have unsaved changes:\n\n{}\n\nDiscard the changes?", paths.join("\n"));
+        let mut text = text_utils::to_os_str(&message);
+        let mut caption = text_utils::to_os_str("Unsaved changes");
+        unsafe {
+            MessageBoxW(
+                self.hwnd,
+                PWSTR(text.as_mut_ptr()),
+                PWSTR(caption.as_mut_ptr()),
+                MESSAGEBOX_STYLE::MB_YESNO | MESSAGEBOX_STYLE::MB_ICONWARNING
+            ) == MESSAGEBOX_RESULT::IDYES
+        }
+    }
+
+    // Called when the window is about to close, the user
+    // is prompted first if any document has unsaved changes
+    pub fn can_quit(&self) -> bool {
+        let dirty_paths: Vec<&str> = self.document_order.iter()
+            .filter(|path| self.documents.get(*path).map_or(false, |document| document.buffer.is_dirty()))
+            .map(|path| path.as_str())
+            .collect();
+        dirty_paths.is_empty() || self.confirm_discard_changes(&dirty_paths)
+    }
+
+    // Documents with unsaved changes are marked with a star after the file name
+    fn get_tab_names(&self) -> Vec<String> {
+        self.document_order.iter().map(|path| {
+            let file_name = Path::new(path).file_name().and_then(|name| name.to_str()).unwrap_or(path.as_str());
+            match self.documents.get(path) {
+                Some(document) if document.buffer.is_dirty() => format!("{} *", file_name),
+                _ => file_name.to_owned()
+            }
+        }).collect()
     }
 
     pub fn draw(&mut self) {
+        self.renderer.update_file_tree_width(&self.file_tree);
+        self.renderer.begin_draw();
+        let prompt = self.go_to_line_prompt.as_ref().map(|line| format!("Go to line: {}", line))
+            .or_else(|| self.shell_command_prompt.as_ref().map(|command_line| format!("Pipe the selection through: {}", command_line)))
+            .or_else(|| self.replace_prompt.as_ref().map(|prompt| match &prompt.replacement {
+                Some(replacement) => format!("Replace {} with: {}  (enter replaces the next, shift+enter all)", prompt.needle, replacement),
+                None => format!("Replace: {}", prompt.needle)
+            }))
+            .or_else(|| self.shell_command.as_ref().map(|command| format!("Running {}, escape cancels it", command.command_line)))
+            .or_else(|| self.status_message.clone());
         if let Some(document) = self.documents.get_mut(&self.current_document) {
             unwrap_hresult(self.renderer.update_buffer_layout(document));
             unwrap_hresult(self.renderer.draw(document));
+            unwrap_hresult(self.renderer.draw_status_bar(document, prompt.as_deref()));
         }
+
+        let current_tab = self.document_order.iter().position(|path| *path == self.current_document);
+        unwrap_hresult(self.renderer.draw_tabs(&self.get_tab_names(), current_tab));
+        unwrap_hresult(self.renderer.draw_file_tree(&self.file_tree));
+        unwrap_hresult(self.renderer.end_draw());
     }
 
     pub fn resize(&mut self, width: u32, height: u32) {
         unwrap_hresult(self.renderer.resize(width, height));
     }
 
+    pub fn apply_semantic_tokens(&mut self, path: &str, data: &[u32]) {
+        if let Some(document) = self.documents.get_mut(path) {
+            if let Some(client) = self.lsp_clients.get(document.buffer.language_identifier) {
+                document.semantic_tokens = decode_semantic_tokens(data, &client.semantic_token_legend);
+            }
+        }
+    }
+
+    // The diagnostics are the only messages initiated by the server that are handled
+    fn handle_lsp_notification(&mut self, method: &str, params: Option<serde_json::Value>) {
+        if method != "textDocument/publishDiagnostics" {
+            return;
+        }
+        let params = match params.and_then(|params| serde_json::from_value::<PublishDiagnosticsParams>(params).ok()) {
+            Some(params) => params,
+            None => return
+        };
+        let document = match uri_to_path(&params.uri)
+            .and_then(|path| self.find_document_path(&path))
+            .and_then(|path| self.documents.get_mut(&path)) {
+            Some(document) => document,
+            None => return
+        };
+
+        // The ranges are converted right away, later edits may leave them
+        // slightly off until the server publishes the diagnostics again
+        let buffer = &document.buffer;
+        let diagnostics = params.diagnostics.iter().filter_map(|diagnostic| {
+            let start = buffer.lsp_position_to_char_offset(&diagnostic.range.start)?;
+            let end = buffer.lsp_position_to_char_offset(&diagnostic.range.end)?;
+            Some(DocumentDiagnostic { range: start..end, severity: DiagnosticSeverity::from_lsp(diagnostic.severity) })
+        }).collect();
+        document.diagnostics = diagnostics;
+    }
+
+    // Interprets a response based on the type of the request with the same
+    // id, messages initiated by the server go to handle_lsp_notification
+    pub fn handle_lsp_response(&mut self, response: GenericResponse) {
+        let id = match (response.id, response.method) {
+            (Some(id), None) => id,
+            (_, Some(method)) => {
+                self.handle_lsp_notification(&method, response.params);
+                return;
+            }
+            _ => return
+        };
+        let (language_identifier, request_type) = match self.lsp_clients.iter_mut().find_map(|(language_identifier, client)| {
+            client.request_types.remove(&id).map(|request_type| (*language_identifier, request_type))
+        }) {
+            Some(request) => request,
+            None => return
+        };
+
+        if let Some(error) = response.error {
+            println!("The {} language server returned error {}: {}", language_identifier, error.code, error.message);
+            return;
+        }
+
+        match request_type {
+            LSPRequestType::InitializationRequest => {
+                if let Some(client) = self.lsp_clients.get_mut(language_identifier) {
+                    if let Some(capabilities) = response.result
+                        .and_then(|result| serde_json::from_value::<InitializeResult>(result).ok())
+                        .map(|result| result.capabilities) {
+                        client.text_document_sync = capabilities.text_document_sync.as_ref()
+                            .map_or(TextDocumentSyncKind::None, TextDocumentSync::change_kind);
+                        if let Some(provider) = capabilities.semantic_tokens_provider {
+                            client.semantic_token_legend = provider.legend.token_types.iter()
+                                .map(|token_type| semantic_token_type_from_name(token_type))
+                                .collect();
+                        }
+                        if let Some(provider) = capabilities.document_on_type_formatting_provider {
+                            client.on_type_formatting_triggers = std::iter::once(&provider.first_trigger_character)
+                                .chain(provider.more_trigger_character.iter())
+                                .filter_map(|trigger| trigger.chars().next())
+                                .collect();
+                        }
+                        if let Some(provider) = capabilities.completion_provider {
+                            client.completion_triggers = provider.trigger_characters.iter()
+                                .filter_map(|trigger| trigger.chars().next())
+                                .collect();
+                        }
+                    }
+                    client.on_initialized();
+
+                    for document in self.documents.values_mut().filter(|document| document.buffer.language_identifier == language_identifier) {
+                        document.buffer.on_type_formatting_triggers = client.on_type_formatting_triggers.clone();
+                    }
+                }
+            }
+            LSPRequestType::SemanticTokensRequest(path) => {
+                if let Some(result) = response.result.and_then(|result| serde_json::from_value::<SemanticTokensResult>(result).ok()) {
+                    self.apply_semantic_tokens(&path, &result.data);
+                }
+            }
+            LSPRequestType::OnTypeFormattingRequest(path, version) => {
+                let edits = match response.result.and_then(|result| serde_json::from_value::<Vec<TextEdit>>(result).ok()) {
+                    Some(edits) => edits,
+                    None => return
+                };
+                if let Some(document) = self.documents.get_mut(&path) {
+                    // The edits are meaningless if the document changed while waiting,
+                    // otherwise they become part of the undo step of the keystroke
+                    if document.buffer.version != version {
+                        return;
+                    }
+                    document.buffer.apply_text_edits_to_current_undo_step(&edits);
+                }
+                self.send_lsp_changes(&path);
+            }
+            LSPRequestType::CompletionRequest(path, version) => {
+                let items = response.result
+                    .and_then(|result| serde_json::from_value::<CompletionResult>(result).ok())
+                    .map_or(Vec::new(), CompletionResult::into_items);
+                let max_items = self.settings.max_completion_items;
+                if path != self.current_document {
+                    return;
+                }
+                if let Some(document) = self.documents.get_mut(&path) {
+                    // A newer request is on its way if the document changed while waiting
+                    if document.buffer.version != version {
+                        return;
+                    }
+                    // Only the label is inserted, so the items have to continue the word before the caret
+                    let (word_start, word) = document.buffer.get_word_before_caret();
+                    let items: Vec<String> = items.into_iter()
+                        .map(|item| item.label.trim().to_owned())
+                        .filter(|label| label.starts_with(&word))
+                        .take(max_items)
+                        .collect();
+                    document.completion = if items.is_empty() {
+                        None
+                    }
+                    else {
+                        Some(CompletionList { items, selected: 0, word_start })
+                    };
+                }
+            }
+            LSPRequestType::HoverRequest(path, version, mouse_pos) => {
+                // The tooltip is only wanted if the mouse is still where it was
+                if path != self.current_document || self.hover_mouse_pos != Some(mouse_pos) {
+                    return;
+                }
+                let text = response.result
+                    .and_then(|result| serde_json::from_value::<HoverResult>(result).ok())
+                    .map(|result| result.contents.into_text())
+                    .filter(|text| !text.is_empty());
+                if let Some(document) = self.documents.get_mut(&path) {
+                    if document.buffer.version == version {
+                        document.hover_tooltip = text.map(|text| HoverTooltip { text, mouse_pos });
+                    }
+                }
+            }
+            LSPRequestType::DefinitionRequest(path, version) => {
+                // Jumping away is only expected if the document is as it was when F12 was pressed
+                let document_unchanged = self.documents.get(&path).map_or(false, |document| document.buffer.version == version);
+                if path != self.current_document || !document_unchanged {
+                    return;
+                }
+                let (target_path, position) = match response.result
+                    .and_then(|result| serde_json::from_value::<DefinitionResult>(result).ok())
+                    .and_then(DefinitionResult::into_first_target)
+                    .and_then(|(uri, position)| uri_to_path(&uri).map(|target_path| (target_path, position))) {
+                    Some(target) => target,
+                    None => {
+                        self.status_message = Some("No definition found".to_owned());
+                        return;
+                    }
+                };
+                self.go_to_definition(&target_path, &position);
+            }
+        }
+    }
+
+    pub fn get_file_tree_hover(&self) -> Option<usize> {
+        self.file_tree.hovered_line_number
+    }
+
+    // Files are opened while directories are expanded or collapsed in place
+    fn file_tree_click(&mut self, line_number: usize) {
+        let (path, is_directory) = match self.file_tree.entries.get(line_number) {
+            Some(entry) => (entry.path.clone(), entry.is_directory),
+            None => return
+        };
+
+        if is_directory {
+            self.file_tree.toggle_directory(line_number);
+        }
+        else if let Some(path) = path.to_str() {
+            self.open_file(path);
+        }
+    }
+
+    pub fn is_block_selecting(&self) -> bool {
+        match self.documents.get(&self.current_document) {
+            Some(document) => document.buffer.is_block_selecting(),
+            None => false
+        }
+    }
+
     pub fn get_current_selection(&self) -> Option<TextRange> {
         if let Some(document) = self.documents.get(&self.current_document) {
             return document.buffer.get_selection_range(
@@ -152,39 +1012,639 @@ impl Editor {
     }
 
     fn open_workspace(&mut self) {
-        // let mut file_dialog: *mut IFileOpenDialog = null_mut();
+        unsafe {
+            let file_dialog: IFileOpenDialog = unwrap_hresult(windows::create_instance(&FileOpenDialog));
+            unwrap_hresult(file_dialog.SetOptions(_FILEOPENDIALOGOPTIONS::FOS_PICKFOLDERS.0 as u32).ok());
+
+            // Show fails if the user cancels the dialog
+            if file_dialog.Show(self.hwnd).is_err() {
+                return;
+            }
+
+            let mut shell_item = None;
+            let shell_item = unwrap_hresult(file_dialog.GetResult(&mut shell_item).and_some(shell_item));
+
+            let mut folder_path = PWSTR::default();
+            unwrap_hresult(shell_item.GetDisplayName(SIGDN::SIGDN_FILESYSPATH, &mut folder_path).ok());
+
+            // We need to get the length of the folder path manually...
+            let mut length = 0;
+            while (*folder_path.0.add(length)) != 0x0000 {
+                length += 1;
+            }
+
+            let path = String::from_utf16_lossy(from_raw_parts(folder_path.0, length));
+            CoTaskMemFree(folder_path.0 as _);
+
+            self.file_tree.set_workspace_root(&path);
+        }
+    }
+
+    pub fn timer_elapsed(&mut self, timer_id: usize) {
+        match timer_id {
+            GIT_DIFF_TIMER_ID => {
+                unsafe { KillTimer(self.hwnd, GIT_DIFF_TIMER_ID); }
+                if let Some(document) = self.documents.get_mut(&self.current_document) {
+                    let lines = document.buffer.get_lines_without_linebreaks();
+                    if let Some(git_diff) = &mut document.git_diff {
+                        git_diff.update(&lines);
+                    }
+                    document.merge_conflicts = git_support::find_merge_conflicts(&lines);
+                }
+            }
+            FILE_CHANGE_TIMER_ID => self.reload_changed_documents(),
+            WORD_INDEX_TIMER_ID => {
+                unsafe { KillTimer(self.hwnd, WORD_INDEX_TIMER_ID); }
+                if let Some(document) = self.documents.get_mut(&self.current_document) {
+                    document.word_index.update(&document.buffer.get_text());
+                }
+            }
+            BRACKET_MATCH_TIMER_ID => {
+                unsafe { KillTimer(self.hwnd, BRACKET_MATCH_TIMER_ID); }
+                if let Some(document) = self.documents.get_mut(&self.current_document) {
+                    document.buffer.match_brackets_at_caret();
+                }
+            }
+            SCRATCH_BACKUP_TIMER_ID => {
+                unsafe { KillTimer(self.hwnd, SCRATCH_BACKUP_TIMER_ID); }
+                self.backup_scratch_documents();
+            }
+            HOVER_TIMER_ID => {
+                unsafe { KillTimer(self.hwnd, HOVER_TIMER_ID); }
+                self.request_lsp_hover();
+            }
+            SHELL_COMMAND_TIMER_ID => {
+                if let Some(command) = &self.shell_command {
+                    let message = format!("{} timed out", command.command_line);
+                    self.cancel_shell_command();
+                    self.status_message = Some(message);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Documents with unsaved changes are never reloaded automatically
+    fn reload_changed_documents(&mut self) {
+        let changed_paths: Vec<String> = self.documents.iter()
+            .filter(|(_, document)| !document.buffer.is_dirty() && document.buffer.has_changed_on_disk())
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in changed_paths {
+            self.reload_document(&path);
+        }
+    }
+
+    fn reload_document(&mut self, path: &str) {
+        if let Some(document) = self.documents.get_mut(path) {
+            match document.buffer.reload() {
+                Ok(line_map) => {
+                    // Keep the same code at the top of the view
+                    if let Some(line_offset) = line_map.get(document.view.line_offset) {
+                        document.view.line_offset = *line_offset;
+                    }
+                    document.blame_hint = None;
+                    document.completion = None;
+                    document.word_index.update(&document.buffer.get_text());
+                    let lines = document.buffer.get_lines_without_linebreaks();
+                    if let Some(git_diff) = &mut document.git_diff {
+                        git_diff.update(&lines);
+                    }
+                    document.merge_conflicts = git_support::find_merge_conflicts(&lines);
+                }
+                Err(error) => {
+                    self.status_message = Some(format!("Failed to reload {}: {}", path, error));
+                    return;
+                }
+            }
+            self.send_lsp_changes(path);
+        }
+    }
+
+    // Restarting the timer on every edit ensures the
+    // diff is only recomputed once typing pauses
+    fn schedule_git_diff_update(&self) {
+        unsafe { SetTimer(self.hwnd, GIT_DIFF_TIMER_ID, self.settings.git_diff_update_delay_ms, None); }
+    }
+
+    fn schedule_word_index_update(&self) {
+        unsafe { SetTimer(self.hwnd, WORD_INDEX_TIMER_ID, self.settings.word_index_update_delay_ms, None); }
+    }
+
+    // The Insert key toggles overwrite mode in the current buffer, the other documents follow it
+    fn sync_overwrite_mode(&mut self) {
+        let overwrite = match self.documents.get(&self.current_document) {
+            Some(document) => document.buffer.overwrite,
+            None => return
+        };
+        if overwrite != self.overwrite {
+            self.overwrite = overwrite;
+            for document in self.documents.values_mut() {
+                document.buffer.overwrite = overwrite;
+            }
+        }
+    }
+
+    fn schedule_scratch_backup(&self) {
+        if self.documents.get(&self.current_document).map_or(false, |document| document.scratch_backup.is_some()) {
+            unsafe { SetTimer(self.hwnd, SCRATCH_BACKUP_TIMER_ID, self.settings.scratch_backup_delay_ms, None); }
+        }
+    }
+
+    // Also called when the editor closes, so edits made since the last backup aren't lost.
+    // A failed backup is shown in the status bar, the text is only safe in the editor then
+    pub fn backup_scratch_documents(&mut self) {
+        for document in self.documents.values() {
+            if let Some(scratch_backup) = &document.scratch_backup {
+                if let Err(error) = scratch_backup::write_backup(scratch_backup, &document.buffer.get_text()) {
+                    self.status_message = Some(format!("Failed to back up {}: {}", document.buffer.path, error));
+                }
+            }
+        }
+    }
+
+    // The enclosing brackets are matched once the caret has stayed put for the delay
+    fn schedule_bracket_match(&mut self) {
+        if !self.settings.highlight_enclosing_brackets {
+            return;
+        }
+        if let Some(document) = self.documents.get_mut(&self.current_document) {
+            if document.buffer.brackets_matched() {
+                return;
+            }
+            if self.settings.bracket_match_delay_ms == 0 {
+                document.buffer.match_brackets_at_caret();
+            }
+            else {
+                unsafe { SetTimer(self.hwnd, BRACKET_MATCH_TIMER_ID, self.settings.bracket_match_delay_ms, None); }
+            }
+        }
+    }
+
+    // Opens the completion list for the word before the caret, if any of the open documents has a longer word starting with it
+    fn open_completion(&mut self) {
+        let (word_start, word) = match self.documents.get(&self.current_document) {
+            Some(document) => document.buffer.get_word_before_caret(),
+            None => return
+        };
+        let items = if word.is_empty() {
+            Vec::new()
+        }
+        else {
+            word_completion::complete_word(&word, self.documents.values().map(|document| &document.word_index), self.settings.max_completion_items)
+        };
+
+        if let Some(document) = self.documents.get_mut(&self.current_document) {
+            document.completion = if items.is_empty() {
+                None
+            }
+            else {
+                Some(CompletionList { items, selected: 0, word_start })
+            };
+        }
+    }
+
+    // Asks the language server of the current document for the completions
+    // at the caret, returns false if the document has no server to ask
+    fn request_lsp_completion(&mut self) -> bool {
+        let document = match self.documents.get(&self.current_document) {
+            Some(document) if !document.buffer.scratch => document,
+            _ => return false
+        };
+        match self.lsp_clients.get_mut(document.buffer.language_identifier) {
+            Some(client) => {
+                let params = TextDocumentPositionParams::new(path_to_uri(&self.current_document), document.buffer.get_caret_lsp_position());
+                client.send_request("textDocument/completion", params, LSPRequestType::CompletionRequest(self.current_document.clone(), document.buffer.version));
+                true
+            }
+            None => false
+        }
+    }
+
+    // Moving the mouse hides the tooltip, the language server is asked
+    // about the word under the mouse once it has rested for the delay
+    fn schedule_hover(&mut self, mouse_pos: MousePos) {
+        if self.hover_mouse_pos == Some(mouse_pos) {
+            return;
+        }
+        self.hover_mouse_pos = Some(mouse_pos);
+        self.hide_hover_tooltip();
+        unsafe { SetTimer(self.hwnd, HOVER_TIMER_ID, self.settings.hover_delay_ms, None); }
+    }
+
+    fn hide_hover_tooltip(&mut self) {
+        if let Some(document) = self.documents.get_mut(&self.current_document) {
+            document.hover_tooltip = None;
+        }
+    }
+
+    pub fn has_hover_tooltip(&self) -> bool {
+        self.documents.get(&self.current_document).map_or(false, |document| document.hover_tooltip.is_some())
+    }
+
+    fn request_lsp_hover(&mut self) {
+        let mouse_pos = match self.hover_mouse_pos {
+            Some(mouse_pos) => mouse_pos,
+            None => return
+        };
+        let document = match self.documents.get(&self.current_document) {
+            Some(document) if !document.buffer.scratch => document,
+            _ => return
+        };
+        let position = match unwrap_hresult(self.renderer.get_hovered_text_pos(document, mouse_pos))
+            .and_then(|text_pos| document.buffer.text_pos_to_lsp_position(text_pos)) {
+            Some(position) => position,
+            None => return
+        };
+
+        if let Some(client) = self.lsp_clients.get_mut(document.buffer.language_identifier) {
+            let request_type = LSPRequestType::HoverRequest(self.current_document.clone(), document.buffer.version, mouse_pos);
+            client.send_request("textDocument/hover", TextDocumentPositionParams::new(path_to_uri(&self.current_document), position), request_type);
+        }
+    }
+
+    fn request_lsp_definition(&mut self) {
+        let document = match self.documents.get(&self.current_document) {
+            Some(document) if !document.buffer.scratch => document,
+            _ => return
+        };
+        if let Some(client) = self.lsp_clients.get_mut(document.buffer.language_identifier) {
+            let params = TextDocumentPositionParams::new(path_to_uri(&self.current_document), document.buffer.get_caret_lsp_position());
+            client.send_request("textDocument/definition", params, LSPRequestType::DefinitionRequest(self.current_document.clone(), document.buffer.version));
+        }
+    }
+
+    // The path of the open document for a path from the language server. Paths are case
+    // insensitive on Windows and servers don't always keep the case of the drive letter
+    fn find_document_path(&self, path: &str) -> Option<String> {
+        self.documents.keys().find(|document_path| document_path.eq_ignore_ascii_case(path)).cloned()
+    }
+
+    // Opens the file of the definition unless it is open already and moves the caret to it
+    fn go_to_definition(&mut self, target_path: &str, position: &Position) {
+        let target_path = match self.find_document_path(target_path) {
+            Some(path) => path,
+            None if Path::new(target_path).is_file() => target_path.to_owned(),
+            None => {
+                self.status_message = Some(format!("The definition is in {}, which can't be opened", target_path));
+                return;
+            }
+        };
+
+        self.open_file(&target_path);
+        if let Some(document) = self.documents.get_mut(&target_path) {
+            document.buffer.move_caret_to_lsp_position(position);
+        }
+    }
+
+    fn is_completion_trigger(&self, chr: char) -> bool {
+        self.documents.get(&self.current_document)
+            .and_then(|document| self.lsp_clients.get(document.buffer.language_identifier))
+            .map_or(false, |client| client.completion_triggers.contains(&chr))
+    }
+
+    // Handles the keys of an open completion list and returns true if the command was used up by it,
+    // any other command closes the list except for typing, which narrows it down afterwards
+    fn execute_completion_command(&mut self, cmd: &EditorCommand) -> bool {
+        let document = match self.documents.get_mut(&self.current_document) {
+            Some(document) => document,
+            None => return false
+        };
+        let completion = match &mut document.completion {
+            Some(completion) => completion,
+            None => return false
+        };
+
+        match *cmd {
+            EditorCommand::KeyPressed(VK_DOWN, false, false) => completion.select_next(),
+            EditorCommand::KeyPressed(VK_UP, false, false) => completion.select_previous(),
+            EditorCommand::KeyPressed(VK_RETURN, false, false) | EditorCommand::KeyPressed(VK_TAB, false, false) => {
+                let word_start = completion.word_start;
+                let item = completion.get_selected_item().to_owned();
+                document.completion = None;
+                document.buffer.complete_word(word_start, &item);
+
+                let path = self.current_document.clone();
+                self.schedule_git_diff_update();
+                self.schedule_word_index_update();
+                self.schedule_scratch_backup();
+                self.send_lsp_changes(&path);
+            }
+            EditorCommand::KeyPressed(VK_ESCAPE, ..) => document.completion = None,
+            EditorCommand::KeyPressed(VK_BACK, ..) | EditorCommand::CharInsert(_) => return false,
+            _ => {
+                document.completion = None;
+                return false;
+            }
+        }
+        true
+    }
+
+    // Digits are typed into the prompt, enter goes to the line and escape cancels.
+    // Any other command closes the prompt and is left to the editor
+    fn execute_go_to_line_command(&mut self, cmd: &EditorCommand) -> bool {
+        let prompt = match &mut self.go_to_line_prompt {
+            Some(prompt) => prompt,
+            None => return false
+        };
+
+        match *cmd {
+            EditorCommand::CharInsert(digit @ 0x30..=0x39) => prompt.push(char::from(digit as u8)),
+            EditorCommand::KeyPressed(VK_BACK, ..) => {
+                prompt.pop();
+            }
+            EditorCommand::KeyPressed(VK_RETURN, ..) => {
+                let line = prompt.parse::<usize>().ok();
+                self.go_to_line_prompt = None;
+                // Lines are numbered from one in the prompt
+                if let Some(line) = line {
+                    self.execute_command(&EditorCommand::GoToLine(line.saturating_sub(1)));
+                }
+            }
+            EditorCommand::KeyPressed(VK_ESCAPE, ..) => self.go_to_line_prompt = None,
+            // The digit keys, their digits follow as CharInsert
+            EditorCommand::KeyPressed(key, false, false) if (0x30..=0x39).contains(&key) || (0x60..=0x69).contains(&key) => {}
+            EditorCommand::MouseMove(_) | EditorCommand::LeftRelease => return false,
+            _ => {
+                self.go_to_line_prompt = None;
+                return false;
+            }
+        }
+        true
+    }
+
+    // The command line is typed into the prompt like the line number in execute_go_to_line_command.
+    // Escape also cancels a command that is still running
+    fn execute_shell_command_prompt(&mut self, cmd: &EditorCommand) -> bool {
+        let prompt = match &mut self.shell_command_prompt {
+            Some(prompt) => prompt,
+            None => {
+                if self.shell_command.is_some() && matches!(cmd, EditorCommand::KeyPressed(VK_ESCAPE, ..)) {
+                    self.cancel_shell_command();
+                    self.status_message = Some("Cancelled the command".to_owned());
+                    return true;
+                }
+                return false;
+            }
+        };
+
+        match *cmd {
+            EditorCommand::CharInsert(character) => {
+                if let Some(chr) = char::from_u32(character as u32) {
+                    prompt.push(chr);
+                }
+            }
+            EditorCommand::KeyPressed(VK_BACK, ..) => {
+                prompt.pop();
+            }
+            EditorCommand::KeyPressed(VK_RETURN, ..) => {
+                let command_line = self.shell_command_prompt.take().unwrap();
+                if !command_line.trim().is_empty() {
+                    self.pipe_selection_through(&command_line);
+                }
+            }
+            EditorCommand::KeyPressed(VK_ESCAPE, ..) => self.shell_command_prompt = None,
+            EditorCommand::KeyPressed(key, _, ctrl_down) if is_typing_key(key, ctrl_down) => {}
+            EditorCommand::MouseMove(_) | EditorCommand::LeftRelease => return false,
+            _ => {
+                self.shell_command_prompt = None;
+                return false;
+            }
+        }
+        true
+    }
+
+    // Replaces the selection with the output of the command, like the ! filter of Vim. The command
+    // runs on a separate thread and its output arrives in handle_shell_command_output
+    fn pipe_selection_through(&mut self, command_line: &str) {
+        if self.shell_command.is_some() {
+            self.status_message = Some("Another command is still running".to_owned());
+            return;
+        }
+
+        let document = match self.documents.get(&self.current_document) {
+            Some(document) => document,
+            None => return
+        };
 
-        // unsafe {
-        //     hr_ok!(
-        //         CoCreateInstance(
-        //             &FileOpenDialog::uuidof(),
-        //             null_mut(), 
-        //             CLSCTX_ALL, 
-        //             &IFileOpenDialog::uuidof(),
-        //             (&mut file_dialog as *mut *mut _) as *mut *mut c_void
-        //         )
-        //     );
+        let child = match spawn_shell_command(command_line) {
+            Ok(child) => child,
+            Err(error) => {
+                self.status_message = Some(format!("Failed to run the command: {}", error));
+                return;
+            }
+        };
 
-        //     hr_ok!((*file_dialog).SetOptions(FOS_PICKFOLDERS));
-        //     hr_ok!((*file_dialog).Show(null_mut()));
+        self.shell_command_count += 1;
+        let id = self.shell_command_count;
+        self.shell_command = Some(RunningShellCommand {
+            id,
+            process_id: child.id(),
+            command_line: command_line.to_owned(),
+            path: self.current_document.clone(),
+            version: document.buffer.version,
+            selection: document.buffer.get_selection_char_range()
+        });
+
+        // The worker leaves the allocation to the WM_SHELL_COMMAND_OUTPUT handler, which frees it
+        let input = document.buffer.get_selected_text();
+        let hwnd = self.hwnd;
+        thread::spawn(move || {
+            let output = Box::new(ShellCommandOutput { id, result: wait_for_shell_command(child, input) });
+            unsafe {
+                SendMessageW(hwnd, WM_SHELL_COMMAND_OUTPUT, WPARAM(Box::into_raw(output) as usize), LPARAM(0));
+            }
+        });
+        unsafe { SetTimer(self.hwnd, SHELL_COMMAND_TIMER_ID, self.settings.shell_command_timeout_ms, None); }
+    }
 
-        //     let mut shell_item: *mut IShellItem = null_mut();
-        //     hr_ok!((*file_dialog).GetResult(&mut shell_item));
+    // The output of a cancelled command is ignored, as is the output for a
+    // document that was edited or closed while the command was running.
+    // If the command failed its error is shown and the selection is left as it was
+    pub fn handle_shell_command_output(&mut self, output: ShellCommandOutput) {
+        let command = match self.shell_command.take() {
+            Some(command) if command.id == output.id => command,
+            command => {
+                self.shell_command = command;
+                return;
+            }
+        };
+        unsafe { KillTimer(self.hwnd, SHELL_COMMAND_TIMER_ID); }
 
-        //     let mut folder_path: *mut u16 = null_mut();
-        //     hr_ok!((*shell_item).GetDisplayName(SIGDN_FILESYSPATH, &mut folder_path)); 
+        let text = match output.result {
+            Ok(text) => text,
+            Err(error) => {
+                // The status bar only has room for a single line
+                self.status_message = Some(error.lines().collect::<Vec<&str>>().join("  "));
+                return;
+            }
+        };
 
-        //     // We need to get the length of the folder path manually...
-        //     let mut length = 0;
-        //     while (*folder_path.add(length)) != 0x0000 {
-        //         length += 1;
-        //     }
+        match self.documents.get_mut(&command.path) {
+            Some(document) if document.buffer.version == command.version && document.buffer.get_selection_char_range() == command.selection => {
+                document.buffer.replace_selection(&text);
+            }
+            _ => {
+                self.status_message = Some(format!("The document changed while {} was running, its output was discarded", command.command_line));
+                return;
+            }
+        }
 
-        //     let slice = from_raw_parts(folder_path, length);
+        self.schedule_git_diff_update();
+        self.schedule_word_index_update();
+        self.schedule_scratch_backup();
+        self.send_lsp_changes(&command.path);
+    }
 
-        //     (*shell_item).Release();
-        //     (*file_dialog).Release();
-        // }
+    // Kills the command with the processes it started, its output is ignored once it arrives
+    fn cancel_shell_command(&mut self) {
+        if let Some(command) = self.shell_command.take() {
+            unsafe { KillTimer(self.hwnd, SHELL_COMMAND_TIMER_ID); }
+            let _ = Command::new("taskkill")
+                .args(["/PID", &command.process_id.to_string(), "/T", "/F"])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn();
+        }
+    }
+
+    // The text to replace is typed into the prompt and confirmed with enter, then the replacement.
+    // Enter then replaces the next occurrence after the caret each time it is pressed, shift+enter
+    // replaces every occurrence. Other commands are handled like in execute_shell_command_prompt
+    fn execute_replace_prompt(&mut self, cmd: &EditorCommand) -> bool {
+        let prompt = match &mut self.replace_prompt {
+            Some(prompt) => prompt,
+            None => return false
+        };
+        let text = prompt.replacement.as_mut().unwrap_or(&mut prompt.needle);
+
+        match *cmd {
+            EditorCommand::CharInsert(character) => {
+                if let Some(chr) = char::from_u32(character as u32) {
+                    text.push(chr);
+                }
+                self.update_search_match_lines();
+            }
+            EditorCommand::KeyPressed(VK_BACK, ..) => {
+                text.pop();
+                self.update_search_match_lines();
+            }
+            EditorCommand::KeyPressed(VK_RETURN, shift_down, _) => {
+                match &prompt.replacement {
+                    Some(replacement) => {
+                        let (needle, replacement) = (prompt.needle.clone(), replacement.clone());
+                        if shift_down {
+                            self.replace_prompt = None;
+                        }
+                        self.replace_in_current_document(&needle, &replacement, shift_down);
+                    }
+                    None if !prompt.needle.is_empty() => prompt.replacement = Some(String::new()),
+                    None => {}
+                }
+                self.update_search_match_lines();
+            }
+            EditorCommand::KeyPressed(VK_ESCAPE, ..) => {
+                self.replace_prompt = None;
+                self.update_search_match_lines();
+            }
+            EditorCommand::KeyPressed(key, _, ctrl_down) if is_typing_key(key, ctrl_down) => {}
+            EditorCommand::MouseMove(_) | EditorCommand::LeftRelease => return false,
+            _ => {
+                self.replace_prompt = None;
+                self.update_search_match_lines();
+                return false;
+            }
+        }
+        true
+    }
+
+    // Finds the lines with the text to replace in the current document
+    // again, they are cleared once the replace prompt is closed
+    fn update_search_match_lines(&mut self) {
+        let needle = self.replace_prompt.as_ref().map_or("", |prompt| prompt.needle.as_str());
+        if let Some(document) = self.documents.get_mut(&self.current_document) {
+            document.search_match_lines = document.buffer.find_lines(needle);
+        }
+    }
+
+    // Once nothing is left to replace the prompt is closed, so the message can be seen
+    fn replace_in_current_document(&mut self, needle: &str, replacement: &str, all: bool) {
+        let document = match self.documents.get_mut(&self.current_document) {
+            Some(document) => document,
+            None => return
+        };
+
+        if all {
+            let replacements = document.buffer.replace_all(needle, replacement);
+            self.status_message = Some(format!("Replaced {} occurrences of {}", replacements, needle));
+        }
+        else if !document.buffer.replace_next(needle, replacement) {
+            self.replace_prompt = None;
+            self.status_message = Some(format!("{} wasn't found", needle));
+            return;
+        }
+
+        let path = self.current_document.clone();
+        self.schedule_git_diff_update();
+        self.schedule_word_index_update();
+        self.schedule_scratch_backup();
+        self.send_lsp_changes(&path);
+    }
+
+    // Clicking one of the actions after a conflict start marker resolves the conflict
+    fn resolve_merge_conflict_at(&mut self, mouse_pos: MousePos) -> bool {
+        let document = match self.documents.get_mut(&self.current_document) {
+            Some(document) => document,
+            None => return false
+        };
+        let (conflict, resolution) = match unwrap_hresult(self.renderer.get_merge_conflict_action_at(document, mouse_pos)) {
+            Some(action) => action,
+            None => return false
+        };
+
+        if document.buffer.resolve_merge_conflict(&conflict, resolution) {
+            document.merge_conflicts = git_support::find_merge_conflicts(&document.buffer.get_lines_without_linebreaks());
+            let path = self.current_document.clone();
+            self.schedule_git_diff_update();
+            self.schedule_word_index_update();
+            self.schedule_scratch_backup();
+            self.send_lsp_changes(&path);
+        }
+        true
+    }
+
+    // Switches to the font family after the current one in the settings
+    fn cycle_font_family(&mut self) {
+        let font_families = &self.settings.font_families;
+        if font_families.is_empty() {
+            return;
+        }
+        let next = font_families.iter()
+            .position(|font_family| font_family == self.renderer.get_font_family())
+            .map_or(0, |index| (index + 1) % font_families.len());
+        let font_family = font_families[next].clone();
+        unwrap_hresult(self.renderer.set_font_family(&font_family));
+    }
+
+    // Hides the diagnostics less severe than the next minimum severity
+    fn cycle_diagnostic_min_severity(&mut self) {
+        let (min_severity, message) = match self.renderer.get_diagnostic_min_severity() {
+            DiagnosticSeverity::Information => (DiagnosticSeverity::Warning, "Showing errors and warnings"),
+            DiagnosticSeverity::Warning => (DiagnosticSeverity::Error, "Showing errors only"),
+            DiagnosticSeverity::Error => (DiagnosticSeverity::Information, "Showing all diagnostics")
+        };
+        self.renderer.set_diagnostic_min_severity(min_severity);
+        self.status_message = Some(message.to_owned());
+    }
+
+    fn cycle_text_antialiasing(&mut self) {
+        self.renderer.set_text_antialiasing(match self.renderer.get_text_antialiasing() {
+            TextAntialiasing::Aliased => TextAntialiasing::Grayscale,
+            TextAntialiasing::Grayscale => TextAntialiasing::ClearType,
+            TextAntialiasing::ClearType => TextAntialiasing::Aliased
+        });
     }
 
     fn change_font_size(zoom_delta: f32, text_renderer: &mut TextRenderer) {
@@ -193,56 +1653,266 @@ impl Editor {
 
     pub fn execute_command(&mut self, cmd: &EditorCommand) {
         match *cmd {
-            EditorCommand::KeyPressed(key, _, ctrl_down) => { 
+            EditorCommand::KeyPressed(..) | EditorCommand::AltKeyPressed(..) | EditorCommand::CharInsert(_) | EditorCommand::LeftClick(..) => {
+                self.status_message = None;
+                self.hide_hover_tooltip();
+            }
+            _ => {}
+        }
+        if self.execute_go_to_line_command(cmd) || self.execute_shell_command_prompt(cmd) || self.execute_replace_prompt(cmd) || self.execute_completion_command(cmd) {
+            return;
+        }
+
+        match *cmd {
+            EditorCommand::KeyPressed(key, shift_down, ctrl_down) => { 
                 match (key, ctrl_down) {
                     (0x4F, true) => self.open_workspace(),
+                    // CTRL+S (Save), the whitespace cleaned up on saving is sent on like any other edit
+                    (0x53, true) => self.save_current_document(),
+                    // F12 (Go to the definition of the symbol at the caret)
+                    (VK_F12, false) => {
+                        self.request_lsp_definition();
+                        return;
+                    }
+                    // CTRL+N (New scratch buffer from the selection)
+                    (0x4E, true) => {
+                        self.open_scratch_document();
+                        return;
+                    }
+                    // CTRL+Tab (Next tab), CTRL+SHIFT+Tab (Previous tab)
+                    (VK_TAB, true) => {
+                        self.execute_command(if shift_down { &EditorCommand::PrevTab } else { &EditorCommand::NextTab });
+                        return;
+                    }
+                    // CTRL+SHIFT+R (Restart crashed language servers without waiting)
+                    (0x52, true) if shift_down => {
+                        self.restart_crashed_lsp_clients(true);
+                        return;
+                    }
+                    // CTRL+R (Reload the document from disk)
+                    (0x52, true) => {
+                        let path = self.current_document.clone();
+                        self.reload_document(&path);
+                        return;
+                    }
+                    // CTRL+L (Scroll the caret into view)
+                    (0x4C, true) => {
+                        if let Some(document) = self.documents.get_mut(&self.current_document) {
+                            document.buffer.ensure_caret_visible();
+                            self.renderer.reveal_caret(document);
+                        }
+                        return;
+                    }
+                    // CTRL+Space (Complete the word before the caret)
+                    (VK_SPACE, true) => {
+                        if !self.request_lsp_completion() && self.settings.word_completion {
+                            self.open_completion();
+                        }
+                        return;
+                    }
+                    // CTRL+SHIFT+C (Copy the selection with its highlighting)
+                    (0x43, true) if shift_down => {
+                        if let Some(document) = self.documents.get_mut(&self.current_document) {
+                            if let Some(html) = self.renderer.get_selection_as_html(document) {
+                                document.buffer.copy_selection_as_html(self.hwnd, &html);
+                            }
+                        }
+                        return;
+                    }
+                    // CTRL+G (Go to line)
+                    (0x47, true) => {
+                        self.go_to_line_prompt = Some(String::new());
+                        return;
+                    }
+                    // CTRL+H (Replace)
+                    (0x48, true) => {
+                        self.replace_prompt = Some(ReplacePrompt { needle: String::new(), replacement: None });
+                        return;
+                    }
+                    // CTRL+W (Close document)
+                    (0x57, true) => {
+                        self.execute_command(&EditorCommand::CloseDocument);
+                        return;
+                    }
                     _ => {}
                 }
             }
+            EditorCommand::LeftClick(mouse_pos, ..) => {
+                if self.renderer.is_in_file_tree(mouse_pos) {
+                    self.file_tree.hovered_line_number = self.renderer.get_file_tree_line(mouse_pos, &self.file_tree);
+                    if let Some(line_number) = self.file_tree.hovered_line_number {
+                        self.file_tree_click(line_number);
+                    }
+                    return;
+                }
+                // Clicking the scrollbar centers the view on the corresponding line
+                if let Some(document) = self.documents.get_mut(&self.current_document) {
+                    if let Some(line) = self.renderer.get_scrollbar_line(mouse_pos, document) {
+                        document.view.line_offset = line.saturating_sub(self.renderer.get_max_rows() / 2);
+                        document.view.pixel_offset = 0.0;
+                        return;
+                    }
+                }
+                if let Some(tab) = self.renderer.get_tab_at(mouse_pos, &self.get_tab_names()) {
+                    let path = self.document_order[tab].clone();
+                    self.switch_document(&path);
+                    return;
+                }
+                if self.resolve_merge_conflict_at(mouse_pos) {
+                    return;
+                }
+            }
+            EditorCommand::LeftDoubleClick(mouse_pos) | EditorCommand::LeftTripleClick(mouse_pos) => {
+                if self.renderer.is_in_file_tree(mouse_pos) {
+                    return;
+                }
+            }
+            EditorCommand::MouseMove(mouse_pos) => {
+                self.file_tree.hovered_line_number = self.renderer.get_file_tree_line(mouse_pos, &self.file_tree);
+                self.schedule_hover(mouse_pos);
+            }
+            // ALT+F (Next font family)
+            EditorCommand::AltKeyPressed(0x46, _) => {
+                self.cycle_font_family();
+                return;
+            }
+            // ALT+A (Next text antialiasing mode)
+            EditorCommand::AltKeyPressed(0x41, _) => {
+                self.cycle_text_antialiasing();
+                return;
+            }
+            // ALT+D (Next minimum diagnostic severity)
+            EditorCommand::AltKeyPressed(0x44, _) => {
+                self.cycle_diagnostic_min_severity();
+                return;
+            }
+            // ALT+! (Pipe the selection through a shell command)
+            EditorCommand::AltKeyPressed(0x31, true) => {
+                let has_selection = self.documents.get(&self.current_document).map_or(false, |document| !document.buffer.get_selected_text().is_empty());
+                if has_selection {
+                    self.shell_command_prompt = Some(String::new());
+                }
+                else {
+                    self.status_message = Some("Select the text to pipe through a command first".to_owned());
+                }
+                return;
+            }
+            EditorCommand::NextTab => self.cycle_documents(true),
+            EditorCommand::PrevTab => self.cycle_documents(false),
+            EditorCommand::CloseDocument => {
+                self.close_document();
+            }
             _ => {}
         }
 
+        let path = self.current_document.clone();
         self.execute_buffer_command(cmd);
+
+        match *cmd {
+            EditorCommand::KeyPressed(..) | EditorCommand::AltKeyPressed(..) | EditorCommand::CharInsert(_) => {
+                self.schedule_git_diff_update();
+                self.schedule_word_index_update();
+                self.schedule_scratch_backup();
+            }
+            _ => {}
+        }
+        self.sync_overwrite_mode();
+        self.schedule_bracket_match();
+
+        let completion_open = self.documents.get(&self.current_document).map_or(false, |document| document.completion.is_some());
+        let completion_triggered = match *cmd {
+            EditorCommand::CharInsert(character) => char::from_u32(character as u32).map_or(false, |chr| self.is_completion_trigger(chr)),
+            _ => false
+        };
+        self.send_lsp_changes(&path);
+
+        // Typing a trigger character of the language server opens the completion list,
+        // typing in an open list narrows it down to the new word or closes it without one
+        if completion_triggered {
+            self.request_lsp_completion();
+        }
+        else if completion_open {
+            let word_is_empty = self.documents.get(&self.current_document).map_or(true, |document| document.buffer.get_word_before_caret().1.is_empty());
+            if word_is_empty {
+                if let Some(document) = self.documents.get_mut(&self.current_document) {
+                    document.completion = None;
+                }
+            }
+            else if !self.request_lsp_completion() {
+                self.open_completion();
+            }
+        }
+        self.restart_crashed_lsp_clients(false);
     }
 
     fn execute_buffer_command(&mut self, cmd: &EditorCommand) {
         if let Some(document) = self.documents.get_mut(&self.current_document) {
             match *cmd {
-                EditorCommand::ScrollUp(ctrl_down) => {
-                    match ctrl_down {
-                        true => Self::change_font_size(SCROLL_ZOOM_DELTA, &mut self.renderer),
-                        false => scroll_view_up(document, SCROLL_LINES_PER_ROLL)
+                EditorCommand::MouseWheel(wheel_delta, ctrl_down) => {
+                    if ctrl_down {
+                        self.zoom_wheel_delta += wheel_delta;
+                        let notches = self.zoom_wheel_delta / WHEEL_DELTA;
+                        self.zoom_wheel_delta -= notches * WHEEL_DELTA;
+                        if notches != 0 {
+                            Self::change_font_size(notches as f32 * self.settings.scroll_zoom_delta, &mut self.renderer);
+                        }
+                    }
+                    else {
+                        // Scrolling is done in pixels, so a fraction of a notch scrolls a fraction of the lines
+                        let line_spacing = self.renderer.get_line_spacing();
+                        let lines = -wheel_delta as f32 / WHEEL_DELTA as f32 * self.settings.scroll_lines_per_roll as f32;
+                        let max_line_offset = self.renderer.get_max_line_offset(document);
+                        scroll_view_by_pixels(document, lines * line_spacing, line_spacing, max_line_offset);
                     }
                 }
-                EditorCommand::ScrollDown(ctrl_down) => {
-                    match ctrl_down {
-                        true => Self::change_font_size(-SCROLL_ZOOM_DELTA, &mut self.renderer),
-                        false => scroll_view_down(document, SCROLL_LINES_PER_ROLL)
+                EditorCommand::MouseHorizontalWheel(wheel_delta) => {
+                    self.horizontal_wheel_delta += wheel_delta * self.settings.scroll_lines_per_roll as i32;
+                    let columns = self.horizontal_wheel_delta / WHEEL_DELTA;
+                    self.horizontal_wheel_delta -= columns * WHEEL_DELTA;
+                    // Wrapped text never scrolls horizontally
+                    if !document.view.word_wrap {
+                        if columns > 0 {
+                            scroll_view_right(document, columns as usize, self.renderer.get_max_columns());
+                        }
+                        else if columns < 0 {
+                            scroll_view_left(document, -columns as usize);
+                        }
                     }
                 }
-                EditorCommand::LeftClick(mouse_pos, shift_down) => {
+                EditorCommand::LeftClick(mouse_pos, shift_down, ctrl_down) => {
+                    let text_pos = unwrap_hresult(self.renderer.mouse_pos_to_text_pos(document, mouse_pos));
+                    document.buffer.execute_command(&BufferCommand::LeftClick(text_pos, shift_down, ctrl_down))
+                }
+                EditorCommand::LeftAltClick(mouse_pos) => {
                     let text_pos = unwrap_hresult(self.renderer.mouse_pos_to_text_pos(document, mouse_pos));
-                    document.buffer.execute_command(&BufferCommand::LeftClick(text_pos, shift_down))
+                    document.buffer.execute_command(&BufferCommand::LeftAltClick(text_pos))
                 }
                 EditorCommand::LeftDoubleClick(mouse_pos) => {
                     let text_pos = unwrap_hresult(self.renderer.mouse_pos_to_text_pos(document, mouse_pos));
                     document.buffer.execute_command(&BufferCommand::LeftDoubleClick(text_pos))
                 }
+                EditorCommand::LeftTripleClick(mouse_pos) => {
+                    let text_pos = unwrap_hresult(self.renderer.mouse_pos_to_text_pos(document, mouse_pos));
+                    document.buffer.execute_command(&BufferCommand::LeftTripleClick(text_pos))
+                }
                 EditorCommand::LeftRelease => document.buffer.execute_command(&BufferCommand::LeftRelease),
                 EditorCommand::MouseMove(mouse_pos) => {
                     if document.buffer.currently_selecting {
+                        let text_origin = self.renderer.get_text_origin();
                         let extents = self.renderer.get_extents();
-                        if mouse_pos.1 > (TEXT_ORIGIN.1 + extents.1) {
-                            scroll_view_down(document, SCROLL_LINES_PER_DRAG);
+                        if mouse_pos.1 > extents.1 {
+                            scroll_view_down(document, self.settings.scroll_lines_per_drag, self.renderer.get_max_line_offset(document));
                         }
-                        else if mouse_pos.1 < TEXT_ORIGIN.1 {
-                            scroll_view_up(document, SCROLL_LINES_PER_DRAG);
+                        else if mouse_pos.1 < text_origin.1 {
+                            scroll_view_up(document, self.settings.scroll_lines_per_drag);
                         }
-                        if mouse_pos.0 > (TEXT_ORIGIN.0 + extents.0) {
-                            scroll_view_right(document, SCROLL_LINES_PER_DRAG, self.renderer.get_max_columns());
-                        }
-                        else if mouse_pos.0 < TEXT_ORIGIN.0 {
-                            scroll_view_left(document, SCROLL_LINES_PER_DRAG);
+                        if !document.view.word_wrap {
+                            if mouse_pos.0 > extents.0 {
+                                scroll_view_right(document, self.settings.scroll_lines_per_drag, self.renderer.get_max_columns());
+                            }
+                            else if mouse_pos.0 < text_origin.0 {
+                                scroll_view_left(document, self.settings.scroll_lines_per_drag);
+                            }
                         }
                         let text_pos = unwrap_hresult(self.renderer.mouse_pos_to_text_pos(document, mouse_pos));
                         document.buffer.execute_command(&BufferCommand::SetMouseSelection(text_pos))
@@ -252,10 +1922,50 @@ impl Editor {
                     if key == VK_RETURN && !ctrl_down {
                         document.view.column_offset = 0;
                     }
-                    document.buffer.execute_command(&BufferCommand::KeyPressed(key, shift_down, ctrl_down, self.hwnd))
+                    // Paging scrolls the view along with the caret
+                    let page_rows = self.renderer.get_max_rows();
+                    match key {
+                        VK_NEXT => scroll_view_down(document, page_rows, self.renderer.get_max_line_offset(document)),
+                        VK_PRIOR => scroll_view_up(document, page_rows),
+                        _ => {}
+                    }
+                    document.buffer.execute_command(&BufferCommand::KeyPressed(key, shift_down, ctrl_down, self.hwnd, page_rows))
                 },
-                EditorCommand::CharInsert(character) => document.buffer.execute_command(&BufferCommand::CharInsert(character))
+                EditorCommand::AltKeyPressed(key, shift_down) => {
+                    match key {
+                        // ALT+Z (Toggle word wrap)
+                        0x5A => {
+                            document.view.word_wrap = !document.view.word_wrap;
+                            document.view.column_offset = 0;
+                        }
+                        // ALT+PageDown (Next changed hunk), ALT+PageUp (Previous changed hunk)
+                        VK_NEXT => jump_to_hunk(document, true),
+                        VK_PRIOR => jump_to_hunk(document, false),
+                        // ALT+B (Toggle blame for the current line)
+                        0x42 => toggle_blame_hint(document),
+                        _ => document.buffer.execute_command(&BufferCommand::AltKeyPressed(key, shift_down))
+                    }
+                }
+                EditorCommand::CharInsert(character) => document.buffer.execute_command(&BufferCommand::CharInsert(character)),
+                EditorCommand::GoToLine(line) => document.buffer.move_caret_to_line(line),
+                EditorCommand::NextTab | EditorCommand::PrevTab | EditorCommand::CloseDocument => {}
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnostics_are_compared_by_how_severe_they_are() {
+        assert!(DiagnosticSeverity::Error > DiagnosticSeverity::Warning);
+        assert!(DiagnosticSeverity::Warning > DiagnosticSeverity::Information);
+        assert!(DiagnosticSeverity::Warning >= DiagnosticSeverity::Warning);
+        assert_eq!(DiagnosticSeverity::from_lsp(Some(1)), DiagnosticSeverity::Error);
+        assert_eq!(DiagnosticSeverity::from_lsp(Some(2)), DiagnosticSeverity::Warning);
+        assert_eq!(DiagnosticSeverity::from_lsp(Some(4)), DiagnosticSeverity::Information);
+        assert_eq!(DiagnosticSeverity::from_lsp(None), DiagnosticSeverity::Error);
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    fs,
+    path::{Path, PathBuf}
+};
+
+pub struct FileTreeEntry {
+    pub path: PathBuf,
+    pub name: String,
+    pub is_directory: bool,
+    pub expanded: bool,
+    pub depth: usize
+}
+
+// The entries are kept as a flat list in display order,
+// expanding a directory inserts its children right after it
+pub struct FileTree {
+    pub root: Option<PathBuf>,
+    pub entries: Vec<FileTreeEntry>,
+    pub hovered_line_number: Option<usize>
+}
+
+fn read_directory(path: &Path, depth: usize) -> Vec<FileTreeEntry> {
+    let mut entries = Vec::new();
+    let directory_entries = match fs::read_dir(path) {
+        Ok(directory_entries) => directory_entries,
+        Err(_) => return entries
+    };
+
+    for directory_entry in directory_entries.flatten() {
+        let is_directory = match directory_entry.file_type() {
+            Ok(file_type) => file_type.is_dir(),
+            Err(_) => continue
+        };
+        entries.push(FileTreeEntry {
+            path: directory_entry.path(),
+            name: directory_entry.file_name().to_string_lossy().into_owned(),
+            is_directory,
+            expanded: false,
+            depth
+        });
+    }
+
+    // Directories are listed first and both are sorted by name
+    entries.sort_by(|a, b| {
+        b.is_directory.cmp(&a.is_directory).then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+    });
+    entries
+}
+
+impl FileTree {
+    pub fn new() -> Self {
+        Self {
+            root: None,
+            entries: Vec::new(),
+            hovered_line_number: None
+        }
+    }
+
+    pub fn set_workspace_root(&mut self, path: &str) {
+        self.root = Some(PathBuf::from(path));
+        self.refresh();
+    }
+
+    // Re-reads the entries of the workspace root, collapsing all directories
+    pub fn refresh(&mut self) {
+        self.hovered_line_number = None;
+        self.entries = match &self.root {
+            Some(root) => read_directory(root, 0),
+            None => Vec::new()
+        };
+    }
+
+    pub fn toggle_directory(&mut self, line_number: usize) {
+        let (path, depth, expanded) = match self.entries.get(line_number) {
+            Some(entry) if entry.is_directory => (entry.path.clone(), entry.depth, entry.expanded),
+            _ => return
+        };
+
+        if expanded {
+            let children = self.entries[line_number + 1..].iter().take_while(|entry| entry.depth > depth).count();
+            self.entries.drain(line_number + 1..line_number + 1 + children);
+        }
+        else {
+            let children = read_directory(&path, depth + 1);
+            self.entries.splice(line_number + 1..line_number + 1, children);
+        }
+        self.entries[line_number].expanded = !expanded;
+    }
+
+    // One line per entry, indented by depth with
+    // directories prefixed by their expansion state
+    pub fn get_text(&self) -> String {
+        let mut text = String::new();
+        for entry in &self.entries {
+            let prefix = match (entry.is_directory, entry.expanded) {
+                (true, true) => "- ",
+                (true, false) => "+ ",
+                (false, _) => "  "
+            };
+            text.push_str("  ".repeat(entry.depth).as_str());
+            text.push_str(prefix);
+            text.push_str(entry.name.as_str());
+            text.push_str("\r\n");
+        }
+        text
+    }
+}
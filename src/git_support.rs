@@ -0,0 +1,453 @@
+use crate::text_utils;
+
+use std::{
+    cmp::min,
+    io::Write,
+    ops::Range,
+    path::Path,
+    process::{Command, Stdio}
+};
+
+// Files with more changed lines than this, or whose lines differ by more
+// edits than the edit distance, are not diffed line by line, every changed
+// line is marked as modified. The trace kept to recover the edits grows
+// with the square of the edit distance
+const MAX_DIFF_LINES: usize = 20000;
+const MAX_DIFF_EDIT_DISTANCE: isize = 2000;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LineChange {
+    Added,
+    Modified,
+    Deleted
+}
+
+// A range of changed lines in the current version of the file.
+// Deleted hunks have no length and mark the line below the deletion
+#[derive(Clone, PartialEq)]
+pub struct DiffHunk {
+    pub change: LineChange,
+    pub line: usize,
+    pub length: usize
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DiffOperation {
+    Equal,
+    Insert,
+    Delete
+}
+
+// The lines of the markers git leaves around a merge conflict, the
+// base marker only appears with the diff3 conflict style
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergeConflict {
+    pub start: usize,
+    pub base: Option<usize>,
+    pub separator: usize,
+    pub end: usize
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ConflictResolution {
+    Current,
+    Incoming,
+    Both
+}
+
+impl MergeConflict {
+    // The lines of our side and their side, without the markers
+    pub fn current_lines(&self) -> Range<usize> {
+        (self.start + 1)..self.base.unwrap_or(self.separator)
+    }
+
+    pub fn incoming_lines(&self) -> Range<usize> {
+        (self.separator + 1)..self.end
+    }
+}
+
+pub struct GitDiff {
+    head_lines: Vec<String>,
+    pub hunks: Vec<DiffHunk>
+}
+
+impl GitDiff {
+    // Reads the version of the file committed at HEAD,
+    // returns None if the file is not tracked by git
+    pub fn new(path: &str) -> Option<Self> {
+        let os_path = Path::new(path);
+        let directory = os_path.parent()?;
+        let file_name = os_path.file_name()?.to_str()?;
+
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(directory)
+            .arg("show")
+            .arg(format!("HEAD:./{}", file_name))
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+
+        let head_text = String::from_utf8_lossy(&output.stdout);
+        Some(Self {
+            head_lines: split_lines(&head_text),
+            hunks: Vec::new()
+        })
+    }
+
+    pub fn update(&mut self, lines: &[String]) {
+        self.hunks = diff_lines(&self.head_lines, lines);
+    }
+}
+
+pub struct BlameInfo {
+    pub commit: String,
+    pub author: String,
+    pub date: String,
+    pub summary: String
+}
+
+impl BlameInfo {
+    pub fn to_hint(&self) -> String {
+        // Lines that only exist in the buffer are blamed on an all zero commit
+        if self.commit.chars().all(|chr| chr == '0') {
+            return "Not committed yet".to_owned();
+        }
+        format!("{} {}, {} \u{2022} {}", &self.commit[..min(8, self.commit.len())], self.author, self.date, self.summary)
+    }
+}
+
+// Blames a single line of the buffer contents, the contents are passed
+// on stdin so unsaved edits don't shift the blamed line
+pub fn blame_line(path: &str, contents: &str, line: usize) -> Option<BlameInfo> {
+    let os_path = Path::new(path);
+    let directory = os_path.parent()?;
+    let file_name = os_path.file_name()?.to_str()?;
+
+    let mut child = Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .arg("blame")
+        .arg("--porcelain")
+        .arg("--contents")
+        .arg("-")
+        .arg("-L")
+        .arg(format!("{},{}", line + 1, line + 1))
+        .arg("--")
+        .arg(file_name)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    child.stdin.take()?.write_all(contents.as_bytes()).ok()?;
+
+    let output = child.wait_with_output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_blame_porcelain(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_blame_porcelain(output: &str) -> Option<BlameInfo> {
+    let mut lines = output.lines();
+    let commit = lines.next()?.split(' ').next()?.to_owned();
+
+    let mut author = String::new();
+    let mut date = String::new();
+    let mut summary = String::new();
+    for line in lines {
+        if let Some(value) = line.strip_prefix("author ") {
+            author = value.to_owned();
+        }
+        else if let Some(value) = line.strip_prefix("author-time ") {
+            date = format_date(value.parse().ok()?);
+        }
+        else if let Some(value) = line.strip_prefix("summary ") {
+            summary = value.to_owned();
+        }
+    }
+    Some(BlameInfo { commit, author, date, summary })
+}
+
+// Formats a unix timestamp as a YYYY-MM-DD date in UTC,
+// see Howard Hinnant's "chrono-Compatible Low-Level Date Algorithms"
+fn format_date(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400) + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// Maps every old line to the corresponding new line,
+// deleted lines map to the line following the deletion
+pub fn map_lines(old: &[String], new: &[String]) -> Vec<usize> {
+    let (prefix, operations) = diff_operations(old, new);
+    let last_line = new.len().saturating_sub(1);
+
+    let mut line_map: Vec<usize> = (0..prefix).collect();
+    let mut new_line = prefix;
+    for operation in operations {
+        match operation {
+            DiffOperation::Equal => {
+                line_map.push(new_line);
+                new_line += 1;
+            }
+            DiffOperation::Insert => new_line += 1,
+            DiffOperation::Delete => line_map.push(min(new_line, last_line))
+        }
+    }
+
+    // The common suffix maps one to one
+    while line_map.len() < old.len() {
+        line_map.push(min(new_line, last_line));
+        new_line += 1;
+    }
+    line_map
+}
+
+// Splits text into lines without their line breaks, keeping
+// the empty last line after a trailing line break like ropey does
+fn split_lines(text: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(chr) = chars.next() {
+        if text_utils::is_linebreak(chr) {
+            if chr == '\r' && chars.peek() == Some(&'\n') {
+                chars.next();
+            }
+            lines.push(line);
+            line = String::new();
+        }
+        else {
+            line.push(chr);
+        }
+    }
+    lines.push(line);
+    lines
+}
+
+// Computes the shortest edit script between the old and new lines using Myers'
+// algorithm, see "An O(ND) Difference Algorithm and Its Variations". None if
+// the script would be longer than MAX_DIFF_EDIT_DISTANCE
+fn myers_diff(old: &[String], new: &[String]) -> Option<Vec<DiffOperation>> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max = n + m;
+    let index = |k: isize| (k + max + 1) as usize;
+
+    let mut v = vec![0_isize; 2 * max as usize + 3];
+    // Walking back from step d only looks at the diagonals -d - 1..=d + 1,
+    // so only those are kept, with diagonal k at k + d + 1
+    let mut trace = Vec::new();
+    let trace_index = |k: isize, d: isize| (k + d + 1) as usize;
+
+    'outer: for d in 0..=max {
+        if d > MAX_DIFF_EDIT_DISTANCE {
+            return None;
+        }
+        trace.push(v[index(-d - 1)..=index(d + 1)].to_vec());
+        let mut k = -d;
+        while k <= d {
+            let mut x = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+                v[index(k + 1)]
+            }
+            else {
+                v[index(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[index(k)] = x;
+            if x >= n && y >= m {
+                break 'outer;
+            }
+            k += 2;
+        }
+    }
+
+    // Walk back through the trace to recover the edit script
+    let mut operations = Vec::new();
+    let mut x = n;
+    let mut y = m;
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let previous_k = if k == -d || (k != d && v[trace_index(k - 1, d)] < v[trace_index(k + 1, d)]) { k + 1 } else { k - 1 };
+        let previous_x = v[trace_index(previous_k, d)];
+        let previous_y = previous_x - previous_k;
+
+        while x > previous_x && y > previous_y {
+            operations.push(DiffOperation::Equal);
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            operations.push(if x == previous_x { DiffOperation::Insert } else { DiffOperation::Delete });
+        }
+        x = previous_x;
+        y = previous_y;
+    }
+
+    operations.reverse();
+    Some(operations)
+}
+
+// Returns the length of the common prefix and
+// the edit script for the lines following it
+fn diff_operations(old: &[String], new: &[String]) -> (usize, Vec<DiffOperation>) {
+    // Strip the common prefix and suffix,
+    // most edits only touch a small part of the file
+    let prefix = old.iter().zip(new.iter()).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let old_changed = &old[prefix..old.len() - suffix];
+    let new_changed = &new[prefix..new.len() - suffix];
+
+    let operations = if old_changed.len() + new_changed.len() > MAX_DIFF_LINES {
+        None
+    }
+    else {
+        myers_diff(old_changed, new_changed)
+    };
+    let operations = operations.unwrap_or_else(|| {
+        let mut operations = vec![DiffOperation::Delete; old_changed.len()];
+        operations.extend(vec![DiffOperation::Insert; new_changed.len()]);
+        operations
+    });
+    (prefix, operations)
+}
+
+pub fn diff_lines(old: &[String], new: &[String]) -> Vec<DiffHunk> {
+    let (prefix, operations) = diff_operations(old, new);
+
+    // Group consecutive insertions and deletions into hunks
+    let mut hunks = Vec::new();
+    let mut line = prefix;
+    let mut i = 0;
+    while i < operations.len() {
+        if operations[i] == DiffOperation::Equal {
+            line += 1;
+            i += 1;
+            continue;
+        }
+
+        let start = line;
+        let mut inserted = 0;
+        let mut deleted = 0;
+        while i < operations.len() && operations[i] != DiffOperation::Equal {
+            if operations[i] == DiffOperation::Insert {
+                inserted += 1;
+                line += 1;
+            }
+            else {
+                deleted += 1;
+            }
+            i += 1;
+        }
+
+        let change = match (inserted, deleted) {
+            (_, 0) => LineChange::Added,
+            (0, _) => LineChange::Deleted,
+            _ => LineChange::Modified
+        };
+        hunks.push(DiffHunk { change, line: start, length: inserted });
+    }
+    hunks
+}
+
+fn is_conflict_marker(line: &str, marker: &str) -> bool {
+    line.strip_prefix(marker).map_or(false, |rest| rest.is_empty() || rest.starts_with(' '))
+}
+
+// Scans for complete sets of conflict markers, a marker out of order starts the search over
+pub fn find_merge_conflicts(lines: &[String]) -> Vec<MergeConflict> {
+    let mut conflicts = Vec::new();
+    let mut start = None;
+    let mut base = None;
+    let mut separator = None;
+    for (line_number, line) in lines.iter().enumerate() {
+        if is_conflict_marker(line, "<<<<<<<") {
+            start = Some(line_number);
+            base = None;
+            separator = None;
+        }
+        else if is_conflict_marker(line, "|||||||") && start.is_some() && separator.is_none() {
+            base = Some(line_number);
+        }
+        else if line == "=======" && start.is_some() && separator.is_none() {
+            separator = Some(line_number);
+        }
+        else if is_conflict_marker(line, ">>>>>>>") {
+            if let (Some(start), Some(separator)) = (start, separator) {
+                conflicts.push(MergeConflict { start, base, separator, end: line_number });
+            }
+            start = None;
+            base = None;
+            separator = None;
+        }
+    }
+    conflicts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    fn hunk(change: LineChange, line: usize, length: usize) -> DiffHunk {
+        DiffHunk { change, line, length }
+    }
+
+    #[test]
+    fn split_lines_handles_every_line_ending() {
+        assert!(split_lines("a\r\nb\nc\rd\n") == lines(&["a", "b", "c", "d", ""]));
+        assert!(split_lines("") == lines(&[""]));
+    }
+
+    #[test]
+    fn diff_lines_finds_added_deleted_and_modified_lines() {
+        let old = lines(&["a", "b", "c"]);
+        assert!(diff_lines(&old, &old).is_empty());
+        assert!(diff_lines(&old, &lines(&["a", "x", "b", "c"])) == vec![hunk(LineChange::Added, 1, 1)]);
+        assert!(diff_lines(&old, &lines(&["a", "c"])) == vec![hunk(LineChange::Deleted, 1, 0)]);
+        assert!(diff_lines(&old, &lines(&["a", "y", "c"])) == vec![hunk(LineChange::Modified, 1, 1)]);
+        assert!(diff_lines(&old, &lines(&["x", "b", "c", "y"])) == vec![hunk(LineChange::Modified, 0, 1), hunk(LineChange::Added, 3, 1)]);
+    }
+
+    #[test]
+    fn very_different_files_are_marked_modified() {
+        let old: Vec<String> = (0..1500).map(|i| format!("old {}", i)).collect();
+        let new: Vec<String> = (0..1500).map(|i| format!("new {}", i)).collect();
+        assert!(diff_lines(&old, &new) == vec![hunk(LineChange::Modified, 0, 1500)]);
+    }
+
+    #[test]
+    fn map_lines_follows_the_lines_around_edits() {
+        let old = lines(&["a", "b", "c", "d"]);
+        assert_eq!(map_lines(&old, &lines(&["a", "c", "x", "d"])), vec![0, 1, 1, 3]);
+        // Lines deleted at the end map to the new last line
+        assert_eq!(map_lines(&lines(&["a", "b"]), &lines(&["a"])), vec![0, 0]);
+    }
+
+    #[test]
+    fn format_date_converts_unix_timestamps() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(951782400), "2000-02-29");
+        assert_eq!(format_date(-86400), "1969-12-31");
+        assert_eq!(format_date(1700000000), "2023-11-14");
+    }
+}
@@ -0,0 +1,121 @@
+use std::ops::Range;
+
+// A range of UTF-16 offsets in the exported text drawn in an RGB color like 0xFB4934
+pub struct ColoredSpan {
+    pub range: Range<usize>,
+    pub color: u32
+}
+
+fn escape_html(text: &[u16]) -> String {
+    String::from_utf16_lossy(text)
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// The text as preformatted HTML with the spans colored, overlapping spans after the first are skipped
+pub fn to_html(text: &[u16], spans: &mut [ColoredSpan], text_color: u32, background_color: u32, font_family: &str) -> String {
+    spans.sort_by_key(|span| span.range.start);
+
+    let mut html = format!(
+        "<pre style=\"font-family:'{}';color:#{:06X};background-color:#{:06X}\">",
+        font_family, text_color, background_color
+    );
+    let mut pos = 0;
+    for span in spans.iter() {
+        let end = span.range.end.min(text.len());
+        if span.range.start < pos || span.range.start >= end {
+            continue;
+        }
+        html.push_str(&escape_html(&text[pos..span.range.start]));
+        html.push_str(&format!("<span style=\"color:#{:06X}\">{}</span>", span.color, escape_html(&text[span.range.start..end])));
+        pos = end;
+    }
+    html.push_str(&escape_html(&text[pos..]));
+    html.push_str("</pre>");
+    html
+}
+
+// Wraps an HTML fragment in the clipboard format Windows uses for HTML,
+// a header with the byte offsets of the document and the fragment in it
+pub fn to_cf_html(fragment: &str) -> String {
+    let prefix = "<html><body>\r\n<!--StartFragment-->";
+    let suffix = "<!--EndFragment-->\r\n</body></html>";
+    let header = |start_html: usize, end_html: usize, start_fragment: usize, end_fragment: usize| {
+        format!(
+            "Version:0.9\r\nStartHTML:{:010}\r\nEndHTML:{:010}\r\nStartFragment:{:010}\r\nEndFragment:{:010}\r\n",
+            start_html, end_html, start_fragment, end_fragment
+        )
+    };
+
+    // The offsets are padded to a fixed width, so the header is equally long whatever they are
+    let header_length = header(0, 0, 0, 0).len();
+    let start_fragment = header_length + prefix.len();
+    let end_fragment = start_fragment + fragment.len();
+    let end_html = end_fragment + suffix.len();
+    format!("{}{}{}{}", header(header_length, end_html, start_fragment, end_fragment), prefix, fragment, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(text: &str) -> Vec<u16> {
+        text.encode_utf16().collect()
+    }
+
+    // The value of a header field like StartHTML as a byte offset
+    fn header_offset(cf_html: &str, name: &str) -> usize {
+        cf_html.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap()
+    }
+
+    #[test]
+    fn cf_html_offsets_point_at_the_document_and_fragment() {
+        // Multi-byte chars, the offsets count bytes
+        let fragment = "<pre>caf\u{E9} \u{1F600}</pre>";
+        let cf_html = to_cf_html(fragment);
+
+        let start_html = header_offset(&cf_html, "StartHTML");
+        let end_html = header_offset(&cf_html, "EndHTML");
+        let start_fragment = header_offset(&cf_html, "StartFragment");
+        let end_fragment = header_offset(&cf_html, "EndFragment");
+        assert!(cf_html[..start_html].starts_with("Version:0.9\r\n"));
+        assert!(cf_html[start_html..].starts_with("<html>"));
+        assert_eq!(end_html, cf_html.len());
+        assert!(cf_html[..start_fragment].ends_with("<!--StartFragment-->"));
+        assert_eq!(&cf_html[start_fragment..end_fragment], fragment);
+        assert!(cf_html[end_fragment..].starts_with("<!--EndFragment-->"));
+    }
+
+    #[test]
+    fn html_special_chars_are_escaped() {
+        let text = utf16("if a < b && b > c {}");
+        let html = to_html(&text, &mut [], 0xFFFFFF, 0x000000, "Consolas");
+        assert_eq!(
+            html,
+            "<pre style=\"font-family:'Consolas';color:#FFFFFF;background-color:#000000\">if a &lt; b &amp;&amp; b &gt; c {}</pre>"
+        );
+    }
+
+    #[test]
+    fn spans_are_colored_in_order_and_overlaps_skipped() {
+        let text = utf16("let x = <y>;");
+        let mut spans = [
+            ColoredSpan { range: 8..11, color: 0xB8BB26 },
+            ColoredSpan { range: 0..3, color: 0xFB4934 },
+            // Overlaps the span before it
+            ColoredSpan { range: 2..5, color: 0x83A598 },
+            // Past the end of the text
+            ColoredSpan { range: 20..25, color: 0x83A598 }
+        ];
+        let html = to_html(&text, &mut spans, 0xEBDBB2, 0x282828, "Consolas");
+        let body = html.split_once('>').unwrap().1;
+        assert_eq!(
+            body,
+            "<span style=\"color:#FB4934\">let</span> x = <span style=\"color:#B8BB26\">&lt;y&gt;</span>;</pre>"
+        );
+    }
+}
@@ -30,12 +30,151 @@ pub const RUST_KEYWORDS: [&str; 38] = ["as", "break", "const", "continue", "crat
 pub const RUST_FILE_EXTENSIONS: [&str; 1] = ["rs"];
 pub const RUST_LANGUAGE_IDENTIFIER: &str = "rust";
 
-#[derive(PartialEq)]
+pub const PYTHON_KEYWORDS: [&str; 35] = ["False", "None", "True", "and", "as", 
+"assert", "async", "await", "break", "class", "continue", "def", "del", "elif", 
+"else", "except", "finally", "for", "from", "global", "if", "import", "in", "is", 
+"lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while", 
+"with", "yield"];
+pub const PYTHON_FILE_EXTENSIONS: [&str; 1] = ["py"];
+pub const PYTHON_LANGUAGE_IDENTIFIER: &str = "python";
+
+// Files of any other type are plain text
+pub const PLAIN_TEXT_LANGUAGE_IDENTIFIER: &str = "";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum SemanticTokenTypes {
     Comment,
     Keyword,
     Literal,
     Preprocessor,
+    Variable,
+    Function,
+    Method,
+    Class,
+    Enum
+}
+
+// The standard token types that are highlighted, clangd and rust-analyzer use these names
+// in their legends as well. Comments, keywords and literals are left to the lexical highlighter
+pub const SEMANTIC_TOKEN_TYPES: [&str; 12] = ["parameter", "variable", "property", "function", "method",
+    "class", "struct", "interface", "type", "enum", "enumMember", "macro"];
+
+pub fn semantic_token_type_from_name(name: &str) -> Option<SemanticTokenTypes> {
+    match name {
+        "parameter" | "variable" | "property" => Some(SemanticTokenTypes::Variable),
+        "function" => Some(SemanticTokenTypes::Function),
+        "method" => Some(SemanticTokenTypes::Method),
+        "class" | "struct" | "interface" | "type" => Some(SemanticTokenTypes::Class),
+        "enum" | "enumMember" => Some(SemanticTokenTypes::Enum),
+        "macro" => Some(SemanticTokenTypes::Preprocessor),
+        _ => None
+    }
+}
+
+// A semantic token at an absolute position, the character
+// offset and length are in UTF-16 code units like in LSP
+#[derive(Clone, Copy, PartialEq)]
+pub struct SemanticToken {
+    pub line: usize,
+    pub character: usize,
+    pub length: usize,
+    pub token_type: SemanticTokenTypes
+}
+
+// Decodes the relative encoding of a semantic tokens response, every token is
+// five integers: deltaLine, deltaStartChar, length, tokenType and tokenModifiers.
+// The start char is relative to the previous token if both are on the same line.
+// The token type indexes the legend of the server, see semantic_token_type_from_name
+pub fn decode_semantic_tokens(data: &[u32], legend: &[Option<SemanticTokenTypes>]) -> Vec<SemanticToken> {
+    let mut semantic_tokens = Vec::new();
+    let mut line = 0;
+    let mut character = 0;
+    for token in data.chunks_exact(5) {
+        if token[0] > 0 {
+            line += token[0] as usize;
+            character = token[1] as usize;
+        }
+        else {
+            character += token[1] as usize;
+        }
+
+        if let Some(token_type) = legend.get(token[3] as usize).copied().flatten() {
+            semantic_tokens.push(SemanticToken { line, character, length: token[2] as usize, token_type });
+        }
+    }
+    semantic_tokens
+}
+
+pub struct CommentTokens {
+    // Starts a comment that runs to the end of the line
+    pub single_line: &'static str,
+    // The delimiters of a comment that can span lines, if the language has one
+    pub multi_line: Option<(&'static str, &'static str)>
+}
+
+// Languages without an entry of their own use the C style comments
+pub fn get_comment_tokens(language_identifier: &str) -> CommentTokens {
+    match language_identifier {
+        PYTHON_LANGUAGE_IDENTIFIER => CommentTokens { single_line: "#", multi_line: None },
+        _ => CommentTokens { single_line: "//", multi_line: Some(("/*", "*/")) }
+    }
+}
+
+// The delimiters of string literals that can span lines
+fn get_multi_line_string_delimiters(language_identifier: &str) -> &'static [&'static str] {
+    match language_identifier {
+        PYTHON_LANGUAGE_IDENTIFIER => &["\"\"\"", "'''"],
+        _ => &[]
+    }
+}
+
+// The quotes that are closed when typed, single quotes are left
+// out where they also appear unpaired, like Rust lifetimes
+pub fn get_autoclose_quotes(language_identifier: &str) -> &'static [char] {
+    match language_identifier {
+        PYTHON_LANGUAGE_IDENTIFIER => &['"', '\''],
+        PLAIN_TEXT_LANGUAGE_IDENTIFIER => &[],
+        _ => &['"']
+    }
+}
+
+// How the line is reindented when an electric character
+// is typed with nothing but whitespace before it
+#[derive(Copy, Clone, PartialEq)]
+pub enum ElectricAction {
+    // Moves the line back one indentation level, for closing scopes
+    Dedent,
+    // Removes the indentation entirely, for C preprocessor directives
+    ColumnZero
+}
+
+// The closing brackets of the settings are electric in every language and dedent
+// the line, these are the electric characters a language has in addition to them
+pub fn get_electric_characters(language_identifier: &str) -> &'static [(char, ElectricAction)] {
+    match language_identifier {
+        CPP_LANGUAGE_IDENTIFIER => &[('#', ElectricAction::ColumnZero)],
+        _ => &[]
+    }
+}
+
+// The length in bytes of a char literal like 'x' or '\n' at the start of the slice.
+// A quote that isn't closed right after one char or escape sequence on the same line
+// isn't a literal, but e.g. a Rust lifetime or an apostrophe
+fn get_char_literal_length(slice: &str) -> Option<usize> {
+    let mut chars = slice.char_indices().skip(1);
+    match chars.next()? {
+        (_, '\\') => {
+            chars.next()?;
+            chars.take_while(|(_, chr)| *chr != '\n' && *chr != '\r')
+                .find(|(_, chr)| *chr == '\'')
+                .map(|(index, _)| index + 1)
+        }
+        (_, '\'') | (_, '\n') | (_, '\r') => None,
+        _ => match chars.next()? {
+            (index, '\'') => Some(index + 1),
+            _ => None
+        }
+    }
 }
 
 fn new_range(start: usize, length: usize) -> DWRITE_TEXT_RANGE {
@@ -45,71 +184,103 @@ fn new_range(start: usize, length: usize) -> DWRITE_TEXT_RANGE {
     }
 }
 
+#[derive(Clone)]
 pub struct LexicalHighlights {
     pub highlight_tokens: Vec<(DWRITE_TEXT_RANGE, SemanticTokenTypes)>,
     pub enclosing_brackets: Option<[Option<usize>; 2]>
 }
 
-pub fn highlight_text(text: &str, start_pos: usize, caret_pos: usize, language_identifier: &'static str, mut start_it: Chars, mut caret_it: Chars) -> LexicalHighlights {
+// The brackets enclosing the caret are searched for at most max_bracket_distance chars in each direction
+pub fn highlight_text(text: &str, start_pos: usize, caret_pos: usize, language_identifier: &'static str, 
+                      mut start_it: Chars, mut caret_it: Chars, max_bracket_distance: usize, brackets: &[(char, char)]) -> LexicalHighlights {
     let mut highlight_tokens = Vec::new();
 
-    // Singleline and multiline comments style
-    // can convert to a match statement 
-    // once languages with different styles are introduced
-    let sl_comment =  "//";
-    let ml_comment = ["/*", "*/"];
+    // Plain text has no comments, strings or keywords, only the brackets are matched
+    let plain_text = language_identifier == PLAIN_TEXT_LANGUAGE_IDENTIFIER;
+    let comment_tokens = get_comment_tokens(language_identifier);
+    let sl_comment = comment_tokens.single_line;
 
-    let string_literal = '"';
-    let escaped_string_literal = "\\\"";
+    let ml_string_delimiters = get_multi_line_string_delimiters(language_identifier);
+    let string_literals: &[char] = match language_identifier {
+        PYTHON_LANGUAGE_IDENTIFIER => &['"', '\''],
+        _ => &['"']
+    };
+    // Single quotes start strings in Python, but only char literals in these
+    let char_literals = language_identifier == CPP_LANGUAGE_IDENTIFIER || language_identifier == RUST_LANGUAGE_IDENTIFIER;
 
     // Initially we need to look back and see if the first line 
     // already inside a multiline comment
     let mut inside_comment = false;
-    let do_match: Vec<char> = ml_comment[0].chars().rev().collect();
-    let dont_match: Vec<char> = ml_comment[1].chars().rev().collect();
-    let length0 = do_match.len();
-    let length1 = dont_match.len();
-    let mut index0 = 0;
-    let mut index1 = 0;
-    while let Some(chr) = start_it.prev() {
-        if chr == do_match[index0] {
-            index0 += 1;
-            // If we found a match, the first line is inside a multiline comment
-            if index0 == length0 {
-                inside_comment = true;
-                break;
+    if let Some(ml_comment) = comment_tokens.multi_line.filter(|_| !plain_text) {
+        let do_match: Vec<char> = ml_comment.0.chars().rev().collect();
+        let dont_match: Vec<char> = ml_comment.1.chars().rev().collect();
+        let length0 = do_match.len();
+        let length1 = dont_match.len();
+        let mut index0 = 0;
+        let mut index1 = 0;
+        while let Some(chr) = start_it.prev() {
+            if chr == do_match[index0] {
+                index0 += 1;
+                // If we found a match, the first line is inside a multiline comment
+                if index0 == length0 {
+                    inside_comment = true;
+                    break;
+                }
             }
-        }
-        else {
-            index0 = 0;
-        }
-        if chr == dont_match[index1] {
-            index1 += 1;
-            // If a closing bracket was found first, return
-            if index1 == length1 {
-                break;
+            else {
+                index0 = 0;
+            }
+            if chr == dont_match[index1] {
+                index1 += 1;
+                // If a closing bracket was found first, return
+                if index1 == length1 {
+                    break;
+                }
+            }
+            else {
+                index1 = 0;
             }
         }
-        else {
-            index1 = 0;
+    }
+
+    // Likewise the first line is inside a multiline string
+    // if an odd number of its delimiters precede it
+    let mut inside_string = None;
+    if !ml_string_delimiters.is_empty() {
+        let mut delimiter_counts = vec![0; ml_string_delimiters.len()];
+        let mut quote_run = ('\0', 0);
+        while let Some(chr) = start_it.prev() {
+            quote_run = match quote_run {
+                (quote, count) if quote == chr => (quote, count + 1),
+                _ => (chr, 1)
+            };
+            if quote_run.1 == 3 {
+                if let Some(index) = ml_string_delimiters.iter().position(|delimiter| delimiter.starts_with(chr)) {
+                    delimiter_counts[index] += 1;
+                }
+                quote_run = ('\0', 0);
+            }
         }
+        inside_string = delimiter_counts.iter().position(|count| count % 2 == 1).map(|index| ml_string_delimiters[index]);
     }
 
     let mut offset = 0;
     let mut identifier = String::from("");
-    while offset < text.len() {
+    while !plain_text && offset < text.len() {
         let slice = unsafe { text.get_unchecked(offset..text.len()) };
         // If we run into a multiline comment ending,
         // insert a comment if the start of the view 
         // was already inside a multiline comment
-        if slice.starts_with(ml_comment[1]) && inside_comment {
-            highlight_tokens.push((new_range(0, offset + 2), SemanticTokenTypes::Comment));
+        let ml_comment_end = comment_tokens.multi_line.filter(|ml_comment| inside_comment && slice.starts_with(ml_comment.1));
+        if let Some(ml_comment) = ml_comment_end {
+            highlight_tokens.push((new_range(0, offset + ml_comment.1.len()), SemanticTokenTypes::Comment));
             inside_comment = false;
         }
-        else if slice.starts_with(ml_comment[0]) {
-            if let Some(mlc_end) = slice.find(ml_comment[1]) {
-                highlight_tokens.push((new_range(offset, mlc_end + 2), SemanticTokenTypes::Comment));
-                offset += mlc_end + 2;
+        else if let Some(ml_comment) = comment_tokens.multi_line.filter(|ml_comment| slice.starts_with(ml_comment.0)) {
+            if let Some(mlc_end) = slice[ml_comment.0.len()..].find(ml_comment.1) {
+                let mlc_length = ml_comment.0.len() + mlc_end + ml_comment.1.len();
+                highlight_tokens.push((new_range(offset, mlc_length), SemanticTokenTypes::Comment));
+                offset += mlc_length;
                 continue;
             }
             else {
@@ -117,21 +288,50 @@ pub fn highlight_text(text: &str, start_pos: usize, caret_pos: usize, language_i
                 break;
             }
         }
-        else if slice.starts_with(string_literal) {
+        else if let Some(delimiter) = inside_string.filter(|delimiter| slice.starts_with(*delimiter)) {
+            highlight_tokens.push((new_range(0, offset + delimiter.len()), SemanticTokenTypes::Literal));
+            inside_string = None;
+            offset += delimiter.len();
+            continue;
+        }
+        else if let Some(delimiter) = ml_string_delimiters.iter().find(|delimiter| slice.starts_with(**delimiter)) {
+            if let Some(string_end) = slice[delimiter.len()..].find(delimiter) {
+                let string_length = 2 * delimiter.len() + string_end;
+                highlight_tokens.push((new_range(offset, string_length), SemanticTokenTypes::Literal));
+                offset += string_length;
+                continue;
+            }
+            else {
+                highlight_tokens.push((new_range(offset, text.len() - offset), SemanticTokenTypes::Literal));
+                break;
+            }
+        }
+        else if let Some(string_literal) = slice.chars().next().filter(|chr| string_literals.contains(chr)) {
             let mut string_offset = 1;
             while string_offset < slice.len() {
                 let string_slice = unsafe { slice.get_unchecked(string_offset..slice.len()) };
-                if string_slice.starts_with(escaped_string_literal) {
+                if string_slice.starts_with('\\') && string_slice[1..].starts_with(string_literal) {
                     string_offset += 2;
                     continue;
                 }
                 if string_slice.starts_with(string_literal) || string_slice.starts_with(|c: char| c == '\n' || c == '\r') {
                     break;
                 }
+                string_offset += string_slice.chars().next().unwrap().len_utf8();
+            }
+            // The closing quote is part of the literal, the line break
+            // ending an unterminated literal is not
+            if slice[string_offset..].starts_with(string_literal) {
                 string_offset += 1;
             }
-            highlight_tokens.push((new_range(offset, string_offset + 1), SemanticTokenTypes::Literal));
-            offset += string_offset + 1;
+            highlight_tokens.push((new_range(offset, string_offset), SemanticTokenTypes::Literal));
+            offset += string_offset;
+            continue;
+        }
+        else if let Some(char_literal_length) = Some(slice).filter(|slice| char_literals && slice.starts_with('\'')).and_then(get_char_literal_length) {
+            highlight_tokens.push((new_range(offset, char_literal_length), SemanticTokenTypes::Literal));
+            offset += char_literal_length;
+            continue;
         }
         else if slice.starts_with(sl_comment) {
             // Find the number of bytes until the next newline
@@ -142,6 +342,7 @@ pub fn highlight_text(text: &str, start_pos: usize, caret_pos: usize, language_i
             }
             else {
                 highlight_tokens.push((new_range(offset, text.len() - offset), SemanticTokenTypes::Comment));
+                break;
             }
         }
         else if slice.starts_with(|c: char| c.is_alphanumeric() || c == '_' || c == '#') {
@@ -151,6 +352,7 @@ pub fn highlight_text(text: &str, start_pos: usize, caret_pos: usize, language_i
             let keyword_match = match language_identifier {
                 CPP_LANGUAGE_IDENTIFIER => CPP_KEYWORDS.contains(&identifier.as_str()),
                 RUST_LANGUAGE_IDENTIFIER => RUST_KEYWORDS.contains(&identifier.as_str()),
+                PYTHON_LANGUAGE_IDENTIFIER => PYTHON_KEYWORDS.contains(&identifier.as_str()),
                 _ => false
             };
             if keyword_match {
@@ -161,7 +363,8 @@ pub fn highlight_text(text: &str, start_pos: usize, caret_pos: usize, language_i
             }
             identifier = String::from("");
         }        
-        offset += 1;
+        // Step a whole char to stay on a char boundary for multibyte content
+        offset += slice.chars().next().unwrap().len_utf8();
     }
 
     // If the first line of the view is inside
@@ -173,6 +376,12 @@ pub fn highlight_text(text: &str, start_pos: usize, caret_pos: usize, language_i
             enclosing_brackets: None
         };
     }
+    if inside_string.is_some() {
+        return LexicalHighlights {
+            highlight_tokens: vec![(new_range(0, text.len()), SemanticTokenTypes::Literal)],
+            enclosing_brackets: None
+        };
+    }
 
     // Closure to figure out if a text offset is inside a comment.
     // Used when searching for matching bracket pairs
@@ -186,41 +395,43 @@ pub fn highlight_text(text: &str, start_pos: usize, caret_pos: usize, language_i
         false
     };
 
-    // TODO: The following part finds matching bracket pairs that
-    // are not inside comments. It searches beyond the visible
-    // text buffer range. In the future perhaps it would be better
-    // to only search a certain distance in case no bracket match is found
+    // The following part finds matching bracket pairs that are not inside
+    // comments. It searches beyond the visible text buffer range, but gives
+    // up after the maximum distance so large files stay responsive
 
     // Iterate backwards searching for an opening bracket
     let mut closed_map: HashMap<char, usize> = HashMap::new();
     let mut bracket_type = ('\0', '\0');
     let mut backwards_offset = 0;
     while let Some(prev_char) = caret_it.prev() {
+        if backwards_offset >= max_bracket_distance {
+            break;
+        }
         let relative_pos_caret = caret_pos as isize - start_pos as isize;
         let relative_pos = relative_pos_caret - backwards_offset as isize;
 
-        if let Some(brackets) = text_utils::is_opening_bracket(prev_char) {
+        if let Some(bracket_pair) = text_utils::is_opening_bracket(prev_char, brackets) {
             if contained_in_comments(relative_pos) {
                 backwards_offset += 1;
                 continue;
             }
-            match closed_map.get_mut(&brackets.1) {
+            match closed_map.get_mut(&bracket_pair.1) {
                 Some(size) if *size > 0 => {
                     *size -= 1;
                 }
                 _ => {
-                    bracket_type = brackets;
+                    bracket_type = bracket_pair;
                     backwards_offset += 1;
                     break;
                 }
             }
         }
-        if let Some(brackets) = text_utils::is_closing_bracket(prev_char) {
+        if let Some(bracket_pair) = text_utils::is_closing_bracket(prev_char, brackets) {
             if contained_in_comments(relative_pos) {
                 backwards_offset += 1;
                 continue;
             }
-            *closed_map.entry(brackets.1).or_insert(0) += 1;
+            *closed_map.entry(bracket_pair.1).or_insert(0) += 1;
         }
         backwards_offset += 1;
     }
@@ -236,17 +447,20 @@ pub fn highlight_text(text: &str, start_pos: usize, caret_pos: usize, language_i
     // Now search forward from the same iterator to find the matching
     // closing bracket
     let mut closing_brackets_left = 0;
-    for (offset, chr) in caret_it.enumerate() {
+    // The distance is counted from the caret, which is behind the opening bracket
+    let max_offset = backwards_offset.saturating_add(max_bracket_distance);
+    for (offset, chr) in caret_it.enumerate().take_while(|(offset, _)| *offset <= max_offset) {
         // Skip the first char as it is the opening bracket itself
         if offset == 0 { continue; }
         let relative_pos_caret = caret_pos as isize - start_pos as isize;
-        let relative_pos = relative_pos_caret - backwards_offset as isize;
+        // Just past the current char, matching the positions of the backwards search
+        let relative_pos = relative_pos_caret - backwards_offset as isize + offset as isize + 1;
 
-        if let Some(brackets) = text_utils::is_closing_bracket(chr) {
+        if let Some(bracket_pair) = text_utils::is_closing_bracket(chr, brackets) {
             if contained_in_comments(relative_pos) {
                 continue;
             }
-            if bracket_type == brackets {
+            if bracket_type == bracket_pair {
                 if closing_brackets_left == 0 {
                     // Get the left bracket position relative to the absolute
                     // start position of the current view
@@ -255,7 +469,7 @@ pub fn highlight_text(text: &str, start_pos: usize, caret_pos: usize, language_i
 
                     // Only include a position if its inside the visible
                     // range of the current text buffer
-                    let visible_range = 0..text.len();
+                    let visible_range = 0..text.chars().count();
                     return LexicalHighlights {
                         highlight_tokens,
                         enclosing_brackets: Some([
@@ -269,11 +483,11 @@ pub fn highlight_text(text: &str, start_pos: usize, caret_pos: usize, language_i
                 }
             }
         }
-        else if let Some(brackets) = text_utils::is_opening_bracket(chr) {
+        else if let Some(bracket_pair) = text_utils::is_opening_bracket(chr, brackets) {
             if contained_in_comments(relative_pos) {
                 continue;
             }
-            if bracket_type == brackets {
+            if bracket_type == bracket_pair {
                 closing_brackets_left += 1;
             }
         }
@@ -284,3 +498,229 @@ pub fn highlight_text(text: &str, start_pos: usize, caret_pos: usize, language_i
         enclosing_brackets: None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::AUTOCOMPLETE_BRACKETS;
+    use ropey::Rope;
+
+    #[test]
+    fn semantic_tokens_are_decoded_with_the_legend_of_the_server() {
+        let legend: Vec<Option<SemanticTokenTypes>> = ["comment", "function", "variable", "unknownType"].iter()
+            .map(|name| semantic_token_type_from_name(name))
+            .collect();
+        let data = [
+            // function at 1:4, variable 6 chars after it on the same line
+            1, 4, 3, 1, 0,
+            0, 6, 2, 2, 0,
+            // On line 3 a comment, a token type that isn't highlighted and one missing from the legend
+            2, 2, 5, 0, 0,
+            0, 7, 1, 3, 0,
+            0, 2, 1, 9, 0,
+            // variable at 4:0
+            1, 0, 4, 2, 0
+        ];
+        let tokens = decode_semantic_tokens(&data, &legend);
+        assert!(tokens == [
+            SemanticToken { line: 1, character: 4, length: 3, token_type: SemanticTokenTypes::Function },
+            SemanticToken { line: 1, character: 10, length: 2, token_type: SemanticTokenTypes::Variable },
+            SemanticToken { line: 4, character: 0, length: 4, token_type: SemanticTokenTypes::Variable }
+        ]);
+    }
+
+    // Highlights the text from the view start onwards, the way
+    // the buffer does for the lines in view
+    fn highlight(text: &str, view_start: usize, caret_pos: usize, language_identifier: &'static str) -> (String, LexicalHighlights) {
+        let rope = Rope::from_str(text);
+        let view = rope.slice(view_start..).to_string();
+        let lexical_highlights = highlight_text(&view, view_start, caret_pos, language_identifier, rope.chars_at(view_start), rope.chars_at(caret_pos), usize::MAX, &AUTOCOMPLETE_BRACKETS);
+        (view, lexical_highlights)
+    }
+
+    fn get_token_texts<'a>(view: &'a str, lexical_highlights: &LexicalHighlights) -> Vec<(&'a str, SemanticTokenTypes)> {
+        lexical_highlights.highlight_tokens.iter().map(|(range, token_type)| {
+            let start = range.startPosition as usize;
+            (&view[start..start + range.length as usize], *token_type)
+        }).collect()
+    }
+
+    #[test]
+    fn keywords_and_line_comments() {
+        let (view, lexical_highlights) = highlight("int a; // note\nreturn a;", 0, 0, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(get_token_texts(&view, &lexical_highlights), vec![
+            ("int", SemanticTokenTypes::Keyword),
+            ("// note", SemanticTokenTypes::Comment),
+            ("return", SemanticTokenTypes::Keyword)
+        ]);
+    }
+
+    #[test]
+    fn line_comments_end_before_crlf() {
+        let (view, lexical_highlights) = highlight("//comment\r\nint a;", 0, 0, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(get_token_texts(&view, &lexical_highlights), vec![
+            ("//comment", SemanticTokenTypes::Comment),
+            ("int", SemanticTokenTypes::Keyword)
+        ]);
+    }
+
+    #[test]
+    fn line_comments_at_the_end_of_the_view() {
+        let (view, lexical_highlights) = highlight("a; // int b;", 0, 0, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(get_token_texts(&view, &lexical_highlights), vec![("// int b;", SemanticTokenTypes::Comment)]);
+    }
+
+    #[test]
+    fn comment_markers_inside_strings() {
+        let (view, lexical_highlights) = highlight("s = \"http://a /* b\";", 0, 0, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(get_token_texts(&view, &lexical_highlights), vec![("\"http://a /* b\"", SemanticTokenTypes::Literal)]);
+    }
+
+    #[test]
+    fn escaped_quotes_inside_strings() {
+        let (view, lexical_highlights) = highlight("\"a \\\" b\" + \"c\"", 0, 0, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(get_token_texts(&view, &lexical_highlights), vec![
+            ("\"a \\\" b\"", SemanticTokenTypes::Literal),
+            ("\"c\"", SemanticTokenTypes::Literal)
+        ]);
+    }
+
+    #[test]
+    fn unterminated_strings_end_at_the_line() {
+        let (view, lexical_highlights) = highlight("\"abc\nint;", 0, 0, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(get_token_texts(&view, &lexical_highlights), vec![
+            ("\"abc", SemanticTokenTypes::Literal),
+            ("int", SemanticTokenTypes::Keyword)
+        ]);
+    }
+
+    #[test]
+    fn char_literals() {
+        let (view, lexical_highlights) = highlight("c = '\"'; d = '\\'';", 0, 0, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(get_token_texts(&view, &lexical_highlights), vec![
+            ("'\"'", SemanticTokenTypes::Literal),
+            ("'\\''", SemanticTokenTypes::Literal)
+        ]);
+    }
+
+    #[test]
+    fn rust_lifetimes_are_not_char_literals() {
+        let (view, lexical_highlights) = highlight("fn f<'a>(s: &'a mut u8) { 'x' }", 0, 0, RUST_LANGUAGE_IDENTIFIER);
+        assert_eq!(get_token_texts(&view, &lexical_highlights), vec![
+            ("fn", SemanticTokenTypes::Keyword),
+            ("mut", SemanticTokenTypes::Keyword),
+            ("'x'", SemanticTokenTypes::Literal)
+        ]);
+    }
+
+    #[test]
+    fn block_comments() {
+        let (view, lexical_highlights) = highlight("int /* a\nb */ int;", 0, 0, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(get_token_texts(&view, &lexical_highlights), vec![
+            ("int", SemanticTokenTypes::Keyword),
+            ("/* a\nb */", SemanticTokenTypes::Comment),
+            ("int", SemanticTokenTypes::Keyword)
+        ]);
+    }
+
+    #[test]
+    fn unterminated_block_comments_run_to_the_end_of_the_view() {
+        let (view, lexical_highlights) = highlight("int a; /* open\nint b;", 0, 0, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(get_token_texts(&view, &lexical_highlights), vec![
+            ("int", SemanticTokenTypes::Keyword),
+            ("/* open\nint b;", SemanticTokenTypes::Comment)
+        ]);
+    }
+
+    #[test]
+    fn view_starting_inside_a_block_comment() {
+        let (view, lexical_highlights) = highlight("/* a\nb */ int c;", 5, 5, CPP_LANGUAGE_IDENTIFIER);
+        let token_texts = get_token_texts(&view, &lexical_highlights);
+        assert!(token_texts.contains(&("b */", SemanticTokenTypes::Comment)));
+        assert!(token_texts.contains(&("int", SemanticTokenTypes::Keyword)));
+    }
+
+    #[test]
+    fn view_entirely_inside_a_block_comment() {
+        let (view, lexical_highlights) = highlight("/* a\nint b;\n", 5, 5, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(get_token_texts(&view, &lexical_highlights), vec![("int b;\n", SemanticTokenTypes::Comment)]);
+        assert!(lexical_highlights.enclosing_brackets.is_none());
+    }
+
+    #[test]
+    fn view_after_a_closed_block_comment() {
+        let (view, lexical_highlights) = highlight("/* a */\nint b;", 8, 8, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(get_token_texts(&view, &lexical_highlights), vec![("int", SemanticTokenTypes::Keyword)]);
+    }
+
+    #[test]
+    fn enclosing_brackets() {
+        let (_, lexical_highlights) = highlight("f(a, [b])", 0, 6, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(lexical_highlights.enclosing_brackets, Some([Some(5), Some(7)]));
+
+        let (_, lexical_highlights) = highlight("f(a, [b])", 0, 3, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(lexical_highlights.enclosing_brackets, Some([Some(1), Some(8)]));
+    }
+
+    #[test]
+    fn brackets_inside_comments_are_skipped() {
+        let (_, lexical_highlights) = highlight("f(a /* ) */, b)", 0, 2, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(lexical_highlights.enclosing_brackets, Some([Some(1), Some(14)]));
+    }
+
+    #[test]
+    fn enclosing_brackets_outside_the_view() {
+        let (_, lexical_highlights) = highlight("{\nx\n}", 2, 2, CPP_LANGUAGE_IDENTIFIER);
+        assert_eq!(lexical_highlights.enclosing_brackets, Some([None, Some(2)]));
+    }
+
+    #[test]
+    fn no_enclosing_brackets() {
+        let (_, lexical_highlights) = highlight("a b c", 0, 2, CPP_LANGUAGE_IDENTIFIER);
+        assert!(lexical_highlights.enclosing_brackets.is_none());
+    }
+
+    #[test]
+    fn brackets_further_than_the_maximum_distance_are_not_matched() {
+        let rope = Rope::from_str("f(aaa)");
+        let lexical_highlights = highlight_text("f(aaa)", 0, 4, CPP_LANGUAGE_IDENTIFIER, rope.chars_at(0), rope.chars_at(4), 3, &AUTOCOMPLETE_BRACKETS);
+        assert_eq!(lexical_highlights.enclosing_brackets, Some([Some(1), Some(5)]));
+
+        let lexical_highlights = highlight_text("f(aaa)", 0, 4, CPP_LANGUAGE_IDENTIFIER, rope.chars_at(0), rope.chars_at(4), 2, &AUTOCOMPLETE_BRACKETS);
+        assert!(lexical_highlights.enclosing_brackets.is_none());
+    }
+
+    #[test]
+    fn only_the_configured_brackets_are_matched() {
+        let rope = Rope::from_str("f<(a)>");
+        let lexical_highlights = highlight_text("f<(a)>", 0, 4, CPP_LANGUAGE_IDENTIFIER, rope.chars_at(0), rope.chars_at(4), usize::MAX, &[('<', '>')]);
+        assert_eq!(lexical_highlights.enclosing_brackets, Some([Some(1), Some(5)]));
+    }
+
+    #[test]
+    fn plain_text_only_matches_brackets() {
+        let (view, lexical_highlights) = highlight("see (http://a.b/c) \"int\" /* x", 0, 5, PLAIN_TEXT_LANGUAGE_IDENTIFIER);
+        assert!(get_token_texts(&view, &lexical_highlights).is_empty());
+        assert_eq!(lexical_highlights.enclosing_brackets, Some([Some(4), Some(17)]));
+    }
+
+    #[test]
+    fn python_comments_and_strings() {
+        let (view, lexical_highlights) = highlight("def f(): # c\n  return 'a'", 0, 0, PYTHON_LANGUAGE_IDENTIFIER);
+        assert_eq!(get_token_texts(&view, &lexical_highlights), vec![
+            ("def", SemanticTokenTypes::Keyword),
+            ("# c", SemanticTokenTypes::Comment),
+            ("return", SemanticTokenTypes::Keyword),
+            ("'a'", SemanticTokenTypes::Literal)
+        ]);
+    }
+
+    #[test]
+    fn view_starting_inside_a_python_multiline_string() {
+        let (view, lexical_highlights) = highlight("\"\"\"a\nb\"\"\" if\n", 5, 5, PYTHON_LANGUAGE_IDENTIFIER);
+        assert_eq!(get_token_texts(&view, &lexical_highlights), vec![
+            ("b\"\"\"", SemanticTokenTypes::Literal),
+            ("if", SemanticTokenTypes::Keyword)
+        ]);
+    }
+}
@@ -0,0 +1,303 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    mem::take,
+    process::{self, Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::atomic::{AtomicI64, AtomicUsize, Ordering},
+    thread
+};
+
+use bindings::{
+    Windows::Win32::WindowsAndMessaging::{HWND, WPARAM, LPARAM, WM_USER, SendMessageW}
+};
+use serde::Serialize;
+
+use crate::{
+    language_support::{SemanticTokenTypes, CPP_LANGUAGE_IDENTIFIER, RUST_LANGUAGE_IDENTIFIER},
+    lsp_structs::{GenericRequest, GenericNotification, InitializeParams, InitializedParams, TextDocumentSyncKind}
+};
+
+pub const WM_LSP_RESPONSE: u32 = WM_USER + 1;
+pub const WM_LSP_CRASH: u32 = WM_USER + 2;
+
+static REQUEST_ID: AtomicI64 = AtomicI64::new(0);
+static CLIENT_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, PartialEq)]
+pub enum LSPRequestType {
+    InitializationRequest,
+    SemanticTokensRequest(String),
+    // The path and the version of the document the request was made for
+    OnTypeFormattingRequest(String, i32),
+    CompletionRequest(String, i32),
+    // Also carries the mouse position the tooltip is drawn at
+    HoverRequest(String, i32, (f32, f32)),
+    DefinitionRequest(String, i32)
+}
+
+pub struct LSPClient {
+    pub language_identifier: &'static str,
+    // Tells the crash of this client apart from one it replaced
+    pub id: usize,
+    server: Child,
+    stdin: ChildStdin,
+
+    // The type of every request still waiting for a response,
+    // keyed by request id so the response can be interpreted
+    pub request_types: HashMap<i64, LSPRequestType>,
+    pub initialized: bool,
+    // Typing one of these characters asks the server to format around it
+    pub on_type_formatting_triggers: Vec<char>,
+    // Typing one of these characters asks the server for completions
+    pub completion_triggers: Vec<char>,
+    // How the server wants the changes to the documents, taken from its capabilities.
+    // Until it has responded the full text is sent, which every server accepts
+    pub text_document_sync: TextDocumentSyncKind,
+    // The types of the semantic tokens in the legend of the server, None for those that aren't highlighted
+    pub semantic_token_legend: Vec<Option<SemanticTokenTypes>>,
+
+    // Nothing but the initialize request may be sent before the server
+    // has responded to it, so other messages are held until then
+    pending_messages: Vec<String>
+}
+
+pub fn path_to_uri(path: &str) -> String {
+    format!("file:///{}", path.replace('\\', "/").trim_start_matches('/').replace(' ', "%20"))
+}
+
+// The inverse of path_to_uri, though servers may percent-encode
+// more than the spaces, such as the colon after the drive letter
+pub fn uri_to_path(uri: &str) -> Option<String> {
+    let encoded_path = uri.strip_prefix("file:///")?;
+    let mut bytes = Vec::new();
+    let mut encoded_bytes = encoded_path.bytes();
+    while let Some(byte) = encoded_bytes.next() {
+        if byte == b'%' {
+            let hex_digits = [encoded_bytes.next()?, encoded_bytes.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex_digits).ok()?, 16).ok()?);
+        }
+        else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok().map(|path| path.replace('/', "\\"))
+}
+
+fn get_server_command(language_identifier: &str) -> Option<&'static str> {
+    match language_identifier {
+        CPP_LANGUAGE_IDENTIFIER => Some("clangd"),
+        RUST_LANGUAGE_IDENTIFIER => Some("rust-analyzer"),
+        _ => None
+    }
+}
+
+// Reads the header lines up to the empty line ending the header and returns the
+// content length, other headers like Content-Type are skipped. None if it is missing
+fn read_content_length<R: BufRead>(reader: &mut R) -> io::Result<Option<usize>> {
+    let mut content_length = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let header = line.trim_end_matches(['\r', '\n']);
+        if header.is_empty() {
+            return Ok(content_length);
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+}
+
+// Runs on a separate thread, the content of every message is passed to the window procedure
+// as an allocation that the WM_LSP_RESPONSE handler takes ownership of and frees.
+// When the server exits or the stream can't be read WM_LSP_CRASH is sent with the client id
+fn read_responses(hwnd: HWND, language_identifier: &'static str, client_id: usize, stdout: ChildStdout) {
+    let mut reader = BufReader::new(stdout);
+    loop {
+        let content_length = match read_content_length(&mut reader) {
+            Ok(Some(content_length)) => content_length,
+            Ok(None) => {
+                println!("Invalid header received from the {} language server", language_identifier);
+                break;
+            }
+            Err(_) => break
+        };
+
+        // Semantic tokens for a large file can take up megabytes, so there is no limit on the size.
+        // The content is read as it arrives rather than allocated up front from a length
+        // that could be corrupted, a message cut short by the end of the stream is dropped
+        let mut content = Vec::new();
+        match (&mut reader).take(content_length as u64).read_to_end(&mut content) {
+            Ok(length) if length == content_length => {}
+            _ => break
+        }
+
+        let allocation = content.into_boxed_slice();
+        unsafe {
+            SendMessageW(hwnd, WM_LSP_RESPONSE, WPARAM(Box::into_raw(allocation) as *mut u8 as usize), LPARAM(content_length as isize));
+        }
+    }
+
+    unsafe {
+        SendMessageW(hwnd, WM_LSP_CRASH, WPARAM(client_id), LPARAM(0));
+    }
+}
+
+impl LSPClient {
+    pub fn new(hwnd: HWND, language_identifier: &'static str) -> Option<Self> {
+        let command = get_server_command(language_identifier)?;
+        let mut server = match Command::new(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn() {
+            Ok(server) => server,
+            Err(error) => {
+                println!("Failed to start {}: {}", command, error);
+                return None;
+            }
+        };
+
+        let id = CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        let stdin = server.stdin.take()?;
+        let stdout = server.stdout.take()?;
+        thread::spawn(move || read_responses(hwnd, language_identifier, id, stdout));
+
+        Some(Self {
+            language_identifier,
+            id,
+            server,
+            stdin,
+            request_types: HashMap::new(),
+            initialized: false,
+            on_type_formatting_triggers: Vec::new(),
+            completion_triggers: Vec::new(),
+            text_document_sync: TextDocumentSyncKind::Full,
+            semantic_token_legend: Vec::new(),
+            pending_messages: Vec::new()
+        })
+    }
+
+    pub fn initialize(&mut self, root_path: &str) {
+        let id = REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+        self.request_types.insert(id, LSPRequestType::InitializationRequest);
+        let params = InitializeParams::new(process::id(), path_to_uri(root_path));
+        self.write(&serde_json::to_string(&GenericRequest::new(id, "initialize", params)).unwrap());
+    }
+
+    // Called once the server has responded to the initialize request
+    pub fn on_initialized(&mut self) {
+        self.initialized = true;
+        self.send_notification("initialized", InitializedParams {});
+        for message in take(&mut self.pending_messages) {
+            self.write(&message);
+        }
+    }
+
+    pub fn send_request<T: Serialize>(&mut self, method: &'static str, params: T, request_type: LSPRequestType) {
+        let id = REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+        self.request_types.insert(id, request_type);
+        self.send(serde_json::to_string(&GenericRequest::new(id, method, params)).unwrap());
+    }
+
+    pub fn send_notification<T: Serialize>(&mut self, method: &'static str, params: T) {
+        self.send(serde_json::to_string(&GenericNotification::new(method, params)).unwrap());
+    }
+
+    fn send(&mut self, content: String) {
+        if !self.initialized {
+            self.pending_messages.push(content);
+            return;
+        }
+        self.write(&content);
+    }
+
+    fn write(&mut self, content: &str) {
+        let message = format!("Content-Length: {}\r\n\r\n{}", content.len(), content);
+        if let Err(error) = self.stdin.write_all(message.as_bytes()).and_then(|_| self.stdin.flush()) {
+            println!("Failed to write to the {} language server: {}", self.language_identifier, error);
+        }
+    }
+}
+
+impl Drop for LSPClient {
+    fn drop(&mut self) {
+        let _ = self.server.kill();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn uris_are_percent_decoded() {
+        assert_eq!(uri_to_path("file:///C:/My%20Projects/a%2Bb.rs").as_deref(), Some("C:\\My Projects\\a+b.rs"));
+        assert_eq!(uri_to_path("file:///C:/caf%C3%A9.rs").as_deref(), Some("C:\\caf\u{E9}.rs"));
+        // Incomplete or invalid escapes
+        assert_eq!(uri_to_path("file:///C:/a%2"), None);
+        assert_eq!(uri_to_path("file:///C:/a%zz"), None);
+    }
+
+    #[test]
+    fn escaped_drive_colons_are_decoded() {
+        assert_eq!(uri_to_path("file:///c%3A/src/main.rs").as_deref(), Some("c:\\src\\main.rs"));
+    }
+
+    #[test]
+    fn only_file_uris_are_paths() {
+        assert_eq!(uri_to_path("untitled:Untitled-1"), None);
+        assert_eq!(uri_to_path("https://example.com/a.rs"), None);
+    }
+
+    #[test]
+    fn paths_survive_the_round_trip_through_uris() {
+        let path = "C:\\Program Files\\nimble\\main.rs";
+        assert_eq!(uri_to_path(&path_to_uri(path)).as_deref(), Some(path));
+    }
+
+    #[test]
+    fn content_length_skips_other_headers() {
+        let mut reader = Cursor::new("Content-Length: 52\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\n\r\n{}");
+        assert_eq!(read_content_length(&mut reader).unwrap(), Some(52));
+        // The content is left to be read after the header
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "{}");
+
+        let mut reader = Cursor::new("content-type: application/vscode-jsonrpc\r\ncontent-length:7\r\n\r\n");
+        assert_eq!(read_content_length(&mut reader).unwrap(), Some(7));
+    }
+
+    #[test]
+    fn header_lines_can_be_longer_than_the_buffer() {
+        let header = format!("X-Padding: {}\r\nContent-Length: 1234\r\n\r\n", "a".repeat(100));
+        let mut reader = BufReader::with_capacity(8, Cursor::new(header));
+        assert_eq!(read_content_length(&mut reader).unwrap(), Some(1234));
+    }
+
+    #[test]
+    fn missing_content_length_is_none() {
+        let mut reader = Cursor::new("Content-Type: application/vscode-jsonrpc\r\n\r\n");
+        assert_eq!(read_content_length(&mut reader).unwrap(), None);
+
+        let mut reader = Cursor::new("Content-Length: many\r\n\r\n");
+        assert_eq!(read_content_length(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn end_of_stream_in_the_header_is_an_error() {
+        let mut reader = Cursor::new("Content-Length: 52\r\nContent-Ty");
+        assert_eq!(read_content_length(&mut reader).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut reader = Cursor::new("");
+        assert_eq!(read_content_length(&mut reader).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
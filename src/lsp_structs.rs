@@ -0,0 +1,557 @@
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::language_support::SEMANTIC_TOKEN_TYPES;
+
+#[derive(Serialize)]
+pub struct GenericRequest<T: Serialize> {
+    jsonrpc: &'static str,
+    id: i64,
+    method: &'static str,
+    params: T
+}
+
+impl<T: Serialize> GenericRequest<T> {
+    pub fn new(id: i64, method: &'static str, params: T) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            id,
+            method,
+            params
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct GenericNotification<T: Serialize> {
+    jsonrpc: &'static str,
+    method: &'static str,
+    params: T
+}
+
+impl<T: Serialize> GenericNotification<T> {
+    pub fn new(method: &'static str, params: T) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            method,
+            params
+        }
+    }
+}
+
+// Everything the server sends is deserialized as a response, notifications
+// and requests from the server have a method and params instead of a result
+#[derive(Deserialize)]
+pub struct GenericResponse {
+    pub id: Option<i64>,
+    pub method: Option<String>,
+    pub params: Option<Value>,
+    pub result: Option<Value>,
+    pub error: Option<ResponseError>
+}
+
+#[derive(Deserialize)]
+pub struct ResponseError {
+    pub code: i64,
+    pub message: String
+}
+
+#[derive(Deserialize)]
+pub struct SemanticTokensResult {
+    pub data: Vec<u32>
+}
+
+#[derive(Deserialize)]
+pub struct InitializeResult {
+    pub capabilities: ServerCapabilities
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerCapabilities {
+    pub text_document_sync: Option<TextDocumentSync>,
+    pub semantic_tokens_provider: Option<SemanticTokensOptions>,
+    pub completion_provider: Option<CompletionOptions>,
+    pub document_on_type_formatting_provider: Option<DocumentOnTypeFormattingOptions>
+}
+
+#[derive(Deserialize)]
+pub struct SemanticTokensOptions {
+    pub legend: SemanticTokensLegend
+}
+
+// The token types the indices in the semantic tokens of the server refer to
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SemanticTokensLegend {
+    pub token_types: Vec<String>
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TextDocumentSyncKind {
+    None,
+    Full,
+    Incremental
+}
+
+// Older servers send the kind alone, as 0 for none, 1 for full and 2 for
+// incremental. Newer ones send options, where a missing kind means none
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum TextDocumentSync {
+    Kind(u8),
+    Options { change: Option<u8> }
+}
+
+impl TextDocumentSync {
+    pub fn change_kind(&self) -> TextDocumentSyncKind {
+        match *self {
+            TextDocumentSync::Kind(1) | TextDocumentSync::Options { change: Some(1) } => TextDocumentSyncKind::Full,
+            TextDocumentSync::Kind(2) | TextDocumentSync::Options { change: Some(2) } => TextDocumentSyncKind::Incremental,
+            _ => TextDocumentSyncKind::None
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompletionOptions {
+    #[serde(default)]
+    pub trigger_characters: Vec<String>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentOnTypeFormattingOptions {
+    pub first_trigger_character: String,
+    #[serde(default)]
+    pub more_trigger_character: Vec<String>
+}
+
+#[derive(Deserialize)]
+pub struct CompletionItem {
+    pub label: String
+}
+
+// Servers may respond with either the items alone or a list that also
+// tells whether it is complete, only the items are used for now
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum CompletionResult {
+    Items(Vec<CompletionItem>),
+    List { items: Vec<CompletionItem> }
+}
+
+impl CompletionResult {
+    pub fn into_items(self) -> Vec<CompletionItem> {
+        match self {
+            CompletionResult::Items(items) | CompletionResult::List { items } => items
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct HoverResult {
+    pub contents: HoverContents
+}
+
+// Newer servers send markup content while older ones send one or more marked strings
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum HoverContents {
+    Markup { kind: String, value: String },
+    MarkedString(MarkedString),
+    MarkedStrings(Vec<MarkedString>)
+}
+
+// Code also comes with its language, which the tooltip has no use for
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum MarkedString {
+    Markdown(String),
+    Code { value: String }
+}
+
+// The code fences are left out of markdown since the tooltip shows plain text
+fn markdown_to_plain_text(markdown: &str) -> String {
+    markdown.lines()
+        .filter(|line| !line.trim_start().starts_with("```"))
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+impl MarkedString {
+    fn into_text(self) -> String {
+        match self {
+            MarkedString::Markdown(markdown) => markdown_to_plain_text(&markdown),
+            MarkedString::Code { value } => value
+        }
+    }
+}
+
+impl HoverContents {
+    pub fn into_text(self) -> String {
+        let text = match self {
+            HoverContents::Markup { kind, value } if kind == "markdown" => markdown_to_plain_text(&value),
+            HoverContents::Markup { value, .. } => value,
+            HoverContents::MarkedString(marked_string) => marked_string.into_text(),
+            HoverContents::MarkedStrings(marked_strings) => marked_strings.into_iter()
+                .map(MarkedString::into_text)
+                .collect::<Vec<String>>()
+                .join("\n\n")
+        };
+        text.trim().to_owned()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Location {
+    pub uri: String,
+    pub range: Range
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocationLink {
+    pub target_uri: String,
+    pub target_selection_range: Range
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum DefinitionResult {
+    Location(Location),
+    Locations(Vec<Location>),
+    LocationLinks(Vec<LocationLink>)
+}
+
+impl DefinitionResult {
+    // The uri and position of the definition, the first one if there are several
+    pub fn into_first_target(self) -> Option<(String, Position)> {
+        match self {
+            DefinitionResult::Location(location) => Some((location.uri, location.range.start)),
+            DefinitionResult::Locations(locations) => locations.into_iter().next()
+                .map(|location| (location.uri, location.range.start)),
+            DefinitionResult::LocationLinks(links) => links.into_iter().next()
+                .map(|link| (link.target_uri, link.target_selection_range.start))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PublishDiagnosticsParams {
+    pub uri: String,
+    pub diagnostics: Vec<Diagnostic>
+}
+
+// The severity is 1 for errors, 2 for warnings, 3 for information and 4 for hints
+#[derive(Deserialize)]
+pub struct Diagnostic {
+    pub range: Range,
+    pub severity: Option<u8>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextEdit {
+    pub range: Range,
+    pub new_text: String
+}
+
+#[derive(Serialize)]
+pub struct InitializedParams {}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InitializeParams {
+    process_id: u32,
+    root_uri: String,
+    capabilities: ClientCapabilities
+}
+
+impl InitializeParams {
+    pub fn new(process_id: u32, root_uri: String) -> Self {
+        Self {
+            process_id,
+            root_uri,
+            capabilities: ClientCapabilities {
+                text_document: TextDocumentClientCapabilities {
+                    hover: HoverClientCapabilities {
+                        content_format: vec!["plaintext", "markdown"]
+                    },
+                    definition: DefinitionClientCapabilities {
+                        link_support: true
+                    },
+                    publish_diagnostics: PublishDiagnosticsClientCapabilities {
+                        related_information: false
+                    },
+                    semantic_tokens: SemanticTokensClientCapabilities {
+                        requests: SemanticTokensRequests { full: true },
+                        token_types: SEMANTIC_TOKEN_TYPES.to_vec(),
+                        token_modifiers: Vec::new(),
+                        formats: vec!["relative"]
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientCapabilities {
+    text_document: TextDocumentClientCapabilities
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextDocumentClientCapabilities {
+    hover: HoverClientCapabilities,
+    definition: DefinitionClientCapabilities,
+    publish_diagnostics: PublishDiagnosticsClientCapabilities,
+    semantic_tokens: SemanticTokensClientCapabilities
+}
+
+// Plain text is preferred, the tooltip doesn't render markdown
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HoverClientCapabilities {
+    content_format: Vec<&'static str>
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DefinitionClientCapabilities {
+    link_support: bool
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublishDiagnosticsClientCapabilities {
+    related_information: bool
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SemanticTokensClientCapabilities {
+    requests: SemanticTokensRequests,
+    token_types: Vec<&'static str>,
+    token_modifiers: Vec<&'static str>,
+    formats: Vec<&'static str>
+}
+
+#[derive(Serialize)]
+pub struct SemanticTokensRequests {
+    full: bool
+}
+
+#[derive(Serialize)]
+pub struct TextDocumentIdentifier {
+    uri: String
+}
+
+#[derive(Serialize)]
+pub struct VersionedTextDocumentIdentifier {
+    uri: String,
+    version: i32
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextDocumentItem {
+    uri: String,
+    language_id: &'static str,
+    version: i32,
+    text: String
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DidOpenTextDocumentParams {
+    text_document: TextDocumentItem
+}
+
+impl DidOpenTextDocumentParams {
+    pub fn new(uri: String, language_id: &'static str, version: i32, text: String) -> Self {
+        Self {
+            text_document: TextDocumentItem {
+                uri,
+                language_id,
+                version,
+                text
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub struct Position {
+    pub line: usize,
+    pub character: usize
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position
+}
+
+#[derive(Serialize)]
+pub struct TextDocumentContentChangeEvent {
+    // Without a range the text replaces the entire document
+    #[serde(skip_serializing_if = "Option::is_none")]
+    range: Option<Range>,
+    text: String
+}
+
+impl TextDocumentContentChangeEvent {
+    pub fn new_full_event(text: String) -> Self {
+        Self {
+            range: None,
+            text
+        }
+    }
+
+    pub fn new_insert_event(position: Position, text: String) -> Self {
+        Self {
+            range: Some(Range {
+                start: position,
+                end: position
+            }),
+            text
+        }
+    }
+
+    pub fn new_delete_event(start: Position, end: Position) -> Self {
+        Self {
+            range: Some(Range {
+                start,
+                end
+            }),
+            text: String::new()
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DidChangeTextDocumentParams {
+    text_document: VersionedTextDocumentIdentifier,
+    content_changes: Vec<TextDocumentContentChangeEvent>
+}
+
+impl DidChangeTextDocumentParams {
+    pub fn new(uri: String, version: i32, content_changes: Vec<TextDocumentContentChangeEvent>) -> Self {
+        Self {
+            text_document: VersionedTextDocumentIdentifier {
+                uri,
+                version
+            },
+            content_changes
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DidCloseTextDocumentParams {
+    text_document: TextDocumentIdentifier
+}
+
+impl DidCloseTextDocumentParams {
+    pub fn new(uri: String) -> Self {
+        Self {
+            text_document: TextDocumentIdentifier {
+                uri
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SemanticTokensParams {
+    text_document: TextDocumentIdentifier
+}
+
+impl SemanticTokensParams {
+    pub fn new(uri: String) -> Self {
+        Self {
+            text_document: TextDocumentIdentifier {
+                uri
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FormattingOptions {
+    tab_size: usize,
+    insert_spaces: bool
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentOnTypeFormattingParams {
+    text_document: TextDocumentIdentifier,
+    position: Position,
+    ch: String,
+    options: FormattingOptions
+}
+
+impl DocumentOnTypeFormattingParams {
+    pub fn new(uri: String, position: Position, ch: char, tab_size: usize, insert_spaces: bool) -> Self {
+        Self {
+            text_document: TextDocumentIdentifier {
+                uri
+            },
+            position,
+            ch: ch.to_string(),
+            options: FormattingOptions {
+                tab_size,
+                insert_spaces
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextDocumentPositionParams {
+    text_document: TextDocumentIdentifier,
+    position: Position
+}
+
+impl TextDocumentPositionParams {
+    pub fn new(uri: String, position: Position) -> Self {
+        Self {
+            text_document: TextDocumentIdentifier {
+                uri
+            },
+            position
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sync_kind(capabilities: &str) -> TextDocumentSyncKind {
+        serde_json::from_str::<ServerCapabilities>(capabilities).unwrap().text_document_sync
+            .as_ref()
+            .map_or(TextDocumentSyncKind::None, TextDocumentSync::change_kind)
+    }
+
+    #[test]
+    fn text_document_sync_is_read_as_a_kind_or_options() {
+        assert!(sync_kind(r#"{"textDocumentSync": 1}"#) == TextDocumentSyncKind::Full);
+        assert!(sync_kind(r#"{"textDocumentSync": 2}"#) == TextDocumentSyncKind::Incremental);
+        assert!(sync_kind(r#"{"textDocumentSync": 0}"#) == TextDocumentSyncKind::None);
+        assert!(sync_kind(r#"{"textDocumentSync": {"openClose": true, "change": 1}}"#) == TextDocumentSyncKind::Full);
+        assert!(sync_kind(r#"{"textDocumentSync": {"openClose": true, "change": 2, "save": {}}}"#) == TextDocumentSyncKind::Incremental);
+        assert!(sync_kind(r#"{"textDocumentSync": {"openClose": true}}"#) == TextDocumentSyncKind::None);
+        assert!(sync_kind("{}") == TextDocumentSyncKind::None);
+    }
+}
@@ -10,15 +10,25 @@ mod buffer;
 mod settings;
 mod language_support;
 mod text_utils;
+mod git_support;
+mod html_export;
+mod file_tree;
+mod lsp_client;
+mod lsp_structs;
 mod util;
+mod word_completion;
+mod scratch_backup;
 
 use buffer::TextRange;
-use editor::{Editor, EditorCommand};
+use editor::{Editor, EditorCommand, ShellCommandOutput, WM_SHELL_COMMAND_OUTPUT};
+use lsp_client::{WM_LSP_RESPONSE, WM_LSP_CRASH};
+use lsp_structs::GenericResponse;
 use util::{pwstr_from_str, unwrap_hresult};
 
 use std::{
     mem::MaybeUninit,
-    ptr::null_mut
+    ptr::null_mut,
+    slice
 };
 
 use bindings::{
@@ -62,9 +72,14 @@ extern "system" fn wnd_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM
 
         let shift_down = (GetKeyState(VK_SHIFT as i32) & 0x80) != 0;
         let ctrl_down = (GetKeyState(VK_CONTROL as i32) & 0x80) != 0;
+        let alt_down = (GetKeyState(VK_MENU as i32) & 0x80) != 0;
 
         static mut MOUSE_FROM_OUTSIDE_WINDOW: bool = false;
         static mut CACHED_SELECTION_RANGE: TextRange = TextRange { start: 0, length: 0 }; 
+        static mut CACHED_FILE_TREE_HOVER: Option<usize> = None;
+        static mut CACHED_HOVER_TOOLTIP: bool = false;
+        // The time and position of the last double click, to detect a triple click
+        static mut LAST_DOUBLE_CLICK: Option<(i32, (f32, f32))> = None;
         match msg {
             WM_PAINT => {
                 let mut ps = MaybeUninit::<PAINTSTRUCT>::uninit();
@@ -87,37 +102,76 @@ extern "system" fn wnd_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM
                 InvalidateRect(hwnd, null_mut(), false);
                 LRESULT(0)
             }
+            WM_CLOSE => {
+                if (*editor).can_quit() {
+                    (*editor).backup_scratch_documents();
+                    DestroyWindow(hwnd);
+                }
+                LRESULT(0)
+            }
             WM_DESTROY | WM_NCDESTROY => {
                 PostQuitMessage(0);
                 LRESULT(0)
             }
             WM_CHAR => {
-                if wparam.0 >= 0x20 && wparam.0 <= 0x7E {
+                // Anything but control characters is inserted, characters outside
+                // the basic multilingual plane arrive as two surrogate messages.
+                // The space of CTRL+Space is left out since it opens the word completion
+                if wparam.0 >= 0x20 && wparam.0 != 0x7F && !(ctrl_down && wparam.0 == 0x20) {
                     (*editor).execute_command(&EditorCommand::CharInsert(wparam.0 as u16));
                 }
                 InvalidateRect(hwnd, null_mut(), false);
                 LRESULT(0)
             }
             WM_MOUSEWHEEL => {
-                if high_word(wparam.0 as i32) > 0 {
-                    (*editor).execute_command(&EditorCommand::ScrollUp(ctrl_down));
+                // The high word is the signed distance the wheel was rotated,
+                // with SHIFT held the wheel scrolls horizontally instead
+                let wheel_delta = high_word(wparam.0 as i32);
+                if shift_down && !ctrl_down {
+                    (*editor).execute_command(&EditorCommand::MouseHorizontalWheel(-wheel_delta));
                 }
                 else {
-                    (*editor).execute_command(&EditorCommand::ScrollDown(ctrl_down));
+                    (*editor).execute_command(&EditorCommand::MouseWheel(wheel_delta, ctrl_down));
                 }
                 InvalidateRect(hwnd, null_mut(), false);
                 LRESULT(0)
             }
+            WM_MOUSEHWHEEL => {
+                (*editor).execute_command(&EditorCommand::MouseHorizontalWheel(high_word(wparam.0 as i32)));
+                InvalidateRect(hwnd, null_mut(), false);
+                LRESULT(0)
+            }
             WM_LBUTTONDOWN => {
                 SetCapture(hwnd);
                 let mouse_pos = (low_word(lparam.0 as i32) as f32, high_word(lparam.0 as i32) as f32);
-                (*editor).execute_command(&EditorCommand::LeftClick(mouse_pos, shift_down));
+                // A click within the double click time and rectangle of a double click is a triple click
+                let last_double_click = LAST_DOUBLE_CLICK;
+                LAST_DOUBLE_CLICK = None;
+                let triple_click = match last_double_click {
+                    Some((time, last_pos)) => {
+                        (GetMessageTime() - time) as u32 <= GetDoubleClickTime()
+                            && (mouse_pos.0 - last_pos.0).abs() <= (GetSystemMetrics(GetSystemMetrics_nIndexFlags::SM_CXDOUBLECLK) / 2) as f32
+                            && (mouse_pos.1 - last_pos.1).abs() <= (GetSystemMetrics(GetSystemMetrics_nIndexFlags::SM_CYDOUBLECLK) / 2) as f32
+                    }
+                    None => false
+                };
+                if triple_click {
+                    (*editor).execute_command(&EditorCommand::LeftTripleClick(mouse_pos));
+                }
+                // ALT+Click starts a block selection
+                else if alt_down {
+                    (*editor).execute_command(&EditorCommand::LeftAltClick(mouse_pos));
+                }
+                else {
+                    (*editor).execute_command(&EditorCommand::LeftClick(mouse_pos, shift_down, ctrl_down));
+                }
                 InvalidateRect(hwnd, null_mut(), false);
                 LRESULT(0)
             }
             WM_LBUTTONDBLCLK => {
                 let mouse_pos = (low_word(lparam.0 as i32) as f32, high_word(lparam.0 as i32) as f32);
                 (*editor).execute_command(&EditorCommand::LeftDoubleClick(mouse_pos));
+                LAST_DOUBLE_CLICK = Some((GetMessageTime(), mouse_pos));
                 InvalidateRect(hwnd, null_mut(), false);
                 LRESULT(0)
             }
@@ -132,6 +186,25 @@ extern "system" fn wnd_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM
                 InvalidateRect(hwnd, null_mut(), false);
                 LRESULT(0)
             }
+            WM_SYSKEYDOWN => {
+                // Bit 29 of the lparam is set if ALT is held down,
+                // let the system handle ALT+F4 and plain F10
+                let alt_down = (lparam.0 >> 29) & 1 == 1;
+                if !alt_down || wparam.0 as u32 == VK_F4 {
+                    return DefWindowProcW(hwnd, msg, wparam, lparam);
+                }
+                (*editor).execute_command(&EditorCommand::AltKeyPressed(wparam.0 as u32, shift_down));
+                InvalidateRect(hwnd, null_mut(), false);
+                LRESULT(0)
+            }
+            WM_SYSCHAR => {
+                // Swallow ALT+<key> characters to avoid the menu beep,
+                // except ALT+Space which opens the system menu
+                if wparam.0 as u32 == VK_SPACE {
+                    return DefWindowProcW(hwnd, msg, wparam, lparam);
+                }
+                LRESULT(0)
+            }
             WM_MOUSEMOVE => {
                 // If the mouse came from outside the window,
                 // track when the mouse leaves the window (and fires the WM_MOUSELEAVE event)
@@ -149,19 +222,59 @@ extern "system" fn wnd_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM
                 let mouse_pos = (low_word(lparam.0 as i32) as f32, high_word(lparam.0 as i32) as f32);
                 (*editor).execute_command(&EditorCommand::MouseMove(mouse_pos));
                 
-                // Only invalidate if selection changes for performance reasons
+                // Only invalidate if selection or file tree hover changes for performance reasons
+                if (*editor).is_block_selecting() {
+                    InvalidateRect(hwnd, null_mut(), false);
+                }
                 if let Some(selection) = (*editor).get_current_selection() {
                     if selection != CACHED_SELECTION_RANGE {
                         InvalidateRect(hwnd, null_mut(), false);
                         CACHED_SELECTION_RANGE = selection;
                     }
                 }
+                let file_tree_hover = (*editor).get_file_tree_hover();
+                if file_tree_hover != CACHED_FILE_TREE_HOVER {
+                    InvalidateRect(hwnd, null_mut(), false);
+                    CACHED_FILE_TREE_HOVER = file_tree_hover;
+                }
+                // Moving the mouse hides the hover tooltip
+                let hover_tooltip = (*editor).has_hover_tooltip();
+                if hover_tooltip != CACHED_HOVER_TOOLTIP {
+                    InvalidateRect(hwnd, null_mut(), false);
+                }
+                CACHED_HOVER_TOOLTIP = hover_tooltip;
+                LRESULT(0)
+            }
+            WM_TIMER => {
+                (*editor).timer_elapsed(wparam.0);
+                InvalidateRect(hwnd, null_mut(), false);
                 LRESULT(0)
             }
             WM_MOUSELEAVE => {
                 MOUSE_FROM_OUTSIDE_WINDOW = true;
                 LRESULT(0)
             }
+            WM_LSP_RESPONSE => {
+                // The reader thread leaves the allocation to us, it is freed when the box is dropped
+                let allocation = Box::from_raw(slice::from_raw_parts_mut(wparam.0 as *mut u8, lparam.0 as usize));
+                match serde_json::from_slice::<GenericResponse>(&allocation) {
+                    Ok(response) => (*editor).handle_lsp_response(response),
+                    Err(error) => println!("Failed to parse language server response: {}", error)
+                }
+                InvalidateRect(hwnd, null_mut(), false);
+                LRESULT(0)
+            }
+            WM_LSP_CRASH => {
+                (*editor).handle_lsp_crash(wparam.0);
+                LRESULT(0)
+            }
+            WM_SHELL_COMMAND_OUTPUT => {
+                // Like the responses of the language servers, the allocation is freed when the box is dropped
+                let output = Box::from_raw(wparam.0 as *mut ShellCommandOutput);
+                (*editor).handle_shell_command_output(*output);
+                InvalidateRect(hwnd, null_mut(), false);
+                LRESULT(0)
+            }
             _ => DefWindowProcW(hwnd, msg, wparam, lparam)
         }
     }
@@ -171,6 +284,8 @@ fn main() {
     let mut editor = Box::<Editor>::new_uninit();
 
     unsafe {
+        // The COM file dialogs require a single threaded apartment
+        unwrap_hresult(windows::initialize_sta());
         unwrap_hresult(SetProcessDpiAwareness(PROCESS_DPI_AWARENESS::PROCESS_PER_MONITOR_DPI_AWARE).ok());
 
         let wnd_class = WNDCLASSW {
@@ -1,16 +1,22 @@
 use crate::{
-    settings,
+    settings::{Settings, CurrentLineHighlight, TextAntialiasing},
     buffer::TextPosition,
-    editor::TextDocument,
+    editor::{TextDocument, DiagnosticSeverity},
     editor::TextView,
     theme::Theme,
-    language_support::SemanticTokenTypes,
+    language_support::{SemanticTokenTypes, PLAIN_TEXT_LANGUAGE_IDENTIFIER},
+    git_support::{LineChange, MergeConflict, ConflictResolution},
+    file_tree::FileTree,
+    html_export::{self, ColoredSpan},
+    text_utils,
     util::pwstr_from_str
 };
 
 use std::{
+    cmp::{min, max},
     collections::HashMap,
-    ptr::null_mut
+    ptr::null_mut,
+    rc::Rc
 };
 
 use bindings::{
@@ -25,6 +31,14 @@ use bindings::{
 };
 use windows::{Abi, Result, Interface};
 
+// Drawn after the start marker of a merge conflict, clicking one resolves the conflict
+const MERGE_CONFLICT_ACTIONS: [(&str, ConflictResolution); 3] = [
+    ("Accept Current", ConflictResolution::Current),
+    ("Accept Incoming", ConflictResolution::Incoming),
+    ("Accept Both", ConflictResolution::Both)
+];
+const MERGE_CONFLICT_ACTION_SEPARATOR: &str = " | ";
+
 fn get_client_size(hwnd: HWND) -> D2D_SIZE_U {
     let mut rect = RECT::default();
     unsafe { GetClientRect(hwnd, &mut rect); }
@@ -62,6 +76,28 @@ fn create_text_format(font_name: PWSTR, font_locale: PWSTR, font_size: f32, dwri
     }
 }
 
+// Programming fonts draw sequences like => and != as ligatures through
+// these features, which are turned on or off explicitly on every layout
+fn create_typography(dwrite_factory: &IDWriteFactory, ligatures: bool) -> Result<IDWriteTypography> {
+    unsafe {
+        let mut typography = None;
+        dwrite_factory.CreateTypography(&mut typography).ok()?;
+        let typography = typography.unwrap();
+        for feature in &[DWRITE_FONT_FEATURE_TAG::DWRITE_FONT_FEATURE_TAG_STANDARD_LIGATURES, DWRITE_FONT_FEATURE_TAG::DWRITE_FONT_FEATURE_TAG_CONTEXTUAL_ALTERNATES] {
+            typography.AddFontFeature(DWRITE_FONT_FEATURE { nameTag: *feature, parameter: ligatures as u32 }).ok()?;
+        }
+        Ok(typography)
+    }
+}
+
+fn get_text_antialias_mode(text_antialiasing: TextAntialiasing) -> D2D1_TEXT_ANTIALIAS_MODE {
+    match text_antialiasing {
+        TextAntialiasing::Aliased   => D2D1_TEXT_ANTIALIAS_MODE::D2D1_TEXT_ANTIALIAS_MODE_ALIASED,
+        TextAntialiasing::Grayscale => D2D1_TEXT_ANTIALIAS_MODE::D2D1_TEXT_ANTIALIAS_MODE_GRAYSCALE,
+        TextAntialiasing::ClearType => D2D1_TEXT_ANTIALIAS_MODE::D2D1_TEXT_ANTIALIAS_MODE_CLEARTYPE
+    }
+}
+
 fn create_d2d1_factory() -> Result<ID2D1Factory> {
     let mut d2d1_factory = None;
     unsafe {
@@ -99,6 +135,11 @@ fn create_render_target(d2d1_factory: &ID2D1Factory, hwnd: HWND) -> Result<ID2D1
     }
 }
 
+fn color_to_rgb(color: D2D1_COLOR_F) -> u32 {
+    let channel = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u32;
+    (channel(color.r) << 16) | (channel(color.g) << 8) | channel(color.b)
+}
+
 fn get_character_spacing(dwrite_factory: &IDWriteFactory, text_format: &IDWriteTextFormat) -> Result<f32> {
     unsafe {
         let mut temp_text_layout = None;
@@ -125,28 +166,60 @@ fn get_character_spacing(dwrite_factory: &IDWriteFactory, text_format: &IDWriteT
     }
 }
 
+// Everything a buffer layout is made from, the
+// layout is reused until one of these changes
+#[derive(PartialEq)]
+struct BufferLayoutKey {
+    version: i32,
+    line_offset: usize,
+    pixel_size: (u32, u32),
+    word_wrap: bool,
+    wrap_width: f32,
+    tab_width: usize
+}
+
+struct BufferLayout {
+    text_layout: IDWriteTextLayout,
+    key: BufferLayoutKey,
+    // In UTF-16 code units, the whole layout is covered when clearing the drawing effects
+    text_length: u32
+}
+
 pub struct TextRenderer {
     pub pixel_size: D2D_SIZE_U,
     pub font_size: f32,
     line_spacing: f32,
     character_spacing: f32,
 
+    // Width of the line number gutter to the left
+    // of the text, grows with the number of lines
+    gutter_width: f32,
+
+    // Width of the file tree panel to the left
+    // of the tabs and gutter, zero without a workspace
+    file_tree_width: f32,
+
     font_name: String,
+    text_antialiasing: TextAntialiasing,
+    // Diagnostics less severe than this aren't drawn
+    diagnostic_min_severity: DiagnosticSeverity,
 
     caret_width: u32,
 
     theme: Theme,
+    settings: Rc<Settings>,
 
     dwrite_factory: IDWriteFactory,
     text_format: IDWriteTextFormat,
+    typography: IDWriteTypography,
     
     render_target: ID2D1HwndRenderTarget,
 
-    buffer_layouts: HashMap<String, IDWriteTextLayout>
+    buffer_layouts: HashMap<String, BufferLayout>
 }
 
 impl TextRenderer {
-    pub fn new(hwnd: HWND, font: &str, font_size: f32) -> Result<Self> {
+    pub fn new(hwnd: HWND, font: &str, font_size: f32, theme_colors: &HashMap<String, D2D1_COLOR_F>, settings: Rc<Settings>) -> Result<Self> {
         unsafe {
             // We'll increase the width from the system width slightly
             let mut caret_width: u32 = 0;
@@ -171,7 +244,7 @@ impl TextRenderer {
             text_format.SetParagraphAlignment(DWRITE_PARAGRAPH_ALIGNMENT::DWRITE_PARAGRAPH_ALIGNMENT_NEAR).ok()?;
             text_format.SetWordWrapping(DWRITE_WORD_WRAPPING::DWRITE_WORD_WRAPPING_NO_WRAP).ok()?;
 
-            let pixel_aligned_line_spacing = f32::ceil(scaled_font_size * settings::LINE_SPACING_FACTOR);
+            let pixel_aligned_line_spacing = f32::ceil(scaled_font_size * settings.line_spacing_factor);
             text_format.SetLineSpacing(
                 DWRITE_LINE_SPACING_METHOD::DWRITE_LINE_SPACING_METHOD_UNIFORM, 
                 pixel_aligned_line_spacing, 
@@ -179,28 +252,71 @@ impl TextRenderer {
             ).ok()?;
 
             let character_spacing = get_character_spacing(&dwrite_factory, &text_format)?;
-            text_format.SetIncrementalTabStop(character_spacing * settings::NUMBER_OF_SPACES_PER_TAB as f32).ok()?;
+            text_format.SetIncrementalTabStop(character_spacing * settings.tab_display_width.unwrap_or(settings.number_of_spaces_per_tab) as f32).ok()?;
+
+            let typography = create_typography(&dwrite_factory, settings.font_ligatures)?;
 
             let d2d1_factory = create_d2d1_factory()?;
             let render_target = create_render_target(&d2d1_factory, hwnd)?;
-            render_target.SetAntialiasMode(D2D1_ANTIALIAS_MODE::D2D1_ANTIALIAS_MODE_ALIASED);
+            render_target.SetAntialiasMode(if settings.antialiased_shapes {
+                D2D1_ANTIALIAS_MODE::D2D1_ANTIALIAS_MODE_PER_PRIMITIVE
+            }
+            else {
+                D2D1_ANTIALIAS_MODE::D2D1_ANTIALIAS_MODE_ALIASED
+            });
+            render_target.SetTextAntialiasMode(get_text_antialias_mode(settings.text_antialiasing));
 
             Ok(Self {
                 pixel_size: get_client_size(hwnd),
                 font_size: scaled_font_size,
                 line_spacing: pixel_aligned_line_spacing,
                 character_spacing,
+                gutter_width: 0.0,
+                file_tree_width: 0.0,
                 font_name: String::from(font),
+                text_antialiasing: settings.text_antialiasing,
+                diagnostic_min_severity: DiagnosticSeverity::from_lsp(Some(settings.diagnostic_min_severity)),
                 caret_width,
-                theme: Theme::new_default(&render_target)?,
+                theme: Theme::new(&render_target, theme_colors)?,
+                settings,
                 dwrite_factory,
                 text_format,
+                typography,
                 render_target,
                 buffer_layouts: HashMap::new()
             })
         }
     }
 
+    pub fn get_text_antialiasing(&self) -> TextAntialiasing {
+        self.text_antialiasing
+    }
+
+    pub fn set_text_antialiasing(&mut self, text_antialiasing: TextAntialiasing) {
+        self.text_antialiasing = text_antialiasing;
+        unsafe {
+            self.render_target.SetTextAntialiasMode(get_text_antialias_mode(text_antialiasing));
+        }
+    }
+
+    pub fn get_diagnostic_min_severity(&self) -> DiagnosticSeverity {
+        self.diagnostic_min_severity
+    }
+
+    pub fn set_diagnostic_min_severity(&mut self, diagnostic_min_severity: DiagnosticSeverity) {
+        self.diagnostic_min_severity = diagnostic_min_severity;
+    }
+
+    pub fn get_font_family(&self) -> &str {
+        &self.font_name
+    }
+
+    // A font family that isn't installed falls back to a system font
+    pub fn set_font_family(&mut self, font_family: &str) -> Result<()> {
+        self.font_name = font_family.to_owned();
+        self.update_text_format(0.0)
+    }
+
     pub fn update_text_format(&mut self, zoom_delta: f32) -> Result<()> {
         self.font_size = f32::max(1.0, self.font_size + zoom_delta);
         unsafe {
@@ -214,7 +330,7 @@ impl TextRenderer {
             self.text_format.SetTextAlignment(DWRITE_TEXT_ALIGNMENT::DWRITE_TEXT_ALIGNMENT_LEADING).ok()?;
             self.text_format.SetParagraphAlignment(DWRITE_PARAGRAPH_ALIGNMENT::DWRITE_PARAGRAPH_ALIGNMENT_NEAR).ok()?;
             self.text_format.SetWordWrapping(DWRITE_WORD_WRAPPING::DWRITE_WORD_WRAPPING_NO_WRAP).ok()?;
-            self.line_spacing = f32::ceil(self.font_size * settings::LINE_SPACING_FACTOR);
+            self.line_spacing = f32::ceil(self.font_size * self.settings.line_spacing_factor);
             self.text_format.SetLineSpacing(
                 DWRITE_LINE_SPACING_METHOD::DWRITE_LINE_SPACING_METHOD_UNIFORM, 
                 self.line_spacing, 
@@ -222,54 +338,183 @@ impl TextRenderer {
             ).ok()?;
     
             self.character_spacing = get_character_spacing(&self.dwrite_factory, &self.text_format)?;
-            self.text_format.SetIncrementalTabStop(self.character_spacing * settings::NUMBER_OF_SPACES_PER_TAB as f32).ok()?;
+            self.text_format.SetIncrementalTabStop(self.character_spacing * self.settings.tab_display_width.unwrap_or(self.settings.number_of_spaces_per_tab) as f32).ok()?;
         }
+        // The layouts were made with the old format
+        self.buffer_layouts.clear();
         Ok(())
     }
 
     pub fn get_max_rows(&self) -> usize {
-        (self.pixel_size.height as f32 / self.line_spacing).ceil() as usize
+        (self.get_document_height() / self.line_spacing).ceil() as usize
+    }
+
+    // The furthest the view scrolls down, which leaves at most the configured number of
+    // blank lines below the last line. Wrapped lines take up more rows than the view
+    // has lines, so wrapped text scrolls until the last line is at the top instead
+    pub fn get_max_line_offset(&self, text_document: &TextDocument) -> usize {
+        let last_line = text_document.buffer.get_number_of_lines() - 1;
+        if text_document.view.word_wrap {
+            return last_line;
+        }
+        let full_rows = (self.get_document_height() / self.line_spacing) as usize;
+        min((last_line + 1 + self.settings.scroll_beyond_last_line).saturating_sub(full_rows), last_line)
+    }
+
+    pub fn get_line_spacing(&self) -> f32 {
+        self.line_spacing
     }
 
     pub fn get_max_columns(&self) -> usize {
-        (self.pixel_size.width as f32 / self.character_spacing) as usize
+        ((self.get_document_width() - self.gutter_width - self.get_scrollbar_width()) / self.character_spacing) as usize
+    }
+
+    // The scrollbar along the right edge is one column wide
+    fn get_scrollbar_width(&self) -> f32 {
+        self.character_spacing
+    }
+
+    // The tab bar fits a single line of text
+    fn get_tab_bar_height(&self) -> f32 {
+        self.line_spacing
+    }
+
+    // The status bar fits a single line of text
+    fn get_status_bar_height(&self) -> f32 {
+        self.line_spacing
+    }
+
+    // The height of the text between the tab bar and the status bar
+    fn get_document_height(&self) -> f32 {
+        self.pixel_size.height as f32 - self.get_tab_bar_height() - self.get_status_bar_height()
+    }
+
+    // The width of the tabs, gutter and text to the right of the file tree
+    fn get_document_width(&self) -> f32 {
+        self.pixel_size.width as f32 - self.file_tree_width
+    }
+
+    // The top left corner of the document text, below the
+    // tab bar and to the right of the file tree and gutter
+    pub fn get_text_origin(&self) -> (f32, f32) {
+        (self.file_tree_width + self.gutter_width, self.get_tab_bar_height())
+    }
+
+    pub fn update_file_tree_width(&mut self, file_tree: &FileTree) {
+        self.file_tree_width = match file_tree.root {
+            Some(_) => self.settings.file_tree_width_columns as f32 * self.character_spacing,
+            None => 0.0
+        };
+    }
+
+    fn get_line_number_digits(text_document: &TextDocument) -> usize {
+        text_document.buffer.get_number_of_lines().to_string().len()
     }
 
     pub fn get_extents(&self) -> (f32, f32) {
         (self.pixel_size.width as f32, self.pixel_size.height as f32)
     }
 
+    // Scrolls the view just enough to show the caret if the buffer asked for it
+    pub fn reveal_caret(&self, text_document: &mut TextDocument) {
+        if text_document.buffer.view_dirty {
+            let (caret_line, _) = text_document.buffer.get_caret_line_and_column();
+            let caret_column = text_document.buffer.get_caret_display_column();
+            self.adjust_text_view(&mut text_document.view, caret_line, caret_column);
+            // Removing lines at the end can leave the view scrolled past the bottom
+            let max_line_offset = self.get_max_line_offset(text_document);
+            if text_document.view.line_offset > max_line_offset {
+                text_document.view.line_offset = max_line_offset;
+                text_document.view.pixel_offset = 0.0;
+            }
+            text_document.buffer.view_dirty = false;
+        }
+    }
+
     fn adjust_text_view(&self, text_view: &mut TextView, caret_line: usize, caret_column: usize) {
         let current_line_start = text_view.line_offset;
         let current_line_end = current_line_start + self.get_max_rows();
         let current_column_start = text_view.column_offset;
         let current_column_end = current_column_start + self.get_max_columns();
     
-        // Check for vertical adjustments
+        // Check for vertical adjustments, a caret on the partially scrolled out top line is shown whole
+        if caret_line == current_line_start {
+            text_view.pixel_offset = 0.0;
+        }
         if !(current_line_start..current_line_end).contains(&caret_line) {
+            text_view.pixel_offset = 0.0;
             if caret_line < current_line_start {
                 text_view.line_offset -= current_line_start - caret_line;
             }
             else {
-                text_view.line_offset += caret_line - current_line_end;
+                text_view.line_offset += caret_line - current_line_end + 1;
             }
         }
     
-        // Check for horizontal adjustments
-        if !(current_column_start..current_column_end).contains(&caret_column) {
+        // Check for horizontal adjustments, wrapped text never scrolls horizontally
+        if !text_view.word_wrap && !(current_column_start..current_column_end).contains(&caret_column) {
             if caret_column < current_column_start {
                 text_view.column_offset -= current_column_start - caret_column;
             }
             else {
-                text_view.column_offset += caret_column - current_column_end;
+                text_view.column_offset += caret_column - current_column_end + 1;
             }
         }    
     }
 
     pub fn update_buffer_layout(&mut self, text_document: &mut TextDocument) -> Result<()> {
+        // Leave room for the digits of the largest line number plus one column of padding
+        self.gutter_width = (Self::get_line_number_digits(text_document) + 1) as f32 * self.character_spacing;
+
+        // The view is adjusted before the layout is made, so the layout matches the view it is drawn in
+        let revealing_caret = text_document.buffer.view_dirty;
+        self.reveal_caret(text_document);
+
+        // Making a layout is the expensive part of drawing, so it is kept while neither
+        // the text nor the view changes. Only the drawing effects of the last frame are cleared
+        let key = self.get_buffer_layout_key(text_document);
+        let mut buffer_layout = match self.buffer_layouts.remove(&text_document.buffer.path) {
+            Some(buffer_layout) if buffer_layout.key == key => {
+                let range = DWRITE_TEXT_RANGE { startPosition: 0, length: buffer_layout.text_length };
+                unsafe { buffer_layout.text_layout.SetDrawingEffect(self.theme.text_brush.as_ref().unwrap(), range).ok()?; }
+                buffer_layout
+            }
+            _ => self.create_buffer_layout(text_document)?
+        };
+
+        // Wrapped lines take up several rows, so the caret line can still
+        // be below the view after revealing it by the number of lines
+        if revealing_caret && text_document.view.word_wrap {
+            let (caret_line, _) = text_document.buffer.get_caret_line_and_column();
+            loop {
+                let line_end = text_document.view.line_offset + self.get_visible_lines(&buffer_layout.text_layout)?;
+                if caret_line < line_end {
+                    break;
+                }
+                text_document.view.line_offset += caret_line - line_end + 1;
+                buffer_layout = self.create_buffer_layout(text_document)?;
+            }
+        }
+        self.buffer_layouts.insert(text_document.buffer.path.to_string(), buffer_layout);
+        Ok(())
+    }
+
+    fn get_buffer_layout_key(&self, text_document: &TextDocument) -> BufferLayoutKey {
+        BufferLayoutKey {
+            version: text_document.buffer.version,
+            line_offset: text_document.view.line_offset,
+            pixel_size: (self.pixel_size.width, self.pixel_size.height),
+            word_wrap: text_document.view.word_wrap,
+            wrap_width: self.get_document_width() - self.gutter_width - self.get_scrollbar_width(),
+            tab_width: text_document.buffer.tab_width
+        }
+    }
+
+    fn create_buffer_layout(&self, text_document: &TextDocument) -> Result<BufferLayout> {
+        // One extra line fills the bottom of the view when the top line is partially scrolled out
         let mut lines = text_document.buffer.get_text_view_as_utf16(
             text_document.view.line_offset, 
-            text_document.view.line_offset + self.get_max_rows()
+            text_document.view.line_offset + self.get_max_rows() + 1
         );
 
         unsafe {
@@ -282,33 +527,122 @@ impl TextRenderer {
                 self.pixel_size.height as f32,
                 &mut text_layout
             ).ok()?;
-            self.buffer_layouts.insert(text_document.buffer.path.to_string(), text_layout.unwrap());
+            let text_layout = text_layout.unwrap();
+
+            // Wrapped text has to fit inside the window, while unwrapped
+            // text is allowed to extend past it and scroll horizontally
+            let key = self.get_buffer_layout_key(text_document);
+            if text_document.view.word_wrap {
+                text_layout.SetWordWrapping(DWRITE_WORD_WRAPPING::DWRITE_WORD_WRAPPING_WRAP).ok()?;
+                text_layout.SetMaxWidth(key.wrap_width).ok()?;
+            }
+            else {
+                text_layout.SetWordWrapping(DWRITE_WORD_WRAPPING::DWRITE_WORD_WRAPPING_NO_WRAP).ok()?;
+            }
+            // Tab characters line up with the tab stops of the document
+            text_layout.SetIncrementalTabStop(self.character_spacing * text_document.buffer.tab_width as f32).ok()?;
+            text_layout.SetTypography(&self.typography, DWRITE_TEXT_RANGE { startPosition: 0, length: lines.len() as u32 }).ok()?;
+            Ok(BufferLayout {
+                text_layout,
+                key,
+                text_length: lines.len() as u32
+            })
         }
-        Ok(())
     }
 
-    pub fn mouse_pos_to_text_pos(&self, text_document: &mut TextDocument, mouse_pos: (f32, f32)) -> Result<TextPosition> {
-        let text_layout = self.buffer_layouts.get(&text_document.buffer.path).unwrap();
-        let column_offset = text_document.view.column_offset as f32 * self.character_spacing;
-        
+    // The number of rows each line of the view takes up in the layout,
+    // which is more than one for a wrapped line
+    fn get_rows_per_line(&self, text_layout: &IDWriteTextLayout) -> Result<Vec<usize>> {
+        let mut line_count = 0;
+        let mut line_metrics;
+        unsafe {
+            // Asking for no metrics fails, but reports how many there are
+            let _ = text_layout.GetLineMetrics(null_mut(), 0, &mut line_count);
+            line_metrics = vec![DWRITE_LINE_METRICS::default(); line_count as usize];
+            text_layout.GetLineMetrics(line_metrics.as_mut_ptr(), line_count, &mut line_count).ok()?;
+        }
+
+        let mut rows_per_line = Vec::new();
+        let mut rows = 0;
+        for metrics in &line_metrics {
+            rows += 1;
+            if metrics.newlineLength > 0 {
+                rows_per_line.push(rows);
+                rows = 0;
+            }
+        }
+        if rows > 0 {
+            rows_per_line.push(rows);
+        }
+        Ok(rows_per_line)
+    }
+
+    // The number of lines that fit entirely in the view, at least one
+    fn get_visible_lines(&self, text_layout: &IDWriteTextLayout) -> Result<usize> {
+        let max_rows = (self.get_document_height() / self.line_spacing) as usize;
+        let mut rows = 0;
+        let visible_lines = self.get_rows_per_line(text_layout)?.into_iter()
+            .take_while(|line_rows| {
+                rows += line_rows;
+                rows <= max_rows
+            })
+            .count();
+        Ok(visible_lines.max(1))
+    }
+
+    pub fn remove_buffer_layout(&mut self, path: &str) {
+        self.buffer_layouts.remove(path);
+    }
+
+    // The text position under the mouse along with whether the mouse
+    // is on the trailing half of the character and inside the text
+    fn hit_test_text(&self, text_layout: &IDWriteTextLayout, text_document: &TextDocument, mouse_pos: (f32, f32)) -> Result<(TextPosition, BOOL, BOOL)> {
+        let column_offset = text_document.view.column_offset as f32 * self.character_spacing - self.gutter_width;
+
+        let mut is_trailing = BOOL::from(false);
         let mut is_inside = BOOL::from(false);
         let mut metrics = DWRITE_HIT_TEST_METRICS::default();
         unsafe {
             text_layout.HitTestPoint(
-                mouse_pos.0 + column_offset,
-                mouse_pos.1,
-                text_document.buffer.get_caret_trailing_as_mut_ref(),
+                mouse_pos.0 - self.file_tree_width + column_offset,
+                mouse_pos.1 - self.get_tab_bar_height() + text_document.view.pixel_offset,
+                &mut is_trailing,
                 &mut is_inside,
                 &mut metrics
             ).ok()?;
         }
-        Ok(TextPosition {
+        let text_pos = TextPosition {
             line_offset: text_document.view.line_offset,
             char_offset: metrics.textPosition as usize
-        })
+        };
+        Ok((text_pos, is_trailing, is_inside))
     }
 
-    fn draw_selection_range(&self, column_offset: f32, text_layout: &IDWriteTextLayout, range: DWRITE_TEXT_RANGE) -> Result<()> {
+    pub fn mouse_pos_to_text_pos(&self, text_document: &mut TextDocument, mouse_pos: (f32, f32)) -> Result<TextPosition> {
+        let text_layout = &self.buffer_layouts.get(&text_document.buffer.path).unwrap().text_layout;
+        let (text_pos, is_trailing, _) = self.hit_test_text(text_layout, text_document, mouse_pos)?;
+        *text_document.buffer.get_caret_trailing_as_mut_ref() = is_trailing;
+        Ok(text_pos)
+    }
+
+    // Unlike mouse_pos_to_text_pos this leaves the caret alone and
+    // gives None unless the mouse is on the text of the document
+    pub fn get_hovered_text_pos(&self, text_document: &TextDocument, mouse_pos: (f32, f32)) -> Result<Option<TextPosition>> {
+        let text_origin = self.get_text_origin();
+        if mouse_pos.0 < text_origin.0 || mouse_pos.1 < text_origin.1 {
+            return Ok(None);
+        }
+        let text_layout = match self.buffer_layouts.get(&text_document.buffer.path) {
+            Some(buffer_layout) => &buffer_layout.text_layout,
+            None => return Ok(None)
+        };
+
+        let (text_pos, _, is_inside) = self.hit_test_text(text_layout, text_document, mouse_pos)?;
+        Ok(if is_inside.as_bool() { Some(text_pos) } else { None })
+    }
+
+    // The rectangles covering a range of the layout, one for every row it spans
+    fn hit_test_text_range(&self, column_offset: f32, text_layout: &IDWriteTextLayout, range: DWRITE_TEXT_RANGE) -> Result<Vec<DWRITE_HIT_TEST_METRICS>> {
         let mut hit_test_count = 0;
         unsafe {
             let error_code = text_layout.HitTestTextRange(
@@ -334,17 +668,71 @@ impl TextRenderer {
                 hit_tests.len() as u32,
                 &mut hit_test_count
             ).ok()?;
+            Ok(hit_tests)
+        }
+    }
 
-            hit_tests.iter().for_each(|metrics| {
-                let highlight_rect = D2D_RECT_F {
-                    left: metrics.left,
-                    top: metrics.top,
-                    right: metrics.left + metrics.width,
-                    bottom: metrics.top + metrics.height
-                };
+    fn draw_selection_range(&self, column_offset: f32, text_layout: &IDWriteTextLayout, range: DWRITE_TEXT_RANGE) -> Result<()> {
+        for metrics in self.hit_test_text_range(column_offset, text_layout, range)? {
+            let highlight_rect = D2D_RECT_F {
+                left: metrics.left,
+                top: metrics.top,
+                right: metrics.left + metrics.width,
+                bottom: metrics.top + metrics.height
+            };
+            unsafe { self.render_target.FillRectangle(&highlight_rect, self.theme.selection_brush.as_ref().unwrap()); }
+        }
+        Ok(())
+    }
 
-                self.render_target.FillRectangle(&highlight_rect, self.theme.selection_brush.as_ref().unwrap());
-            });
+    // Diagnostics are underlined with a zigzag at the bottom of the
+    // rows they cover, colored by severity with errors drawn last. The
+    // ones less severe than the minimum severity are left out
+    fn draw_diagnostics(&self, column_offset: f32, text_document: &TextDocument, text_layout: &IDWriteTextLayout) -> Result<()> {
+        let line_start = text_document.view.line_offset;
+        let line_end = line_start + self.get_max_rows();
+        let amplitude = f32::max(1.0, f32::floor(self.character_spacing / 4.0));
+
+        let severities = [
+            (DiagnosticSeverity::Information, &self.theme.diagnostic_information_brush),
+            (DiagnosticSeverity::Warning, &self.theme.diagnostic_warning_brush),
+            (DiagnosticSeverity::Error, &self.theme.diagnostic_error_brush)
+        ];
+        for (severity, brush) in severities.iter().filter(|(severity, _)| *severity >= self.diagnostic_min_severity) {
+            let brush = brush.as_ref().unwrap();
+            for diagnostic in text_document.diagnostics.iter().filter(|diagnostic| diagnostic.severity == *severity) {
+                let view_range = match text_document.buffer.get_view_range(&diagnostic.range, line_start, line_end) {
+                    Some(view_range) => view_range,
+                    None => continue
+                };
+                let hit_tests = if view_range.length == 0 {
+                    // An empty range at the end of the text, it is underlined a character wide after the last char
+                    let mut metrics = DWRITE_HIT_TEST_METRICS::default();
+                    let mut point = (0.0, 0.0);
+                    unsafe { text_layout.HitTestTextPosition(view_range.start, false, &mut point.0, &mut point.1, &mut metrics).ok()?; }
+                    metrics.left = point.0 - column_offset;
+                    metrics.width = 0.0;
+                    vec![metrics]
+                }
+                else {
+                    self.hit_test_text_range(column_offset, text_layout, DWRITE_TEXT_RANGE { startPosition: view_range.start, length: view_range.length })?
+                };
+                for metrics in hit_tests {
+                    let right = metrics.left + f32::max(metrics.width, self.character_spacing);
+                    let bottom = metrics.top + metrics.height - 1.0;
+                    let mut x = metrics.left;
+                    let mut up = true;
+                    while x < right {
+                        let next_x = f32::min(x + amplitude, right);
+                        let (from_y, to_y) = if up { (bottom, bottom - amplitude) } else { (bottom - amplitude, bottom) };
+                        unsafe {
+                            self.render_target.DrawLine(D2D_POINT_2F { x, y: from_y }, D2D_POINT_2F { x: next_x, y: to_y }, brush, 1.0, None);
+                        }
+                        x = next_x;
+                        up = !up;
+                    }
+                }
+            }
         }
         Ok(())
     }
@@ -413,24 +801,84 @@ impl TextRenderer {
         Ok(())
     }
 
+    // Spaces are drawn as dots and tabs as arrows across the tab
+    fn draw_whitespace(&self, column_offset: f32, text_layout: &IDWriteTextLayout, whitespace: &[(usize, char)]) -> Result<()> {
+        let brush = self.theme.comment_brush.as_ref().unwrap();
+        for (pos, chr) in whitespace {
+            let rect = self.get_rect_from_hit_test(*pos as u32, column_offset, text_layout)?;
+            let center = D2D_POINT_2F { x: (rect.left + rect.right) / 2.0, y: (rect.top + rect.bottom) / 2.0 };
+            let size = (self.character_spacing / 8.0).max(1.0);
+            unsafe {
+                if *chr == ' ' {
+                    let dot = D2D_RECT_F {
+                        left: center.x - size,
+                        top: center.y - size,
+                        right: center.x + size,
+                        bottom: center.y + size
+                    };
+                    self.render_target.FillRectangle(&dot, brush);
+                }
+                else {
+                    let tip = D2D_POINT_2F { x: rect.right - size, y: center.y };
+                    let head_length = 2.0 * size;
+                    self.render_target.DrawLine(D2D_POINT_2F { x: rect.left + size, y: center.y }, tip, brush, 1.0, None);
+                    self.render_target.DrawLine(D2D_POINT_2F { x: tip.x - head_length, y: center.y - head_length }, tip, brush, 1.0, None);
+                    self.render_target.DrawLine(D2D_POINT_2F { x: tip.x - head_length, y: center.y + head_length }, tip, brush, 1.0, None);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn get_token_brush(&self, token_type: SemanticTokenTypes) -> &ID2D1SolidColorBrush {
+        match token_type {
+            SemanticTokenTypes::Comment      => self.theme.comment_brush.as_ref().unwrap(),
+            SemanticTokenTypes::Keyword      => self.theme.keyword_brush.as_ref().unwrap(),
+            SemanticTokenTypes::Literal      => self.theme.literal_brush.as_ref().unwrap(),
+            SemanticTokenTypes::Preprocessor => self.theme.macro_preprocessor_brush.as_ref().unwrap(),
+            SemanticTokenTypes::Variable     => self.theme.variable_brush.as_ref().unwrap(),
+            SemanticTokenTypes::Function     => self.theme.function_brush.as_ref().unwrap(),
+            SemanticTokenTypes::Method       => self.theme.method_brush.as_ref().unwrap(),
+            SemanticTokenTypes::Class        => self.theme.class_brush.as_ref().unwrap(),
+            SemanticTokenTypes::Enum         => self.theme.enum_brush.as_ref().unwrap()
+        }
+    }
+
     fn draw_text(&self, column_offset: f32, text_document: &mut TextDocument, text_layout: &IDWriteTextLayout) -> Result<()> {
         unsafe {
-            let lexical_highlights = text_document.buffer.get_lexical_highlights(text_document.view.line_offset, text_document.view.line_offset + self.get_max_rows());
+            let line_start = text_document.view.line_offset;
+            let line_end = line_start + self.get_max_rows();
+            for semantic_token in &text_document.semantic_tokens {
+                if semantic_token.line >= line_end {
+                    break;
+                }
+                if let Some(offset) = text_document.buffer.get_view_offset(semantic_token.line, semantic_token.character, line_start) {
+                    let range = DWRITE_TEXT_RANGE { startPosition: offset as u32, length: semantic_token.length as u32 };
+                    text_layout.SetDrawingEffect(self.get_token_brush(semantic_token.token_type), range).ok()?;
+                }
+            }
+
+            let lexical_highlights = text_document.buffer.get_lexical_highlights(line_start, line_end);
             // In case of overlap, lexical highlights trump semantic for now.
             // This is to ensure that commenting out big sections of code happen
             // instantaneously
             for (range, token_type) in lexical_highlights.highlight_tokens {
-                match token_type {
-                    SemanticTokenTypes::Comment      => { text_layout.SetDrawingEffect(self.theme.comment_brush.as_ref().unwrap(), range).ok()?; },
-                    SemanticTokenTypes::Keyword      => { text_layout.SetDrawingEffect(self.theme.keyword_brush.as_ref().unwrap(), range).ok()?; },
-                    SemanticTokenTypes::Literal      => { text_layout.SetDrawingEffect(self.theme.literal_brush.as_ref().unwrap(), range).ok()?; },
-                    SemanticTokenTypes::Preprocessor => { text_layout.SetDrawingEffect(self.theme.macro_preprocessor_brush.as_ref().unwrap(), range).ok()?; },
-                }
+                text_layout.SetDrawingEffect(self.get_token_brush(token_type), range).ok()?;
             }
 
             if let Some(selection_range) = text_document.buffer.get_selection_range(text_document.view.line_offset, text_document.view.line_offset + self.get_max_rows()) {
                 self.draw_selection_range(column_offset, text_layout, DWRITE_TEXT_RANGE { startPosition: selection_range.start, length: selection_range.length })?;
             }
+            for selection_range in text_document.buffer.get_secondary_selection_ranges(text_document.view.line_offset, text_document.view.line_offset + self.get_max_rows()) {
+                self.draw_selection_range(column_offset, text_layout, DWRITE_TEXT_RANGE { startPosition: selection_range.start, length: selection_range.length })?;
+            }
+            for selection_range in text_document.buffer.get_block_selection_ranges(text_document.view.line_offset, text_document.view.line_offset + self.get_max_rows()) {
+                self.draw_selection_range(column_offset, text_layout, DWRITE_TEXT_RANGE { startPosition: selection_range.start, length: selection_range.length })?;
+            }
+            if self.settings.show_whitespace_in_selection {
+                let whitespace = text_document.buffer.get_selected_whitespace(text_document.view.line_offset, text_document.view.line_offset + self.get_max_rows());
+                self.draw_whitespace(column_offset, text_layout, &whitespace)?;
+            }
             if let Some(enclosing_bracket_ranges) = lexical_highlights.enclosing_brackets {
                 self.draw_enclosing_brackets(column_offset, &text_layout, enclosing_bracket_ranges)?;
             }
@@ -445,24 +893,473 @@ impl TextRenderer {
         Ok(())
     }
 
+    fn draw_line_numbers(&self, text_document: &TextDocument, rows_per_line: &[usize]) -> Result<()> {
+        let digits = Self::get_line_number_digits(text_document);
+        let first_line = text_document.view.line_offset;
+        let last_line = min(first_line + self.get_max_rows() + 1, text_document.buffer.get_number_of_lines());
+
+        // The rows a wrapped line continues on are left without a number
+        let (caret_line, _) = text_document.buffer.get_caret_line_and_column();
+        let mut caret_line_number_offset = None;
+        let mut line_numbers = String::new();
+        for line in first_line..last_line {
+            if line == caret_line {
+                caret_line_number_offset = Some(line_numbers.len());
+            }
+            let rows = rows_per_line.get(line - first_line).copied().unwrap_or(1);
+            line_numbers.push_str(format!("{:>1$}\r\n", line + 1, digits).as_str());
+            line_numbers.push_str("\r\n".repeat(rows - 1).as_str());
+        }
+        let mut line_numbers = text_utils::to_os_str(line_numbers.as_str());
+
+        unsafe {
+            let mut text_layout = None;
+            self.dwrite_factory.CreateTextLayout(
+                PWSTR(line_numbers.as_mut_ptr()),
+                line_numbers.len() as u32,
+                &self.text_format,
+                self.gutter_width,
+                self.pixel_size.height as f32,
+                &mut text_layout
+            ).ok()?;
+
+            // The line numbers are ASCII, so the byte offset is also the UTF-16 offset
+            if let (CurrentLineHighlight::Gutter, Some(offset)) = (self.settings.current_line_highlight, caret_line_number_offset) {
+                let range = DWRITE_TEXT_RANGE { startPosition: offset as u32, length: digits as u32 };
+                text_layout.as_ref().unwrap().SetDrawingEffect(self.theme.caret_brush.as_ref().unwrap(), range).ok()?;
+            }
+
+            self.render_target.DrawTextLayout(
+                D2D_POINT_2F { x: 0.0, y: 0.0 },
+                text_layout.as_ref().unwrap(),
+                self.theme.line_number_brush.as_ref().unwrap(),
+                D2D1_DRAW_TEXT_OPTIONS::D2D1_DRAW_TEXT_OPTIONS_NONE
+            );
+        }
+        Ok(())
+    }
+
+    // Marks lines changed since the last git commit 
+    // in the padding column at the right of the gutter
+    fn draw_git_diff(&self, text_document: &TextDocument, rows_per_line: &[usize]) {
+        let git_diff = match &text_document.git_diff {
+            Some(git_diff) => git_diff,
+            None => return
+        };
+
+        let first_line = text_document.view.line_offset;
+        let last_line = first_line + self.get_max_rows();
+        let left = self.gutter_width - self.character_spacing * 0.6;
+        let right = self.gutter_width - self.character_spacing * 0.3;
+
+        // Lines above the view are a single row, lines in the view take up as many rows as they are wrapped to
+        let line_to_y = |line: usize| {
+            if line < first_line {
+                return (line as f32 - first_line as f32) * self.line_spacing;
+            }
+            let rows: usize = (first_line..line).map(|line| rows_per_line.get(line - first_line).copied().unwrap_or(1)).sum();
+            rows as f32 * self.line_spacing
+        };
+
+        for hunk in &git_diff.hunks {
+            if hunk.line + hunk.length < first_line || hunk.line > last_line {
+                continue;
+            }
+            let top = line_to_y(hunk.line);
+            let (rect, brush) = match hunk.change {
+                LineChange::Added | LineChange::Modified => {
+                    let rect = D2D_RECT_F {
+                        left,
+                        top,
+                        right,
+                        bottom: line_to_y(hunk.line + hunk.length)
+                    };
+                    let brush = if hunk.change == LineChange::Added { &self.theme.diff_added_brush } else { &self.theme.diff_modified_brush };
+                    (rect, brush)
+                }
+                // Deleted lines are marked by a thin bar between the surrounding lines
+                LineChange::Deleted => {
+                    let rect = D2D_RECT_F {
+                        left: left - self.character_spacing * 0.3,
+                        top: top - 1.0,
+                        right,
+                        bottom: top + 1.0
+                    };
+                    (rect, &self.theme.diff_deleted_brush)
+                }
+            };
+            unsafe {
+                self.render_target.FillRectangle(&rect, brush.as_ref().unwrap());
+            }
+        }
+    }
+
+    fn draw_blame_hint(&self, column_offset: f32, text_document: &TextDocument, text_layout: &IDWriteTextLayout) -> Result<()> {
+        let (line, hint) = match &text_document.blame_hint {
+            Some(blame_hint) => blame_hint,
+            None => return Ok(())
+        };
+        let (caret_line, _) = text_document.buffer.get_caret_line_and_column();
+        if *line != caret_line {
+            return Ok(());
+        }
+        let line_end_offset = match text_document.buffer.get_line_end_offset(*line, text_document.view.line_offset) {
+            Some(line_end_offset) => line_end_offset,
+            None => return Ok(())
+        };
+
+        let mut hint_pos: (f32, f32) = (0.0, 0.0);
+        let mut metrics = DWRITE_HIT_TEST_METRICS::default();
+        unsafe {
+            text_layout.HitTestTextPosition(
+                line_end_offset as u32,
+                false,
+                &mut hint_pos.0,
+                &mut hint_pos.1,
+                &mut metrics
+            ).ok()?;
+
+            let mut hint_text = text_utils::to_os_str(hint);
+            let mut hint_layout = None;
+            self.dwrite_factory.CreateTextLayout(
+                PWSTR(hint_text.as_mut_ptr()),
+                hint_text.len() as u32,
+                &self.text_format,
+                self.pixel_size.width as f32,
+                self.line_spacing,
+                &mut hint_layout
+            ).ok()?;
+
+            // Leave a few columns of space between the line and the hint
+            self.render_target.DrawTextLayout(
+                D2D_POINT_2F { x: hint_pos.0 + self.character_spacing * 4.0 - column_offset, y: hint_pos.1 },
+                hint_layout.as_ref().unwrap(),
+                self.theme.comment_brush.as_ref().unwrap(),
+                D2D1_DRAW_TEXT_OPTIONS::D2D1_DRAW_TEXT_OPTIONS_NONE
+            );
+        }
+        Ok(())
+    }
+
+    // The top and bottom of the rows a line of the view takes up in the layout
+    fn get_line_extent(&self, text_document: &TextDocument, text_layout: &IDWriteTextLayout, line: usize) -> Result<Option<(f32, f32)>> {
+        let line_offset = text_document.view.line_offset;
+        let (line_start_offset, line_end_offset) = match (
+            text_document.buffer.get_view_offset(line, 0, line_offset),
+            text_document.buffer.get_line_end_offset(line, line_offset)
+        ) {
+            (Some(line_start_offset), Some(line_end_offset)) => (line_start_offset, line_end_offset),
+            _ => return Ok(None)
+        };
+
+        let mut top: (f32, f32) = (0.0, 0.0);
+        let mut bottom: (f32, f32) = (0.0, 0.0);
+        let mut metrics = DWRITE_HIT_TEST_METRICS::default();
+        unsafe {
+            text_layout.HitTestTextPosition(line_start_offset as u32, false, &mut top.0, &mut top.1, &mut metrics).ok()?;
+            text_layout.HitTestTextPosition(line_end_offset as u32, false, &mut bottom.0, &mut bottom.1, &mut metrics).ok()?;
+        }
+        Ok(Some((top.1, bottom.1 + metrics.height)))
+    }
+
+    // Tints the lines of both sides of every merge conflict in the view, the
+    // start marker goes with our side and the separator with their side
+    fn draw_merge_conflicts(&self, text_document: &TextDocument, text_layout: &IDWriteTextLayout) -> Result<()> {
+        let view_start = text_document.view.line_offset;
+        let view_end = min(view_start + self.get_max_rows() + 1, text_document.buffer.get_number_of_lines());
+        for conflict in &text_document.merge_conflicts {
+            let sides = [
+                (conflict.start..conflict.base.unwrap_or(conflict.separator), &self.theme.conflict_current_brush),
+                (conflict.separator..(conflict.end + 1), &self.theme.conflict_incoming_brush)
+            ];
+            for (lines, brush) in sides.iter() {
+                let first_line = max(lines.start, view_start);
+                let last_line = min(lines.end, view_end);
+                if first_line >= last_line {
+                    continue;
+                }
+                if let (Some((top, _)), Some((_, bottom))) = (
+                    self.get_line_extent(text_document, text_layout, first_line)?,
+                    self.get_line_extent(text_document, text_layout, last_line - 1)?
+                ) {
+                    let rect = D2D_RECT_F {
+                        left: self.gutter_width,
+                        top,
+                        right: self.get_document_width() - self.get_scrollbar_width(),
+                        bottom
+                    };
+                    unsafe { self.render_target.FillRectangle(&rect, brush.as_ref().unwrap()); }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Where the conflict actions start in the layout, a few columns after the start marker like the blame hint
+    fn get_merge_conflict_actions_pos(&self, text_document: &TextDocument, text_layout: &IDWriteTextLayout, conflict: &MergeConflict) -> Result<Option<(f32, f32, f32)>> {
+        let view_start = text_document.view.line_offset;
+        if conflict.start < view_start || conflict.start > view_start + self.get_max_rows() {
+            return Ok(None);
+        }
+        let line_end_offset = match text_document.buffer.get_line_end_offset(conflict.start, view_start) {
+            Some(line_end_offset) => line_end_offset,
+            None => return Ok(None)
+        };
+
+        let mut pos: (f32, f32) = (0.0, 0.0);
+        let mut metrics = DWRITE_HIT_TEST_METRICS::default();
+        unsafe {
+            text_layout.HitTestTextPosition(line_end_offset as u32, false, &mut pos.0, &mut pos.1, &mut metrics).ok()?;
+        }
+        Ok(Some((pos.0 + self.character_spacing * 4.0, pos.1, metrics.height)))
+    }
+
+    fn draw_merge_conflict_actions(&self, column_offset: f32, text_document: &TextDocument, text_layout: &IDWriteTextLayout) -> Result<()> {
+        let actions = MERGE_CONFLICT_ACTIONS.iter().map(|(label, _)| *label).collect::<Vec<&str>>().join(MERGE_CONFLICT_ACTION_SEPARATOR);
+        let mut actions_text = text_utils::to_os_str(&actions);
+        for conflict in &text_document.merge_conflicts {
+            let (x, y, _) = match self.get_merge_conflict_actions_pos(text_document, text_layout, conflict)? {
+                Some(pos) => pos,
+                None => continue
+            };
+
+            unsafe {
+                let mut actions_layout = None;
+                self.dwrite_factory.CreateTextLayout(
+                    PWSTR(actions_text.as_mut_ptr()),
+                    actions_text.len() as u32,
+                    &self.text_format,
+                    self.pixel_size.width as f32,
+                    self.line_spacing,
+                    &mut actions_layout
+                ).ok()?;
+
+                self.render_target.DrawTextLayout(
+                    D2D_POINT_2F { x: x - column_offset, y },
+                    actions_layout.as_ref().unwrap(),
+                    self.theme.comment_brush.as_ref().unwrap(),
+                    D2D1_DRAW_TEXT_OPTIONS::D2D1_DRAW_TEXT_OPTIONS_NONE
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // The conflict and the action under the mouse, the actions are measured in columns of the monospaced font
+    pub fn get_merge_conflict_action_at(&self, text_document: &TextDocument, mouse_pos: (f32, f32)) -> Result<Option<(MergeConflict, ConflictResolution)>> {
+        let text_layout = match self.buffer_layouts.get(&text_document.buffer.path) {
+            Some(buffer_layout) => &buffer_layout.text_layout,
+            None => return Ok(None)
+        };
+        let column_offset = text_document.view.column_offset as f32 * self.character_spacing - self.gutter_width;
+        let layout_x = mouse_pos.0 - self.file_tree_width + column_offset;
+        let layout_y = mouse_pos.1 - self.get_tab_bar_height() + text_document.view.pixel_offset;
+
+        for conflict in &text_document.merge_conflicts {
+            let (x, y, height) = match self.get_merge_conflict_actions_pos(text_document, text_layout, conflict)? {
+                Some(pos) => pos,
+                None => continue
+            };
+            if layout_y < y || layout_y >= y + height || layout_x < x {
+                continue;
+            }
+
+            let column = ((layout_x - x) / self.character_spacing) as usize;
+            let mut action_start = 0;
+            for (label, resolution) in MERGE_CONFLICT_ACTIONS.iter() {
+                let label_length = label.chars().count();
+                if (action_start..action_start + label_length).contains(&column) {
+                    return Ok(Some((conflict.clone(), *resolution)));
+                }
+                action_start += label_length + MERGE_CONFLICT_ACTION_SEPARATOR.len();
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_scrollbar_track_height(&self) -> f32 {
+        self.get_document_height()
+    }
+
+    // Maps a click on the scrollbar track to the line at that position in the
+    // document, relative to the whole file. Clicks above or below the track,
+    // like on the status bar, aren't on the scrollbar
+    pub fn get_scrollbar_line(&self, mouse_pos: (f32, f32), text_document: &TextDocument) -> Option<usize> {
+        let track_left = self.pixel_size.width as f32 - self.get_scrollbar_width();
+        let track_y = mouse_pos.1 - self.get_tab_bar_height();
+        let track_height = self.get_scrollbar_track_height();
+        if mouse_pos.0 < track_left || track_y < 0.0 || track_y >= track_height {
+            return None;
+        }
+
+        let number_of_lines = text_document.buffer.get_number_of_lines();
+        let line = (track_y / track_height * number_of_lines as f32) as usize;
+        Some(min(line, number_of_lines - 1))
+    }
+
+    // Draws the scrollbar track with the visible part of the document and markers
+    // for the changed lines, the search matches and the diagnostics, positioned
+    // relative to the whole file. Errors are drawn last so they stay on top
+    fn draw_scrollbar(&self, text_document: &TextDocument) {
+        let track_height = self.get_scrollbar_track_height();
+        let number_of_lines = text_document.buffer.get_number_of_lines();
+        let left = self.get_document_width() - self.get_scrollbar_width();
+        let right = self.get_document_width();
+        let line_to_y = |line: usize| line as f32 / number_of_lines as f32 * track_height;
+        // Markers are at least a couple of pixels high to stay visible in long files
+        let draw_marker = |first_line: usize, end_line: usize, brush: &Option<ID2D1SolidColorBrush>| {
+            let top = line_to_y(first_line);
+            let marker_rect = D2D_RECT_F {
+                left: left + self.get_scrollbar_width() * 0.25,
+                top,
+                right: right - self.get_scrollbar_width() * 0.25,
+                bottom: f32::max(line_to_y(end_line), top + 2.0)
+            };
+            unsafe { self.render_target.FillRectangle(&marker_rect, brush.as_ref().unwrap()); }
+        };
+
+        unsafe {
+            let track_rect = D2D_RECT_F {
+                left,
+                top: 0.0,
+                right,
+                bottom: track_height
+            };
+            self.render_target.FillRectangle(&track_rect, self.theme.status_bar_brush.as_ref().unwrap());
+
+            let thumb_top = line_to_y(text_document.view.line_offset);
+            let thumb_rect = D2D_RECT_F {
+                left,
+                top: thumb_top,
+                right,
+                bottom: f32::max(line_to_y(text_document.view.line_offset + self.get_max_rows()), thumb_top + 4.0)
+            };
+            self.render_target.FillRectangle(&thumb_rect, self.theme.selection_brush.as_ref().unwrap());
+        }
+
+        if let Some(git_diff) = &text_document.git_diff {
+            for hunk in &git_diff.hunks {
+                let brush = match hunk.change {
+                    LineChange::Added => &self.theme.diff_added_brush,
+                    LineChange::Modified => &self.theme.diff_modified_brush,
+                    LineChange::Deleted => &self.theme.diff_deleted_brush
+                };
+                draw_marker(hunk.line, hunk.line + hunk.length, brush);
+            }
+        }
+
+        // The matches are found when the text to replace changes, so lines
+        // past the end of a document that changed since then are left out
+        for line in text_document.search_match_lines.iter().filter(|line| **line < number_of_lines) {
+            draw_marker(*line, *line + 1, &self.theme.text_brush);
+        }
+
+        let severities = [
+            (DiagnosticSeverity::Information, &self.theme.diagnostic_information_brush),
+            (DiagnosticSeverity::Warning, &self.theme.diagnostic_warning_brush),
+            (DiagnosticSeverity::Error, &self.theme.diagnostic_error_brush)
+        ];
+        for (severity, brush) in severities.iter().filter(|(severity, _)| *severity >= self.diagnostic_min_severity) {
+            for diagnostic in text_document.diagnostics.iter().filter(|diagnostic| diagnostic.severity == *severity) {
+                let first_line = min(text_document.buffer.get_line_of_char(diagnostic.range.start), number_of_lines - 1);
+                let last_line = min(text_document.buffer.get_line_of_char(diagnostic.range.end), number_of_lines - 1);
+                draw_marker(first_line, last_line + 1, *brush);
+            }
+        }
+    }
+
+    // A band behind the row of the caret across the whole width of the text,
+    // left out while text is selected so it doesn't compete with the selection
+    fn draw_current_line_highlight(&self, text_document: &mut TextDocument, text_layout: &IDWriteTextLayout) -> Result<()> {
+        let line_start = text_document.view.line_offset;
+        let line_end = text_document.view.line_offset + self.get_max_rows();
+        if text_document.buffer.get_selection_range(line_start, line_end).is_some() {
+            return Ok(());
+        }
+        let caret_offset = match text_document.buffer.get_caret_offset(line_start, line_end) {
+            Some(caret_offset) => caret_offset,
+            None => return Ok(())
+        };
+
+        let mut caret_pos: (f32, f32) = (0.0, 0.0);
+        let mut metrics = DWRITE_HIT_TEST_METRICS::default();
+        unsafe {
+            text_layout.HitTestTextPosition(
+                caret_offset as u32,
+                text_document.buffer.get_caret_trailing(),
+                &mut caret_pos.0,
+                &mut caret_pos.1,
+                &mut metrics
+            ).ok()?;
+
+            let rect = D2D_RECT_F {
+                left: self.gutter_width,
+                top: caret_pos.1,
+                right: self.get_document_width() - self.get_scrollbar_width(),
+                bottom: caret_pos.1 + metrics.height
+            };
+            self.render_target.FillRectangle(&rect, self.theme.current_line_brush.as_ref().unwrap());
+        }
+        Ok(())
+    }
+
+    fn draw_caret_column_guide(&self, column_offset: f32, text_document: &TextDocument) {
+        let caret_column = text_document.buffer.get_caret_display_column();
+        let x = caret_column as f32 * self.character_spacing - column_offset;
+
+        let rect = D2D_RECT_F {
+            left: x,
+            top: text_document.view.pixel_offset,
+            right: x + 1.0,
+            bottom: self.get_document_height() + text_document.view.pixel_offset
+        };
+        unsafe {
+            self.render_target.FillRectangle(&rect, self.theme.caret_column_brush.as_ref().unwrap());
+        }
+    }
+
     fn draw_caret(&self, column_offset: f32, text_document: &mut TextDocument, text_layout: &IDWriteTextLayout) -> Result<()> {
-        if let Some(caret_offset) = text_document.buffer.get_caret_offset(text_document.view.line_offset, text_document.view.line_offset + self.get_max_rows()) {
+        let line_start = text_document.view.line_offset;
+        let line_end = text_document.view.line_offset + self.get_max_rows();
+
+        let mut carets: Vec<(usize, BOOL)> = text_document.buffer.get_secondary_caret_offsets(line_start, line_end)
+            .into_iter()
+            .map(|caret_offset| (caret_offset, BOOL::from(false)))
+            .collect();
+        if let Some(caret_offset) = text_document.buffer.get_caret_offset(line_start, line_end) {
+            carets.push((caret_offset, text_document.buffer.get_caret_trailing()));
+        }
+
+        for (caret_offset, caret_trailing) in carets {
             let mut caret_pos: (f32, f32) = (0.0, 0.0);
             let mut metrics = DWRITE_HIT_TEST_METRICS::default();
             unsafe {
                 text_layout.HitTestTextPosition(
                     caret_offset as u32,
-                    text_document.buffer.get_caret_trailing(),
+                    caret_trailing,
                     &mut caret_pos.0,
                     &mut caret_pos.1,
                     &mut metrics
                 ).ok()?;
 
-                let rect = D2D_RECT_F {
-                    left: caret_pos.0 - (self.caret_width as f32 / 2.0) - column_offset,
-                    top: caret_pos.1,
-                    right: caret_pos.0 + (self.caret_width as f32 / 2.0) - column_offset,
-                    bottom: caret_pos.1 + metrics.height
+                // In overwrite mode the caret covers the character it replaces,
+                // the line break at the end of a line counts as one column
+                let rect = if text_document.buffer.overwrite && self.settings.block_caret_in_overwrite_mode {
+                    let width = if caret_trailing.as_bool() || metrics.width == 0.0 { self.character_spacing } else { metrics.width };
+                    D2D_RECT_F {
+                        left: caret_pos.0 - column_offset,
+                        top: caret_pos.1,
+                        right: caret_pos.0 + width - column_offset,
+                        bottom: caret_pos.1 + metrics.height
+                    }
+                }
+                else {
+                    D2D_RECT_F {
+                        left: caret_pos.0 - (self.caret_width as f32 / 2.0) - column_offset,
+                        top: caret_pos.1,
+                        right: caret_pos.0 + (self.caret_width as f32 / 2.0) - column_offset,
+                        bottom: caret_pos.1 + metrics.height
+                    }
                 };
 
                 self.render_target.FillRectangle(&rect, self.theme.caret_brush.as_ref().unwrap());
@@ -471,38 +1368,403 @@ impl TextRenderer {
         Ok(())
     }
 
-    pub fn draw(&self, text_document: &mut TextDocument) -> Result<()> {
+    // The completion list is drawn below the caret, with the selected item highlighted
+    fn draw_completion_list(&self, column_offset: f32, text_document: &mut TextDocument, text_layout: &IDWriteTextLayout) -> Result<()> {
+        let completion = match &text_document.completion {
+            Some(completion) => completion,
+            None => return Ok(())
+        };
+        let line_start = text_document.view.line_offset;
+        let line_end = text_document.view.line_offset + self.get_max_rows();
+        let caret_offset = match text_document.buffer.get_caret_offset(line_start, line_end) {
+            Some(caret_offset) => caret_offset,
+            None => return Ok(())
+        };
+
+        let mut caret_pos: (f32, f32) = (0.0, 0.0);
+        let mut metrics = DWRITE_HIT_TEST_METRICS::default();
+        unsafe {
+            text_layout.HitTestTextPosition(
+                caret_offset as u32,
+                false,
+                &mut caret_pos.0,
+                &mut caret_pos.1,
+                &mut metrics
+            ).ok()?;
+
+            let longest_item = completion.items.iter().map(|item| item.chars().count()).max().unwrap_or(0);
+            let left = caret_pos.0 - column_offset;
+            let top = caret_pos.1 + self.line_spacing;
+            let list_rect = D2D_RECT_F {
+                left,
+                top,
+                right: left + (longest_item + 2) as f32 * self.character_spacing,
+                bottom: top + completion.items.len() as f32 * self.line_spacing
+            };
+            self.render_target.FillRectangle(&list_rect, self.theme.status_bar_brush.as_ref().unwrap());
+
+            let selected_top = top + completion.selected as f32 * self.line_spacing;
+            let selected_rect = D2D_RECT_F {
+                left: list_rect.left,
+                top: selected_top,
+                right: list_rect.right,
+                bottom: selected_top + self.line_spacing
+            };
+            self.render_target.FillRectangle(&selected_rect, self.theme.selection_brush.as_ref().unwrap());
+
+            let mut items = text_utils::to_os_str(completion.items.join("\r\n").as_str());
+            let mut items_layout = None;
+            self.dwrite_factory.CreateTextLayout(
+                PWSTR(items.as_mut_ptr()),
+                items.len() as u32,
+                &self.text_format,
+                list_rect.right - list_rect.left,
+                list_rect.bottom - list_rect.top,
+                &mut items_layout
+            ).ok()?;
+
+            self.render_target.DrawTextLayout(
+                D2D_POINT_2F { x: left + self.character_spacing, y: top },
+                items_layout.as_ref().unwrap(),
+                self.theme.text_brush.as_ref().unwrap(),
+                D2D1_DRAW_TEXT_OPTIONS::D2D1_DRAW_TEXT_OPTIONS_NONE
+            );
+        }
+        Ok(())
+    }
+
+    // The tooltip is drawn below the mouse, or above it when there is no room below
+    fn draw_hover_tooltip(&self, text_document: &TextDocument) -> Result<()> {
+        let tooltip = match &text_document.hover_tooltip {
+            Some(tooltip) => tooltip,
+            None => return Ok(())
+        };
+        let extents = self.get_extents();
+        let max_width = f32::min(
+            self.settings.max_hover_tooltip_columns as f32 * self.character_spacing,
+            extents.0 - 2.0 * self.character_spacing
+        );
+
+        let mut text = text_utils::to_os_str(&tooltip.text);
+        let mut text_layout = None;
+        unsafe {
+            self.dwrite_factory.CreateTextLayout(
+                PWSTR(text.as_mut_ptr()),
+                text.len() as u32,
+                &self.text_format,
+                f32::max(max_width, self.character_spacing),
+                extents.1,
+                &mut text_layout
+            ).ok()?;
+            let text_layout = text_layout.unwrap();
+            text_layout.SetWordWrapping(DWRITE_WORD_WRAPPING::DWRITE_WORD_WRAPPING_WRAP).ok()?;
+
+            let mut metrics = DWRITE_TEXT_METRICS::default();
+            text_layout.GetMetrics(&mut metrics).ok()?;
+            let width = metrics.width + 2.0 * self.character_spacing;
+            let height = metrics.height;
+
+            let left = f32::max(0.0, f32::min(tooltip.mouse_pos.0, extents.0 - width));
+            let below = tooltip.mouse_pos.1 + self.line_spacing;
+            let top = if below + height > extents.1 { f32::max(0.0, tooltip.mouse_pos.1 - height) } else { below };
+            let tooltip_rect = D2D_RECT_F {
+                left,
+                top,
+                right: left + width,
+                bottom: top + height
+            };
+            self.render_target.FillRectangle(&tooltip_rect, self.theme.status_bar_brush.as_ref().unwrap());
+
+            self.render_target.DrawTextLayout(
+                D2D_POINT_2F { x: left + self.character_spacing, y: top },
+                &text_layout,
+                self.theme.text_brush.as_ref().unwrap(),
+                D2D1_DRAW_TEXT_OPTIONS::D2D1_DRAW_TEXT_OPTIONS_NONE
+            );
+        }
+        Ok(())
+    }
+
+    fn get_tab_width(&self, tab_name: &str) -> f32 {
+        (tab_name.chars().count() + 2) as f32 * self.character_spacing
+    }
+
+    pub fn get_tab_at(&self, mouse_pos: (f32, f32), tab_names: &[String]) -> Option<usize> {
+        if mouse_pos.1 < 0.0 || mouse_pos.1 >= self.get_tab_bar_height() {
+            return None;
+        }
+
+        let mut tab_left = self.file_tree_width;
+        for (index, tab_name) in tab_names.iter().enumerate() {
+            let tab_right = tab_left + self.get_tab_width(tab_name);
+            if (tab_left..tab_right).contains(&mouse_pos.0) {
+                return Some(index);
+            }
+            tab_left = tab_right;
+        }
+        None
+    }
+
+    pub fn draw_tabs(&self, tab_names: &[String], current_tab: Option<usize>) -> Result<()> {
+        unsafe {
+            let tab_bar_rect = D2D_RECT_F {
+                left: self.file_tree_width,
+                top: 0.0,
+                right: self.pixel_size.width as f32,
+                bottom: self.get_tab_bar_height()
+            };
+            self.render_target.FillRectangle(&tab_bar_rect, self.theme.status_bar_brush.as_ref().unwrap());
+
+            let mut tab_left = self.file_tree_width;
+            for (index, tab_name) in tab_names.iter().enumerate() {
+                let tab_width = self.get_tab_width(tab_name);
+                if current_tab == Some(index) {
+                    let tab_rect = D2D_RECT_F {
+                        left: tab_left,
+                        top: 0.0,
+                        right: tab_left + tab_width,
+                        bottom: self.get_tab_bar_height()
+                    };
+                    self.render_target.FillRectangle(&tab_rect, self.theme.selection_brush.as_ref().unwrap());
+                }
+
+                let mut tab_text = text_utils::to_os_str(tab_name);
+                let mut text_layout = None;
+                self.dwrite_factory.CreateTextLayout(
+                    PWSTR(tab_text.as_mut_ptr()),
+                    tab_text.len() as u32,
+                    &self.text_format,
+                    tab_width,
+                    self.get_tab_bar_height(),
+                    &mut text_layout
+                ).ok()?;
+
+                self.render_target.DrawTextLayout(
+                    D2D_POINT_2F { x: tab_left + self.character_spacing, y: 0.0 },
+                    text_layout.as_ref().unwrap(),
+                    self.theme.text_brush.as_ref().unwrap(),
+                    D2D1_DRAW_TEXT_OPTIONS::D2D1_DRAW_TEXT_OPTIONS_NONE
+                );
+                tab_left += tab_width;
+            }
+        }
+        Ok(())
+    }
+
+    // The selection as CF_HTML, colored by the lexical highlights in the colors of the theme
+    pub fn get_selection_as_html(&self, text_document: &mut TextDocument) -> Option<String> {
+        let (first_line, last_line) = text_document.buffer.get_selected_lines()?;
+        let selection = text_document.buffer.get_selection_range(first_line, last_line)?;
+        let selection_start = selection.start as usize;
+        let selection_end = (selection.start + selection.length) as usize;
+
+        let text = text_document.buffer.get_text_view_as_utf16(first_line, last_line + 1);
+        let lexical_highlights = text_document.buffer.get_lexical_highlights(first_line, last_line + 1);
+        let mut spans: Vec<ColoredSpan> = lexical_highlights.highlight_tokens.into_iter()
+            .filter_map(|(range, token_type)| {
+                let start = (range.startPosition as usize).clamp(selection_start, selection_end);
+                let end = ((range.startPosition + range.length) as usize).clamp(selection_start, selection_end);
+                if start == end {
+                    return None;
+                }
+                let color = unsafe { color_to_rgb(self.get_token_brush(token_type).GetColor()) };
+                Some(ColoredSpan { range: (start - selection_start)..(end - selection_start), color })
+            })
+            .collect();
+
+        let text_color = unsafe { color_to_rgb(self.theme.text_brush.as_ref().unwrap().GetColor()) };
+        let html = html_export::to_html(
+            &text[selection_start..selection_end],
+            &mut spans,
+            text_color,
+            color_to_rgb(self.theme.background_color),
+            &self.font_name
+        );
+        Some(html_export::to_cf_html(&html))
+    }
+
+    // Shows the path of the document, whether it has unsaved changes,
+    // the line and column of the caret, the language of the document and a warning
+    // if its line endings are mixed. A prompt waiting for input is shown instead while there is one
+    pub fn draw_status_bar(&self, text_document: &TextDocument, prompt: Option<&str>) -> Result<()> {
+        let (caret_line, caret_column) = text_document.buffer.get_caret_line_and_column();
+        let language = match text_document.buffer.language_identifier {
+            PLAIN_TEXT_LANGUAGE_IDENTIFIER => "plain text",
+            language_identifier => language_identifier
+        };
+        let status = match prompt {
+            Some(prompt) => prompt.to_owned(),
+            None => format!(
+                "{}{}    Ln {}, Col {}    {}{}{}",
+                text_document.buffer.path,
+                if text_document.buffer.is_dirty() { " *" } else { "" },
+                caret_line + 1,
+                caret_column + 1,
+                language,
+                if text_document.buffer.overwrite { "    OVR" } else { "" },
+                if text_document.buffer.mixed_line_endings { "    Mixed line endings, ALT+E normalizes them" } else { "" }
+            )
+        };
+
+        unsafe {
+            let top = self.pixel_size.height as f32 - self.get_status_bar_height();
+            let status_bar_rect = D2D_RECT_F {
+                left: self.file_tree_width,
+                top,
+                right: self.pixel_size.width as f32,
+                bottom: self.pixel_size.height as f32
+            };
+            self.render_target.FillRectangle(&status_bar_rect, self.theme.status_bar_brush.as_ref().unwrap());
+
+            let mut status_text = text_utils::to_os_str(&status);
+            let mut text_layout = None;
+            self.dwrite_factory.CreateTextLayout(
+                PWSTR(status_text.as_mut_ptr()),
+                status_text.len() as u32,
+                &self.text_format,
+                self.get_document_width(),
+                self.get_status_bar_height(),
+                &mut text_layout
+            ).ok()?;
+
+            self.render_target.DrawTextLayout(
+                D2D_POINT_2F { x: self.file_tree_width + self.character_spacing, y: top },
+                text_layout.as_ref().unwrap(),
+                self.theme.text_brush.as_ref().unwrap(),
+                D2D1_DRAW_TEXT_OPTIONS::D2D1_DRAW_TEXT_OPTIONS_NONE
+            );
+        }
+        Ok(())
+    }
+
+    pub fn is_in_file_tree(&self, mouse_pos: (f32, f32)) -> bool {
+        mouse_pos.0 >= 0.0 && mouse_pos.0 < self.file_tree_width
+    }
+
+    pub fn get_file_tree_line(&self, mouse_pos: (f32, f32), file_tree: &FileTree) -> Option<usize> {
+        if !self.is_in_file_tree(mouse_pos) || mouse_pos.1 < 0.0 {
+            return None;
+        }
+        let line_number = (mouse_pos.1 / self.line_spacing) as usize;
+        if line_number < file_tree.entries.len() {
+            Some(line_number)
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn draw_file_tree(&self, file_tree: &FileTree) -> Result<()> {
+        if file_tree.root.is_none() {
+            return Ok(());
+        }
+
+        unsafe {
+            let panel_rect = D2D_RECT_F {
+                left: 0.0,
+                top: 0.0,
+                right: self.file_tree_width,
+                bottom: self.pixel_size.height as f32
+            };
+            self.render_target.FillRectangle(&panel_rect, self.theme.status_bar_brush.as_ref().unwrap());
+            self.render_target.PushAxisAlignedClip(&panel_rect, D2D1_ANTIALIAS_MODE::D2D1_ANTIALIAS_MODE_ALIASED);
+
+            if let Some(line_number) = file_tree.hovered_line_number {
+                let hover_rect = D2D_RECT_F {
+                    left: 0.0,
+                    top: line_number as f32 * self.line_spacing,
+                    right: self.file_tree_width,
+                    bottom: (line_number + 1) as f32 * self.line_spacing
+                };
+                self.render_target.FillRectangle(&hover_rect, self.theme.selection_brush.as_ref().unwrap());
+            }
+
+            let mut entries = text_utils::to_os_str(file_tree.get_text().as_str());
+            let mut text_layout = None;
+            self.dwrite_factory.CreateTextLayout(
+                PWSTR(entries.as_mut_ptr()),
+                entries.len() as u32,
+                &self.text_format,
+                self.file_tree_width,
+                self.pixel_size.height as f32,
+                &mut text_layout
+            ).ok()?;
+
+            self.render_target.DrawTextLayout(
+                D2D_POINT_2F { x: 0.0, y: 0.0 },
+                text_layout.as_ref().unwrap(),
+                self.theme.text_brush.as_ref().unwrap(),
+                D2D1_DRAW_TEXT_OPTIONS::D2D1_DRAW_TEXT_OPTIONS_NONE
+            );
+            self.render_target.PopAxisAlignedClip();
+        }
+        Ok(())
+    }
+
+    pub fn begin_draw(&self) {
         unsafe {
             self.render_target.BeginDraw();
 
             self.render_target.SetTransform(&Matrix3x2::identity());
             self.render_target.Clear(&self.theme.background_color);
+        }
+    }
 
-            let text_layout = self.buffer_layouts.get(&text_document.buffer.path).unwrap();
+    pub fn end_draw(&self) -> Result<()> {
+        unsafe {
+            self.render_target.EndDraw(null_mut(), null_mut()).ok()
+        }
+    }
 
-            if text_document.buffer.view_dirty {
-                let (caret_line, caret_column) = text_document.buffer.get_caret_line_and_column();
-                self.adjust_text_view(&mut text_document.view, caret_line, caret_column);
-                text_document.buffer.view_dirty = false;
-            }
+    pub fn draw(&self, text_document: &mut TextDocument) -> Result<()> {
+        unsafe {
+            // The document is drawn below the tab bar and to the right of the file tree,
+            // the text and gutter are shifted up by the part of the top line scrolled out of view
+            let pixel_offset = text_document.view.pixel_offset;
+            self.render_target.SetTransform(&Matrix3x2::translation(self.file_tree_width, self.get_tab_bar_height() - pixel_offset));
+
+            let text_layout = &self.buffer_layouts.get(&text_document.buffer.path).unwrap().text_layout;
+
+            // The text is offset to the right by the gutter
+            let column_offset = (text_document.view.column_offset as f32) * self.character_spacing - self.gutter_width;
 
-            let column_offset = (text_document.view.column_offset as f32) * self.character_spacing;
+            let rows_per_line = self.get_rows_per_line(&text_layout)?;
+            self.draw_line_numbers(text_document, &rows_per_line)?;
+            self.draw_git_diff(text_document, &rows_per_line);
 
-            // TODO
-            // let clip_rect = D2D_RECT_F {
-            //     left: 0.0,
-            //     top: 0.0,
-            //     right: 0.0,
-            //     bottom: 0.0
-            // };
-            // self.render_target.PushAxisAlignedClip(&clip_rect, D2D1_ANTIALIAS_MODE::D2D1_ANTIALIAS_MODE_ALIASED);
+            // Clip the text to avoid drawing into the gutter when scrolled horizontally
+            let clip_rect = D2D_RECT_F {
+                left: self.gutter_width,
+                top: pixel_offset,
+                right: self.get_document_width() - self.get_scrollbar_width(),
+                bottom: self.get_document_height() + pixel_offset
+            };
+            self.render_target.PushAxisAlignedClip(&clip_rect, D2D1_ANTIALIAS_MODE::D2D1_ANTIALIAS_MODE_ALIASED);
+
+            if self.settings.current_line_highlight == CurrentLineHighlight::FullWidth {
+                self.draw_current_line_highlight(text_document, &text_layout)?;
+            }
+            self.draw_merge_conflicts(text_document, &text_layout)?;
+            if self.settings.show_caret_column_guide && !text_document.view.word_wrap {
+                self.draw_caret_column_guide(column_offset, text_document);
+            }
 
             // Adjust origin to account for column offset
             self.draw_text(column_offset, text_document, &text_layout)?;
+            self.draw_diagnostics(column_offset, text_document, &text_layout)?;
+            self.draw_blame_hint(column_offset, text_document, &text_layout)?;
+            self.draw_merge_conflict_actions(column_offset, text_document, &text_layout)?;
             self.draw_caret(column_offset, text_document, &text_layout)?;
-            // self.render_target.PopAxisAlignedClip();
+            self.render_target.PopAxisAlignedClip();
 
-            self.render_target.EndDraw(null_mut(), null_mut()).ok()?;
+            self.draw_completion_list(column_offset, text_document, &text_layout)?;
+
+            // The scrollbar doesn't move with the text
+            self.render_target.SetTransform(&Matrix3x2::translation(self.file_tree_width, self.get_tab_bar_height()));
+            self.draw_scrollbar(text_document);
+
+            self.render_target.SetTransform(&Matrix3x2::identity());
+            self.draw_hover_tooltip(text_document)?;
         }
         Ok(())
     }
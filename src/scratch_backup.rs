@@ -0,0 +1,193 @@
+use crate::language_support::{CPP_LANGUAGE_IDENTIFIER, RUST_LANGUAGE_IDENTIFIER, PYTHON_LANGUAGE_IDENTIFIER, PLAIN_TEXT_LANGUAGE_IDENTIFIER};
+
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io,
+    os::windows::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH}
+};
+
+// Plain text has no language identifier to use as the extension
+const PLAIN_TEXT_EXTENSION: &str = "txt";
+
+// Held open by the editor that owns the backup directory it is in
+const LOCK_FILE_NAME: &str = "lock";
+
+static BACKUP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Scratch documents have no file of their own, so their
+// text is backed up here to survive the editor closing
+fn get_backup_root() -> PathBuf {
+    env::temp_dir().join("nimble_scratch")
+}
+
+fn language_to_extension(language_identifier: &str) -> &str {
+    match language_identifier {
+        PLAIN_TEXT_LANGUAGE_IDENTIFIER => PLAIN_TEXT_EXTENSION,
+        language_identifier => language_identifier
+    }
+}
+
+fn extension_to_language(extension: &str) -> &'static str {
+    [CPP_LANGUAGE_IDENTIFIER, RUST_LANGUAGE_IDENTIFIER, PYTHON_LANGUAGE_IDENTIFIER].iter()
+        .find(|language_identifier| **language_identifier == extension)
+        .copied()
+        .unwrap_or(PLAIN_TEXT_LANGUAGE_IDENTIFIER)
+}
+
+// Opens the lock file without sharing it, which fails while another editor has it open
+fn lock_directory(directory: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create(true).truncate(false).share_mode(0).open(directory.join(LOCK_FILE_NAME))
+}
+
+fn get_timestamp() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_millis())
+}
+
+// Every running editor backs up its scratch documents to a directory of its own and keeps
+// the lock file in it open until it exits. The backups left in a directory whose lock can
+// be opened belong to an editor that has exited, so they are moved over and restored
+pub struct ScratchBackups {
+    directory: PathBuf,
+    _lock: File
+}
+
+impl ScratchBackups {
+    pub fn lock() -> io::Result<Self> {
+        Self::lock_in(&get_backup_root())
+    }
+
+    fn lock_in(root: &Path) -> io::Result<Self> {
+        let directory = root.join(format!("{}-{}-{}", get_timestamp(), process::id(), BACKUP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&directory)?;
+        let lock = lock_directory(&directory)?;
+        Ok(Self {
+            directory,
+            _lock: lock
+        })
+    }
+
+    // A path for a new backup, the id is unique across the
+    // running editors and the extension is the language
+    pub fn new_backup_path(&self, language_identifier: &str) -> PathBuf {
+        let id = format!("{}-{}-{}", get_timestamp(), process::id(), BACKUP_COUNTER.fetch_add(1, Ordering::Relaxed));
+        self.directory.join(id).with_extension(language_to_extension(language_identifier))
+    }
+
+    // Moves the backups of the editors that have exited into the directory of this one and returns
+    // the path, text and language of every backup in it, oldest first. The backups keep their ids,
+    // and the lock of the directory they are in is held while they are moved, so two editors
+    // starting at the same time can't both claim them
+    pub fn claim_backups(&self) -> Vec<(PathBuf, String, &'static str)> {
+        if let Some(root) = self.directory.parent() {
+            for directory in fs::read_dir(root).into_iter().flatten().flatten().map(|directory_entry| directory_entry.path()) {
+                if directory == self.directory || !directory.is_dir() {
+                    continue;
+                }
+                let lock = match lock_directory(&directory) {
+                    Ok(lock) => lock,
+                    Err(_) => continue
+                };
+                for path in fs::read_dir(&directory).into_iter().flatten().flatten().map(|directory_entry| directory_entry.path()) {
+                    if let Some(file_name) = path.file_name().filter(|file_name| *file_name != LOCK_FILE_NAME) {
+                        let _ = fs::rename(&path, self.directory.join(file_name));
+                    }
+                }
+                drop(lock);
+                let _ = fs::remove_dir_all(&directory);
+            }
+        }
+
+        let mut backups: Vec<(PathBuf, String, &'static str)> = fs::read_dir(&self.directory).into_iter().flatten().flatten()
+            .map(|directory_entry| directory_entry.path())
+            .filter(|path| !path.ends_with(LOCK_FILE_NAME))
+            .filter_map(|path| {
+                let text = fs::read_to_string(&path).ok()?;
+                let language_identifier = extension_to_language(path.extension()?.to_str()?);
+                Some((path, text, language_identifier))
+            })
+            .collect();
+        // The ids are the time the backup was made, the process and the count of backups before it
+        backups.sort_by_key(|(path, _, _)| {
+            path.file_stem().and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.split('-').map(|part| part.parse::<u128>().ok()).collect::<Option<Vec<u128>>>())
+        });
+        backups
+    }
+}
+
+pub fn write_backup(path: &Path, text: &str) -> io::Result<()> {
+    fs::write(path, text)
+}
+
+pub fn delete_backup(path: &Path) {
+    let _ = fs::remove_file(path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A backup root of its own for every test, so they can run at the same time
+    fn test_root(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("nimble_scratch_test_{}_{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        root
+    }
+
+    #[test]
+    fn languages_survive_the_round_trip_through_file_names() {
+        let root = test_root("languages");
+        let languages = [CPP_LANGUAGE_IDENTIFIER, RUST_LANGUAGE_IDENTIFIER, PYTHON_LANGUAGE_IDENTIFIER, PLAIN_TEXT_LANGUAGE_IDENTIFIER];
+        {
+            let backups = ScratchBackups::lock_in(&root).unwrap();
+            for language_identifier in languages.iter() {
+                write_backup(&backups.new_backup_path(language_identifier), language_identifier).unwrap();
+            }
+        }
+
+        let backups = ScratchBackups::lock_in(&root).unwrap();
+        let restored = backups.claim_backups();
+        assert_eq!(restored.len(), languages.len());
+        for ((path, text, language_identifier), expected) in restored.iter().zip(languages.iter()) {
+            assert_eq!(text, expected);
+            assert_eq!(language_identifier, expected);
+            assert_eq!(path.parent(), Some(backups.directory.as_path()));
+        }
+        assert_eq!(backups.new_backup_path(PLAIN_TEXT_LANGUAGE_IDENTIFIER).extension().unwrap(), PLAIN_TEXT_EXTENSION);
+        assert_eq!(extension_to_language("md"), PLAIN_TEXT_LANGUAGE_IDENTIFIER);
+
+        drop(backups);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn backups_are_restored_oldest_first() {
+        let root = test_root("order");
+        {
+            let first_run = ScratchBackups::lock_in(&root).unwrap();
+            for text in ["first", "second"].iter() {
+                write_backup(&first_run.new_backup_path(PLAIN_TEXT_LANGUAGE_IDENTIFIER), text).unwrap();
+            }
+        }
+        {
+            let second_run = ScratchBackups::lock_in(&root).unwrap();
+            write_backup(&second_run.new_backup_path(PLAIN_TEXT_LANGUAGE_IDENTIFIER), "third").unwrap();
+            // Written later, but with an older timestamp
+            write_backup(&second_run.directory.join("0-0-0.txt"), "oldest").unwrap();
+        }
+
+        let backups = ScratchBackups::lock_in(&root).unwrap();
+        let texts: Vec<String> = backups.claim_backups().into_iter().map(|(_, text, _)| text).collect();
+        assert_eq!(texts, ["oldest", "first", "second", "third"]);
+        // The directories of the earlier runs are removed once their backups are claimed
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 1);
+
+        drop(backups);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -1,6 +1,339 @@
+use serde::Deserialize;
+use std::{
+    cmp::{min, max},
+    fs
+};
+
+// The settings are loaded from this file at startup if it exists,
+// the constants below are the defaults for settings missing from it
+pub const SETTINGS_FILE: &str = "settings.json";
+
+// ALT+F switches to the next of the font families at runtime
+pub const FONT_FAMILY: &str = "Consolas";
+pub const FONT_FAMILIES: [&str; 3] = ["Consolas", "Cascadia Code", "Courier New"];
+pub const FONT_SIZE: f32 = 20.0;
+// Whether fonts with ligatures join sequences like => and != into one glyph
+pub const FONT_LIGATURES: bool = true;
+
+#[derive(Copy, Clone, PartialEq, Deserialize)]
+pub enum TextAntialiasing {
+    Aliased,
+    Grayscale,
+    ClearType
+}
+
+// How the edges of the text are smoothed, ClearType uses the subpixels of LCD
+// displays and ALT+A cycles through the modes. Shapes like the caret and the
+// selection are drawn with crisp aliased edges unless antialiased_shapes is set
+pub const TEXT_ANTIALIASING: TextAntialiasing = TextAntialiasing::ClearType;
+pub const ANTIALIASED_SHAPES: bool = false;
+
 pub const SCROLL_LINES_PER_ROLL: usize = 3;
 pub const SCROLL_LINES_PER_DRAG: usize = 1;
 pub const SCROLL_ZOOM_DELTA: f32 = 3.0;
+// How many blank lines the view can scroll past the last line, zero keeps the last line at the bottom
+pub const SCROLL_BEYOND_LAST_LINE: usize = 0;
 pub const NUMBER_OF_SPACES_PER_TAB: usize = 4;
+// How many columns a tab character is drawn as, independent of the indentation
+// inserted by Tab. When unset tabs are as wide as the indentation of the document
+pub const TAB_DISPLAY_WIDTH: Option<usize> = None;
 pub const LINE_SPACING_FACTOR: f32 = 1.2;
+pub const GIT_DIFF_UPDATE_DELAY_MS: u32 = 500;
+pub const FILE_TREE_WIDTH_COLUMNS: usize = 30;
+// The colors are loaded from this file if it exists, see Theme::load_colors
+pub const THEME_FILE: &str = "theme.json";
+// Draws a faint vertical line through the caret column across the view
+pub const SHOW_CARET_COLUMN_GUIDE: bool = false;
+// The caret is drawn as a block over the character it replaces in overwrite mode
+pub const BLOCK_CARET_IN_OVERWRITE_MODE: bool = true;
+// Draws the spaces and tabs inside the selection as dots and arrows
+pub const SHOW_WHITESPACE_IN_SELECTION: bool = false;
+#[derive(Copy, Clone, PartialEq, Deserialize)]
+pub enum CurrentLineHighlight {
+    Off,
+    Gutter,
+    FullWidth
+}
+
+// Marks the line the caret is on, either with a band across the whole
+// line or only by drawing its line number in the caret color
+pub const CURRENT_LINE_HIGHLIGHT: CurrentLineHighlight = CurrentLineHighlight::Off;
+// Ropey counts an empty line after a final line break, when hidden it gets no
+// line number and the view and caret movement stop at the line before it
+pub const SHOW_FINAL_EMPTY_LINE: bool = true;
+// Whether documents start out with long lines wrapped to the window, ALT+Z toggles it per document
+pub const WORD_WRAP: bool = false;
+
+// Reload open documents without unsaved changes when they are
+// modified by another program, e.g. a formatter run on save
+pub const AUTO_RELOAD_CHANGED_FILES: bool = true;
+pub const FILE_CHANGE_POLL_INTERVAL_MS: u32 = 1000;
+// Files are opened with the caret at the end instead of the start,
+// either all of them or those with one of the extensions, e.g. logs
+pub const OPEN_FILES_AT_END: bool = false;
+pub const OPEN_AT_END_EXTENSIONS: [&str; 0] = [];
+// Scratch documents can't be saved, so their text is backed up to a temporary
+// directory once typing pauses and they are restored the next time the editor
+// starts. Closing one that has text asks first, since that discards the backup
+pub const BACKUP_SCRATCH_DOCUMENTS: bool = true;
+pub const SCRATCH_BACKUP_DELAY_MS: u32 = 1000;
+pub const CONFIRM_CLOSING_SCRATCH_DOCUMENTS: bool = true;
 pub const AUTOCOMPLETE_BRACKETS: [(char, char); 3] = [('{', '}'), ('(', ')'), ('[', ']')];
+// Typing a quote inserts the closing quote as well, which quotes depends on the language
+pub const AUTOCLOSE_QUOTES: bool = true;
+// The brackets enclosing the caret are only highlighted
+// when they are within this many chars of it
+pub const MAX_BRACKET_MATCH_DISTANCE: usize = 50_000;
+// Outlines the brackets enclosing the caret, they are
+// matched once the caret has stopped moving for the delay
+pub const HIGHLIGHT_ENCLOSING_BRACKETS: bool = true;
+pub const BRACKET_MATCH_DELAY_MS: u32 = 100;
+
+#[derive(Copy, Clone, PartialEq, Deserialize)]
+pub enum IndentStyle {
+    Spaces,
+    Tabs
+}
+
+// When set, this indentation is always used instead of
+// the indentation detected from the contents of a file
+pub const INDENT_OVERRIDE: Option<(IndentStyle, usize)> = None;
+pub const INDENT_DETECTION_SAMPLE_LINES: usize = 1000;
+
+#[derive(Copy, Clone, PartialEq, Deserialize)]
+pub enum HomeBehavior {
+    Smart,
+    Absolute
+}
+
+// Smart moves Home to the first non-whitespace character and toggles
+// between it and column zero, absolute always moves to column zero
+pub const HOME_BEHAVIOR: HomeBehavior = HomeBehavior::Smart;
+
+// Enter keeps the indentation of the line and indents new scopes,
+// closing brackets and other electric characters reindent the line
+pub const AUTO_INDENT: bool = true;
+
+// Longer runs of blank lines are shortened when normalizing whitespace
+pub const MAX_CONSECUTIVE_BLANK_LINES: usize = 1;
+// Comment blocks are rewrapped to fit within this many columns
+pub const COMMENT_REFLOW_WIDTH: usize = 80;
+// Pressing enter on a blank line never makes the run of
+// blank lines longer than MAX_CONSECUTIVE_BLANK_LINES
+pub const TRIM_BLANK_LINES_ON_ENTER: bool = false;
+// When set, saving removes the blank lines at the end of
+// a file beyond this many, the final line break is kept
+pub const MAX_FINAL_BLANK_LINES: Option<usize> = None;
+// Saving removes the spaces and tabs at the end of every line
+pub const TRIM_TRAILING_WHITESPACE: bool = false;
+
+// CTRL+Space completes the word before the caret from
+// the words in the open documents, which are indexed
+// again once typing pauses for the delay
+pub const WORD_COMPLETION: bool = true;
+pub const MAX_COMPLETION_ITEMS: usize = 10;
+pub const WORD_INDEX_UPDATE_DELAY_MS: u32 = 500;
+
+// Resting the mouse on a word for the delay shows what the language
+// server knows about it in a tooltip at most this many columns wide
+pub const HOVER_DELAY_MS: u32 = 500;
+pub const MAX_HOVER_TOOLTIP_COLUMNS: usize = 80;
+
+// A command the selection is piped through with ALT+! is
+// killed if it hasn't finished after this many milliseconds
+pub const SHELL_COMMAND_TIMEOUT_MS: u32 = 10000;
+
+// Diagnostics less severe than this aren't drawn, numbered like the language
+// server protocol from 1 for errors only to 4 for everything including hints.
+// ALT+D cycles through showing errors, warnings and information
+pub const DIAGNOSTIC_MIN_SEVERITY: u8 = 4;
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub font_family: String,
+    pub font_families: Vec<String>,
+    pub font_size: f32,
+    pub font_ligatures: bool,
+    pub text_antialiasing: TextAntialiasing,
+    pub antialiased_shapes: bool,
+    pub scroll_lines_per_roll: usize,
+    pub scroll_lines_per_drag: usize,
+    pub scroll_zoom_delta: f32,
+    pub scroll_beyond_last_line: usize,
+    pub number_of_spaces_per_tab: usize,
+    pub tab_display_width: Option<usize>,
+    pub line_spacing_factor: f32,
+    pub git_diff_update_delay_ms: u32,
+    pub file_tree_width_columns: usize,
+    pub theme_file: String,
+    pub show_caret_column_guide: bool,
+    pub block_caret_in_overwrite_mode: bool,
+    pub show_whitespace_in_selection: bool,
+    pub current_line_highlight: CurrentLineHighlight,
+    pub show_final_empty_line: bool,
+    pub word_wrap: bool,
+    pub auto_reload_changed_files: bool,
+    pub file_change_poll_interval_ms: u32,
+    pub open_files_at_end: bool,
+    pub open_at_end_extensions: Vec<String>,
+    pub backup_scratch_documents: bool,
+    pub scratch_backup_delay_ms: u32,
+    pub confirm_closing_scratch_documents: bool,
+    pub autocomplete_brackets: Vec<(char, char)>,
+    pub autoclose_quotes: bool,
+    pub max_bracket_match_distance: usize,
+    pub highlight_enclosing_brackets: bool,
+    pub bracket_match_delay_ms: u32,
+    pub indent_override: Option<(IndentStyle, usize)>,
+    pub indent_detection_sample_lines: usize,
+    pub home_behavior: HomeBehavior,
+    pub auto_indent: bool,
+    pub max_consecutive_blank_lines: usize,
+    pub comment_reflow_width: usize,
+    pub trim_blank_lines_on_enter: bool,
+    pub max_final_blank_lines: Option<usize>,
+    pub trim_trailing_whitespace: bool,
+    pub word_completion: bool,
+    pub max_completion_items: usize,
+    pub word_index_update_delay_ms: u32,
+    pub hover_delay_ms: u32,
+    pub max_hover_tooltip_columns: usize,
+    pub shell_command_timeout_ms: u32,
+    pub diagnostic_min_severity: u8
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            font_family: FONT_FAMILY.to_owned(),
+            font_families: FONT_FAMILIES.iter().map(|font_family| font_family.to_string()).collect(),
+            font_size: FONT_SIZE,
+            font_ligatures: FONT_LIGATURES,
+            text_antialiasing: TEXT_ANTIALIASING,
+            antialiased_shapes: ANTIALIASED_SHAPES,
+            scroll_lines_per_roll: SCROLL_LINES_PER_ROLL,
+            scroll_lines_per_drag: SCROLL_LINES_PER_DRAG,
+            scroll_zoom_delta: SCROLL_ZOOM_DELTA,
+            scroll_beyond_last_line: SCROLL_BEYOND_LAST_LINE,
+            number_of_spaces_per_tab: NUMBER_OF_SPACES_PER_TAB,
+            tab_display_width: TAB_DISPLAY_WIDTH,
+            line_spacing_factor: LINE_SPACING_FACTOR,
+            git_diff_update_delay_ms: GIT_DIFF_UPDATE_DELAY_MS,
+            file_tree_width_columns: FILE_TREE_WIDTH_COLUMNS,
+            theme_file: THEME_FILE.to_owned(),
+            show_caret_column_guide: SHOW_CARET_COLUMN_GUIDE,
+            block_caret_in_overwrite_mode: BLOCK_CARET_IN_OVERWRITE_MODE,
+            show_whitespace_in_selection: SHOW_WHITESPACE_IN_SELECTION,
+            current_line_highlight: CURRENT_LINE_HIGHLIGHT,
+            show_final_empty_line: SHOW_FINAL_EMPTY_LINE,
+            word_wrap: WORD_WRAP,
+            auto_reload_changed_files: AUTO_RELOAD_CHANGED_FILES,
+            file_change_poll_interval_ms: FILE_CHANGE_POLL_INTERVAL_MS,
+            open_files_at_end: OPEN_FILES_AT_END,
+            open_at_end_extensions: OPEN_AT_END_EXTENSIONS.iter().map(|extension| extension.to_string()).collect(),
+            backup_scratch_documents: BACKUP_SCRATCH_DOCUMENTS,
+            scratch_backup_delay_ms: SCRATCH_BACKUP_DELAY_MS,
+            confirm_closing_scratch_documents: CONFIRM_CLOSING_SCRATCH_DOCUMENTS,
+            autocomplete_brackets: AUTOCOMPLETE_BRACKETS.to_vec(),
+            autoclose_quotes: AUTOCLOSE_QUOTES,
+            max_bracket_match_distance: MAX_BRACKET_MATCH_DISTANCE,
+            highlight_enclosing_brackets: HIGHLIGHT_ENCLOSING_BRACKETS,
+            bracket_match_delay_ms: BRACKET_MATCH_DELAY_MS,
+            indent_override: INDENT_OVERRIDE,
+            indent_detection_sample_lines: INDENT_DETECTION_SAMPLE_LINES,
+            home_behavior: HOME_BEHAVIOR,
+            auto_indent: AUTO_INDENT,
+            max_consecutive_blank_lines: MAX_CONSECUTIVE_BLANK_LINES,
+            comment_reflow_width: COMMENT_REFLOW_WIDTH,
+            trim_blank_lines_on_enter: TRIM_BLANK_LINES_ON_ENTER,
+            max_final_blank_lines: MAX_FINAL_BLANK_LINES,
+            trim_trailing_whitespace: TRIM_TRAILING_WHITESPACE,
+            word_completion: WORD_COMPLETION,
+            max_completion_items: MAX_COMPLETION_ITEMS,
+            word_index_update_delay_ms: WORD_INDEX_UPDATE_DELAY_MS,
+            hover_delay_ms: HOVER_DELAY_MS,
+            max_hover_tooltip_columns: MAX_HOVER_TOOLTIP_COLUMNS,
+            shell_command_timeout_ms: SHELL_COMMAND_TIMEOUT_MS,
+            diagnostic_min_severity: DIAGNOSTIC_MIN_SEVERITY
+        }
+    }
+}
+
+impl Settings {
+    // Reads the settings from a JSON object with the field names as keys, the
+    // defaults are used if the file is missing, a file that can't be parsed is an error
+    pub fn load(path: &str) -> serde_json::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Self::parse(&contents),
+            Err(_) => Ok(Self::default())
+        }
+    }
+
+    fn parse(contents: &str) -> serde_json::Result<Self> {
+        serde_json::from_str::<Self>(contents).map(Self::validate)
+    }
+
+    // Widths of zero would divide by zero when laying out tabs, the font can't
+    // be made with a size or line spacing that isn't positive, and severities
+    // outside of the ones the language server protocol has are clamped to them
+    fn validate(mut self) -> Self {
+        self.number_of_spaces_per_tab = max(self.number_of_spaces_per_tab, 1);
+        if let Some((_, indent_width)) = &mut self.indent_override {
+            *indent_width = max(*indent_width, 1);
+        }
+        self.tab_display_width = self.tab_display_width.map(|tab_width| max(tab_width, 1));
+        if self.font_size <= 0.0 {
+            self.font_size = FONT_SIZE;
+        }
+        if self.line_spacing_factor <= 0.0 {
+            self.line_spacing_factor = LINE_SPACING_FACTOR;
+        }
+        self.diagnostic_min_severity = min(max(self.diagnostic_min_severity, 1), 4);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_widths_are_raised_to_one() {
+        let settings = Settings::parse(r#"{ "number_of_spaces_per_tab": 0, "indent_override": ["Tabs", 0], "tab_display_width": 0 }"#).unwrap();
+        assert_eq!(settings.number_of_spaces_per_tab, 1);
+        assert!(matches!(settings.indent_override, Some((IndentStyle::Tabs, 1))));
+        assert_eq!(settings.tab_display_width, Some(1));
+    }
+
+    #[test]
+    fn font_sizes_and_line_spacing_must_be_positive() {
+        let settings = Settings::parse(r#"{ "font_size": 0, "line_spacing_factor": -1.5 }"#).unwrap();
+        assert_eq!(settings.font_size, FONT_SIZE);
+        assert_eq!(settings.line_spacing_factor, LINE_SPACING_FACTOR);
+
+        let settings = Settings::parse(r#"{ "font_size": 14.5, "line_spacing_factor": 1.0 }"#).unwrap();
+        assert_eq!(settings.font_size, 14.5);
+        assert_eq!(settings.line_spacing_factor, 1.0);
+    }
+
+    #[test]
+    fn valid_settings_are_kept() {
+        let settings = Settings::parse(r#"{ "number_of_spaces_per_tab": 2, "indent_override": ["Spaces", 8] }"#).unwrap();
+        assert_eq!(settings.number_of_spaces_per_tab, 2);
+        assert!(matches!(settings.indent_override, Some((IndentStyle::Spaces, 8))));
+    }
+
+    #[test]
+    fn only_settings_that_cant_be_parsed_are_an_error() {
+        assert!(Settings::load("this settings file does not exist.json").is_ok());
+        assert!(Settings::parse(r#"{ "font_size": "large" }"#).is_err());
+    }
+
+    #[test]
+    fn diagnostic_severities_are_clamped_to_the_lsp_ones() {
+        assert_eq!(Settings::parse(r#"{ "diagnostic_min_severity": 0 }"#).unwrap().diagnostic_min_severity, 1);
+        assert_eq!(Settings::parse(r#"{ "diagnostic_min_severity": 2 }"#).unwrap().diagnostic_min_severity, 2);
+        assert_eq!(Settings::parse(r#"{ "diagnostic_min_severity": 9 }"#).unwrap().diagnostic_min_severity, 4);
+    }
+}
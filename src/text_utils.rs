@@ -1,5 +1,3 @@
-use crate::settings::AUTOCOMPLETE_BRACKETS;
-
 use std::{
     ffi::OsStr,
     iter::once,
@@ -40,8 +38,9 @@ pub fn is_linebreak(chr: char) -> bool {
     chr == '\u{0085}' || chr == '\u{2028}' || chr == '\u{2029}'
 }
 
-pub fn is_opening_bracket(chr: char) -> Option<(char, char)> {
-    for bracket in &AUTOCOMPLETE_BRACKETS {
+// The bracket pairs are those configured in the settings
+pub fn is_opening_bracket(chr: char, brackets: &[(char, char)]) -> Option<(char, char)> {
+    for bracket in brackets {
         if chr == bracket.0 {
             return Some(*bracket);
         }
@@ -49,8 +48,8 @@ pub fn is_opening_bracket(chr: char) -> Option<(char, char)> {
     None
 }
 
-pub fn is_closing_bracket(chr: char) -> Option<(char, char)> {
-    for bracket in &AUTOCOMPLETE_BRACKETS {
+pub fn is_closing_bracket(chr: char, brackets: &[(char, char)]) -> Option<(char, char)> {
+    for bracket in brackets {
         if chr == bracket.1 {
             return Some(*bracket);
         }
@@ -4,6 +4,11 @@ use bindings::{
 };
 use windows::Result;
 
+use std::{
+    collections::HashMap,
+    fs
+};
+
 const DEFAULT_BACKGROUND_COLOR: D2D1_COLOR_F = create_color(0x282828FF);
 const DEFAULT_STATUS_BAR_COLOR: D2D1_COLOR_F = create_color(0x141414FF);
 const DEFAULT_BRACKET_COLOR: D2D1_COLOR_F = create_color(0xFFFFFFFF);
@@ -21,6 +26,25 @@ const DEFAULT_KEYWORD_COLOR: D2D1_COLOR_F = create_color(0xFB4934FF);
 const DEFAULT_LITERAL_COLOR: D2D1_COLOR_F = create_color(0xFE8019FF);
 const DEFAULT_MACRO_PREPROCESSOR_COLOR: D2D1_COLOR_F = create_color(0xEE7AE9FF);
 const DEFAULT_PRIMITIVE_COLOR: D2D1_COLOR_F = create_color(0xCDF916FF);
+const DEFAULT_DIFF_ADDED_COLOR: D2D1_COLOR_F = create_color(0x98971AFF);
+const DEFAULT_DIFF_MODIFIED_COLOR: D2D1_COLOR_F = create_color(0x458588FF);
+const DEFAULT_DIFF_DELETED_COLOR: D2D1_COLOR_F = create_color(0xCC241DFF);
+const DEFAULT_CARET_COLUMN_COLOR: D2D1_COLOR_F = create_color(0x3C3836FF);
+const DEFAULT_CURRENT_LINE_COLOR: D2D1_COLOR_F = create_color(0x32302FFF);
+const DEFAULT_CONFLICT_CURRENT_COLOR: D2D1_COLOR_F = create_color(0x2F3A2AFF);
+const DEFAULT_CONFLICT_INCOMING_COLOR: D2D1_COLOR_F = create_color(0x2A3540FF);
+const DEFAULT_DIAGNOSTIC_ERROR_COLOR: D2D1_COLOR_F = create_color(0xFB4934FF);
+const DEFAULT_DIAGNOSTIC_WARNING_COLOR: D2D1_COLOR_F = create_color(0xFABD2FFF);
+const DEFAULT_DIAGNOSTIC_INFORMATION_COLOR: D2D1_COLOR_F = create_color(0x83A598FF);
+
+// Colors are written as hex strings in RGBA order, e.g. "0x282828FF"
+fn parse_color(hex: &str) -> Option<D2D1_COLOR_F> {
+    let digits = hex.trim_start_matches("0x").trim_start_matches('#');
+    if digits.len() != 8 {
+        return None;
+    }
+    u32::from_str_radix(digits, 16).ok().map(create_color)
+}
 
 const fn create_color(color: u32) -> D2D1_COLOR_F {
     D2D1_COLOR_F {
@@ -48,8 +72,17 @@ pub struct Theme {
     pub keyword_brush: Option<ID2D1SolidColorBrush>,
     pub literal_brush: Option<ID2D1SolidColorBrush>,
     pub macro_preprocessor_brush: Option<ID2D1SolidColorBrush>,
-    pub primitive_brush: Option<ID2D1SolidColorBrush>
-
+    pub primitive_brush: Option<ID2D1SolidColorBrush>,
+    pub diff_added_brush: Option<ID2D1SolidColorBrush>,
+    pub diff_modified_brush: Option<ID2D1SolidColorBrush>,
+    pub diff_deleted_brush: Option<ID2D1SolidColorBrush>,
+    pub caret_column_brush: Option<ID2D1SolidColorBrush>,
+    pub current_line_brush: Option<ID2D1SolidColorBrush>,
+    pub conflict_current_brush: Option<ID2D1SolidColorBrush>,
+    pub conflict_incoming_brush: Option<ID2D1SolidColorBrush>,
+    pub diagnostic_error_brush: Option<ID2D1SolidColorBrush>,
+    pub diagnostic_warning_brush: Option<ID2D1SolidColorBrush>,
+    pub diagnostic_information_brush: Option<ID2D1SolidColorBrush>
 }
 
 impl Default for Theme {
@@ -72,14 +105,43 @@ impl Default for Theme {
             literal_brush: None,
             macro_preprocessor_brush: None,
             primitive_brush: None,
+            diff_added_brush: None,
+            diff_modified_brush: None,
+            diff_deleted_brush: None,
+            caret_column_brush: None,
+            current_line_brush: None,
+            conflict_current_brush: None,
+            conflict_incoming_brush: None,
+            diagnostic_error_brush: None,
+            diagnostic_warning_brush: None,
+            diagnostic_information_brush: None,
         }
     }
 }
 
 impl Theme {
-    pub fn new_default(render_target: &ID2D1HwndRenderTarget) -> Result<Self> {
+    // Loads the colors from a JSON table mapping names like "background" or 
+    // "keyword" to colors, colors missing from the table keep their default.
+    // A missing file leaves every color at its default, a file that can't be
+    // parsed or has colors that aren't hex strings is an error
+    pub fn load_colors(path: &str) -> std::result::Result<HashMap<String, D2D1_COLOR_F>, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return Ok(HashMap::new())
+        };
+
+        let table = serde_json::from_str::<HashMap<String, String>>(&contents).map_err(|error| error.to_string())?;
+        table.into_iter().map(|(name, hex)| match parse_color(&hex) {
+            Some(color) => Ok((name, color)),
+            None => Err(format!("{} isn't a color like 0x282828FF", hex))
+        }).collect()
+    }
+
+    pub fn new(render_target: &ID2D1HwndRenderTarget, colors: &HashMap<String, D2D1_COLOR_F>) -> Result<Self> {
+        let color = |name: &str, default_color: D2D1_COLOR_F| *colors.get(name).unwrap_or(&default_color);
+
         let mut theme = Self {
-            background_color: DEFAULT_BACKGROUND_COLOR,
+            background_color: color("background", DEFAULT_BACKGROUND_COLOR),
             status_bar_brush: None,
             bracket_brush: None,
             text_brush: None,
@@ -95,7 +157,17 @@ impl Theme {
             keyword_brush: None,
             literal_brush: None,
             macro_preprocessor_brush: None,
-            primitive_brush: None
+            primitive_brush: None,
+            diff_added_brush: None,
+            diff_modified_brush: None,
+            diff_deleted_brush: None,
+            caret_column_brush: None,
+            current_line_brush: None,
+            conflict_current_brush: None,
+            conflict_incoming_brush: None,
+            diagnostic_error_brush: None,
+            diagnostic_warning_brush: None,
+            diagnostic_information_brush: None
         };
 
         let brush_properties = D2D1_BRUSH_PROPERTIES {
@@ -104,22 +176,32 @@ impl Theme {
         };
 
         unsafe {
-            render_target.CreateSolidColorBrush(&DEFAULT_TEXT_COLOR, &brush_properties, &mut theme.text_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_STATUS_BAR_COLOR, &brush_properties, &mut theme.status_bar_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_BRACKET_COLOR, &brush_properties, &mut theme.bracket_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_LINE_NUMBER_COLOR, &brush_properties, &mut theme.line_number_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_CARET_COLOR, &brush_properties, &mut theme.caret_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_SELECTION_COLOR, &brush_properties, &mut theme.selection_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_VARIABLE_COLOR, &brush_properties, &mut theme.variable_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_FUNCTION_COLOR, &brush_properties, &mut theme.function_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_METHOD_COLOR, &brush_properties, &mut theme.method_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_CLASS_COLOR, &brush_properties, &mut theme.class_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_ENUM_COLOR, &brush_properties, &mut theme.enum_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_COMMENT_COLOR, &brush_properties, &mut theme.comment_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_KEYWORD_COLOR, &brush_properties, &mut theme.keyword_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_LITERAL_COLOR, &brush_properties, &mut theme.literal_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_MACRO_PREPROCESSOR_COLOR, &brush_properties, &mut theme.macro_preprocessor_brush).ok()?;
-            render_target.CreateSolidColorBrush(&DEFAULT_PRIMITIVE_COLOR, &brush_properties, &mut theme.primitive_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("text", DEFAULT_TEXT_COLOR), &brush_properties, &mut theme.text_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("status_bar", DEFAULT_STATUS_BAR_COLOR), &brush_properties, &mut theme.status_bar_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("bracket", DEFAULT_BRACKET_COLOR), &brush_properties, &mut theme.bracket_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("line_number", DEFAULT_LINE_NUMBER_COLOR), &brush_properties, &mut theme.line_number_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("caret", DEFAULT_CARET_COLOR), &brush_properties, &mut theme.caret_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("selection", DEFAULT_SELECTION_COLOR), &brush_properties, &mut theme.selection_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("variable", DEFAULT_VARIABLE_COLOR), &brush_properties, &mut theme.variable_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("function", DEFAULT_FUNCTION_COLOR), &brush_properties, &mut theme.function_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("method", DEFAULT_METHOD_COLOR), &brush_properties, &mut theme.method_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("class", DEFAULT_CLASS_COLOR), &brush_properties, &mut theme.class_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("enum", DEFAULT_ENUM_COLOR), &brush_properties, &mut theme.enum_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("comment", DEFAULT_COMMENT_COLOR), &brush_properties, &mut theme.comment_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("keyword", DEFAULT_KEYWORD_COLOR), &brush_properties, &mut theme.keyword_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("literal", DEFAULT_LITERAL_COLOR), &brush_properties, &mut theme.literal_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("macro_preprocessor", DEFAULT_MACRO_PREPROCESSOR_COLOR), &brush_properties, &mut theme.macro_preprocessor_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("primitive", DEFAULT_PRIMITIVE_COLOR), &brush_properties, &mut theme.primitive_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("diff_added", DEFAULT_DIFF_ADDED_COLOR), &brush_properties, &mut theme.diff_added_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("diff_modified", DEFAULT_DIFF_MODIFIED_COLOR), &brush_properties, &mut theme.diff_modified_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("diff_deleted", DEFAULT_DIFF_DELETED_COLOR), &brush_properties, &mut theme.diff_deleted_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("caret_column", DEFAULT_CARET_COLUMN_COLOR), &brush_properties, &mut theme.caret_column_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("current_line", DEFAULT_CURRENT_LINE_COLOR), &brush_properties, &mut theme.current_line_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("conflict_current", DEFAULT_CONFLICT_CURRENT_COLOR), &brush_properties, &mut theme.conflict_current_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("conflict_incoming", DEFAULT_CONFLICT_INCOMING_COLOR), &brush_properties, &mut theme.conflict_incoming_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("diagnostic_error", DEFAULT_DIAGNOSTIC_ERROR_COLOR), &brush_properties, &mut theme.diagnostic_error_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("diagnostic_warning", DEFAULT_DIAGNOSTIC_WARNING_COLOR), &brush_properties, &mut theme.diagnostic_warning_brush).ok()?;
+            render_target.CreateSolidColorBrush(&color("diagnostic_information", DEFAULT_DIAGNOSTIC_INFORMATION_COLOR), &brush_properties, &mut theme.diagnostic_information_brush).ok()?;
         }
 
         Ok(theme)
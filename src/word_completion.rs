@@ -0,0 +1,114 @@
+use crate::text_utils;
+
+use std::{
+    collections::BTreeSet,
+    ops::Bound
+};
+
+// The words of a document, kept sorted so the words
+// starting with a prefix are next to each other
+pub struct WordIndex {
+    words: BTreeSet<String>
+}
+
+impl WordIndex {
+    pub fn new(text: &str) -> Self {
+        let mut word_index = Self {
+            words: BTreeSet::new()
+        };
+        word_index.update(text);
+        word_index
+    }
+
+    // Words starting with a digit are numbers, which aren't worth completing
+    pub fn update(&mut self, text: &str) {
+        self.words = text.split(|chr| !text_utils::is_word(chr))
+            .filter(|word| word.chars().next().map_or(false, |chr| !chr.is_numeric()))
+            .map(|word| word.to_owned())
+            .collect();
+    }
+
+    fn words_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a String> + 'a {
+        self.words.range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(move |word| word.starts_with(prefix))
+            .filter(move |word| word.as_str() != prefix)
+    }
+}
+
+// The words starting with the prefix in any of the indices, sorted and without duplicates
+pub fn complete_word<'a, I: Iterator<Item = &'a WordIndex>>(prefix: &str, word_indices: I, max_items: usize) -> Vec<String> {
+    let mut words = BTreeSet::new();
+    for word_index in word_indices {
+        words.extend(word_index.words_with_prefix(prefix));
+    }
+    words.into_iter().take(max_items).cloned().collect()
+}
+
+// The completions for the word before the caret, shown in a list below it
+pub struct CompletionList {
+    pub items: Vec<String>,
+    pub selected: usize,
+
+    // The char position in the buffer where the completed word starts
+    pub word_start: usize
+}
+
+impl CompletionList {
+    pub fn select_next(&mut self) {
+        self.selected = (self.selected + 1) % self.items.len();
+    }
+
+    pub fn select_previous(&mut self) {
+        self.selected = (self.selected + self.items.len() - 1) % self.items.len();
+    }
+
+    pub fn get_selected_item(&self) -> &str {
+        &self.items[self.selected]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::iter;
+
+    #[test]
+    fn words_starting_with_the_prefix_are_completed() {
+        let word_index = WordIndex::new("print(prefix, pretty); private_value = 2 + prior");
+        assert_eq!(complete_word("pri", iter::once(&word_index), 10), ["print", "prior", "private_value"]);
+        assert_eq!(complete_word("pri", iter::once(&word_index), 2), ["print", "prior"]);
+        assert!(complete_word("x", iter::once(&word_index), 10).is_empty());
+    }
+
+    #[test]
+    fn numbers_are_not_completed() {
+        let word_index = WordIndex::new("value 1234 1st x2");
+        assert!(complete_word("1", iter::once(&word_index), 10).is_empty());
+        assert_eq!(complete_word("x", iter::once(&word_index), 10), ["x2"]);
+    }
+
+    #[test]
+    fn the_word_being_typed_is_not_completed() {
+        // The word before the caret is in the text, and so in the index
+        let word_index = WordIndex::new("count counter cou");
+        assert_eq!(complete_word("cou", iter::once(&word_index), 10), ["count", "counter"]);
+        assert_eq!(complete_word("count", iter::once(&word_index), 10), ["counter"]);
+    }
+
+    #[test]
+    fn words_from_every_index_are_merged() {
+        let first = WordIndex::new("alpha beta");
+        let second = WordIndex::new("alpha alphabet");
+        assert_eq!(complete_word("al", vec![&first, &second].into_iter(), 10), ["alpha", "alphabet"]);
+    }
+
+    #[test]
+    fn updating_the_index_follows_edits() {
+        let mut word_index = WordIndex::new("old_name = 1");
+        assert_eq!(complete_word("old", iter::once(&word_index), 10), ["old_name"]);
+
+        word_index.update("new_name = 1");
+        assert!(complete_word("old", iter::once(&word_index), 10).is_empty());
+        assert_eq!(complete_word("new", iter::once(&word_index), 10), ["new_name"]);
+    }
+}